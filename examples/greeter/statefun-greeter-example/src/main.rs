@@ -6,7 +6,7 @@ use statefun::transport::hyper::HyperHttpTransport;
 use statefun::transport::Transport;
 use statefun::{
     specs, Address, Context, Effects, EgressIdentifier, FunctionRegistry, FunctionType, Message,
-    TypeName,
+    RegistrationError, TypeName,
 };
 use types::{EgressRecord, MyUserProfile, UserLogin};
 
@@ -17,7 +17,7 @@ fn main() -> anyhow::Result<()> {
     env_logger::init();
 
     let mut function_registry = FunctionRegistry::new();
-    register_functions(&mut function_registry);
+    register_functions(&mut function_registry)?;
 
     let hyper_transport = HyperHttpTransport::new("0.0.0.0:1108".parse()?);
     hyper_transport.run(function_registry)?;
@@ -25,18 +25,22 @@ fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-pub fn register_functions(function_registry: &mut FunctionRegistry) {
+pub fn register_functions(
+    function_registry: &mut FunctionRegistry,
+) -> Result<(), RegistrationError> {
     function_registry.register_fn(
         user_function_type(),
         specs![seen_count_spec(), last_seen_timestamp_spec()],
         user,
-    );
+    )?;
 
     function_registry.register_fn(
         greet_function_type(),
         vec![], // no state
         greet,
-    );
+    )?;
+
+    Ok(())
 }
 
 pub fn user(context: Context, message: Message) -> Effects {
@@ -53,20 +57,20 @@ pub fn user(context: Context, message: Message) -> Effects {
         Err(error) => panic!("Could not receive UserLogin: {:?}", error),
     };
 
-    let seen_count = context.get_state(seen_count_spec());
-    let seen_count = match seen_count {
-        Some(count) => count.unwrap() + 1,
-        None => 1,
-    };
+    // logins are sent by the ingress, so there is no caller we could reply to
+    if context.is_ingress() {
+        log::debug!("Received login of {:?} from ingress", login.user_name);
+    }
+
+    let seen_count = context.get_state_or_default(seen_count_spec()).unwrap() + 1;
 
     let now_ms = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
         Ok(n) => n.as_secs() as i64,
         Err(_) => panic!("SystemTime before UNIX EPOCH!"),
     };
-    let last_seen_timestamp_ms = match context.get_state(last_seen_timestamp_spec()) {
-        Some(seen_ms) => seen_ms.unwrap(),
-        None => now_ms,
-    };
+    let last_seen_timestamp_ms = context
+        .get_state_or(last_seen_timestamp_spec(), now_ms)
+        .unwrap();
 
     let mut effects = Effects::new();
     effects
@@ -111,7 +115,7 @@ pub fn greet(_context: Context, message: Message) -> Effects {
 
     effects
         .egress(
-            EgressIdentifier::new("io.statefun.playground", "egress"),
+            EgressIdentifier::from_static("io.statefun.playground", "egress"),
             &egress_record,
         )
         .unwrap();
@@ -139,9 +143,9 @@ fn create_greetings_message(profile: UserProfile) -> String {
 
 // lazy_static does not work here for some reason
 fn user_function_type() -> FunctionType {
-    FunctionType::new("greeter.fns", "user")
+    FunctionType::from_static("greeter.fns", "user")
 }
 
 fn greet_function_type() -> FunctionType {
-    FunctionType::new("greeter.fns", "greet")
+    FunctionType::from_static("greeter.fns", "greet")
 }
@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use statefun::StatefunJson;
 use statefun_greeter_example_proto::example::UserProfile;
 
 ///
@@ -10,7 +11,8 @@ pub enum LoginType {
 }
 
 ///
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, StatefunJson, Debug)]
+#[statefun(typename = "greeter.types/UserLogin")]
 pub struct UserLogin {
     pub user_id: String,
     pub user_name: String,
@@ -18,7 +20,8 @@ pub struct UserLogin {
 }
 
 /// A customized response sent to the user
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, StatefunJson, Debug)]
+#[statefun(typename = "io.statefun.playground/EgressRecord")]
 pub struct EgressRecord {
     // The name of the user being greeted
     pub topic: String,
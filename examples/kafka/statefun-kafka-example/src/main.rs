@@ -10,11 +10,11 @@ use statefun_kafka_example_proto::example::GreetRequest;
 use statefun_kafka_example_proto::example::GreetResponse;
 
 fn greeter_function_type() -> FunctionType {
-    FunctionType::new("example", "greeter")
+    FunctionType::from_static("example", "greeter")
 }
 
 fn relay_function_type() -> FunctionType {
-    FunctionType::new("example", "relay")
+    FunctionType::from_static("example", "relay")
 }
 
 // 'seen_count' will automatically be purged 5 seconds after the last write
@@ -64,11 +64,7 @@ pub fn greet(context: Context, message: Message) -> Effects {
 
     log::info!("We should greet {:?}", greet_request.get_name());
 
-    let seen_count = context.get_state(seen_count_spec());
-    let seen_count = match seen_count {
-        Some(count) => count.unwrap() + 1,
-        None => 1,
-    };
+    let seen_count = context.get_state_or_default(seen_count_spec()).unwrap() + 1;
 
     log::info!(
         "We have seen {:?} {:?} times.",
@@ -139,7 +135,7 @@ pub fn relay(_context: Context, message: Message) -> Effects {
 
     effects
         .kafka_keyed_egress(
-            EgressIdentifier::new("example", "greets"),
+            EgressIdentifier::from_static("example", "greets"),
             "greetings",
             my_greet.0.get_name(),
             &my_greet,
@@ -154,9 +150,9 @@ fn main() -> anyhow::Result<()> {
 
     let mut function_registry = FunctionRegistry::new();
 
-    function_registry.register_fn(greeter_function_type(), specs![seen_count_spec()], greet);
+    function_registry.register_fn(greeter_function_type(), specs![seen_count_spec()], greet)?;
 
-    function_registry.register_fn(relay_function_type(), vec![], relay);
+    function_registry.register_fn(relay_function_type(), vec![], relay)?;
 
     let hyper_transport = HyperHttpTransport::new("0.0.0.0:5000".parse()?);
     hyper_transport.run(function_registry)?;
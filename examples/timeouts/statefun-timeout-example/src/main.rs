@@ -6,7 +6,7 @@ use statefun::transport::hyper::HyperHttpTransport;
 use statefun::transport::Transport;
 use statefun::{
     specs, Address, Context, Effects, EgressIdentifier, FunctionRegistry, FunctionType, Message,
-    TypeName,
+    RegistrationError, TypeName,
 };
 use types::{DelayedMessage, EgressRecord, MyUserProfile, UserLogin};
 
@@ -18,7 +18,7 @@ fn main() -> anyhow::Result<()> {
     env_logger::init();
 
     let mut function_registry = FunctionRegistry::new();
-    register_functions(&mut function_registry);
+    register_functions(&mut function_registry)?;
 
     let hyper_transport = HyperHttpTransport::new("0.0.0.0:1108".parse()?);
     hyper_transport.run(function_registry)?;
@@ -26,7 +26,9 @@ fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-pub fn register_functions(function_registry: &mut FunctionRegistry) {
+pub fn register_functions(
+    function_registry: &mut FunctionRegistry,
+) -> Result<(), RegistrationError> {
     function_registry.register_fn(
         user_function_type(),
         specs![
@@ -35,19 +37,21 @@ pub fn register_functions(function_registry: &mut FunctionRegistry) {
             last_seen_timestamp_spec()
         ],
         user,
-    );
+    )?;
 
     function_registry.register_fn(
         greet_function_type(),
         vec![], // no state
         greet,
-    );
+    )?;
 
     function_registry.register_fn(
         delayed_function_type(),
         vec![], // no state
         delayed,
-    );
+    )?;
+
+    Ok(())
 }
 
 pub fn user(context: Context, message: Message) -> Effects {
@@ -66,11 +70,7 @@ pub fn user(context: Context, message: Message) -> Effects {
 
     log::info!("We should update user count {:?}", &user_login.user_name);
 
-    let seen_count = context.get_state(seen_count_spec());
-    let seen_count = match seen_count {
-        Some(count) => count.unwrap() + 1,
-        None => 1,
-    };
+    let seen_count = context.get_state_or_default(seen_count_spec()).unwrap() + 1;
 
     let is_first_visit = context.get_state(is_first_visit_spec()).is_none();
 
@@ -96,10 +96,9 @@ pub fn user(context: Context, message: Message) -> Effects {
         let delayed_message = DelayedMessage::new(current_time);
 
         effects
-            .send_after(
+            .send_after_cancellable_by_address(
                 Address::new(delayed_function_type(), &user_login.user_name),
                 Duration::from_secs(3),
-                "cancel-token".to_string(),
                 &delayed_message,
             )
             .unwrap();
@@ -112,7 +111,10 @@ pub fn user(context: Context, message: Message) -> Effects {
             .unwrap();
 
         // cancel any pending message
-        effects.cancel_delayed_message("cancel-token".to_string());
+        effects.cancel_delayed_messages_to(Address::new(
+            delayed_function_type(),
+            &user_login.user_name,
+        ));
     }
 
     effects
@@ -154,7 +156,7 @@ pub fn greet(_context: Context, message: Message) -> Effects {
 
     effects
         .egress(
-            EgressIdentifier::new("io.statefun.playground", "egress"),
+            EgressIdentifier::from_static("io.statefun.playground", "egress"),
             &egress_record,
         )
         .unwrap();
@@ -202,13 +204,13 @@ fn create_greetings_message(profile: UserProfile) -> String {
 
 // lazy_static does not work here for some reason
 fn user_function_type() -> FunctionType {
-    FunctionType::new("greeter.fns", "user")
+    FunctionType::from_static("greeter.fns", "user")
 }
 
 fn greet_function_type() -> FunctionType {
-    FunctionType::new("greeter.fns", "greet")
+    FunctionType::from_static("greeter.fns", "greet")
 }
 
 fn delayed_function_type() -> FunctionType {
-    FunctionType::new("greeter.fns", "delayed")
+    FunctionType::from_static("greeter.fns", "delayed")
 }
@@ -1,18 +1,17 @@
-use statefun::{Expiration, ExpirationType, ValueSpec};
+use statefun::ValueSpec;
 use std::time::Duration;
 
 // 'seen_count' will automatically be purged 5 seconds after the last write
 pub fn seen_count_spec() -> ValueSpec<i32> {
-    ValueSpec::<i32>::new(
-        "seen_count",
-        Expiration::new(ExpirationType::AfterWrite, Duration::from_secs(5)),
-    )
+    ValueSpec::<i32>::builder("seen_count")
+        .expire_after_write(Duration::from_secs(5))
+        .build()
 }
 
 pub fn is_first_visit_spec() -> ValueSpec<bool> {
-    ValueSpec::<bool>::new("is_first_visit", Expiration::never())
+    ValueSpec::<bool>::builder("is_first_visit").build()
 }
 
 pub fn last_seen_timestamp_spec() -> ValueSpec<i64> {
-    ValueSpec::<i64>::new("last_seen_timestamp", Expiration::never())
+    ValueSpec::<i64>::builder("last_seen_timestamp").build()
 }
@@ -0,0 +1,112 @@
+//! Derive macros for the [statefun](https://docs.rs/statefun/) crate. Use these through the
+//! `derive` feature of `statefun`, which re-exports them.
+
+#![deny(missing_docs)]
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, DeriveInput, LitStr};
+
+/// Derives `Serializable` and `TypeName` for a type that implements `serde::Serialize` and
+/// `serde::Deserialize`, encoding it as JSON. The typename is given using the `statefun`
+/// attribute:
+///
+/// ```
+/// use serde::{Deserialize, Serialize};
+/// use statefun::{Serializable, StatefunJson, TypeName};
+///
+/// #[derive(Serialize, Deserialize, StatefunJson, Debug, PartialEq)]
+/// #[statefun(typename = "greeter.types/UserLogin")]
+/// struct UserLogin {
+///     user_name: String,
+/// }
+///
+/// let login = UserLogin {
+///     user_name: "flink".to_string(),
+/// };
+/// let typename = UserLogin::get_typename().to_string();
+/// let serialized = Serializable::serialize(&login, typename.clone())?;
+/// assert_eq!(serialized, br#"{"user_name":"flink"}"#);
+/// let deserialized = <UserLogin as Serializable<UserLogin>>::deserialize(typename, &serialized)?;
+/// assert_eq!(deserialized, login);
+/// # Ok::<(), String>(())
+/// ```
+///
+/// The typename is required:
+///
+/// ```compile_fail
+/// use serde::{Deserialize, Serialize};
+/// use statefun::StatefunJson;
+///
+/// #[derive(Serialize, Deserialize, StatefunJson)]
+/// struct UserLogin {
+///     user_name: String,
+/// }
+/// ```
+#[proc_macro_derive(StatefunJson, attributes(statefun))]
+pub fn derive_statefun_json(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match statefun_json(input) {
+        Ok(tokens) => tokens.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+fn statefun_json(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let typename = typename(&input)?;
+    let name = &input.ident;
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::statefun::TypeName for #name #type_generics #where_clause {
+            fn get_typename() -> &'static str {
+                #typename
+            }
+        }
+
+        impl #impl_generics ::statefun::Serializable<#name #type_generics>
+            for #name #type_generics #where_clause
+        {
+            fn serialize(&self, _typename: String) -> Result<Vec<u8>, String> {
+                ::statefun::__private::serde_json::to_vec(self).map_err(|error| error.to_string())
+            }
+
+            fn deserialize(
+                _typename: String,
+                buffer: &[u8],
+            ) -> Result<#name #type_generics, String> {
+                ::statefun::__private::serde_json::from_slice(buffer)
+                    .map_err(|error| error.to_string())
+            }
+        }
+    })
+}
+
+/// Returns the typename of the `#[statefun(typename = "...")]` attribute.
+fn typename(input: &DeriveInput) -> syn::Result<LitStr> {
+    let mut typename = None;
+    for attribute in input.attrs.iter() {
+        if !attribute.path().is_ident("statefun") {
+            continue;
+        }
+        attribute.parse_nested_meta(|meta| {
+            if meta.path.is_ident("typename") {
+                typename = Some(meta.value()?.parse::<LitStr>()?);
+                Ok(())
+            } else {
+                Err(meta.error("unsupported statefun attribute, expected `typename`"))
+            }
+        })?;
+    }
+    match typename {
+        Some(typename) if typename.value().contains('/') => Ok(typename),
+        Some(typename) => Err(syn::Error::new(
+            typename.span(),
+            "the typename must have the form \"namespace/name\"",
+        )),
+        None => Err(syn::Error::new_spanned(
+            &input.ident,
+            "missing #[statefun(typename = \"namespace/name\")] attribute",
+        )),
+    }
+}
@@ -0,0 +1,72 @@
+//! Measures invoking large batches through the `FunctionRegistry`, where the function reads its
+//! own and its caller's address in every invocation.
+//!
+//! Run using `cargo bench -p statefun --bench batch`.
+
+use std::hint::black_box;
+use std::time::Instant;
+
+use statefun::testing::BatchBuilder;
+use statefun::{Address, Context, Effects, FunctionRegistry, FunctionType, Message};
+
+const ITERATIONS: u32 = 200;
+
+const GREETER: FunctionType = FunctionType::from_static("greeter.fns", "greet");
+
+fn measure(name: &str, registry: &FunctionRegistry, batch: &BatchBuilder) {
+    let to_function = batch.to_proto();
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        black_box(
+            batch
+                .invoke(registry)
+                .expect("invoking the batch failed")
+                .into_proto(),
+        );
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{:<32} {:>10.1} ns/invocation",
+        name,
+        elapsed.as_nanos() as f64
+            / ITERATIONS as f64
+            / to_function.get_invocation().get_invocations().len() as f64
+    );
+}
+
+fn batch(invocations: usize) -> BatchBuilder {
+    let caller = Address::new(FunctionType::new("greeter.fns", "caller"), "caller");
+    (0..invocations).fold(
+        BatchBuilder::new(Address::new(GREETER.clone(), "flink")),
+        |batch, i| {
+            batch
+                .with_message_from(caller.clone(), &format!("message {}", i))
+                .expect("could not serialize the message")
+        },
+    )
+}
+
+fn main() {
+    let mut registry = FunctionRegistry::new();
+    registry
+        .register_fn(
+            GREETER.clone(),
+            vec![],
+            |context: Context, _message: Message| {
+                for _ in 0..4 {
+                    black_box(context.self_address());
+                    black_box(context.caller_address());
+                }
+                Effects::new()
+            },
+        )
+        .expect("could not register the function");
+
+    for invocations in [10, 100, 1_000] {
+        measure(
+            &format!("batch of {} invocations", invocations),
+            &registry,
+            &batch(invocations),
+        );
+    }
+}
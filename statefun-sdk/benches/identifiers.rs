@@ -0,0 +1,42 @@
+//! Compares constructing `FunctionType`/`EgressIdentifier` on every invocation with creating them
+//! once using `from_static()` and cloning them.
+//!
+//! Run using `cargo bench -p statefun --bench identifiers`.
+
+use std::hint::black_box;
+use std::time::Instant;
+
+use statefun::{Address, EgressIdentifier, FunctionType};
+
+const ITERATIONS: u32 = 1_000_000;
+
+const GREETER: FunctionType = FunctionType::from_static("greeter.fns", "greet");
+const EGRESS: EgressIdentifier = EgressIdentifier::from_static("io.statefun.playground", "egress");
+
+fn measure<F: Fn()>(name: &str, invocation: F) {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        invocation();
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{:<32} {:>8.1} ns/invocation",
+        name,
+        elapsed.as_nanos() as f64 / ITERATIONS as f64
+    );
+}
+
+fn main() {
+    measure("new() per invocation", || {
+        black_box(Address::new(
+            FunctionType::new("greeter.fns", "greet"),
+            "id",
+        ));
+        black_box(EgressIdentifier::new("io.statefun.playground", "egress"));
+    });
+
+    measure("from_static() + clone()", || {
+        black_box(Address::new(GREETER.clone(), "id"));
+        black_box(EGRESS.clone());
+    });
+}
@@ -0,0 +1,48 @@
+//! Measures a send-heavy function: one that serializes and sends a batch of built-in values on
+//! every invocation.
+//!
+//! Run using `cargo bench -p statefun --bench serialization`.
+
+use std::hint::black_box;
+use std::time::Instant;
+
+use statefun::{Address, Effects, FunctionType};
+
+const ITERATIONS: u32 = 100_000;
+const MESSAGES_PER_INVOCATION: i32 = 16;
+
+const COUNTER: FunctionType = FunctionType::from_static("bench.fns", "counter");
+
+fn measure<F: Fn()>(name: &str, invocation: F) {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        invocation();
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{:<32} {:>8.1} ns/invocation",
+        name,
+        elapsed.as_nanos() as f64 / ITERATIONS as f64
+    );
+}
+
+fn main() {
+    let target = Address::new(COUNTER, "target");
+    let greeting = "Hello, this is a greeting of moderate length!".to_string();
+
+    measure("send i32", || {
+        let mut effects = Effects::new();
+        for i in 0..MESSAGES_PER_INVOCATION {
+            effects.send(target.clone(), &i).unwrap();
+        }
+        black_box(effects);
+    });
+
+    measure("send String", || {
+        let mut effects = Effects::new();
+        for _ in 0..MESSAGES_PER_INVOCATION {
+            effects.send(target.clone(), &greeting).unwrap();
+        }
+        black_box(effects);
+    });
+}
@@ -1,6 +1,9 @@
 use crate::FunctionType;
 use statefun_proto::request_reply::Address as ProtoAddress;
 use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+#[cfg(feature = "uuid")]
+use uuid::Uuid;
 
 /// The unique identity of an individual stateful function.
 ///
@@ -10,7 +13,7 @@ use std::fmt::{Display, Formatter};
 ///
 /// This must be used when sending messages to stateful functions as part of the function
 /// [Effects](Effects).
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Address {
     /// `FunctionType` of the stateful function that this `Address` refers to.
     pub function_type: FunctionType,
@@ -34,6 +37,44 @@ impl Address {
         }
     }
 
+    /// Creates a new `Address` whose id is the given UUID in its canonical form, 32 lowercase hex
+    /// digits in groups of 8-4-4-4-12. Formatting UUID keys the same way everywhere ensures that
+    /// all senders address the same function instance.
+    #[cfg(feature = "uuid")]
+    pub fn new_uuid(function_type: FunctionType, uuid: Uuid) -> Self {
+        Address {
+            function_type,
+            id: uuid.hyphenated().to_string(),
+        }
+    }
+
+    /// Parses the id of this `Address` as a UUID in the canonical form that `new_uuid()` produces.
+    /// Ids in any other form are rejected, including UUIDs with uppercase digits or without
+    /// hyphens, because they would address a different function instance.
+    #[cfg(feature = "uuid")]
+    pub fn uuid_id(&self) -> Result<Uuid, String> {
+        match Uuid::parse_str(&self.id) {
+            Ok(uuid) if uuid.hyphenated().to_string() == self.id => Ok(uuid),
+            _ => Err(format!("Id is not a canonical UUID: {:?}", self.id)),
+        }
+    }
+
+    /// Parses the id of this `Address`, for example ids that are numbers or that implement
+    /// `FromStr` for a structured key.
+    pub fn parse_id<T: FromStr>(&self) -> Result<T, T::Err> {
+        self.id.parse()
+    }
+
+    /// Splits the id of this `Address` at every occurrence of the separator, for structured ids
+    /// such as `"tenant:123:user:456"`. An empty id has no parts, otherwise separators at the
+    /// start or end, or repeated separators, produce empty parts, like `str::split()`.
+    pub fn id_parts(&self, separator: char) -> Vec<&str> {
+        if self.id.is_empty() {
+            return Vec::new();
+        }
+        self.id.split(separator).collect()
+    }
+
     /// Converts the Protobuf `Address` into an `Address`. We don't implement `From`/`Into` for this
     /// because we want to keep it out of the public API.
     pub fn from_proto(proto_address: &ProtoAddress) -> Self {
@@ -56,3 +97,73 @@ impl Address {
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{Address, FunctionType};
+
+    fn function_type() -> FunctionType {
+        FunctionType::new("namespace", "foo")
+    }
+
+    #[test]
+    fn parse_id() {
+        let address = Address::new(function_type(), "42");
+        assert_eq!(address.parse_id::<u64>(), Ok(42));
+        assert!(Address::new(function_type(), "user-42")
+            .parse_id::<u64>()
+            .is_err());
+    }
+
+    #[test]
+    fn id_parts() {
+        let address = Address::new(function_type(), "tenant:123:user:456");
+        assert_eq!(address.id_parts(':'), vec!["tenant", "123", "user", "456"]);
+
+        assert!(Address::new(function_type(), "").id_parts(':').is_empty());
+        assert_eq!(
+            Address::new(function_type(), "tenant:").id_parts(':'),
+            vec!["tenant", ""]
+        );
+        assert_eq!(
+            Address::new(function_type(), ":tenant::").id_parts(':'),
+            vec!["", "tenant", "", ""]
+        );
+        assert_eq!(
+            Address::new(function_type(), ":").id_parts(':'),
+            vec!["", ""]
+        );
+    }
+
+    #[cfg(feature = "uuid")]
+    #[test]
+    fn uuid_round_trip() {
+        let uuid = uuid::Uuid::from_u128(0x67e5_5044_10b1_426f_9247_bb68_0e5f_e0c8);
+        let address = Address::new_uuid(function_type(), uuid);
+        assert_eq!(address.id, "67e55044-10b1-426f-9247-bb680e5fe0c8");
+        assert_eq!(address.uuid_id(), Ok(uuid));
+
+        let uuid = uuid::Uuid::from_u128(1);
+        let address = Address::new_uuid(function_type(), uuid);
+        assert_eq!(address.id, "00000000-0000-0000-0000-000000000001");
+        assert_eq!(address.uuid_id(), Ok(uuid));
+    }
+
+    #[cfg(feature = "uuid")]
+    #[test]
+    fn reject_non_uuid_ids() {
+        for id in &[
+            "user-42",
+            "67e5504410b1426f9247bb680e5fe0c8",
+            "67E55044-10B1-426F-9247-BB680E5FE0C8",
+            "67e55044-10b1-426f-9247-bb680e5fe0cg",
+            "67e55044+10b1-426f-9247-bb680e5fe0c8",
+        ] {
+            assert!(
+                Address::new(function_type(), id).uuid_id().is_err(),
+                "{}",
+                id
+            );
+        }
+    }
+}
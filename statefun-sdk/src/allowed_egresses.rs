@@ -0,0 +1,89 @@
+//! Restricts the egresses that a function may send to, see `FunctionRegistry::allow_egresses()`.
+//!
+//! `Effects` don't know which function they belong to, so the registry publishes the allowed
+//! egresses of the invoked function in a thread-local for the duration of the invocation, or for
+//! every poll of an asynchronous function, see `scope()`.
+use std::cell::RefCell;
+use std::future::Future;
+
+use futures::future::{self, BoxFuture};
+
+use crate::EgressIdentifier;
+
+thread_local! {
+    static ALLOWED_EGRESSES: RefCell<Option<Vec<EgressIdentifier>>> = const { RefCell::new(None) };
+}
+
+/// Publishes the allowed egresses of the invoked function until it is dropped. `None` allows all
+/// egresses. The previously published egresses are restored on drop, so that a nested scope
+/// doesn't clobber the outer one.
+pub(crate) struct AllowedEgressesGuard {
+    previous: Option<Vec<EgressIdentifier>>,
+}
+
+impl AllowedEgressesGuard {
+    pub(crate) fn set(allowed: Option<Vec<EgressIdentifier>>) -> AllowedEgressesGuard {
+        let previous = ALLOWED_EGRESSES.with(|current| current.replace(allowed));
+        AllowedEgressesGuard { previous }
+    }
+}
+
+impl Drop for AllowedEgressesGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        ALLOWED_EGRESSES.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+/// Returns an error if the invoked function may not send to the given egress.
+pub(crate) fn check(identifier: &EgressIdentifier) -> Result<(), String> {
+    ALLOWED_EGRESSES.with(|current| match &*current.borrow() {
+        Some(allowed) if !is_allowed(allowed, identifier) => {
+            Err(format!("{} is not allowed for this function", identifier))
+        }
+        _ => Ok(()),
+    })
+}
+
+/// Returns `true` if the given egress is one of the allowed egresses.
+pub(crate) fn is_allowed(allowed: &[EgressIdentifier], identifier: &EgressIdentifier) -> bool {
+    allowed
+        .iter()
+        .any(|allowed| allowed.namespace == identifier.namespace && allowed.name == identifier.name)
+}
+
+/// Publishes the allowed egresses whenever the given future is polled. An asynchronous function
+/// can resume on another thread after an `.await`, so setting the thread-local once is not enough.
+pub(crate) fn scope<'a, T: 'a>(
+    allowed: Option<Vec<EgressIdentifier>>,
+    mut future: BoxFuture<'a, T>,
+) -> impl Future<Output = T> + 'a {
+    future::poll_fn(move |cx| {
+        let _guard = AllowedEgressesGuard::set(allowed.clone());
+        future.as_mut().poll(cx)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::allowed_egresses::{check, AllowedEgressesGuard};
+    use crate::*;
+
+    #[test]
+    fn nested_guard_restores_outer_egresses() {
+        let kafka = EgressIdentifier::new("io", "kafka");
+        let kinesis = EgressIdentifier::new("io", "kinesis");
+
+        let outer = AllowedEgressesGuard::set(Some(vec![kafka.clone()]));
+        {
+            let _inner = AllowedEgressesGuard::set(Some(vec![kinesis.clone()]));
+            assert!(check(&kinesis).is_ok());
+            assert!(check(&kafka).is_err());
+        }
+        assert!(check(&kafka).is_ok());
+        assert!(check(&kinesis).is_err());
+
+        drop(outer);
+        assert!(check(&kinesis).is_ok());
+    }
+}
@@ -0,0 +1,284 @@
+use std::marker::PhantomData;
+
+use crate::type_name::derived_typename;
+use crate::{Serializable, TypeName};
+
+/// A compression algorithm for use with [Compressed](Compressed).
+///
+/// The SDK ships gzip and zstd, see `Gzip` and `Zstd`, behind the optional `flate2` and `zstd`
+/// features, so that not everyone pulls in the compression libraries. Other algorithms can be
+/// added by implementing this trait.
+pub trait Compression {
+    /// The suffix that is appended to the typename of compressed values, for example "gzip".
+    const SUFFIX: &'static str;
+
+    /// Compresses the given bytes.
+    fn compress(bytes: &[u8]) -> Result<Vec<u8>, String>;
+
+    /// Decompresses bytes that were compressed using `compress()`.
+    fn decompress(bytes: &[u8]) -> Result<Vec<u8>, String>;
+}
+
+/// Wraps a message type to compress its serialized bytes, for example to save bandwidth when
+/// sending large records to an egress:
+/// `effects.kafka_egress(id, topic, &Compressed::<GzipCompression, _>::new(record))`. See `Gzip`
+/// and `Zstd` for shorthands of the shipped algorithms.
+///
+/// The typename is the typename of the wrapped type with the suffix of the `Compression`, for
+/// example "com.example/User+gzip". Consumers must understand this convention and decompress the
+/// bytes themselves. Note that some egresses, like Kafka, don't pass the typename on, so consumers
+/// of those have to know that the values are compressed.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Compressed<C, T> {
+    value: T,
+    compression: PhantomData<C>,
+}
+
+impl<C, T> Compressed<C, T> {
+    /// Creates a `Compressed` that compresses the given value when it is serialized.
+    pub fn new(value: T) -> Compressed<C, T> {
+        Compressed {
+            value,
+            compression: PhantomData,
+        }
+    }
+
+    /// Returns the wrapped value.
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+
+impl<C: Compression + 'static, T: TypeName + 'static> TypeName for Compressed<C, T> {
+    fn get_typename() -> &'static str {
+        derived_typename::<Compressed<C, T>, _>(|| format!("{}+{}", T::get_typename(), C::SUFFIX))
+    }
+}
+
+impl<C: Compression, T: Serializable<T> + TypeName> Serializable<Compressed<C, T>>
+    for Compressed<C, T>
+{
+    fn serialize(&self, _typename: String) -> Result<Vec<u8>, String> {
+        C::compress(&self.value.serialize(T::get_typename().to_string())?)
+    }
+
+    fn deserialize(_typename: String, buffer: &[u8]) -> Result<Compressed<C, T>, String> {
+        let decompressed = C::decompress(buffer)?;
+        T::deserialize(T::get_typename().to_string(), &decompressed).map(Compressed::new)
+    }
+}
+
+/// The gzip `Compression`, using `flate2`.
+#[cfg(feature = "flate2")]
+#[derive(Debug, PartialEq, Clone)]
+pub struct GzipCompression;
+
+#[cfg(feature = "flate2")]
+impl Compression for GzipCompression {
+    const SUFFIX: &'static str = "gzip";
+
+    fn compress(bytes: &[u8]) -> Result<Vec<u8>, String> {
+        use std::io::Write;
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder
+            .write_all(bytes)
+            .map_err(|error| error.to_string())?;
+        encoder.finish().map_err(|error| error.to_string())
+    }
+
+    fn decompress(bytes: &[u8]) -> Result<Vec<u8>, String> {
+        use std::io::Read;
+
+        let mut result = Vec::new();
+        flate2::read::GzDecoder::new(bytes)
+            .read_to_end(&mut result)
+            .map_err(|error| error.to_string())?;
+        Ok(result)
+    }
+}
+
+/// Wraps a message type to compress its serialized bytes using gzip:
+/// `effects.kafka_egress(id, topic, &Gzip(record))`. This is the same as
+/// `Compressed<GzipCompression, T>`, so the typename has the suffix "+gzip" and consumers must
+/// decompress the bytes themselves.
+#[cfg(feature = "flate2")]
+#[derive(Debug, PartialEq, Clone)]
+pub struct Gzip<T>(pub T);
+
+#[cfg(feature = "flate2")]
+impl<T: TypeName + 'static> TypeName for Gzip<T> {
+    fn get_typename() -> &'static str {
+        Compressed::<GzipCompression, T>::get_typename()
+    }
+}
+
+#[cfg(feature = "flate2")]
+impl<T: Serializable<T> + TypeName> Serializable<Gzip<T>> for Gzip<T> {
+    fn serialize(&self, typename: String) -> Result<Vec<u8>, String> {
+        GzipCompression::compress(&self.0.serialize(typename)?)
+    }
+
+    fn deserialize(typename: String, buffer: &[u8]) -> Result<Gzip<T>, String> {
+        T::deserialize(typename, &GzipCompression::decompress(buffer)?).map(Gzip)
+    }
+}
+
+/// The zstd `Compression`, using the `zstd` crate at its default level.
+#[cfg(feature = "zstd")]
+#[derive(Debug, PartialEq, Clone)]
+pub struct ZstdCompression;
+
+#[cfg(feature = "zstd")]
+impl Compression for ZstdCompression {
+    const SUFFIX: &'static str = "zstd";
+
+    fn compress(bytes: &[u8]) -> Result<Vec<u8>, String> {
+        zstd::encode_all(bytes, 0).map_err(|error| error.to_string())
+    }
+
+    fn decompress(bytes: &[u8]) -> Result<Vec<u8>, String> {
+        zstd::decode_all(bytes).map_err(|error| error.to_string())
+    }
+}
+
+/// Wraps a message type to compress its serialized bytes using zstd:
+/// `effects.kafka_egress(id, topic, &Zstd(record))`. This is the same as
+/// `Compressed<ZstdCompression, T>`, so the typename has the suffix "+zstd" and consumers must
+/// decompress the bytes themselves.
+#[cfg(feature = "zstd")]
+#[derive(Debug, PartialEq, Clone)]
+pub struct Zstd<T>(pub T);
+
+#[cfg(feature = "zstd")]
+impl<T: TypeName + 'static> TypeName for Zstd<T> {
+    fn get_typename() -> &'static str {
+        Compressed::<ZstdCompression, T>::get_typename()
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl<T: Serializable<T> + TypeName> Serializable<Zstd<T>> for Zstd<T> {
+    fn serialize(&self, typename: String) -> Result<Vec<u8>, String> {
+        ZstdCompression::compress(&self.0.serialize(typename)?)
+    }
+
+    fn deserialize(typename: String, buffer: &[u8]) -> Result<Zstd<T>, String> {
+        T::deserialize(typename, &ZstdCompression::decompress(buffer)?).map(Zstd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    /// A run-length encoding, which is enough to check that the bytes are passed through it.
+    struct RunLength;
+
+    impl Compression for RunLength {
+        const SUFFIX: &'static str = "rle";
+
+        fn compress(bytes: &[u8]) -> Result<Vec<u8>, String> {
+            let mut result = Vec::new();
+            for byte in bytes {
+                match result.len() {
+                    len if len >= 2 && result[len - 1] == *byte && result[len - 2] < u8::MAX => {
+                        result[len - 2] += 1
+                    }
+                    _ => result.extend_from_slice(&[1, *byte]),
+                }
+            }
+            Ok(result)
+        }
+
+        fn decompress(bytes: &[u8]) -> Result<Vec<u8>, String> {
+            let runs = bytes.chunks_exact(2);
+            if !runs.remainder().is_empty() {
+                return Err("Incomplete run".to_string());
+            }
+            let mut result = Vec::new();
+            for run in runs {
+                result.resize(result.len() + run[0] as usize, run[1]);
+            }
+            Ok(result)
+        }
+    }
+
+    #[test]
+    fn compressed_round_trip() {
+        type Rle<T> = Compressed<RunLength, T>;
+        assert_eq!(
+            Rle::<String>::get_typename(),
+            "io.statefun.types/string+rle"
+        );
+
+        let record = "a".repeat(100);
+        let serialized = Rle::new(record.clone())
+            .serialize(Rle::<String>::get_typename().to_string())
+            .unwrap();
+        let uncompressed = record
+            .serialize(String::get_typename().to_string())
+            .unwrap();
+        assert!(serialized.len() < uncompressed.len());
+
+        let deserialized =
+            Rle::<String>::deserialize(Rle::<String>::get_typename().to_string(), &serialized)
+                .unwrap();
+        assert_eq!(deserialized.into_inner(), record);
+    }
+
+    #[cfg(feature = "flate2")]
+    #[test]
+    fn gzip_round_trip() {
+        use crate::io::kafka::KafkaEgress;
+        use statefun_proto::kafka_egress::KafkaProducerRecord;
+
+        assert_eq!(
+            Gzip::<String>::get_typename(),
+            "io.statefun.types/string+gzip"
+        );
+
+        let record = "a".repeat(1000);
+        let mut effects = Effects::new();
+        effects
+            .kafka_egress(
+                EgressIdentifier::new("namespace", "kafka"),
+                "records",
+                &Gzip(record.clone()),
+            )
+            .unwrap();
+
+        let (_identifier, typename, value) = &effects.egress_messages[0];
+        let kafka_record = KafkaProducerRecord::deserialize(typename.clone(), value).unwrap();
+        let compressed = kafka_record.get_value_bytes();
+        assert!(compressed.len() < record.len());
+        assert_eq!(
+            Gzip::<String>::deserialize(String::new(), compressed),
+            Ok(Gzip(record))
+        );
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_round_trip() {
+        assert_eq!(
+            Zstd::<String>::get_typename(),
+            "io.statefun.types/string+zstd"
+        );
+
+        let record = "a".repeat(1000);
+        let compressed = Zstd(record.clone()).serialize(String::new()).unwrap();
+        assert!(compressed.len() < record.len());
+        assert_eq!(
+            Zstd::<String>::deserialize(String::new(), &compressed),
+            Ok(Zstd(record.clone()))
+        );
+        assert_eq!(
+            Compressed::<ZstdCompression, String>::deserialize(String::new(), &compressed)
+                .unwrap()
+                .into_inner(),
+            record
+        );
+        assert!(Zstd::<String>::deserialize(String::new(), b"not zstd").is_err());
+    }
+}
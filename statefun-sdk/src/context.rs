@@ -1,68 +1,538 @@
+use crate::expiration::NEVER;
+use crate::message_metadata::VALID_UNTIL_KEY;
+use crate::state_backend::BackedValues;
+use crate::value_spec_base::StateKey;
 use crate::Address;
+use crate::DelayedTokens;
+use crate::Effects;
 use crate::Expiration;
 use crate::Serializable;
 use crate::ValueSpec;
 use crate::ValueSpecBase;
-use statefun_proto::request_reply::Address as ProtoAddress;
 use std::collections::HashMap;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 /// Context for a single invocation of a stateful function.
 ///
 /// The context may be used to obtain the [Address](Address) of the function of the current
 /// invocation or the calling function (if the function was invoked by another function), or to
 /// access state.
+///
+/// Messages that originate from an ingress don't have a caller, use `is_ingress()` to check for
+/// this.
 #[derive(Debug)]
 pub struct Context<'a> {
     pub(crate) state: &'a HashMap<ValueSpecBase, Vec<u8>>,
-    self_address: &'a ProtoAddress,
-    caller_address: &'a ProtoAddress,
+    self_address: &'a Address,
+    caller_address: Option<Address>,
+    declared_specs: Option<&'a [ValueSpecBase]>,
+    backed_values: Option<&'a BackedValues>,
+    deadline: Option<Instant>,
+    message_metadata: HashMap<String, String>,
+    batch_index: usize,
+    batch_size: usize,
 }
 
 impl<'a> Context<'a> {
     ///
     pub(crate) fn new(
         state: &'a HashMap<ValueSpecBase, Vec<u8>>,
-        self_address: &'a ProtoAddress,
-        caller_address: &'a ProtoAddress,
+        self_address: &'a Address,
+        caller_address: Option<Address>,
     ) -> Self {
         Context {
             state,
             self_address,
             caller_address,
+            declared_specs: None,
+            backed_values: None,
+            deadline: None,
+            message_metadata: HashMap::new(),
+            batch_index: 0,
+            batch_size: 1,
+        }
+    }
+
+    /// Returns this `Context` with the given deadline of the current request.
+    pub(crate) fn with_deadline(self, deadline: Option<Instant>) -> Self {
+        Context { deadline, ..self }
+    }
+
+    /// Returns this `Context` with the position of the current invocation in its batch.
+    pub(crate) fn with_batch_position(self, batch_index: usize, batch_size: usize) -> Self {
+        Context {
+            batch_index,
+            batch_size,
+            ..self
+        }
+    }
+
+    /// Returns this `Context` with the metadata of the message of the current invocation.
+    pub(crate) fn with_message_metadata(self, message_metadata: HashMap<String, String>) -> Self {
+        Context {
+            message_metadata,
+            ..self
+        }
+    }
+
+    /// Returns this `Context` with the `ValueSpec`s that the invoked function was registered with.
+    pub(crate) fn with_declared_specs<'b>(self, declared_specs: &'b [ValueSpecBase]) -> Context<'b>
+    where
+        'a: 'b,
+    {
+        Context {
+            declared_specs: Some(declared_specs),
+            ..self
         }
     }
 
+    /// Returns this `Context` with the values of the states of the invoked function that are kept
+    /// in a `StateBackend`.
+    pub(crate) fn with_backed_values<'b>(self, backed_values: &'b BackedValues) -> Context<'b>
+    where
+        'a: 'b,
+    {
+        Context {
+            backed_values: Some(backed_values),
+            ..self
+        }
+    }
+
+    /// Returns the `ValueSpec`s that the invoked function was registered with. This allows
+    /// handling all state of a function generically, for example to back it up or to delete it,
+    /// without listing the states again.
+    pub fn declared_specs(&self) -> &[ValueSpecBase] {
+        self.declared_specs.unwrap_or(&[])
+    }
+
+    /// Returns the `Expiration` that the state with the given name was registered with, or `None`
+    /// if the invoked function declares no such state. Flink doesn't send the expiration of
+    /// states, so this is the registered policy, not how long the state actually has left.
+    pub fn expiration_for(&self, name: &str) -> Option<Expiration> {
+        self.declared_specs()
+            .iter()
+            .find(|value_spec| value_spec.name == name)
+            .map(|value_spec| value_spec.expiration.clone())
+    }
+
     /// Returns the [Address](Address) of the stateful function that is being called. This is the
     /// statefun equivalent of `self`.
     pub fn self_address(&self) -> Address {
-        Address::from_proto(self.self_address)
+        self.self_address.clone()
     }
 
     /// Returns the [Address](Address) of the stateful function that caused this function
-    /// invocation, that is, the caller.
-    pub fn caller_address(&self) -> Address {
-        Address::from_proto(self.caller_address)
+    /// invocation, that is, the caller. Returns `None` if the message was not sent by a stateful
+    /// function, which is the case for messages from an ingress.
+    pub fn caller_address(&self) -> Option<Address> {
+        self.caller_address.clone()
+    }
+
+    /// Returns the point in time by which the current request should be answered, if the
+    /// transport was configured with an invocation timeout. Functions can use this to abort work
+    /// that won't finish in time.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Returns the number of invocations in the batch that Flink sent with the current request.
+    /// Flink batches the messages of a function instance while a previous request is in flight,
+    /// so a large batch indicates a backlog, which functions can use to adapt their behavior, for
+    /// example to flush less often.
+    pub fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    /// Returns the zero-based position of the current invocation in its batch, see
+    /// `batch_size()`.
+    pub fn batch_index(&self) -> usize {
+        self.batch_index
+    }
+
+    /// Returns the metadata that the sender attached to the message of this invocation using
+    /// `Effects::send_with_metadata()`. This is empty for messages without metadata.
+    pub fn message_metadata(&self) -> &HashMap<String, String> {
+        &self.message_metadata
+    }
+
+    /// Returns `true` if the message of this invocation was sent using
+    /// `Effects::send_after_valid_until()` and arrived after its validity ended. Functions should
+    /// ignore such messages.
+    pub fn is_expired(&self) -> bool {
+        let valid_until_millis = match self.message_metadata.get(VALID_UNTIL_KEY) {
+            Some(valid_until_millis) => valid_until_millis,
+            None => return false,
+        };
+        let valid_until_millis: u128 = match valid_until_millis.parse() {
+            Ok(valid_until_millis) => valid_until_millis,
+            Err(_) => return false,
+        };
+        match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(now) => now.as_millis() > valid_until_millis,
+            Err(_) => false,
+        }
+    }
+
+    /// Returns `true` if the message of this invocation originates from an ingress rather than
+    /// from another stateful function. This is derived from the absence of a caller.
+    pub fn is_ingress(&self) -> bool {
+        self.caller_address.is_none()
     }
 
     /// Returns the state (or persisted) value that previous invocations of this stateful function
     /// might have persisted under the given name.
-    /// If the state does not exist, returns None.
+    /// If the state does not exist, returns None. If the state was allocated by Flink but never
+    /// written, returns the default of the `ValueSpec`, if it has one.
     /// If the state does exist but could not be deserialized, returns an error within the option.
     pub fn get_state<T: Serializable<T>>(
         &self,
         value_spec: ValueSpec<T>,
+    ) -> Option<Result<T, String>> {
+        self.read_state(&value_spec)
+    }
+
+    /// Returns the state like `get_state()`, but returns the given default if the state does not
+    /// exist. An error is returned if the state exists but could not be deserialized.
+    pub fn get_state_or<T: Serializable<T>>(
+        &self,
+        value_spec: ValueSpec<T>,
+        default: T,
+    ) -> Result<T, String> {
+        self.read_state(&value_spec).unwrap_or(Ok(default))
+    }
+
+    /// Returns the state like `get_state_or()`, with the `Default` of the type as the default.
+    pub fn get_state_or_default<T: Serializable<T> + Default>(
+        &self,
+        value_spec: ValueSpec<T>,
+    ) -> Result<T, String> {
+        self.get_state_or(value_spec, T::default())
+    }
+
+    /// Returns the state like `get_state()`, but returns the given default if the state does not
+    /// exist or cannot be deserialized, for example because it is corrupt or was written with an
+    /// incompatible schema. In the latter case, a deletion of the state is added to the `effects`,
+    /// so that the function recovers instead of failing on every invocation.
+    ///
+    /// Note that this silently discards the stored value: only use this for state that can be
+    /// rebuilt, such as caches or counters that may restart.
+    pub fn get_state_or_reset<T: Serializable<T>>(
+        &self,
+        value_spec: ValueSpec<T>,
+        effects: &mut Effects,
+        default: T,
+    ) -> T {
+        match self.read_state(&value_spec) {
+            Some(Ok(value)) => value,
+            Some(Err(error)) => {
+                log::warn!(
+                    "{} resets state {:?} that could not be deserialized: {}",
+                    self.self_address.function_type,
+                    value_spec.spec.name,
+                    error
+                );
+                effects.delete_state(value_spec);
+                default
+            }
+            None => default,
+        }
+    }
+
+    fn read_state<T: Serializable<T>>(
+        &self,
+        value_spec: &ValueSpec<T>,
     ) -> Option<Result<T, String>> {
         let typename = value_spec.spec.typename.to_string();
 
+        let backed_value = self
+            .backed_values
+            .and_then(|backed_values| backed_values.get(&value_spec.spec.name));
+        if let Some(backed_value) = backed_value {
+            return match backed_value {
+                Ok(Some(serialized)) => Some(T::deserialize(typename, serialized)),
+                Ok(None) => None,
+                Err(error) => Some(Err(error.clone())),
+            };
+        }
+
+        if cfg!(debug_assertions) {
+            self.warn_if_undeclared(&value_spec.spec.name);
+        }
+
+        let state = self.initialized_state(&value_spec.spec);
+        if let (None, Some(default)) = (state, &value_spec.default) {
+            // Flink sends allocated but uninitialized state without a typename
+            let uninitialized_key = (value_spec.spec.name.as_str(), "", &NEVER);
+            if self.state.contains_key(&uninitialized_key as &dyn StateKey) {
+                return Some(T::deserialize(typename, default));
+            }
+        }
+        state.map(|serialized| T::deserialize(typename, serialized))
+    }
+
+    /// Returns the cancellation tokens of the delayed messages that this function sent using
+    /// `Effects::send_after_tracked()` in previous invocations for the current key, see
+    /// `DelayedTokens`. Tokens of messages that were already delivered are included, because
+    /// Flink doesn't notify the function about delivery.
+    ///
+    /// This is empty if the function did not declare `DelayedTokens::value_spec()`.
+    pub fn scheduled_tokens(&self) -> Vec<String> {
+        match self.read_state(&DelayedTokens::value_spec()) {
+            Some(Ok(tokens)) => tokens.tokens,
+            _ => Vec::new(),
+        }
+    }
+
+    /// Returns the serialized bytes of the state with the given name without deserializing them,
+    /// or `None` if the state does not exist. Use this together with `Effects::egress_raw()` to
+    /// forward large state values without decoding them, see also `Effects::egress_state_raw()`.
+    pub fn get_state_raw(&self, name: &str) -> Option<&[u8]> {
+        self.raw_state(name)
+            .map(|(_typename, serialized)| serialized)
+    }
+
+    /// Returns the typename and serialized bytes of the state with the given name. State that was
+    /// written has a typename, so we prefer that over an uninitialized entry of the same name.
+    pub(crate) fn raw_state(&self, name: &str) -> Option<(&str, &[u8])> {
+        self.state
+            .iter()
+            .filter(|(value_spec, _serialized)| value_spec.name == name)
+            .max_by_key(|(value_spec, _serialized)| !value_spec.typename.is_empty())
+            .map(|(value_spec, serialized)| (value_spec.typename.as_str(), serialized.as_slice()))
+    }
+
+    /// Returns the serialized bytes of the state of the given spec, if it was written before.
+    pub(crate) fn initialized_state(&self, value_spec: &ValueSpecBase) -> Option<&[u8]> {
         // note: Flink doesn't give us the TTL when passing existing state around,
         // so we have to leave 'expiration' to its default when doing state lookups
-        let key = ValueSpecBase::new(
-            value_spec.spec.name.as_str(),
-            value_spec.spec.typename.as_str(),
-            Expiration::never(),
+        let key = (
+            value_spec.name.as_str(),
+            value_spec.typename.as_str(),
+            &NEVER,
         );
+        self.state
+            .get(&key as &dyn StateKey)
+            .map(|serialized| serialized.as_slice())
+    }
 
-        let state = self.state.get(&key);
-        state.map(|serialized| T::deserialize(typename, serialized))
+    /// Reading state that the function didn't declare when registering it will never return a
+    /// value, because Flink only provides declared state. This is easy to get wrong, so we warn
+    /// about it in debug builds.
+    fn warn_if_undeclared(&self, name: &str) {
+        if let Some(declared_specs) = self.declared_specs {
+            if !declared_specs.iter().any(|spec| spec.name == name) {
+                log::warn!(
+                    "{} reads state {:?} that is not declared in its registered specs",
+                    self.self_address.function_type,
+                    name
+                );
+            }
+        }
+    }
+
+    /// Returns the sorted names of all states that Flink sent with this invocation, whether they
+    /// were written or not. Compare these with the registered `ValueSpec`s to debug invocations
+    /// that keep asking Flink for missing states.
+    pub fn state_names(&self) -> Vec<String> {
+        let mut state_names: Vec<String> = self
+            .state
+            .keys()
+            .map(|value_spec| value_spec.name.clone())
+            .collect();
+        state_names.sort_unstable();
+        state_names.dedup();
+        state_names
+    }
+
+    /// Returns the name, typename, and serialized bytes of every state value that is available to
+    /// this invocation, regardless of the `ValueSpec`s the function was registered with. This is
+    /// useful for generic tooling, such as backing up all state of a function.
+    ///
+    /// Note that the typename is empty for state that Flink allocated but that was never written.
+    pub fn all_states(&self) -> impl Iterator<Item = (&str, &str, &[u8])> {
+        self.state.iter().map(|(value_spec, serialized)| {
+            (
+                value_spec.name.as_str(),
+                value_spec.typename.as_str(),
+                serialized.as_slice(),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use std::collections::HashMap;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn all_states() {
+        let mut state = HashMap::new();
+        state.insert(
+            ValueSpecBase::new("a", i32::get_typename(), Expiration::never()),
+            vec![1],
+        );
+        state.insert(
+            ValueSpecBase::new("b", String::get_typename(), Expiration::never()),
+            vec![2, 3],
+        );
+        state.insert(ValueSpecBase::new("c", "", Expiration::never()), vec![]);
+
+        let address = Address::new(FunctionType::new("namespace", "foo"), "id");
+        let context = Context::new(&state, &address, None);
+
+        let mut all_states: Vec<(&str, &str, &[u8])> = context.all_states().collect();
+        all_states.sort();
+        assert_eq!(
+            all_states,
+            vec![
+                ("a", i32::get_typename(), &[1u8][..]),
+                ("b", String::get_typename(), &[2u8, 3][..]),
+                ("c", "", &[][..]),
+            ]
+        );
+    }
+
+    #[test]
+    fn expiration_for() {
+        let after_write = Expiration::new(ExpirationType::AfterWrite, Duration::from_secs(60));
+        let declared_specs: Vec<ValueSpecBase> = vec![
+            ValueSpec::<i32>::new("count", after_write.clone()).into(),
+            ValueSpec::<String>::new("name", Expiration::never()).into(),
+        ];
+        let state = HashMap::new();
+        let address = Address::new(FunctionType::new("namespace", "foo"), "id");
+
+        let context = Context::new(&state, &address, None).with_declared_specs(&declared_specs);
+        assert_eq!(context.expiration_for("count"), Some(after_write));
+        assert_eq!(context.expiration_for("name"), Some(Expiration::never()));
+        assert_eq!(context.expiration_for("unknown"), None);
+
+        let context = Context::new(&state, &address, None);
+        assert_eq!(context.expiration_for("count"), None);
+    }
+
+    #[test]
+    fn state_names() {
+        let mut state = HashMap::new();
+        state.insert(
+            ValueSpecBase::new("b", i32::get_typename(), Expiration::never()),
+            vec![1],
+        );
+        state.insert(ValueSpecBase::new("b", "", Expiration::never()), vec![]);
+        state.insert(ValueSpecBase::new("a", "", Expiration::never()), vec![]);
+        let address = Address::new(FunctionType::new("namespace", "foo"), "id");
+
+        let context = Context::new(&state, &address, None);
+        assert_eq!(context.state_names(), vec!["a", "b"]);
+
+        let state = HashMap::new();
+        let context = Context::new(&state, &address, None);
+        assert!(context.state_names().is_empty());
+    }
+
+    #[test]
+    fn is_ingress() {
+        let state = HashMap::new();
+        let address = Address::new(FunctionType::new("namespace", "foo"), "id");
+
+        let context = Context::new(&state, &address, None);
+        assert!(context.is_ingress());
+        assert_eq!(context.caller_address(), None);
+
+        let context = Context::new(&state, &address, Some(address.clone()));
+        assert!(!context.is_ingress());
+        assert_eq!(context.caller_address(), Some(address.clone()));
+    }
+
+    #[test]
+    fn uninitialized_state_with_default() {
+        let mut state = HashMap::new();
+        state.insert(ValueSpecBase::new("count", "", Expiration::never()), vec![]);
+        let address = Address::new(FunctionType::new("namespace", "foo"), "id");
+        let context = Context::new(&state, &address, None);
+
+        let count = ValueSpec::<i32>::new("count", Expiration::never()).with_default(&7);
+        assert_eq!(context.get_state(count.unwrap()), Some(Ok(7)));
+
+        let undeclared = ValueSpec::<i32>::new("undeclared", Expiration::never()).with_default(&7);
+        assert_eq!(context.get_state(undeclared.unwrap()), None);
+    }
+
+    #[test]
+    fn is_expired() {
+        let state = HashMap::new();
+        let address = Address::new(FunctionType::new("namespace", "foo"), "id");
+        let now = SystemTime::now();
+
+        let mut effects = Effects::new();
+        let delay = Duration::from_secs(1);
+        let expired = now - Duration::from_secs(60);
+        let valid = now + Duration::from_secs(60);
+        for valid_until in [expired, valid].iter() {
+            effects
+                .send_after_valid_until(address.clone(), delay, *valid_until, "".to_string(), &1)
+                .unwrap();
+        }
+
+        let is_expired: Vec<bool> = effects
+            .delayed_invocations
+            .iter()
+            .map(|delayed| {
+                let (_typed_value, metadata) = message_metadata::unwrap(&delayed.bytes).unwrap();
+                Context::new(&state, &address, None)
+                    .with_message_metadata(metadata)
+                    .is_expired()
+            })
+            .collect();
+        assert_eq!(is_expired, vec![true, false]);
+
+        // messages without a validity never expire
+        assert!(!Context::new(&state, &address, None).is_expired());
+    }
+
+    #[test]
+    fn get_state_or() {
+        let count = || ValueSpec::<i32>::new("count", Expiration::never());
+        let missing = || ValueSpec::<i32>::new("missing", Expiration::never());
+        let mut state = HashMap::new();
+        state.insert(count().into(), 5.serialize(String::new()).unwrap());
+        let address = Address::new(FunctionType::new("namespace", "foo"), "id");
+        let context = Context::new(&state, &address, None);
+
+        assert_eq!(context.get_state_or(count(), 1), Ok(5));
+        assert_eq!(context.get_state_or(missing(), 1), Ok(1));
+        assert_eq!(context.get_state_or_default(missing()), Ok(0));
+
+        // a truncated fixed32 is not a valid `io.statefun.types/int`
+        state.insert(count().into(), vec![0x0d, 0x01]);
+        let context = Context::new(&state, &address, None);
+        assert!(context.get_state_or(count(), 1).is_err());
+        assert!(context.get_state_or_default(count()).is_err());
+    }
+
+    #[test]
+    fn get_state_or_reset() {
+        let count = || ValueSpec::<i32>::new("count", Expiration::never());
+        let mut state = HashMap::new();
+        // a truncated fixed32 is not a valid `io.statefun.types/int`
+        state.insert(count().into(), vec![0x0d, 0x01]);
+        let address = Address::new(FunctionType::new("namespace", "foo"), "id");
+        let context = Context::new(&state, &address, None);
+
+        let mut effects = Effects::new();
+        assert!(context.get_state(count()).unwrap().is_err());
+        assert_eq!(context.get_state_or_reset(count(), &mut effects, 0), 0);
+        assert_eq!(effects.state_updates.len(), 1);
+        assert!(matches!(
+            &effects.state_updates[0],
+            StateUpdate::Delete(value_spec) if value_spec.name == "count"
+        ));
+
+        let mut effects = Effects::new();
+        let missing = ValueSpec::<i32>::new("missing", Expiration::never());
+        assert_eq!(context.get_state_or_reset(missing, &mut effects, 3), 3);
+        assert!(effects.state_updates.is_empty());
     }
 }
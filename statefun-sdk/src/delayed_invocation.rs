@@ -1,17 +1,24 @@
 use crate::Address;
 use std::time::Duration;
 
+/// A message that is sent to a stateful function after a delay, as added by
+/// `Effects::send_after()` and related methods. See `Effects::delayed_messages()`.
 #[derive(Debug)]
-pub(crate) struct DelayedInvocation {
+pub struct DelayedInvocation {
+    /// The address of the function that receives the message.
     pub address: Address,
+    /// The delay after which the message is delivered.
     pub delay: Duration,
+    /// The token for cancelling the message, which is empty for messages that can't be cancelled.
     pub cancellation_token: String,
+    /// The typename of the message.
     pub typename: String,
+    /// The serialized message.
     pub bytes: Vec<u8>,
 }
 
 impl DelayedInvocation {
-    pub fn new(
+    pub(crate) fn new(
         address: Address,
         delay: Duration,
         cancellation_token: String,
@@ -0,0 +1,96 @@
+use crate::{Expiration, Serializable, TypeName, ValueSpec};
+use protobuf::{CodedInputStream, CodedOutputStream};
+
+const DELAYED_TOKENS_STATE_NAME: &str = "statefun_delayed_tokens";
+
+/// The cancellation tokens of delayed messages that a function sent using
+/// `Effects::send_after_tracked()`.
+///
+/// Flink does not offer a way to cancel all delayed messages of a key, so the SDK keeps track of
+/// the tokens it issued in a dedicated state. To use tracked delayed messages, a function must
+/// declare `DelayedTokens::value_spec()` in the specs it is registered with.
+///
+/// Functions can list the tracked tokens using `Context::scheduled_tokens()` and cancel single
+/// messages using `Effects::cancel_scheduled()`.
+///
+/// Note that this state grows with every distinct token that is scheduled and is only cleared by
+/// `Effects::cancel_all_delayed()`. Tokens of delayed messages that were already delivered stay
+/// in the state until then, because Flink does not notify the function about delivery.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct DelayedTokens {
+    pub(crate) tokens: Vec<String>,
+}
+
+impl DelayedTokens {
+    /// Returns the `ValueSpec` of the state that holds the tracked cancellation tokens.
+    pub fn value_spec() -> ValueSpec<DelayedTokens> {
+        ValueSpec::new(DELAYED_TOKENS_STATE_NAME, Expiration::never())
+    }
+
+    /// Returns the tracked cancellation tokens.
+    pub fn tokens(&self) -> &[String] {
+        &self.tokens
+    }
+
+    /// Adds the given token, if it is not already tracked.
+    pub(crate) fn add(&mut self, token: String) {
+        if !self.tokens.contains(&token) {
+            self.tokens.push(token);
+        }
+    }
+
+    /// Removes the given token, if it is tracked.
+    pub(crate) fn remove(&mut self, token: &str) {
+        self.tokens.retain(|tracked| tracked != token);
+    }
+}
+
+impl TypeName for DelayedTokens {
+    fn get_typename() -> &'static str {
+        "io.statefun.rust.types/delayed-tokens"
+    }
+}
+
+/// The tokens are encoded like a protobuf message with a single `repeated string` field.
+impl Serializable<DelayedTokens> for DelayedTokens {
+    fn serialize(&self, _typename: String) -> Result<Vec<u8>, String> {
+        let mut buffer = Vec::new();
+        {
+            let mut output = CodedOutputStream::vec(&mut buffer);
+            for token in self.tokens.iter() {
+                if let Err(error) = output.write_string(1, token) {
+                    return Err(error.to_string());
+                }
+            }
+            if let Err(error) = output.flush() {
+                return Err(error.to_string());
+            }
+        }
+        Ok(buffer)
+    }
+
+    fn deserialize(_typename: String, buffer: &[u8]) -> Result<DelayedTokens, String> {
+        let mut input = CodedInputStream::from_bytes(buffer);
+        let mut tokens = Vec::new();
+        loop {
+            match input.eof() {
+                Ok(true) => break,
+                Ok(false) => {}
+                Err(error) => return Err(error.to_string()),
+            }
+            let (field_number, wire_type) = match input.read_tag_unpack() {
+                Ok(tag) => tag,
+                Err(error) => return Err(error.to_string()),
+            };
+            let result = if field_number == 1 {
+                input.read_string().map(|token| tokens.push(token))
+            } else {
+                input.skip_field(wire_type)
+            };
+            if let Err(error) = result {
+                return Err(error.to_string());
+            }
+        }
+        Ok(DelayedTokens { tokens })
+    }
+}
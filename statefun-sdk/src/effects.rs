@@ -1,11 +1,24 @@
+use crate::allowed_egresses;
+use crate::message::GENERIC_TYPENAME;
+use crate::message_metadata;
+use crate::serialization_errors;
 use crate::Address;
+use crate::Context;
 use crate::DelayedInvocation;
+use crate::DelayedTokens;
 use crate::EgressIdentifier;
+use crate::ErrorRecord;
 use crate::Serializable;
 use crate::StateUpdate;
+use crate::Tick;
 use crate::TypeName;
 use crate::ValueSpec;
-use std::time::Duration;
+use crate::ValueSpecBase;
+use protobuf::well_known_types::Any;
+use protobuf::Message;
+use statefun_proto::request_reply::FromFunction_InvocationResponse;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 /// Effects (or side effects) of a stateful function invocation.
 ///
@@ -18,9 +31,14 @@ use std::time::Duration;
 pub struct Effects {
     pub(crate) invocations: Vec<(Address, String, Vec<u8>)>,
     pub(crate) delayed_invocations: Vec<DelayedInvocation>,
-    pub(crate) cancelled_delayed_invocations: Vec<String>,
+    /// The cancellation tokens, each with the number of delayed invocations that were added before
+    /// it, so that the `InvocationBridge` can keep cancellations and delayed invocations in order.
+    pub(crate) cancelled_delayed_invocations: Vec<(usize, String)>,
     pub(crate) egress_messages: Vec<(EgressIdentifier, String, Vec<u8>)>,
     pub(crate) state_updates: Vec<StateUpdate>,
+    /// Responses of functions that were registered using `FunctionRegistry::register_raw_fn()`,
+    /// which the `InvocationBridge` merges into the batch response as they are.
+    pub(crate) raw_responses: Vec<FromFunction_InvocationResponse>,
 }
 
 impl Effects {
@@ -32,6 +50,7 @@ impl Effects {
             cancelled_delayed_invocations: Vec::new(),
             egress_messages: Vec::new(),
             state_updates: Vec::new(),
+            raw_responses: Vec::new(),
         }
     }
 
@@ -41,12 +60,64 @@ impl Effects {
         address: Address,
         value: &T,
     ) -> Result<(), String> {
-        let serialized = value.serialize(T::get_typename().to_string())?;
+        let serialized = serialize(value)?;
         self.invocations
             .push((address, T::get_typename().to_string(), serialized));
         Ok(())
     }
 
+    /// Sends the given serialized message of the given type to the stateful function identified by
+    /// the address, wrapped in a generic `google.protobuf.Any`. The receiver can unwrap it using
+    /// `Message::get_generic()`. Use this to forward messages whose type is not known.
+    pub fn send_generic(
+        &mut self,
+        address: Address,
+        typename: &str,
+        bytes: Vec<u8>,
+    ) -> Result<(), String> {
+        let mut any = Any::new();
+        any.set_type_url(typename.to_string());
+        any.set_value(bytes);
+        let serialized = match any.write_to_bytes() {
+            Ok(result) => result,
+            Err(error) => return Err(error.to_string()),
+        };
+        self.invocations
+            .push((address, GENERIC_TYPENAME.to_string(), serialized));
+        Ok(())
+    }
+
+    /// Sends already serialized bytes with the given typename to the stateful function identified
+    /// by the address, as they are. The bytes are not checked against the typename. Together with
+    /// `Message::get_type()` and `Message::raw_bytes()` this allows relaying messages without
+    /// deserializing them, in which case the receiver gets the message exactly as it was sent.
+    pub fn forward_raw(&mut self, address: Address, typename: &str, bytes: Vec<u8>) {
+        self.invocations
+            .push((address, typename.to_string(), bytes));
+    }
+
+    /// Sends a message together with the given metadata to the stateful function identified by
+    /// the address. The receiving function can read the metadata using
+    /// `Context::message_metadata()`.
+    ///
+    /// StateFun has no notion of metadata, so the message is wrapped in an envelope that only this
+    /// SDK understands. Only use this when the receiving function is implemented with this SDK.
+    pub fn send_with_metadata<T: Serializable<T> + TypeName>(
+        &mut self,
+        address: Address,
+        value: &T,
+        metadata: &HashMap<String, String>,
+    ) -> Result<(), String> {
+        let serialized = serialize(value)?;
+        let envelope = message_metadata::wrap(T::get_typename(), &serialized, metadata)?;
+        self.invocations.push((
+            address,
+            message_metadata::MESSAGE_WITH_METADATA_TYPENAME.to_string(),
+            envelope,
+        ));
+        Ok(())
+    }
+
     /// Sends a delayed message to the stateful function identified by the address after the
     /// specified delay. The cancellation token is optional, if set it can be used to cancel
     /// the delayed invocation on a best-effort basis. For cancelling see cancel_delayed_message().
@@ -57,7 +128,7 @@ impl Effects {
         cancellation_token: String,
         value: &T,
     ) -> Result<(), String> {
-        let serialized = value.serialize(T::get_typename().to_string())?;
+        let serialized = serialize(value)?;
         self.delayed_invocations.push(DelayedInvocation::new(
             address,
             delay,
@@ -68,10 +139,388 @@ impl Effects {
         Ok(())
     }
 
+    /// Sends a delayed message to the stateful function identified by the address after the
+    /// specified delay, without a cancellation token. The delayed message can't be cancelled
+    /// using `cancel_delayed_message()`, use `send_after()` for that.
+    pub fn send_after_uncancellable<T: Serializable<T> + TypeName>(
+        &mut self,
+        address: Address,
+        delay: Duration,
+        value: &T,
+    ) -> Result<(), String> {
+        self.send_after(address, delay, String::new(), value)
+    }
+
+    /// Sends a delayed message like `send_after()`, with a cancellation token that is derived from
+    /// the address, so that the message can be cancelled using `cancel_delayed_messages_to()`
+    /// without keeping track of the token.
+    ///
+    /// Flink can only cancel delayed messages by their token, so all messages that are sent using
+    /// this to the same address share one token. Only use this for at most one outstanding delayed
+    /// message per address, for example for a timeout that is cancelled and sent again on every
+    /// event.
+    pub fn send_after_cancellable_by_address<T: Serializable<T> + TypeName>(
+        &mut self,
+        address: Address,
+        delay: Duration,
+        value: &T,
+    ) -> Result<(), String> {
+        let cancellation_token = address_cancellation_token(&address);
+        self.send_after(address, delay, cancellation_token, value)
+    }
+
+    /// Cancels the delayed message to the given address that was sent using
+    /// `send_after_cancellable_by_address()`, in this or in a previous invocation. As with
+    /// `cancel_delayed_message()` this happens on a best-effort basis. Delayed messages that were
+    /// sent with another token are not affected.
+    pub fn cancel_delayed_messages_to(&mut self, address: Address) {
+        self.cancel_delayed_message(address_cancellation_token(&address));
+    }
+
+    /// Sends a delayed message like `send_after()` that should be ignored if it's delivered after
+    /// `valid_until`, for example because Flink fires it late after a recovery.
+    ///
+    /// StateFun can't drop expired delayed messages, so the deadline is sent along with the message
+    /// as metadata (see `send_with_metadata()`) and the receiving function has to check
+    /// `Context::is_expired()` before handling the message. The deadline is compared against the
+    /// wall clock of the receiving function, so clock skew between hosts affects it.
+    pub fn send_after_valid_until<T: Serializable<T> + TypeName>(
+        &mut self,
+        address: Address,
+        delay: Duration,
+        valid_until: SystemTime,
+        cancellation_token: String,
+        value: &T,
+    ) -> Result<(), String> {
+        let valid_until_millis = match valid_until.duration_since(UNIX_EPOCH) {
+            Ok(since_epoch) => since_epoch.as_millis(),
+            Err(_) => 0,
+        };
+        let mut metadata = HashMap::new();
+        metadata.insert(
+            message_metadata::VALID_UNTIL_KEY.to_string(),
+            valid_until_millis.to_string(),
+        );
+        let serialized = serialize(value)?;
+        let envelope = message_metadata::wrap(T::get_typename(), &serialized, &metadata)?;
+        self.delayed_invocations.push(DelayedInvocation::new(
+            address,
+            delay,
+            cancellation_token,
+            message_metadata::MESSAGE_WITH_METADATA_TYPENAME.to_string(),
+            envelope,
+        ));
+        Ok(())
+    }
+
+    /// Schedules a `Tick` to be sent to this function after the given interval, using the given
+    /// cancellation token. Calling this on every tick makes the function periodically invoke
+    /// itself, for example to do maintenance work. Use `Message::is_tick()` to detect ticks and
+    /// `cancel_delayed_message()` with the same token to stop ticking.
+    pub fn schedule_tick(
+        &mut self,
+        context: &Context,
+        interval: Duration,
+        cancellation_token: &str,
+    ) -> Result<(), String> {
+        self.send_after(
+            context.self_address(),
+            interval,
+            cancellation_token.to_string(),
+            &Tick,
+        )
+    }
+
+    /// Sends a delayed message like `send_after()`, but additionally records the cancellation
+    /// token in the `DelayedTokens` state of this function so that the message can later be
+    /// cancelled using `cancel_all_delayed()`. The function must declare
+    /// `DelayedTokens::value_spec()` in its registered specs.
+    pub fn send_after_tracked<T: Serializable<T> + TypeName>(
+        &mut self,
+        context: &Context,
+        address: Address,
+        delay: Duration,
+        cancellation_token: String,
+        value: &T,
+    ) -> Result<(), String> {
+        let mut tokens = self.current_delayed_tokens(context)?;
+        self.send_after(address, delay, cancellation_token.clone(), value)?;
+        tokens.add(cancellation_token);
+        self.update_state(DelayedTokens::value_spec(), &tokens)
+    }
+
+    /// Sends a delayed message like `send_after_tracked()`, after cancelling the delayed message
+    /// that was previously sent with the same token, if it is still tracked. This keeps at most one
+    /// outstanding delayed message per token, for example for a "flush" timer that is rescheduled
+    /// on every event. The function must declare `DelayedTokens::value_spec()` in its registered
+    /// specs.
+    pub fn schedule_once<T: Serializable<T> + TypeName>(
+        &mut self,
+        context: &Context,
+        address: Address,
+        delay: Duration,
+        cancellation_token: &str,
+        value: &T,
+    ) -> Result<(), String> {
+        let tokens = self.current_delayed_tokens(context)?;
+        if tokens
+            .tokens()
+            .iter()
+            .any(|token| token == cancellation_token)
+        {
+            self.cancel_delayed_message(cancellation_token.to_string());
+        }
+        self.send_after_tracked(
+            context,
+            address,
+            delay,
+            cancellation_token.to_string(),
+            value,
+        )
+    }
+
+    /// Cancels all delayed messages that were sent using `send_after_tracked()`, in this or in
+    /// previous invocations for the current key, and clears the `DelayedTokens` state. As with
+    /// `cancel_delayed_message()` this happens on a best-effort basis.
+    pub fn cancel_all_delayed(&mut self, context: &Context) -> Result<(), String> {
+        let tokens = self.current_delayed_tokens(context)?;
+        for token in tokens.tokens {
+            self.cancel_delayed_message(token);
+        }
+        self.delete_state(DelayedTokens::value_spec());
+        Ok(())
+    }
+
+    /// Cancels the delayed message with the given token that was sent using
+    /// `send_after_tracked()`, in this or in a previous invocation for the current key, and stops
+    /// tracking the token. As with `cancel_delayed_message()` this happens on a best-effort basis.
+    pub fn cancel_scheduled(&mut self, context: &Context, token: &str) -> Result<(), String> {
+        let mut tokens = self.current_delayed_tokens(context)?;
+        self.cancel_delayed_message(token.to_string());
+        tokens.remove(token);
+        self.update_state(DelayedTokens::value_spec(), &tokens)
+    }
+
+    /// Returns the tracked delayed tokens, taking into account updates that were staged in these
+    /// `Effects` but are not yet visible in the `Context`.
+    fn current_delayed_tokens(&self, context: &Context) -> Result<DelayedTokens, String> {
+        let value_spec = DelayedTokens::value_spec();
+        match self.current_state(context, &value_spec.spec) {
+            Some(serialized) => DelayedTokens::deserialize(value_spec.spec.typename, serialized),
+            None => Ok(DelayedTokens::default()),
+        }
+    }
+
+    /// Returns the serialized value of the given state as it will be after applying these
+    /// effects, taking into account updates that were already staged.
+    fn current_state<'b>(
+        &'b self,
+        context: &'b Context,
+        value_spec: &ValueSpecBase,
+    ) -> Option<&'b [u8]> {
+        for state_update in self.state_updates.iter().rev() {
+            match state_update {
+                StateUpdate::Update(staged_spec, serialized)
+                    if staged_spec.name == value_spec.name =>
+                {
+                    return Some(serialized);
+                }
+                StateUpdate::Delete(staged_spec) if staged_spec.name == value_spec.name => {
+                    return None;
+                }
+                _ => {}
+            }
+        }
+        context.initialized_state(value_spec)
+    }
+
+    /// Returns the number of messages to other stateful functions that were added so far,
+    /// including delayed messages.
+    pub fn pending_invocations(&self) -> usize {
+        self.invocations.len() + self.delayed_invocations.len()
+    }
+
+    /// Returns the number of egress messages that were added so far.
+    pub fn pending_egresses(&self) -> usize {
+        self.egress_messages.len()
+    }
+
+    /// Returns the number of state updates and deletions that were added so far. Multiple updates
+    /// of the same state are counted individually.
+    pub fn pending_state_updates(&self) -> usize {
+        self.state_updates.len()
+    }
+
+    /// Returns the messages to other stateful functions that were added so far, in order, with
+    /// their typename and serialized bytes. Together with the other accessors, this allows tests
+    /// to check what a function produced.
+    pub fn sent_messages(&self) -> &[(Address, String, Vec<u8>)] {
+        &self.invocations
+    }
+
+    /// Returns the delayed messages that were added so far, in order.
+    pub fn delayed_messages(&self) -> &[DelayedInvocation] {
+        &self.delayed_invocations
+    }
+
+    /// Returns the egress messages that were added so far, in order, with their typename and
+    /// serialized bytes.
+    pub fn egresses(&self) -> &[(EgressIdentifier, String, Vec<u8>)] {
+        &self.egress_messages
+    }
+
+    /// Returns the state updates and deletions that were added so far, in order. Multiple updates
+    /// of the same state are not coalesced.
+    pub fn state_updates(&self) -> &[StateUpdate] {
+        &self.state_updates
+    }
+
+    /// Returns whether no effects were added so far, or all of them were discarded.
+    pub fn is_empty(&self) -> bool {
+        self.invocations.is_empty()
+            && self.delayed_invocations.is_empty()
+            && self.cancelled_delayed_invocations.is_empty()
+            && self.egress_messages.is_empty()
+            && self.state_updates.is_empty()
+            && self.raw_responses.is_empty()
+    }
+
+    /// Discards all effects that were added so far, for example when a function decides midway
+    /// that it shouldn't send anything after all. Use `checkpoint()` and `rollback_to()` to only
+    /// discard some of them.
+    pub fn clear(&mut self) {
+        self.invocations.clear();
+        self.delayed_invocations.clear();
+        self.cancelled_delayed_invocations.clear();
+        self.egress_messages.clear();
+        self.state_updates.clear();
+        self.raw_responses.clear();
+    }
+
+    /// Returns a summary of these effects for logging and troubleshooting. The summary contains
+    /// the targets, typenames, and sizes of all messages and state updates, but not their
+    /// serialized bytes.
+    #[cfg(feature = "json")]
+    pub fn to_debug_json(&self) -> serde_json::Value {
+        use serde_json::json;
+
+        fn target(address: &Address) -> String {
+            format!(
+                "{}/{}/{}",
+                address.function_type.get_namespace(),
+                address.function_type.get_name(),
+                address.id
+            )
+        }
+
+        let invocations: Vec<serde_json::Value> = self
+            .invocations
+            .iter()
+            .map(|(address, typename, bytes)| {
+                json!({"target": target(address), "typename": typename, "size": bytes.len()})
+            })
+            .collect();
+        let delayed_invocations: Vec<serde_json::Value> = self
+            .delayed_invocations
+            .iter()
+            .map(|invocation| {
+                json!({
+                    "target": target(&invocation.address),
+                    "delay_ms": invocation.delay.as_millis() as u64,
+                    "cancellation_token": invocation.cancellation_token,
+                    "typename": invocation.typename,
+                    "size": invocation.bytes.len(),
+                })
+            })
+            .collect();
+        let cancelled_delayed_invocations: Vec<&str> = self
+            .cancelled_delayed_invocations
+            .iter()
+            .map(|(_position, token)| token.as_str())
+            .collect();
+        let egress_messages: Vec<serde_json::Value> = self
+            .egress_messages
+            .iter()
+            .map(|(identifier, typename, bytes)| {
+                json!({
+                    "egress": format!("{}/{}", identifier.namespace, identifier.name),
+                    "typename": typename,
+                    "size": bytes.len(),
+                })
+            })
+            .collect();
+        let state_updates: Vec<serde_json::Value> = self
+            .state_updates
+            .iter()
+            .map(|state_update| match state_update {
+                StateUpdate::Update(value_spec, bytes) => json!({
+                    "state": value_spec.name,
+                    "mutation": "update",
+                    "typename": value_spec.typename,
+                    "size": bytes.len(),
+                }),
+                StateUpdate::Delete(value_spec) => {
+                    json!({"state": value_spec.name, "mutation": "delete"})
+                }
+            })
+            .collect();
+
+        json!({
+            "invocations": invocations,
+            "delayed_invocations": delayed_invocations,
+            "cancelled_delayed_invocations": cancelled_delayed_invocations,
+            "egress_messages": egress_messages,
+            "state_updates": state_updates,
+        })
+    }
+
+    /// Returns a checkpoint of the effects that were added so far. Use `rollback_to()` to discard
+    /// all effects that were added after the checkpoint was taken.
+    pub fn checkpoint(&self) -> EffectsCheckpoint {
+        EffectsCheckpoint {
+            invocations: self.invocations.len(),
+            delayed_invocations: self.delayed_invocations.len(),
+            cancelled_delayed_invocations: self.cancelled_delayed_invocations.len(),
+            egress_messages: self.egress_messages.len(),
+            state_updates: self.state_updates.len(),
+        }
+    }
+
+    /// Discards all effects that were added after the given checkpoint was taken. Effects that were
+    /// already discarded by an earlier rollback stay discarded.
+    pub fn rollback_to(&mut self, checkpoint: EffectsCheckpoint) {
+        self.invocations.truncate(checkpoint.invocations);
+        self.delayed_invocations
+            .truncate(checkpoint.delayed_invocations);
+        self.cancelled_delayed_invocations
+            .truncate(checkpoint.cancelled_delayed_invocations);
+        self.egress_messages.truncate(checkpoint.egress_messages);
+        self.state_updates.truncate(checkpoint.state_updates);
+    }
+
     /// Cancels a delayed message on a best-effort basis. Note that the message might have already
     /// been delivered, leading to a no-op operation.
     pub fn cancel_delayed_message(&mut self, cancellation_token: String) {
-        self.cancelled_delayed_invocations.push(cancellation_token);
+        self.cancelled_delayed_invocations
+            .push((self.delayed_invocations.len(), cancellation_token));
+    }
+
+    /// Sends the given Protobuf message to the egress, packed into a `google.protobuf.Any` like
+    /// older versions of StateFun did. Use this to keep feeding consumers that expect `Any`
+    /// payloads, the receiving side of this is `Message::get_generic()`.
+    pub fn egress_any<M: protobuf::Message>(
+        &mut self,
+        identifier: EgressIdentifier,
+        message: &M,
+    ) -> Result<(), String> {
+        allowed_egresses::check(&identifier)?;
+        let serialized = match Any::pack(message).and_then(|any| any.write_to_bytes()) {
+            Ok(result) => result,
+            Err(error) => return Err(error.to_string()),
+        };
+        self.egress_messages
+            .push((identifier, GENERIC_TYPENAME.to_string(), serialized));
+        Ok(())
     }
 
     /// Sends a message to the egress identifier by the `EgressIdentifier`.
@@ -80,27 +529,631 @@ impl Effects {
         identifier: EgressIdentifier,
         value: &T,
     ) -> Result<(), String> {
-        let serialized = value.serialize(T::get_typename().to_string())?;
+        allowed_egresses::check(&identifier)?;
+        let serialized = serialize(value)?;
         self.egress_messages
             .push((identifier, T::get_typename().to_string(), serialized));
         Ok(())
     }
 
+    /// Sends already serialized bytes with the given typename to the egress identified by the
+    /// `EgressIdentifier`. The bytes are not checked against the typename.
+    ///
+    /// This can't fail, so a disallowed egress (see `FunctionRegistry::allow_egresses()`) is only
+    /// reported once the function returns, as an `InvocationError::DisallowedEgress`.
+    pub fn egress_raw(&mut self, identifier: EgressIdentifier, typename: &str, bytes: Vec<u8>) {
+        self.egress_messages
+            .push((identifier, typename.to_string(), bytes));
+    }
+
+    /// Sends an `ErrorRecord` about the given message to the egress identified by the
+    /// `EgressIdentifier`, for example a dead-letter topic. The record contains the typename of the
+    /// message, the error, and the current time.
+    ///
+    /// Use this instead of panicking when a function can't process a message.
+    pub fn egress_error(
+        &mut self,
+        identifier: EgressIdentifier,
+        original: &crate::Message,
+        error: &str,
+    ) -> Result<(), String> {
+        let timestamp_millis = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(elapsed) => elapsed.as_millis() as u64,
+            Err(_) => 0,
+        };
+        let record = ErrorRecord {
+            original_typename: original.get_type(),
+            error: error.to_string(),
+            timestamp_millis,
+        };
+        self.egress(identifier, &record)
+    }
+
+    /// Sends the state with the given name to the egress identified by the `EgressIdentifier`,
+    /// using the typename and serialized bytes of the state as they were received from Flink.
+    ///
+    /// The state is never deserialized, which makes this a cheap way of exporting large state
+    /// values. This fails if the state does not exist or was never written.
+    pub fn egress_state_raw(
+        &mut self,
+        context: &Context,
+        identifier: EgressIdentifier,
+        state_name: &str,
+    ) -> Result<(), String> {
+        allowed_egresses::check(&identifier)?;
+        match context.raw_state(state_name) {
+            Some((typename, serialized)) if !typename.is_empty() => {
+                self.egress_raw(identifier, typename, serialized.to_vec());
+                Ok(())
+            }
+            Some(_) => Err(format!("State {:?} was never written", state_name)),
+            None => Err(format!("State {:?} does not exist", state_name)),
+        }
+    }
+
+    /// Updates the state like `update_state()`, but only if the serialized value differs from the
+    /// current value of the state. The current value includes updates that were already added to
+    /// these effects. Returns whether an update was added.
+    ///
+    /// Use this to avoid needlessly writing state that rarely changes.
+    pub fn update_state_if_changed<T: Serializable<T>>(
+        &mut self,
+        value_spec: ValueSpec<T>,
+        value: &T,
+        context: &Context,
+    ) -> Result<bool, String> {
+        let serialized = serialize_state(&value_spec, value)?;
+        if self.current_state(context, &value_spec.spec) == Some(serialized.as_slice()) {
+            return Ok(false);
+        }
+        self.state_updates
+            .push(StateUpdate::Update(value_spec.into(), serialized));
+        Ok(true)
+    }
+
+    /// Migrates a state to a new name or type: reads the state of `old_spec`, converts it using
+    /// `migrate`, writes the result to `new_spec` and deletes the old state. Updates of the old
+    /// state that were already added to these effects are taken into account. Returns whether
+    /// there was an old state to migrate.
+    ///
+    /// Both specs must be declared in the registered specs of the function.
+    pub fn migrate_state<Old, New, F>(
+        &mut self,
+        context: &Context,
+        old_spec: ValueSpec<Old>,
+        new_spec: ValueSpec<New>,
+        migrate: F,
+    ) -> Result<bool, String>
+    where
+        Old: Serializable<Old>,
+        New: Serializable<New>,
+        F: Fn(Old) -> New,
+    {
+        let old_value = match self.current_state(context, &old_spec.spec) {
+            Some(serialized) => Old::deserialize(old_spec.spec.typename.clone(), serialized)?,
+            None => return Ok(false),
+        };
+        self.update_state(new_spec, &migrate(old_value))?;
+        self.delete_state(old_spec);
+        Ok(true)
+    }
+
+    /// Deletes every state that the invoked function declared in its registered specs, see
+    /// `Context::declared_specs()`. Use this to tear down all state of a key that is done, instead
+    /// of deleting each state separately.
+    pub fn expire_self(&mut self, context: &Context) {
+        for value_spec in context.declared_specs() {
+            self.state_updates
+                .push(StateUpdate::Delete(value_spec.clone()));
+        }
+    }
+
     /// Deletes the state kept under the given name.
     pub fn delete_state<T: Serializable<T>>(&mut self, value_spec: ValueSpec<T>) {
         self.state_updates
             .push(StateUpdate::Delete(value_spec.into()));
     }
 
-    /// Updates the state stored under the given name to the given value.
+    /// Updates the state stored under the given name to the given value. The `NonFinitePolicy` of
+    /// the `ValueSpec` is applied to floating point values.
     pub fn update_state<T: Serializable<T>>(
         &mut self,
         value_spec: ValueSpec<T>,
         value: &T,
     ) -> Result<(), String> {
-        let serialized = value.serialize(value_spec.spec.typename.to_string())?;
+        let serialized = serialize_state(&value_spec, value)?;
         self.state_updates
             .push(StateUpdate::Update(value_spec.into(), serialized));
         Ok(())
     }
 }
+
+/// Serializes a message, reporting a failure to the hook of the transport.
+fn serialize<T: Serializable<T> + TypeName>(value: &T) -> Result<Vec<u8>, String> {
+    let typename = T::get_typename();
+    value
+        .serialize(typename.to_string())
+        .inspect_err(|error| serialization_errors::report(typename, error))
+}
+
+/// Serializes the value of a state, reporting a failure to the hook of the transport.
+fn serialize_state<T: Serializable<T>>(
+    value_spec: &ValueSpec<T>,
+    value: &T,
+) -> Result<Vec<u8>, String> {
+    let typename = &value_spec.spec.typename;
+    value
+        .serialize_with_policy(typename.to_string(), value_spec.non_finite_policy)
+        .inspect_err(|error| serialization_errors::report(typename, error))
+}
+
+/// Returns the cancellation token of delayed messages that were sent using
+/// `Effects::send_after_cancellable_by_address()`. The prefix keeps it apart from tokens that are
+/// chosen by users.
+fn address_cancellation_token(address: &Address) -> String {
+    format!(
+        "statefun-rust/address/{}/{}/{}",
+        address.function_type.get_namespace(),
+        address.function_type.get_name(),
+        address.id
+    )
+}
+
+/// A checkpoint of the effects that were added to an `Effects`, see `Effects::checkpoint()`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct EffectsCheckpoint {
+    invocations: usize,
+    delayed_invocations: usize,
+    cancelled_delayed_invocations: usize,
+    egress_messages: usize,
+    state_updates: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use protobuf::Message as ProtoMessage;
+
+    use crate::*;
+
+    fn address() -> Address {
+        Address::new(FunctionType::new("namespace", "foo"), "id")
+    }
+
+    #[test]
+    fn forward_raw() {
+        let message = Message::from_value(&"hello".to_string()).unwrap();
+
+        let mut effects = Effects::new();
+        effects.forward_raw(address(), &message.get_type(), message.raw_bytes().to_vec());
+
+        let (target, typename, bytes) = &effects.invocations[0];
+        assert_eq!(target, &address());
+        assert_eq!(typename, "io.statefun.types/string");
+        assert_eq!(
+            String::deserialize(typename.clone(), bytes),
+            Ok("hello".to_string())
+        );
+    }
+
+    #[test]
+    fn cancel_delayed_messages_to() {
+        let other = Address::new(FunctionType::new("namespace", "foo"), "other");
+        let mut effects = Effects::new();
+        effects
+            .send_after_cancellable_by_address(address(), Duration::from_secs(1), &1)
+            .unwrap();
+        effects
+            .send_after_cancellable_by_address(other.clone(), Duration::from_secs(1), &2)
+            .unwrap();
+        effects.cancel_delayed_messages_to(address());
+
+        let tokens: Vec<&str> = effects
+            .delayed_messages()
+            .iter()
+            .map(|delayed| delayed.cancellation_token.as_str())
+            .collect();
+        assert_eq!(
+            tokens,
+            vec![
+                "statefun-rust/address/namespace/foo/id",
+                "statefun-rust/address/namespace/foo/other"
+            ]
+        );
+        assert_eq!(
+            effects.cancelled_delayed_invocations,
+            vec![(2, "statefun-rust/address/namespace/foo/id".to_string())]
+        );
+    }
+
+    #[test]
+    fn send_after_uncancellable() {
+        let mut effects = Effects::new();
+        effects
+            .send_after_uncancellable(address(), Duration::from_secs(1), &"hello".to_string())
+            .unwrap();
+
+        let invocation = &effects.delayed_invocations[0];
+        assert_eq!(invocation.address, address());
+        assert_eq!(invocation.delay, Duration::from_secs(1));
+        assert_eq!(invocation.cancellation_token, "");
+    }
+
+    #[test]
+    fn inspect() {
+        let count = || ValueSpec::<i32>::new("count", Expiration::never());
+        let mut effects = Effects::new();
+        effects.send(address(), &"hello".to_string()).unwrap();
+        effects
+            .send_after_uncancellable(address(), Duration::from_secs(1), &true)
+            .unwrap();
+        effects
+            .egress(EgressIdentifier::new("namespace", "egress"), &1)
+            .unwrap();
+        effects.update_state(count(), &2).unwrap();
+        effects.delete_state(count());
+
+        let (target, typename, bytes) = &effects.sent_messages()[0];
+        assert_eq!(target, &address());
+        assert_eq!(
+            String::deserialize(typename.clone(), bytes),
+            Ok("hello".to_string())
+        );
+
+        let delayed = &effects.delayed_messages()[0];
+        assert_eq!(delayed.delay, Duration::from_secs(1));
+        assert_eq!(delayed.typename, bool::get_typename());
+
+        let (identifier, typename, bytes) = &effects.egresses()[0];
+        assert_eq!(identifier.name, "egress");
+        assert_eq!(i32::deserialize(typename.clone(), bytes), Ok(1));
+
+        assert_eq!(
+            effects.state_updates(),
+            [
+                StateUpdate::Update(count().into(), 2.serialize(String::new()).unwrap()),
+                StateUpdate::Delete(count().into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn clear() {
+        let mut effects = Effects::new();
+        assert!(effects.is_empty());
+
+        effects.send(address(), &"hello".to_string()).unwrap();
+        effects
+            .send_after(
+                address(),
+                Duration::from_secs(1),
+                "token".to_string(),
+                &"hello".to_string(),
+            )
+            .unwrap();
+        effects.cancel_delayed_message("token".to_string());
+        effects
+            .egress(EgressIdentifier::new("namespace", "egress"), &1)
+            .unwrap();
+        effects
+            .update_state(ValueSpec::<i32>::new("foo", Expiration::never()), &1)
+            .unwrap();
+        assert!(!effects.is_empty());
+
+        effects.clear();
+        assert!(effects.is_empty());
+        assert_eq!(effects.checkpoint(), Effects::new().checkpoint());
+    }
+
+    #[test]
+    fn rollback_to_checkpoint() {
+        let mut effects = Effects::new();
+        effects.send(address(), &"before".to_string()).unwrap();
+        effects
+            .update_state(ValueSpec::<i32>::new("foo", Expiration::never()), &1)
+            .unwrap();
+
+        let checkpoint = effects.checkpoint();
+
+        effects.send(address(), &"after".to_string()).unwrap();
+        effects
+            .send_after(
+                address(),
+                Duration::from_secs(1),
+                "token".to_string(),
+                &"after".to_string(),
+            )
+            .unwrap();
+        effects.cancel_delayed_message("token".to_string());
+        effects
+            .egress(
+                EgressIdentifier::new("namespace", "egress"),
+                &"after".to_string(),
+            )
+            .unwrap();
+        effects.delete_state(ValueSpec::<i32>::new("bar", Expiration::never()));
+
+        effects.rollback_to(checkpoint);
+
+        assert_eq!(effects.invocations.len(), 1);
+        assert_eq!(
+            effects.invocations[0].2,
+            "before".to_string().serialize("".to_string()).unwrap()
+        );
+        assert!(effects.delayed_invocations.is_empty());
+        assert!(effects.cancelled_delayed_invocations.is_empty());
+        assert!(effects.egress_messages.is_empty());
+        assert_eq!(effects.state_updates.len(), 1);
+    }
+
+    #[test]
+    fn egress_any() {
+        let mut target = statefun_proto::request_reply::Address::new();
+        target.set_namespace("com.example".to_string());
+        target.set_id("legacy".to_string());
+
+        let mut effects = Effects::new();
+        effects
+            .egress_any(EgressIdentifier::new("namespace", "legacy"), &target)
+            .unwrap();
+
+        let (_identifier, typename, serialized) = &effects.egress_messages[0];
+        assert_eq!(typename, "type.googleapis.com/google.protobuf.Any");
+        let any = protobuf::well_known_types::Any::parse_from_bytes(serialized).unwrap();
+        assert_eq!(
+            any.get_type_url(),
+            "type.googleapis.com/io.statefun.sdk.reqreply.Address"
+        );
+        assert_eq!(any.unpack().unwrap(), Some(target));
+    }
+
+    #[test]
+    fn migrate_state() {
+        let old_spec = || ValueSpec::<i32>::new("count", Expiration::never());
+        let new_spec = || ValueSpec::<String>::new("count_text", Expiration::never());
+        let mut state = HashMap::new();
+        state.insert(old_spec().into(), 42.serialize(String::new()).unwrap());
+        let address = address();
+        let context = Context::new(&state, &address, None);
+
+        let mut effects = Effects::new();
+        let migrated = effects
+            .migrate_state(&context, old_spec(), new_spec(), |count| count.to_string())
+            .unwrap();
+        assert!(migrated);
+
+        let expected_spec: ValueSpecBase = new_spec().into();
+        match &effects.state_updates[..] {
+            [StateUpdate::Update(new, serialized), StateUpdate::Delete(old)] => {
+                assert_eq!(new, &expected_spec);
+                assert_eq!(
+                    String::deserialize(String::new(), serialized),
+                    Ok("42".to_string())
+                );
+                assert_eq!(old.name, "count");
+            }
+            updates => panic!("unexpected state updates: {:?}", updates),
+        }
+
+        // once the old state is deleted there is nothing left to migrate
+        assert!(!effects
+            .migrate_state(&context, old_spec(), new_spec(), |count| count.to_string())
+            .unwrap());
+    }
+
+    #[test]
+    fn expire_self() {
+        let declared_specs: Vec<ValueSpecBase> = vec![
+            ValueSpec::<i32>::new("count", Expiration::never()).into(),
+            ValueSpec::<String>::new("name", Expiration::never()).into(),
+        ];
+        let state = HashMap::new();
+        let address = address();
+        let context = Context::new(&state, &address, None).with_declared_specs(&declared_specs);
+
+        let mut effects = Effects::new();
+        effects.expire_self(&context);
+
+        let deleted: Vec<&str> = effects
+            .state_updates
+            .iter()
+            .map(|state_update| match state_update {
+                StateUpdate::Delete(value_spec) => value_spec.name(),
+                StateUpdate::Update(value_spec, _) => {
+                    panic!("unexpected update of {:?}", value_spec)
+                }
+            })
+            .collect();
+        assert_eq!(deleted, vec!["count", "name"]);
+    }
+
+    #[test]
+    fn egress_state_raw() {
+        let blob = vec![1u8, 2, 3, 4];
+        let mut state = HashMap::new();
+        state.insert(
+            ValueSpecBase::new("blob", "com.example/Blob", Expiration::never()),
+            blob.clone(),
+        );
+        state.insert(ValueSpecBase::new("empty", "", Expiration::never()), vec![]);
+        let address = address();
+        let context = Context::new(&state, &address, None);
+
+        assert_eq!(context.get_state_raw("blob"), Some(blob.as_slice()));
+
+        let mut effects = Effects::new();
+        effects
+            .egress_state_raw(
+                &context,
+                EgressIdentifier::new("namespace", "export"),
+                "blob",
+            )
+            .unwrap();
+        assert!(effects
+            .egress_state_raw(
+                &context,
+                EgressIdentifier::new("namespace", "export"),
+                "empty"
+            )
+            .is_err());
+        assert!(effects
+            .egress_state_raw(
+                &context,
+                EgressIdentifier::new("namespace", "export"),
+                "missing"
+            )
+            .is_err());
+
+        assert_eq!(effects.egress_messages.len(), 1);
+        let (identifier, typename, bytes) = &effects.egress_messages[0];
+        assert_eq!(identifier.name, "export");
+        assert_eq!(typename, "com.example/Blob");
+        assert_eq!(bytes, &blob);
+    }
+
+    #[test]
+    fn update_state_if_changed() {
+        let count = || ValueSpec::<i32>::new("count", Expiration::never());
+        let mut state = HashMap::new();
+        state.insert(
+            ValueSpecBase::new("count", i32::get_typename(), Expiration::never()),
+            1.serialize(i32::get_typename().to_string()).unwrap(),
+        );
+        let address = address();
+        let context = Context::new(&state, &address, None);
+
+        let mut effects = Effects::new();
+        assert!(!effects
+            .update_state_if_changed(count(), &1, &context)
+            .unwrap());
+        assert!(effects.state_updates.is_empty());
+
+        assert!(effects
+            .update_state_if_changed(count(), &2, &context)
+            .unwrap());
+        assert_eq!(effects.state_updates.len(), 1);
+
+        // the staged update is the current value now
+        assert!(!effects
+            .update_state_if_changed(count(), &2, &context)
+            .unwrap());
+        assert_eq!(effects.state_updates.len(), 1);
+    }
+
+    #[test]
+    fn pending_counts() {
+        let mut effects = Effects::new();
+        assert_eq!(effects.pending_invocations(), 0);
+        assert_eq!(effects.pending_egresses(), 0);
+        assert_eq!(effects.pending_state_updates(), 0);
+
+        effects.send(address(), &"hello".to_string()).unwrap();
+        effects
+            .send_after(
+                address(),
+                Duration::from_secs(1),
+                "token".to_string(),
+                &"later".to_string(),
+            )
+            .unwrap();
+        effects.cancel_delayed_message("other-token".to_string());
+        effects
+            .egress(EgressIdentifier::new("namespace", "egress"), &1)
+            .unwrap();
+        effects
+            .update_state(ValueSpec::<i32>::new("foo", Expiration::never()), &1)
+            .unwrap();
+        effects
+            .update_state(ValueSpec::<i32>::new("foo", Expiration::never()), &2)
+            .unwrap();
+        effects.delete_state(ValueSpec::<i32>::new("bar", Expiration::never()));
+
+        assert_eq!(effects.pending_invocations(), 2);
+        assert_eq!(effects.pending_egresses(), 1);
+        assert_eq!(effects.pending_state_updates(), 3);
+    }
+
+    #[test]
+    fn egress_error() {
+        let mut typed_value = TypedValue::new();
+        typed_value.set_typename("com.example/order".to_string());
+        typed_value.set_has_value(true);
+        typed_value.set_value(vec![0xff]);
+        let original = Message::new(typed_value);
+
+        let mut effects = Effects::new();
+        effects
+            .egress_error(
+                EgressIdentifier::new("com.example", "dead-letters"),
+                &original,
+                "unknown order format",
+            )
+            .unwrap();
+
+        let (identifier, typename, serialized) = &effects.egress_messages[0];
+        assert_eq!(
+            identifier.to_string(),
+            "EgressIdentifier com.example/dead-letters"
+        );
+        assert_eq!(typename, ErrorRecord::get_typename());
+        let record = ErrorRecord::deserialize(typename.clone(), serialized).unwrap();
+        assert_eq!(record.original_typename, "com.example/order");
+        assert_eq!(record.error, "unknown order format");
+        assert!(record.timestamp_millis > 0);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn to_debug_json() {
+        let mut effects = Effects::new();
+        effects.send(address(), &"hello".to_string()).unwrap();
+        effects
+            .send_after(
+                address(),
+                Duration::from_millis(1500),
+                "token".to_string(),
+                &"later".to_string(),
+            )
+            .unwrap();
+        effects.cancel_delayed_message("other-token".to_string());
+        effects
+            .egress(EgressIdentifier::new("namespace", "egress"), &true)
+            .unwrap();
+        effects
+            .update_state(ValueSpec::<i32>::new("foo", Expiration::never()), &1)
+            .unwrap();
+        effects.delete_state(ValueSpec::<i32>::new("bar", Expiration::never()));
+
+        let hello_size = "hello".to_string().serialize("".to_string()).unwrap().len();
+        let later_size = "later".to_string().serialize("".to_string()).unwrap().len();
+        let bool_size = true.serialize("".to_string()).unwrap().len();
+        let int_size = 1.serialize("".to_string()).unwrap().len();
+        assert_eq!(
+            effects.to_debug_json(),
+            serde_json::json!({
+                "invocations": [
+                    {"target": "namespace/foo/id", "typename": "io.statefun.types/string", "size": hello_size},
+                ],
+                "delayed_invocations": [
+                    {
+                        "target": "namespace/foo/id",
+                        "delay_ms": 1500,
+                        "cancellation_token": "token",
+                        "typename": "io.statefun.types/string",
+                        "size": later_size,
+                    },
+                ],
+                "cancelled_delayed_invocations": ["other-token"],
+                "egress_messages": [
+                    {"egress": "namespace/egress", "typename": "io.statefun.types/bool", "size": bool_size},
+                ],
+                "state_updates": [
+                    {"state": "foo", "mutation": "update", "typename": "io.statefun.types/int", "size": int_size},
+                    {"state": "bar", "mutation": "delete"},
+                ],
+            })
+        );
+    }
+}
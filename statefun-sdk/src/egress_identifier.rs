@@ -1,21 +1,33 @@
+use std::borrow::Cow;
 use std::fmt::{Display, Formatter};
 
 /// A reference to an _egress_, consisting of a namespace and a name.
 ///
 /// This has to be used when sending messages to an egress as part of the function
 /// [Effects](Effects).
-#[derive(Debug)]
+///
+/// Cloning an `EgressIdentifier` that was created using `from_static()` does not allocate.
+#[derive(Debug, Clone)]
 pub struct EgressIdentifier {
-    pub(crate) namespace: String,
-    pub(crate) name: String,
+    pub(crate) namespace: Cow<'static, str>,
+    pub(crate) name: Cow<'static, str>,
 }
 
 impl EgressIdentifier {
     /// Creates a new `EgressIdentifier` from the given namespace and name.
     pub fn new(namespace: &str, name: &str) -> EgressIdentifier {
         EgressIdentifier {
-            namespace: namespace.to_string(),
-            name: name.to_string(),
+            namespace: Cow::Owned(namespace.to_string()),
+            name: Cow::Owned(name.to_string()),
+        }
+    }
+
+    /// Creates a new `EgressIdentifier` from the given static namespace and name, without
+    /// allocating. This can be used to define an `EgressIdentifier` as a constant.
+    pub const fn from_static(namespace: &'static str, name: &'static str) -> EgressIdentifier {
+        EgressIdentifier {
+            namespace: Cow::Borrowed(namespace),
+            name: Cow::Borrowed(name),
         }
     }
 }
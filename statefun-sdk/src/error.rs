@@ -20,4 +20,78 @@ pub enum InvocationError {
     /// Missing state, ask Flink to prepare state storage and it will initiate the call again
     #[error(transparent)]
     MissingStates(MissingStates),
+
+    /// The message could not be decoded to the type that the function was registered for, see
+    /// `FunctionRegistry::register_checked_fn()`.
+    #[error("invalid message for function {function_type}: {reason}")]
+    InvalidMessage {
+        /// The function that received the message.
+        function_type: FunctionType,
+        /// Why the message could not be decoded.
+        reason: String,
+    },
+
+    /// The function returned an error, see `FunctionRegistry::register_fallible_fn()`.
+    #[error("function {function_type} failed: {error}")]
+    UserFunction {
+        /// The function that failed.
+        function_type: FunctionType,
+        /// The error that the function returned.
+        #[source]
+        error: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    /// A state that is kept in a `StateBackend` could not be read or written.
+    #[error("state backend of {function_type} failed for state {state_name:?}: {reason}")]
+    StateBackendError {
+        /// The function whose state could not be accessed.
+        function_type: FunctionType,
+        /// The name of the state.
+        state_name: String,
+        /// The error that the backend returned.
+        reason: String,
+    },
+
+    /// The function sent a message to an egress that it is not allowed to send to, see
+    /// `FunctionRegistry::allow_egresses()`.
+    #[error("function {function_type} is not allowed to send to {egress}")]
+    DisallowedEgress {
+        /// The function that sent the message.
+        function_type: FunctionType,
+        /// The egress that the message was sent to.
+        egress: String,
+    },
+
+    /// The typename of the message is not one of the typenames that the function accepts, see
+    /// `FunctionRegistry::allow_typenames()`. The message was not decoded.
+    #[error("function {function_type} does not accept messages of type {typename:?}")]
+    DisallowedTypename {
+        /// The function that received the message.
+        function_type: FunctionType,
+        /// The typename of the rejected message.
+        typename: String,
+    },
+}
+
+/// Errors that can occur when registering a function with the `FunctionRegistry`.
+#[derive(Error, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum RegistrationError {
+    /// One of the `ValueSpec`s of the function has an empty name.
+    #[error("function {0} declares a state with an empty name")]
+    EmptyStateName(FunctionType),
+
+    /// Two `ValueSpec`s of the function share the same name.
+    #[error("function {0} declares the state {1:?} more than once")]
+    DuplicateStateName(FunctionType, String),
+
+    /// The typename of one of the `ValueSpec`s of the function, which is taken from the `TypeName`
+    /// implementation of the state type, is not of the form `<namespace>/<name>`.
+    #[error("function {0} declares the state {1:?} with the invalid typename {2:?}")]
+    InvalidStateTypename(FunctionType, String, String),
+
+    /// An egress was declared with a record typename that the SDK doesn't know the egress kind
+    /// of, see `FunctionRegistry::register_egress()`.
+    #[error("egress {0} declares the unsupported record type {1:?}")]
+    UnsupportedEgressRecordType(String, String),
 }
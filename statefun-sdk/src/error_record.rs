@@ -0,0 +1,66 @@
+use protobuf::{CodedInputStream, CodedOutputStream, ProtobufResult};
+
+use crate::{Serializable, TypeName};
+
+/// The record that `Effects::egress_error()` sends to an egress, for example a dead-letter topic,
+/// when a function can't process a message.
+///
+/// It is encoded like this Protobuf message, so that consumers in other languages can read it:
+///
+/// ```protobuf
+/// message ErrorRecord {
+///     string original_typename = 1;
+///     string error = 2;
+///     uint64 timestamp_millis = 3;
+/// }
+/// ```
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct ErrorRecord {
+    /// The typename of the message that could not be processed.
+    pub original_typename: String,
+    /// A description of the error.
+    pub error: String,
+    /// The time at which the error occurred, in milliseconds since the Unix epoch.
+    pub timestamp_millis: u64,
+}
+
+impl TypeName for ErrorRecord {
+    fn get_typename() -> &'static str {
+        "io.statefun.rust.types/error-record"
+    }
+}
+
+impl Serializable<ErrorRecord> for ErrorRecord {
+    fn serialize(&self, _typename: String) -> Result<Vec<u8>, String> {
+        let encode = || -> ProtobufResult<Vec<u8>> {
+            let mut buffer = Vec::new();
+            {
+                let mut output = CodedOutputStream::vec(&mut buffer);
+                output.write_string(1, &self.original_typename)?;
+                output.write_string(2, &self.error)?;
+                output.write_uint64(3, self.timestamp_millis)?;
+                output.flush()?;
+            }
+            Ok(buffer)
+        };
+        encode().map_err(|error| error.to_string())
+    }
+
+    fn deserialize(_typename: String, buffer: &[u8]) -> Result<ErrorRecord, String> {
+        let decode = || -> ProtobufResult<ErrorRecord> {
+            let mut input = CodedInputStream::from_bytes(buffer);
+            let mut record = ErrorRecord::default();
+            while !input.eof()? {
+                let (field_number, wire_type) = input.read_tag_unpack()?;
+                match field_number {
+                    1 => record.original_typename = input.read_string()?,
+                    2 => record.error = input.read_string()?,
+                    3 => record.timestamp_millis = input.read_uint64()?,
+                    _ => input.skip_field(wire_type)?,
+                }
+            }
+            Ok(record)
+        };
+        decode().map_err(|error| error.to_string())
+    }
+}
@@ -1,5 +1,11 @@
 use std::time::Duration;
 
+/// The expiration of state that Flink passes to functions, see `Expiration::never()`.
+pub(crate) const NEVER: Expiration = Expiration {
+    expiration_type: None,
+    time_to_live: Duration::from_secs(0),
+};
+
 /// Specifies the expiration type and time to live for a given state
 #[derive(Debug, Hash, Eq, PartialEq, Clone)]
 pub struct Expiration {
@@ -19,6 +25,23 @@ impl Expiration {
         }
     }
 
+    /// Creates an `Expiration` that expires the state the given time after its initial creation or
+    /// last write. The time to live is parsed from a human readable duration such as "5m" or
+    /// "1h 30m".
+    #[cfg(feature = "humantime")]
+    pub fn after_write_str(time_to_live: &str) -> Result<Expiration, String> {
+        parse_duration(time_to_live)
+            .map(|time_to_live| Expiration::new(ExpirationType::AfterWrite, time_to_live))
+    }
+
+    /// Creates an `Expiration` that expires the state the given time after its last read or
+    /// write. The time to live is parsed from a human readable duration such as "5m" or "1h 30m".
+    #[cfg(feature = "humantime")]
+    pub fn after_invoke_str(time_to_live: &str) -> Result<Expiration, String> {
+        parse_duration(time_to_live)
+            .map(|time_to_live| Expiration::new(ExpirationType::AfterInvoke, time_to_live))
+    }
+
     /// Helper function to mark the state as never expiring
     pub fn never() -> Expiration {
         Expiration {
@@ -28,6 +51,14 @@ impl Expiration {
     }
 }
 
+#[cfg(feature = "humantime")]
+fn parse_duration(duration: &str) -> Result<Duration, String> {
+    match humantime::parse_duration(duration) {
+        Ok(result) => Ok(result),
+        Err(error) => Err(format!("Invalid duration {:?}: {}", duration, error)),
+    }
+}
+
 /// Specifies the expiration time for a given state
 #[derive(Debug, Hash, Eq, PartialEq, Clone)]
 pub enum ExpirationType {
@@ -37,3 +68,32 @@ pub enum ExpirationType {
     /// After initial create or the last write
     AfterWrite = 2,
 }
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "humantime")]
+    #[test]
+    fn parse_valid_durations() {
+        use crate::{Expiration, ExpirationType};
+        use std::time::Duration;
+
+        assert_eq!(
+            Expiration::after_write_str("5m").unwrap(),
+            Expiration::new(ExpirationType::AfterWrite, Duration::from_secs(5 * 60))
+        );
+        assert_eq!(
+            Expiration::after_invoke_str("1h 30m").unwrap(),
+            Expiration::new(ExpirationType::AfterInvoke, Duration::from_secs(90 * 60))
+        );
+    }
+
+    #[cfg(feature = "humantime")]
+    #[test]
+    fn parse_invalid_durations() {
+        use crate::Expiration;
+
+        assert!(Expiration::after_write_str("").is_err());
+        assert!(Expiration::after_write_str("5 parsecs").is_err());
+        assert!(Expiration::after_invoke_str("-1h").is_err());
+    }
+}
@@ -1,20 +1,42 @@
 //! The function registry keeps a mapping from `FunctionType` to stateful functions.
 
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 
+use futures::executor;
+use futures::future::{self, BoxFuture, FutureExt};
+
+use crate::allowed_egresses;
+use crate::middleware::BoxedMiddleware;
+use crate::module_spec;
+use crate::state_backend::{BackedStates, BackedValues};
 use crate::InvocationError::FunctionNotFound;
 use crate::Message;
 use crate::MissingStates;
+use crate::Next;
 use crate::ValueSpecBase;
-use crate::{Context, Effects, FunctionType, InvocationError};
+use crate::{Address, StateBackend, StateUpdate};
+use crate::{Context, Effects, EgressIdentifier, FunctionType, InvocationError, RegistrationError};
+use crate::{Serializable, TypeName};
+use statefun_proto::request_reply::FromFunction_InvocationResponse;
 
 /// Keeps a mapping from `FunctionType` to stateful functions. Use this together with a
 /// [Transport](crate::transport::Transport) to serve stateful functions.
 ///
 /// Use `register_fn()` to register functions before handing the registry over to a `Transport` for
 /// serving.
+///
+/// The registry is `Send` and `Sync`, registered functions must therefore be safe to invoke
+/// concurrently from multiple threads.
 pub struct FunctionRegistry {
-    functions: HashMap<FunctionType, Box<dyn InvokableFunction + Send>>,
+    functions: HashMap<FunctionType, Box<dyn InvokableFunction + Send + Sync>>,
+    default_function: Option<Box<dyn InvokableFunction + Send + Sync>>,
+    middleware: Vec<BoxedMiddleware>,
+    egresses: Vec<(EgressIdentifier, String)>,
+    allowed_typenames: HashMap<FunctionType, Vec<String>>,
+    allowed_egresses: HashMap<FunctionType, Vec<EgressIdentifier>>,
+    backed_states: HashMap<FunctionType, BackedStates>,
+    missing_state_counts: Mutex<HashMap<FunctionType, u64>>,
 }
 
 #[allow(clippy::new_without_default)]
@@ -23,18 +45,310 @@ impl FunctionRegistry {
     pub fn new() -> FunctionRegistry {
         FunctionRegistry {
             functions: HashMap::new(),
+            default_function: None,
+            middleware: Vec::new(),
+            egresses: Vec::new(),
+            allowed_typenames: HashMap::new(),
+            allowed_egresses: HashMap::new(),
+            backed_states: HashMap::new(),
+            missing_state_counts: Mutex::new(HashMap::new()),
         }
     }
 
     /// Registers the given function under the `function_type`.
     /// Hint: Use the `specs![]` macro to pass your list of typed ValueSpec's,
     /// for example `specs![ValueSpec::<i32>::new("integer"), ValueSpec::<String>::new("str")]
-    pub fn register_fn<F: Fn(Context, Message) -> Effects + Send + 'static>(
+    ///
+    /// This fails if one of the `value_specs` has an empty name, or if multiple `value_specs`
+    /// share the same name.
+    pub fn register_fn<F: Fn(Context, Message) -> Effects + Send + Sync + 'static>(
+        &mut self,
+        function_type: FunctionType,
+        value_specs: Vec<ValueSpecBase>,
+        function: F,
+    ) -> Result<(), RegistrationError> {
+        self.register_invokable(function_type, value_specs, move |context, message| {
+            Ok(function(context, message))
+        })
+    }
+
+    /// Registers the given function under the `function_type`, like `register_fn()`, but the
+    /// function is only called with messages that can be decoded to `T`.
+    ///
+    /// Messages of another type, or messages that fail to decode, are answered with an
+    /// `InvocationError::InvalidMessage` instead of calling the function, which the transport
+    /// turns into an error response.
+    pub fn register_checked_fn<T, F>(
+        &mut self,
+        function_type: FunctionType,
+        value_specs: Vec<ValueSpecBase>,
+        function: F,
+    ) -> Result<(), RegistrationError>
+    where
+        T: Serializable<T> + TypeName,
+        F: Fn(Context, T) -> Effects + Send + Sync + 'static,
+    {
+        let target_function = function_type.clone();
+        self.register_invokable(
+            function_type,
+            value_specs,
+            move |context, message| match message.get::<T>() {
+                Ok(value) => Ok(function(context, value)),
+                Err(reason) => Err(InvocationError::InvalidMessage {
+                    function_type: target_function.clone(),
+                    reason,
+                }),
+            },
+        )
+    }
+
+    /// Registers the given function under the `function_type`, like `register_fn()`, but the
+    /// function may fail instead of panicking, which allows using `?` on the results of
+    /// `Message::get()` and the methods of `Effects`. An error is returned from the invocation as
+    /// an `InvocationError::UserFunction`, which the transport answers with an error response, so
+    /// that Flink retries the batch.
+    pub fn register_fallible_fn<F>(
+        &mut self,
+        function_type: FunctionType,
+        value_specs: Vec<ValueSpecBase>,
+        function: F,
+    ) -> Result<(), RegistrationError>
+    where
+        F: Fn(Context, Message) -> Result<Effects, Box<dyn std::error::Error + Send + Sync>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        let target_function = function_type.clone();
+        self.register_invokable(function_type, value_specs, move |context, message| {
+            function(context, message).map_err(|error| InvocationError::UserFunction {
+                function_type: target_function.clone(),
+                error,
+            })
+        })
+    }
+
+    /// Registers the given function under the `function_type`, like `register_fn()`, but the
+    /// function returns the Protobuf `FromFunction_InvocationResponse` of the `statefun-proto`
+    /// crate instead of `Effects`. This is an escape hatch for when `Effects` can't express what is
+    /// needed on the wire.
+    ///
+    /// The messages, delayed messages, and egress messages of the response are added to the
+    /// response of the batch as they are. State mutations are coalesced with those of the other
+    /// invocations of the batch and are visible to later invocations.
+    pub fn register_raw_fn<F>(
+        &mut self,
+        function_type: FunctionType,
+        value_specs: Vec<ValueSpecBase>,
+        function: F,
+    ) -> Result<(), RegistrationError>
+    where
+        F: Fn(Context, Message) -> FromFunction_InvocationResponse + Send + Sync + 'static,
+    {
+        self.register_invokable(function_type, value_specs, move |context, message| {
+            let mut effects = Effects::new();
+            effects.raw_responses.push(function(context, message));
+            Ok(effects)
+        })
+    }
+
+    /// Registers the given asynchronous function under the `function_type`, like `register_fn()`.
+    /// Use this for functions that have to wait for external services, for example to enrich a
+    /// message before producing effects, without blocking a thread of the transport. The function
+    /// returns a boxed future, which an `async` block can be turned into using `Box::pin()`:
+    ///
+    /// ```
+    /// # use statefun::{Context, Effects, FunctionRegistry, FunctionType, Message};
+    /// let mut registry = FunctionRegistry::new();
+    /// registry.register_async_fn(
+    ///     FunctionType::new("example", "enricher"),
+    ///     vec![],
+    ///     |_context: Context, _message: Message| {
+    ///         Box::pin(async move {
+    ///             // await an external service here
+    ///             Effects::new()
+    ///         })
+    ///     },
+    /// )?;
+    /// # Ok::<(), statefun::RegistrationError>(())
+    /// ```
+    ///
+    /// Missing states are detected before the function is called, so no asynchronous work is
+    /// started for an invocation that has to be retried once Flink provides the states.
+    ///
+    /// The `HyperHttpTransport` awaits the future, also when middleware was added using `wrap()`.
+    /// Invoking the function synchronously using `invoke()` blocks on the future until it
+    /// completes.
+    pub fn register_async_fn<F>(
         &mut self,
         function_type: FunctionType,
         value_specs: Vec<ValueSpecBase>,
         function: F,
+    ) -> Result<(), RegistrationError>
+    where
+        F: for<'a> Fn(Context<'a>, Message) -> BoxFuture<'a, Effects> + Send + Sync + 'static,
+    {
+        validate_value_specs(&function_type, &value_specs)?;
+        let async_function = AsyncFnInvokableFunction {
+            function,
+            value_specs,
+        };
+        self.functions
+            .insert(function_type, Box::new(async_function));
+        Ok(())
+    }
+
+    /// Restricts the function of the given type to messages with one of the given typenames, for
+    /// example because it is exposed to an untrusted ingress. Messages of other types are rejected
+    /// with an `InvocationError::DisallowedTypename` before the function or any middleware sees
+    /// them, so they are never decoded. Calling this again replaces the allowed typenames.
+    pub fn allow_typenames(&mut self, function_type: FunctionType, typenames: &[&str]) {
+        let typenames = typenames
+            .iter()
+            .map(|typename| typename.to_string())
+            .collect();
+        self.allowed_typenames.insert(function_type, typenames);
+    }
+
+    /// Restricts the function of the given type to sending to the given egresses, so that a bug
+    /// can't leak messages into an unrelated topic. Sending to any other egress makes the egress
+    /// methods of `Effects` return an error. Messages that bypass these checks, for example using
+    /// `Effects::egress_raw()`, fail the invocation with an `InvocationError::DisallowedEgress`.
+    /// Calling this again replaces the allowed egresses.
+    pub fn allow_egresses(&mut self, function_type: FunctionType, egresses: &[EgressIdentifier]) {
+        self.allowed_egresses
+            .insert(function_type, egresses.to_vec());
+    }
+
+    /// Keeps the state with the given name of the function of the given type in the given
+    /// `StateBackend` instead of in Flink. The state must not be part of the specs that the
+    /// function is registered with, because Flink would allocate it otherwise.
+    ///
+    /// Only `Context::get_state()` and the state updates and deletions of the returned `Effects`
+    /// go through the backend. Helpers that inspect the raw state that Flink provided, such as
+    /// `Context::all_states()`, don't see it.
+    pub fn back_state_with(
+        &mut self,
+        function_type: FunctionType,
+        state_name: &str,
+        backend: Arc<dyn StateBackend>,
     ) {
+        self.backed_states
+            .entry(function_type)
+            .or_default()
+            .backends
+            .push((state_name.to_string(), backend));
+    }
+
+    /// Declares an egress that the registered functions send records of the given typename to,
+    /// so that `export_module_spec()` includes it. Only the record typenames of the egresses in
+    /// [io](crate::io) are supported, because the typename determines the kind of the egress.
+    pub fn register_egress(
+        &mut self,
+        identifier: EgressIdentifier,
+        record_typename: &str,
+    ) -> Result<(), RegistrationError> {
+        if module_spec::egress_kind(record_typename).is_none() {
+            return Err(RegistrationError::UnsupportedEgressRecordType(
+                identifier.to_string(),
+                record_typename.to_string(),
+            ));
+        }
+        self.egresses
+            .push((identifier, record_typename.to_string()));
+        Ok(())
+    }
+
+    /// Returns the `module.yaml` that declares the registered functions, served at the given URL,
+    /// and the egresses that were declared using `register_egress()`. Settings that the SDK can't
+    /// know, such as the address of a Kafka cluster, have to be filled in.
+    pub fn export_module_spec(&self, endpoint_url: &str) -> String {
+        let mut namespaces: Vec<String> = self
+            .functions
+            .keys()
+            .map(FunctionType::get_namespace)
+            .collect();
+        namespaces.sort_unstable();
+        namespaces.dedup();
+        module_spec::render(&namespaces, &self.egresses, endpoint_url)
+    }
+
+    /// Adds a middleware that runs around every invocation of every registered function, for
+    /// example to check authorization, measure timing, or apply rate limits in one place.
+    ///
+    /// Middleware is asynchronous like the functions of `register_async_fn()`, so that it can
+    /// await asynchronous functions instead of blocking a thread of the transport:
+    ///
+    /// ```
+    /// use futures::future::FutureExt;
+    /// use statefun::{Effects, FunctionRegistry};
+    ///
+    /// let mut registry = FunctionRegistry::new();
+    /// registry.wrap(|function_type, context, message, next| {
+    ///     async move {
+    ///         log::debug!("invoking {}", function_type);
+    ///         next.run(context, message).await
+    ///     }
+    ///     .boxed()
+    /// });
+    /// ```
+    ///
+    /// The middleware receives the `FunctionType` of the invoked function and controls the rest
+    /// of the invocation:
+    ///
+    ///  - awaiting `Next::run()` invokes the next middleware, or the function itself, and returns
+    ///    its `Effects`, which the middleware can inspect and add to before returning them,
+    ///  - returning its own `Effects` without calling `Next::run()` short-circuits the invocation:
+    ///    neither the remaining middleware nor the function run, and the returned `Effects` are
+    ///    the complete response, for example a rejection that an authorization layer sends to an
+    ///    egress.
+    ///
+    /// Middleware that is added first runs outermost.
+    pub fn wrap<M>(&mut self, middleware: M)
+    where
+        M: for<'a> Fn(FunctionType, Context<'a>, Message, Next<'a>) -> BoxFuture<'a, Effects>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.middleware.push(Box::new(middleware));
+    }
+
+    /// Registers a function that is invoked for messages to any `FunctionType` that no function is
+    /// registered for, instead of failing the invocation with `InvocationError::FunctionNotFound`.
+    /// The function is called with the type of the addressed function, for example to log or to
+    /// forward unexpected messages. Registering another default function replaces this one.
+    ///
+    /// The default function has no `ValueSpec`s, so it can't access state.
+    pub fn register_default<F>(&mut self, function: F)
+    where
+        F: Fn(FunctionType, Context, Message) -> Effects + Send + Sync + 'static,
+    {
+        let default_function = FnInvokableFunction {
+            function: move |context: Context, message| {
+                Ok(function(
+                    context.self_address().function_type,
+                    context,
+                    message,
+                ))
+            },
+            marker: ::std::marker::PhantomData,
+            value_specs: Vec::new(),
+        };
+        self.default_function = Some(Box::new(default_function));
+    }
+
+    fn register_invokable<F>(
+        &mut self,
+        function_type: FunctionType,
+        value_specs: Vec<ValueSpecBase>,
+        function: F,
+    ) -> Result<(), RegistrationError>
+    where
+        F: Fn(Context, Message) -> Result<Effects, InvocationError> + Send + Sync + 'static,
+    {
+        validate_value_specs(&function_type, &value_specs)?;
+
         let callable_function = FnInvokableFunction {
             function,
             marker: ::std::marker::PhantomData,
@@ -42,102 +356,358 @@ impl FunctionRegistry {
         };
         self.functions
             .insert(function_type, Box::new(callable_function));
+        Ok(())
     }
 
     /// Invokes the function that is registered for the given `FunctionType`. This will return
-    /// `Err` if no function is registered under the given type.
+    /// `Err` if no function is registered under the given type, unless a default function was
+    /// registered using `register_default()`.
+    ///
+    /// This only needs a shared reference to the registry and can be called concurrently.
+    ///
+    /// This blocks on middleware and on functions that were registered using
+    /// `register_async_fn()`, use `invoke_async()` to await them instead.
     pub fn invoke(
         &self,
         target_function: FunctionType,
         context: Context,
         message: Message,
     ) -> Result<Effects, InvocationError> {
-        let function = self.functions.get(&target_function);
-        match function {
-            Some(fun) => fun.invoke(context, message),
-            None => Err(FunctionNotFound(target_function)),
+        executor::block_on(self.invoke_async(target_function, context, message))
+    }
+
+    /// Invokes the function that is registered for the given `FunctionType`, like `invoke()`, but
+    /// awaits the middleware and functions that were registered using `register_async_fn()`
+    /// instead of blocking on them.
+    pub async fn invoke_async(
+        &self,
+        target_function: FunctionType,
+        context: Context<'_>,
+        message: Message,
+    ) -> Result<Effects, InvocationError> {
+        let function = self.function_for(&target_function, &message)?;
+        let self_address = context.self_address();
+        let backed_values = match self.backed_states.get(&target_function) {
+            Some(backed_states) => backed_states.read(&self_address).await,
+            None => BackedValues::default(),
+        };
+        let context = context.with_backed_values(&backed_values);
+        let allowed_egresses = self.allowed_egresses.get(&target_function).cloned();
+        let error = Mutex::new(None);
+        let next = Next::new(&target_function, &self.middleware, function, &error);
+        let effects = allowed_egresses::scope(allowed_egresses, next.run(context, message)).await;
+        match error.into_inner().unwrap() {
+            Some(error) => Err(error),
+            None => {
+                self.complete_invocation(target_function, &self_address, effects)
+                    .await
+            }
+        }
+    }
+
+    /// Returns the function that is registered for the given `FunctionType`, if it may be invoked
+    /// with the given message.
+    fn function_for(
+        &self,
+        target_function: &FunctionType,
+        message: &Message,
+    ) -> Result<&(dyn InvokableFunction + Send + Sync), InvocationError> {
+        let function = match self
+            .functions
+            .get(target_function)
+            .or(self.default_function.as_ref())
+        {
+            Some(function) => function,
+            None => return Err(FunctionNotFound(target_function.clone())),
+        };
+        if let Some(allowed_typenames) = self.allowed_typenames.get(target_function) {
+            let typename = message.get_type();
+            if !allowed_typenames.contains(&typename) {
+                return Err(InvocationError::DisallowedTypename {
+                    function_type: target_function.clone(),
+                    typename,
+                });
+            }
+        }
+        Ok(function.as_ref())
+    }
+
+    /// Checks the egresses of a successful invocation and writes the states that are kept in a
+    /// `StateBackend`.
+    async fn complete_invocation(
+        &self,
+        target_function: FunctionType,
+        self_address: &Address,
+        effects: Effects,
+    ) -> Result<Effects, InvocationError> {
+        if let Some(allowed_egresses) = self.allowed_egresses.get(&target_function) {
+            let disallowed = effects.egress_messages.iter().find(|(identifier, _, _)| {
+                !allowed_egresses::is_allowed(allowed_egresses, identifier)
+            });
+            if let Some((identifier, _, _)) = disallowed {
+                return Err(InvocationError::DisallowedEgress {
+                    function_type: target_function,
+                    egress: identifier.to_string(),
+                });
+            }
+        }
+        match self.backed_states.get(&target_function) {
+            Some(backed_states) => {
+                apply_backed_state_updates(&target_function, self_address, backed_states, effects)
+                    .await
+            }
+            None => Ok(effects),
+        }
+    }
+
+    /// Returns the types of all registered functions, ordered by namespace and name.
+    pub fn function_types(&self) -> Vec<FunctionType> {
+        let mut function_types: Vec<FunctionType> = self.functions.keys().cloned().collect();
+        function_types
+            .sort_by_key(|function_type| (function_type.get_namespace(), function_type.get_name()));
+        function_types
+    }
+
+    /// Returns the `ValueSpec`s that the function of the given type was registered with, or `None`
+    /// if no such function is registered.
+    pub fn value_specs(&self, function_type: &FunctionType) -> Option<&[ValueSpecBase]> {
+        self.functions
+            .get(function_type)
+            .map(|function| function.value_specs())
+    }
+
+    /// Returns how often an invocation of each function had to be answered by asking Flink for
+    /// missing state, which costs a round-trip. A count that keeps growing indicates that a
+    /// declared state never gets allocated, so the function can never make progress.
+    pub fn missing_state_counts(&self) -> HashMap<FunctionType, u64> {
+        self.missing_state_counts.lock().unwrap().clone()
+    }
+
+    /// Records that an invocation of the given function was answered by asking for missing state.
+    pub(crate) fn record_missing_states(&self, function_type: &FunctionType) {
+        let mut missing_state_counts = self.missing_state_counts.lock().unwrap();
+        *missing_state_counts
+            .entry(function_type.clone())
+            .or_insert(0) += 1;
+    }
+}
+
+/// Returns `InvocationError::MissingStates` if Flink didn't provide all of the given states.
+fn check_states(value_specs: &[ValueSpecBase], context: &Context) -> Result<(), InvocationError> {
+    let mut missing_states: Vec<ValueSpecBase> = Vec::new();
+
+    // NOTE: The API is very tricky:
+    //
+    // Context for a function's state can be in one of three states:
+    // A) Missing, for example when this is a brand new state variable Flink doesn't know about.
+    // B) Allocated but uninitialized, when Flink allocates storage for this state variable
+    //    but doesn't have any value stored in it yet.
+    // C) Allocated and initialized, when a function has stored a value in a state variable
+    //    successfully (this means Flink received the response for a state mutation).
+    //
+    // In each of these three cases Flink sends wildly different `ToFunction.PersistedValue`
+    // in the request.
+    //
+    // - Assume a new state value called `my_state` that stores an `i32`
+    // - When a state value is first introduced in a function, in the first call the context
+    //   will not contain this state value. We return `incomplete_invocation_context` to let
+    //   Flink allocate storage for this state.
+    // - Flink then prepares storage for `my_state` and calls the function again.
+    //   The context will contain `ValueSpecBase { name: "my_state", typename: "" }: []`
+    //   Note how the `typename` is still empty here despite it being set in the previous
+    //   `incomplete_invocation_context` response. This could be a Flink Statefun bug..
+    // - Afterwards when we initialize this state to a value, e.g. 42, context will contain:
+    //   `ValueSpecBase { name: "my_state", typename: "io.statefun.types/int" }: [0x42]`
+    //
+    // - Therefore we cannot check the typename consistently as it's only ever set after the
+    //   first time we write to the state.
+    //
+    // See also:
+    //   - https://issues.apache.org/jira/browse/FLINK-20265
+    //   - https://github.com/apache/flink-statefun/pull/177
+
+    for value_spec in value_specs.iter() {
+        let mut found: bool = false;
+        for context_spec in context.state.iter() {
+            if value_spec.name.eq(&context_spec.0.name) {
+                found = true;
+                break;
+            }
+        }
+
+        if !found {
+            missing_states.push(value_spec.clone());
+        }
+    }
+
+    if !missing_states.is_empty() {
+        return Err(InvocationError::MissingStates(MissingStates {
+            states: missing_states,
+        }));
+    }
+    Ok(())
+}
+
+/// Checks that the given `ValueSpec`s can be registered for a function.
+fn validate_value_specs(
+    function_type: &FunctionType,
+    value_specs: &[ValueSpecBase],
+) -> Result<(), RegistrationError> {
+    // missing states are detected by name, so names have to be unique within a function
+    for (index, value_spec) in value_specs.iter().enumerate() {
+        if value_spec.name.is_empty() {
+            return Err(RegistrationError::EmptyStateName(function_type.clone()));
+        }
+        if value_specs[..index]
+            .iter()
+            .any(|other| other.name == value_spec.name)
+        {
+            return Err(RegistrationError::DuplicateStateName(
+                function_type.clone(),
+                value_spec.name.clone(),
+            ));
+        }
+        // Flink rejects the state when it is first requested otherwise, which is much harder
+        // to trace back to the `TypeName` implementation
+        if !is_valid_typename(&value_spec.typename) {
+            return Err(RegistrationError::InvalidStateTypename(
+                function_type.clone(),
+                value_spec.name.clone(),
+                value_spec.typename.clone(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Applies the state updates of the given effects that belong to states in a `StateBackend` and
+/// returns the effects without them, so that only the remaining updates are sent to Flink.
+async fn apply_backed_state_updates(
+    function_type: &FunctionType,
+    address: &Address,
+    backed_states: &BackedStates,
+    mut effects: Effects,
+) -> Result<Effects, InvocationError> {
+    let mut flink_state_updates = Vec::new();
+    for state_update in effects.state_updates {
+        let state_name = match &state_update {
+            StateUpdate::Update(value_spec, _) | StateUpdate::Delete(value_spec) => {
+                value_spec.name.clone()
+            }
+        };
+        let backend = match backed_states.backend(&state_name) {
+            Some(backend) => backend,
+            None => {
+                flink_state_updates.push(state_update);
+                continue;
+            }
+        };
+        let result = match &state_update {
+            StateUpdate::Update(_, serialized) => {
+                backend.set(address, &state_name, serialized).await
+            }
+            StateUpdate::Delete(_) => backend.delete(address, &state_name).await,
+        };
+        if let Err(reason) = result {
+            return Err(InvocationError::StateBackendError {
+                function_type: function_type.clone(),
+                state_name,
+                reason,
+            });
         }
     }
+    effects.state_updates = flink_state_updates;
+    Ok(effects)
+}
+
+/// Returns `true` if the typename has the `<namespace>/<name>` form that Flink requires.
+fn is_valid_typename(typename: &str) -> bool {
+    match typename.rfind('/') {
+        Some(index) => index > 0 && index < typename.len() - 1,
+        None => false,
+    }
 }
 
 /// A function that can be invoked. This is used as trait objects in the `FunctionRegistry`.
-trait InvokableFunction {
-    fn invoke(&self, context: Context, message: Message) -> Result<Effects, InvocationError>;
+pub(crate) trait InvokableFunction {
+    /// Invokes the function without blocking on asynchronous functions. Missing states are
+    /// detected before the returned future is created. Synchronous functions are invoked right
+    /// away and return a ready future.
+    fn invoke_async<'a>(
+        &'a self,
+        context: Context<'a>,
+        message: Message,
+    ) -> BoxFuture<'a, Result<Effects, InvocationError>>;
+
+    /// Returns the `ValueSpec`s that the function was registered with.
+    fn value_specs(&self) -> &[ValueSpecBase];
 }
 
 /// An `InvokableFunction` that is backed by a `Fn`.
-struct FnInvokableFunction<F: Fn(Context, Message) -> Effects> {
+struct FnInvokableFunction<F: Fn(Context, Message) -> Result<Effects, InvocationError>> {
     function: F,
     marker: ::std::marker::PhantomData<Message>,
     value_specs: Vec<ValueSpecBase>,
 }
 
-impl<F: Fn(Context, Message) -> Effects> InvokableFunction for FnInvokableFunction<F> {
-    fn invoke(&self, context: Context, message: Message) -> Result<Effects, InvocationError> {
-        let mut missing_states: Vec<ValueSpecBase> = Vec::new();
-
-        // NOTE: The API is very tricky:
-        //
-        // Context for a function's state can be in one of three states:
-        // A) Missing, for example when this is a brand new state variable Flink doesn't know about.
-        // B) Allocated but uninitialized, when Flink allocates storage for this state variable
-        //    but doesn't have any value stored in it yet.
-        // C) Allocated and initialized, when a function has stored a value in a state variable
-        //    successfully (this means Flink received the response for a state mutation).
-        //
-        // In each of these three cases Flink sends wildly different `ToFunction.PersistedValue`
-        // in the request.
-        //
-        // - Assume a new state value called `my_state` that stores an `i32`
-        // - When a state value is first introduced in a function, in the first call the context
-        //   will not contain this state value. We return `incomplete_invocation_context` to let
-        //   Flink allocate storage for this state.
-        // - Flink then prepares storage for `my_state` and calls the function again.
-        //   The context will contain `ValueSpecBase { name: "my_state", typename: "" }: []`
-        //   Note how the `typename` is still empty here despite it being set in the previous
-        //   `incomplete_invocation_context` response. This could be a Flink Statefun bug..
-        // - Afterwards when we initialize this state to a value, e.g. 42, context will contain:
-        //   `ValueSpecBase { name: "my_state", typename: "io.statefun.types/int" }: [0x42]`
-        //
-        // - Therefore we cannot check the typename consistently as it's only ever set after the
-        //   first time we write to the state.
-        //
-        // See also:
-        //   - https://issues.apache.org/jira/browse/FLINK-20265
-        //   - https://github.com/apache/flink-statefun/pull/177
-
-        for value_spec in self.value_specs.iter() {
-            let mut found: bool = false;
-            for context_spec in context.state.iter() {
-                if value_spec.name.eq(&context_spec.0.name) {
-                    found = true;
-                    break;
-                }
-            }
+impl<F: Fn(Context, Message) -> Result<Effects, InvocationError>> InvokableFunction
+    for FnInvokableFunction<F>
+{
+    fn value_specs(&self) -> &[ValueSpecBase] {
+        &self.value_specs
+    }
 
-            if !found {
-                missing_states.push(value_spec.clone());
-            }
-        }
+    fn invoke_async<'a>(
+        &'a self,
+        context: Context<'a>,
+        message: Message,
+    ) -> BoxFuture<'a, Result<Effects, InvocationError>> {
+        let result = check_states(&self.value_specs, &context).and_then(|()| {
+            let context = context.with_declared_specs(&self.value_specs);
+            (self.function)(context, message)
+        });
+        Box::pin(future::ready(result))
+    }
+}
 
-        if !missing_states.is_empty() {
-            return Err(InvocationError::MissingStates(MissingStates {
-                states: missing_states,
-            }));
-        }
+struct AsyncFnInvokableFunction<F> {
+    function: F,
+    value_specs: Vec<ValueSpecBase>,
+}
 
-        let effects = (self.function)(context, message);
-        Ok(effects)
+impl<F> InvokableFunction for AsyncFnInvokableFunction<F>
+where
+    F: for<'a> Fn(Context<'a>, Message) -> BoxFuture<'a, Effects>,
+{
+    fn value_specs(&self) -> &[ValueSpecBase] {
+        &self.value_specs
+    }
+
+    fn invoke_async<'a>(
+        &'a self,
+        context: Context<'a>,
+        message: Message,
+    ) -> BoxFuture<'a, Result<Effects, InvocationError>> {
+        if let Err(error) = check_states(&self.value_specs, &context) {
+            return Box::pin(future::ready(Err(error)));
+        }
+        let context = context.with_declared_specs(&self.value_specs);
+        Box::pin((self.function)(context, message).map(Ok))
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::function_registry::is_valid_typename;
     use crate::FunctionRegistry;
     use crate::*;
+    use futures::future::{BoxFuture, FutureExt};
     use protobuf::well_known_types::StringValue;
     use protobuf::Message as ProtoMessage;
     use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
 
     fn to_typed_value(typename: String, value: Vec<u8>) -> TypedValue {
         let mut res = TypedValue::new();
@@ -147,21 +717,74 @@ mod tests {
         res
     }
 
+    fn assert_sync<T: Sync>() {}
+
+    fn assert_send<T: Send>() {}
+
+    #[test]
+    fn registry_is_thread_safe() {
+        assert_sync::<FunctionRegistry>();
+        assert_send::<FunctionRegistry>();
+    }
+
     #[test]
     fn call_registered_function() -> anyhow::Result<()> {
         let state = HashMap::new();
-        let address = address_foo().into_proto();
-        let context = Context::new(&state, &address, &address);
+        let address = address_foo();
+        let context = Context::new(&state, &address, Some(address_foo()));
 
+        let mut registry = FunctionRegistry::new();
+        registry
+            .register_fn(
+                function_type_foo(),
+                vec![],
+                |_context, _message: Message| Effects::new(),
+            )
+            .unwrap();
+
+        let message = Message::new(to_typed_value("some-type".to_string(), vec![]));
+        let _effects = registry.invoke(function_type_foo(), context, message)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn function_sees_declared_specs() -> anyhow::Result<()> {
+        let count = || ValueSpec::<i32>::new("count", Expiration::never());
+        let name = || ValueSpec::<String>::new("name", Expiration::never());
+        let mut state = HashMap::new();
+        state.insert(count().into(), vec![]);
+        state.insert(name().into(), vec![]);
+        let address = address_foo();
+        let context = Context::new(&state, &address, None);
+
+        let seen_specs = Arc::new(Mutex::new(Vec::new()));
+        let function_seen_specs = Arc::clone(&seen_specs);
         let mut registry = FunctionRegistry::new();
         registry.register_fn(
             function_type_foo(),
-            vec![],
-            |_context, _message: Message| Effects::new(),
-        );
+            vec![count().into(), name().into()],
+            move |context, _message: Message| {
+                *function_seen_specs.lock().unwrap() = context.declared_specs().to_vec();
+                Effects::new()
+            },
+        )?;
 
         let message = Message::new(to_typed_value("some-type".to_string(), vec![]));
-        let _effects = registry.invoke(function_type_foo(), context, message)?;
+        registry.invoke(function_type_foo(), context, message)?;
+
+        let seen_specs = seen_specs.lock().unwrap();
+        let names: Vec<(&str, &str)> = seen_specs
+            .iter()
+            .map(|spec| (spec.name(), spec.typename()))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                ("count", i32::get_typename()),
+                ("name", String::get_typename())
+            ]
+        );
 
         Ok(())
     }
@@ -169,8 +792,8 @@ mod tests {
     #[test]
     fn call_unknown_function() -> anyhow::Result<()> {
         let state = HashMap::new();
-        let address = address_foo().into_proto();
-        let context = Context::new(&state, &address, &address);
+        let address = address_foo();
+        let context = Context::new(&state, &address, Some(address_foo()));
 
         let registry = FunctionRegistry::new();
         let message = Message::new(to_typed_value("some-type".to_string(), vec![]));
@@ -181,6 +804,165 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn reject_empty_state_name() {
+        let mut registry = FunctionRegistry::new();
+        let result = registry.register_fn(
+            function_type_foo(),
+            vec![ValueSpec::<i32>::new("", Expiration::never()).into()],
+            |_context, _message: Message| Effects::new(),
+        );
+        assert_eq!(
+            result,
+            Err(RegistrationError::EmptyStateName(function_type_foo()))
+        );
+    }
+
+    struct Untyped;
+
+    impl TypeName for Untyped {
+        fn get_typename() -> &'static str {
+            ""
+        }
+    }
+
+    impl Serializable<Untyped> for Untyped {
+        fn serialize(&self, _typename: String) -> Result<Vec<u8>, String> {
+            Ok(Vec::new())
+        }
+
+        fn deserialize(_typename: String, _buffer: &[u8]) -> Result<Untyped, String> {
+            Ok(Untyped)
+        }
+    }
+
+    #[test]
+    fn reject_invalid_state_typename() {
+        let mut registry = FunctionRegistry::new();
+        let result = registry.register_fn(
+            function_type_foo(),
+            vec![ValueSpec::<Untyped>::new("untyped", Expiration::never()).into()],
+            |_context, _message: Message| Effects::new(),
+        );
+        assert_eq!(
+            result,
+            Err(RegistrationError::InvalidStateTypename(
+                function_type_foo(),
+                "untyped".to_string(),
+                "".to_string()
+            ))
+        );
+
+        assert!(is_valid_typename("com.example/user"));
+        assert!(!is_valid_typename("user"));
+        assert!(!is_valid_typename("/user"));
+        assert!(!is_valid_typename("com.example/"));
+    }
+
+    #[test]
+    fn export_module_spec() -> anyhow::Result<()> {
+        let mut registry = FunctionRegistry::new();
+        registry.register_fn(
+            function_type_foo(),
+            vec![],
+            |_context, _message: Message| Effects::new(),
+        )?;
+        registry.register_fn(
+            function_type_bar(),
+            vec![],
+            |_context, _message: Message| Effects::new(),
+        )?;
+        registry.register_egress(
+            EgressIdentifier::new("com.example", "greetings"),
+            io::kafka::RECORD_TYPENAME,
+        )?;
+
+        let result = registry.register_egress(
+            EgressIdentifier::new("com.example", "custom"),
+            "com.example/Record",
+        );
+        assert!(matches!(
+            result,
+            Err(RegistrationError::UnsupportedEgressRecordType(_, _))
+        ));
+
+        assert_eq!(
+            registry.export_module_spec("http://functions:8000/statefun"),
+            "kind: io.statefun.endpoints.v2/http\n\
+             spec:\n\
+             \x20 functions: namespace/*\n\
+             \x20 urlPathTemplate: http://functions:8000/statefun\n\
+             ---\n\
+             kind: io.statefun.kafka.v1/egress\n\
+             spec:\n\
+             \x20 id: com.example/greetings\n\
+             \x20 # replace with the bootstrap servers of the cluster\n\
+             \x20 address: kafka:9092\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn reject_duplicate_state_name() {
+        let mut registry = FunctionRegistry::new();
+        let result = registry.register_fn(
+            function_type_foo(),
+            vec![
+                ValueSpec::<i32>::new("count", Expiration::never()).into(),
+                ValueSpec::<String>::new("name", Expiration::never()).into(),
+                ValueSpec::<String>::new("count", Expiration::never()).into(),
+            ],
+            |_context, _message: Message| Effects::new(),
+        );
+        assert_eq!(
+            result,
+            Err(RegistrationError::DuplicateStateName(
+                function_type_foo(),
+                "count".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn checked_function_rejects_wrong_message_type() -> anyhow::Result<()> {
+        let state = HashMap::new();
+        let address = address_foo();
+
+        let mut registry = FunctionRegistry::new();
+        registry
+            .register_checked_fn(function_type_foo(), vec![], |_context, _message: String| {
+                Effects::new()
+            })
+            .unwrap();
+
+        let message = Message::new(to_typed_value(
+            i32::get_typename().to_string(),
+            42.serialize(i32::get_typename().to_string()).unwrap(),
+        ));
+        let context = Context::new(&state, &address, None);
+        let result = registry.invoke(function_type_foo(), context, message);
+        match result {
+            Err(InvocationError::InvalidMessage {
+                function_type,
+                reason,
+            }) => {
+                assert_eq!(function_type, function_type_foo());
+                assert!(reason.contains(i32::get_typename()));
+            }
+            other => panic!("Expected InvalidMessage, got {:?}", other),
+        }
+
+        let message = Message::new(to_typed_value(
+            String::get_typename().to_string(),
+            "hello".to_string().serialize("".to_string()).unwrap(),
+        ));
+        let context = Context::new(&state, &address, None);
+        registry.invoke(function_type_foo(), context, message)?;
+
+        Ok(())
+    }
+
     /// Have to wrap the struct to implement Serializable
     pub struct MyStringValue(pub StringValue);
 
@@ -212,30 +994,34 @@ mod tests {
         let state = HashMap::new();
 
         let mut registry = FunctionRegistry::new();
-        registry.register_fn(function_type_foo(), vec![], |context, _message: Message| {
-            let mut effects = Effects::new();
+        registry
+            .register_fn(function_type_foo(), vec![], |context, _message: Message| {
+                let mut effects = Effects::new();
 
-            let mut message = StringValue::new();
-            message.set_value("function_foo".to_owned());
-            let message = MyStringValue(message);
-            effects.send(context.self_address(), &message).unwrap();
+                let mut message = StringValue::new();
+                message.set_value("function_foo".to_owned());
+                let message = MyStringValue(message);
+                effects.send(context.self_address(), &message).unwrap();
 
-            effects
-        });
+                effects
+            })
+            .unwrap();
 
-        registry.register_fn(function_type_bar(), vec![], |context, _message: Message| {
-            let mut effects = Effects::new();
+        registry
+            .register_fn(function_type_bar(), vec![], |context, _message: Message| {
+                let mut effects = Effects::new();
 
-            let mut message = StringValue::new();
-            message.set_value("function_bar".to_owned());
-            let message = MyStringValue(message);
-            effects.send(context.self_address(), &message).unwrap();
+                let mut message = StringValue::new();
+                message.set_value("function_bar".to_owned());
+                let message = MyStringValue(message);
+                effects.send(context.self_address(), &message).unwrap();
 
-            effects
-        });
+                effects
+            })
+            .unwrap();
 
-        let address_foo = address_foo().into_proto();
-        let context = Context::new(&state, &address_foo, &address_foo);
+        let address_foo = address_foo();
+        let context = Context::new(&state, &address_foo, Some(address_foo.clone()));
         let message = Message::new(to_typed_value("some-type".to_string(), vec![]));
         let effects_foo = registry.invoke(function_type_foo(), context, message)?;
         assert_eq!(
@@ -246,8 +1032,8 @@ mod tests {
             "function_foo",
         );
 
-        let address_bar = address_bar().into_proto();
-        let context = Context::new(&state, &address_bar, &address_bar);
+        let address_bar = address_bar();
+        let context = Context::new(&state, &address_bar, Some(address_bar.clone()));
         let message = Message::new(to_typed_value("some-type".to_string(), vec![]));
         let effects_bar = registry.invoke(function_type_bar(), context, message)?;
         assert_eq!(
@@ -261,6 +1047,528 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn warn_on_undeclared_state_read() -> anyhow::Result<()> {
+        crate::test_logger::init();
+
+        let state = HashMap::new();
+        let address = address_foo();
+        let context = Context::new(&state, &address, Some(address_foo()));
+
+        let mut registry = FunctionRegistry::new();
+        register!(registry, function_type_foo(), [], |context, _message| {
+            let undeclared = ValueSpec::<i32>::new("undeclared_state", Expiration::never());
+            assert!(context.get_state(undeclared).is_none());
+            Effects::new()
+        })
+        .unwrap();
+
+        let message = Message::new(to_typed_value("some-type".to_string(), vec![]));
+        registry.invoke(function_type_foo(), context, message)?;
+
+        let warnings = crate::test_logger::captured(log::Level::Warn);
+        assert!(warnings
+            .iter()
+            .any(|warning| warning.contains("\"undeclared_state\"")));
+
+        Ok(())
+    }
+
+    fn egress_function(_context: Context, _message: Message) -> Effects {
+        let mut effects = Effects::new();
+        effects.egress_raw(
+            EgressIdentifier::new("namespace", "egress"),
+            "some-type",
+            vec![],
+        );
+        effects
+    }
+
+    #[test]
+    fn reject_disallowed_typenames() -> anyhow::Result<()> {
+        let state = HashMap::new();
+        let address = address_foo();
+
+        let mut registry = FunctionRegistry::new();
+        registry.register_fn(function_type_foo(), vec![], egress_function)?;
+        registry.allow_typenames(function_type_foo(), &[String::get_typename()]);
+
+        let message = Message::new(to_typed_value(
+            String::get_typename().to_string(),
+            "hello".to_string().serialize(String::new()).unwrap(),
+        ));
+        let context = Context::new(&state, &address, None);
+        let effects = registry.invoke(function_type_foo(), context, message)?;
+        assert_eq!(effects.pending_egresses(), 1);
+
+        let message = Message::new(to_typed_value("com.example/Evil".to_string(), vec![0xff]));
+        let context = Context::new(&state, &address, None);
+        let result = registry.invoke(function_type_foo(), context, message);
+        match result {
+            Err(InvocationError::DisallowedTypename {
+                function_type,
+                typename,
+            }) => {
+                assert_eq!(function_type, function_type_foo());
+                assert_eq!(typename, "com.example/Evil");
+            }
+            _ => panic!("expected a DisallowedTypename error"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn restrict_egresses() -> anyhow::Result<()> {
+        let state = HashMap::new();
+        let address = address_foo();
+
+        let mut registry = FunctionRegistry::new();
+        registry.register_fn(function_type_foo(), vec![], |_context, message| {
+            let mut effects = Effects::new();
+            let egress = message.get::<String>().unwrap();
+            let result = effects.egress(EgressIdentifier::new("namespace", &egress), &egress);
+            effects
+                .send(Address::new(function_type_bar(), "result"), &result.is_ok())
+                .unwrap();
+            effects
+        })?;
+        registry.register_fn(function_type_bar(), vec![], egress_function)?;
+        let allowed = [EgressIdentifier::new("namespace", "allowed")];
+        registry.allow_egresses(function_type_foo(), &allowed);
+        registry.allow_egresses(function_type_bar(), &allowed);
+
+        let invoke = |function_type: FunctionType, egress: &str| {
+            let message = Message::new(to_typed_value(
+                String::get_typename().to_string(),
+                egress.to_string().serialize(String::new()).unwrap(),
+            ));
+            let context = Context::new(&state, &address, None);
+            registry.invoke(function_type, context, message)
+        };
+
+        let effects = invoke(function_type_foo(), "allowed")?;
+        assert_eq!(effects.pending_egresses(), 1);
+        assert!(bool::deserialize(String::new(), &effects.invocations[0].2).unwrap());
+
+        let effects = invoke(function_type_foo(), "forbidden")?;
+        assert_eq!(effects.pending_egresses(), 0);
+        assert!(!bool::deserialize(String::new(), &effects.invocations[0].2).unwrap());
+
+        // Egresses that bypass the checks in Effects fail the whole invocation.
+        match invoke(function_type_bar(), "ignored") {
+            Err(InvocationError::DisallowedEgress {
+                function_type,
+                egress,
+            }) => {
+                assert_eq!(function_type, function_type_bar());
+                assert_eq!(egress, "EgressIdentifier namespace/egress");
+            }
+            _ => panic!("expected a DisallowedEgress error"),
+        }
+
+        Ok(())
+    }
+
+    /// A `StateBackend` that keeps the state in memory and records the operations on it.
+    #[derive(Default)]
+    struct RecordingBackend {
+        values: Mutex<HashMap<String, Vec<u8>>>,
+        operations: Mutex<Vec<String>>,
+    }
+
+    impl StateBackend for RecordingBackend {
+        fn get<'a>(
+            &'a self,
+            address: &'a Address,
+            name: &'a str,
+        ) -> BoxFuture<'a, Result<Option<Vec<u8>>, String>> {
+            async move {
+                self.operations
+                    .lock()
+                    .unwrap()
+                    .push(format!("get {}", name));
+                let key = format!("{}/{}", address.id, name);
+                Ok(self.values.lock().unwrap().get(&key).cloned())
+            }
+            .boxed()
+        }
+
+        fn set<'a>(
+            &'a self,
+            address: &'a Address,
+            name: &'a str,
+            value: &'a [u8],
+        ) -> BoxFuture<'a, Result<(), String>> {
+            async move {
+                self.operations
+                    .lock()
+                    .unwrap()
+                    .push(format!("set {}", name));
+                let key = format!("{}/{}", address.id, name);
+                self.values.lock().unwrap().insert(key, value.to_vec());
+                Ok(())
+            }
+            .boxed()
+        }
+
+        fn delete<'a>(
+            &'a self,
+            address: &'a Address,
+            name: &'a str,
+        ) -> BoxFuture<'a, Result<(), String>> {
+            async move {
+                self.operations
+                    .lock()
+                    .unwrap()
+                    .push(format!("delete {}", name));
+                let key = format!("{}/{}", address.id, name);
+                self.values.lock().unwrap().remove(&key);
+                Ok(())
+            }
+            .boxed()
+        }
+    }
+
+    #[test]
+    fn state_backend() -> anyhow::Result<()> {
+        let visits = || ValueSpec::<i32>::new("visits", Expiration::never());
+        let backend = Arc::new(RecordingBackend::default());
+
+        let mut registry = FunctionRegistry::new();
+        registry.register_fn(function_type_foo(), vec![], move |context, _message| {
+            let count = match context.get_state(visits()) {
+                Some(count) => count.unwrap(),
+                None => 0,
+            };
+            let mut effects = Effects::new();
+            effects.update_state(visits(), &(count + 1)).unwrap();
+            effects
+        })?;
+        registry.back_state_with(function_type_foo(), "visits", backend.clone());
+
+        let state = HashMap::new();
+        let address = address_foo();
+        for _ in 0..2 {
+            let message = Message::new(to_typed_value("some-type".to_string(), vec![]));
+            let context = Context::new(&state, &address, None);
+            let effects = registry.invoke(function_type_foo(), context, message)?;
+            assert_eq!(effects.pending_state_updates(), 0);
+        }
+
+        assert_eq!(
+            *backend.operations.lock().unwrap(),
+            vec!["get visits", "set visits", "get visits", "set visits"]
+        );
+        let stored = backend.values.lock().unwrap()["doctor/visits"].clone();
+        assert_eq!(i32::deserialize(String::new(), &stored), Ok(2));
+
+        Ok(())
+    }
+
+    #[test]
+    fn middleware_short_circuits() -> anyhow::Result<()> {
+        let state = HashMap::new();
+        let address = address_foo();
+
+        let mut registry = FunctionRegistry::new();
+        registry.register_fn(function_type_foo(), vec![], egress_function)?;
+        // only other functions are authorized to invoke functions
+        registry.wrap(|_function_type, context, message, next| {
+            async move {
+                if context.is_ingress() {
+                    return Effects::new();
+                }
+                next.run(context, message).await
+            }
+            .boxed()
+        });
+
+        let message = || Message::new(to_typed_value("some-type".to_string(), vec![]));
+        let context = Context::new(&state, &address, None);
+        let effects = registry.invoke(function_type_foo(), context, message())?;
+        assert_eq!(effects.pending_egresses(), 0);
+
+        let context = Context::new(&state, &address, Some(address_bar()));
+        let effects = registry.invoke(function_type_foo(), context, message())?;
+        assert_eq!(effects.pending_egresses(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn middleware_replaces_response() -> anyhow::Result<()> {
+        let state = HashMap::new();
+        let address = address_foo();
+        let invocations = Arc::new(Mutex::new(0));
+        let function_invocations = Arc::clone(&invocations);
+
+        let mut registry = FunctionRegistry::new();
+        registry.register_fn(function_type_foo(), vec![], move |context, message| {
+            *function_invocations.lock().unwrap() += 1;
+            egress_function(context, message)
+        })?;
+        // deny messages from function bar with a rejection egress
+        registry.wrap(|_function_type, context, message, next| {
+            async move {
+                if context.caller_address() == Some(address_bar()) {
+                    let mut effects = Effects::new();
+                    effects
+                        .egress(
+                            EgressIdentifier::new("namespace", "rejections"),
+                            &"denied".to_string(),
+                        )
+                        .unwrap();
+                    return effects;
+                }
+                next.run(context, message).await
+            }
+            .boxed()
+        });
+
+        let message = Message::new(to_typed_value("some-type".to_string(), vec![]));
+        let context = Context::new(&state, &address, Some(address_bar()));
+        let effects = registry.invoke(function_type_foo(), context, message)?;
+
+        assert_eq!(*invocations.lock().unwrap(), 0);
+        assert_eq!(effects.egress_messages.len(), 1);
+        let (identifier, _typename, serialized) = &effects.egress_messages[0];
+        assert_eq!(identifier.name, "rejections");
+        assert_eq!(
+            String::deserialize(String::new(), serialized),
+            Ok("denied".to_string())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn middleware_records_timing() -> anyhow::Result<()> {
+        let state = HashMap::new();
+        let address = address_foo();
+        let timings = Arc::new(Mutex::new(Vec::new()));
+        let middleware_timings = Arc::clone(&timings);
+
+        let mut registry = FunctionRegistry::new();
+        registry.register_fn(function_type_foo(), vec![], |context, message| {
+            thread::sleep(Duration::from_millis(10));
+            egress_function(context, message)
+        })?;
+        registry.wrap(move |function_type, context, message, next| {
+            let timings = Arc::clone(&middleware_timings);
+            async move {
+                let start = Instant::now();
+                let effects = next.run(context, message).await;
+                timings
+                    .lock()
+                    .unwrap()
+                    .push((function_type, start.elapsed()));
+                effects
+            }
+            .boxed()
+        });
+
+        let context = Context::new(&state, &address, None);
+        let message = Message::new(to_typed_value("some-type".to_string(), vec![]));
+        let effects = registry.invoke(function_type_foo(), context, message)?;
+        assert_eq!(effects.pending_egresses(), 1);
+
+        let timings = timings.lock().unwrap();
+        assert_eq!(timings.len(), 1);
+        assert_eq!(timings[0].0, function_type_foo());
+        assert!(timings[0].1 >= Duration::from_millis(10));
+
+        Ok(())
+    }
+
+    #[test]
+    fn middleware_does_not_hide_missing_states() -> anyhow::Result<()> {
+        let state = HashMap::new();
+        let address = address_foo();
+
+        let mut registry = FunctionRegistry::new();
+        registry.register_fn(
+            function_type_foo(),
+            vec![ValueSpec::<i32>::new("count", Expiration::never()).into()],
+            egress_function,
+        )?;
+        registry.wrap(|_function_type, context, message, next| next.run(context, message));
+
+        let context = Context::new(&state, &address, None);
+        let message = Message::new(to_typed_value("some-type".to_string(), vec![]));
+        let result = registry.invoke(function_type_foo(), context, message);
+        assert!(matches!(result, Err(InvocationError::MissingStates(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn default_function() -> anyhow::Result<()> {
+        let state = HashMap::new();
+
+        let mut registry = FunctionRegistry::new();
+        registry.register_fn(
+            function_type_foo(),
+            vec![],
+            |_context, _message: Message| Effects::new(),
+        )?;
+        registry.register_default(|function_type, _context, _message: Message| {
+            let mut effects = Effects::new();
+            effects
+                .egress(
+                    EgressIdentifier::new("namespace", "unknown"),
+                    &function_type.to_string(),
+                )
+                .unwrap();
+            effects
+        });
+
+        let address = address_foo();
+        let message = Message::new(to_typed_value("some-type".to_string(), vec![]));
+        let context = Context::new(&state, &address, None);
+        let effects = registry.invoke(function_type_foo(), context, message)?;
+        assert_eq!(effects.pending_egresses(), 0);
+
+        let address = address_bar();
+        let message = Message::new(to_typed_value("some-type".to_string(), vec![]));
+        let context = Context::new(&state, &address, None);
+        let effects = registry.invoke(function_type_bar(), context, message)?;
+        let (_identifier, typename, value) = &effects.egress_messages[0];
+        assert_eq!(
+            String::deserialize(typename.clone(), value).unwrap(),
+            function_type_bar().to_string()
+        );
+
+        assert_eq!(registry.function_types(), vec![function_type_foo()]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn fallible_function() -> anyhow::Result<()> {
+        let state = HashMap::new();
+        let address = address_foo();
+
+        let mut registry = FunctionRegistry::new();
+        registry.register_fallible_fn(function_type_foo(), vec![], |_context, message| {
+            let greeting = message.get::<String>()?;
+            let mut effects = Effects::new();
+            effects.egress(EgressIdentifier::new("namespace", "egress"), &greeting)?;
+            Ok(effects)
+        })?;
+
+        let message = Message::new(to_typed_value(
+            String::get_typename().to_string(),
+            "hello".to_string().serialize(String::new()).unwrap(),
+        ));
+        let context = Context::new(&state, &address, None);
+        let effects = registry.invoke(function_type_foo(), context, message)?;
+        assert_eq!(effects.pending_egresses(), 1);
+
+        let message = Message::new(to_typed_value("com.example/Other".to_string(), vec![]));
+        let context = Context::new(&state, &address, None);
+        match registry.invoke(function_type_foo(), context, message) {
+            Err(InvocationError::UserFunction {
+                function_type,
+                error,
+            }) => {
+                assert_eq!(function_type, function_type_foo());
+                assert!(error.to_string().contains("com.example/Other"));
+            }
+            _ => panic!("expected a UserFunction error"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn async_function() -> anyhow::Result<()> {
+        let mut state = HashMap::new();
+        state.insert(
+            ValueSpec::<i32>::new("count", Expiration::never()).into(),
+            41.serialize(String::new()).unwrap(),
+        );
+        let address = address_foo();
+
+        let mut registry = FunctionRegistry::new();
+        registry.register_async_fn(
+            function_type_foo(),
+            vec![ValueSpec::<i32>::new("count", Expiration::never()).into()],
+            |context: Context, _message: Message| {
+                Box::pin(async move {
+                    let (sender, receiver) = futures::channel::oneshot::channel();
+                    thread::spawn(move || sender.send(1));
+                    let increment: i32 = receiver.await.unwrap();
+                    let count: i32 = context
+                        .get_state(ValueSpec::new("count", Expiration::never()))
+                        .unwrap()
+                        .unwrap();
+                    let mut effects = Effects::new();
+                    effects
+                        .update_state(
+                            ValueSpec::new("count", Expiration::never()),
+                            &(count + increment),
+                        )
+                        .unwrap();
+                    effects
+                })
+            },
+        )?;
+
+        let context = Context::new(&state, &address, None);
+        let message = Message::new(to_typed_value("some-type".to_string(), vec![]));
+        let effects = futures::executor::block_on(registry.invoke_async(
+            function_type_foo(),
+            context,
+            message,
+        ))?;
+        assert_eq!(
+            effects.state_updates,
+            vec![StateUpdate::Update(
+                ValueSpec::<i32>::new("count", Expiration::never()).into(),
+                42.serialize(String::new()).unwrap()
+            )]
+        );
+
+        // invoking it synchronously blocks on the future
+        let context = Context::new(&state, &address, None);
+        let message = Message::new(to_typed_value("some-type".to_string(), vec![]));
+        let effects = registry.invoke(function_type_foo(), context, message)?;
+        assert_eq!(effects.pending_state_updates(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn async_function_is_not_called_with_missing_states() -> anyhow::Result<()> {
+        let state = HashMap::new();
+        let address = address_foo();
+        let called = Arc::new(Mutex::new(false));
+
+        let mut registry = FunctionRegistry::new();
+        let function_called = Arc::clone(&called);
+        registry.register_async_fn(
+            function_type_foo(),
+            vec![ValueSpec::<i32>::new("count", Expiration::never()).into()],
+            move |_context: Context, _message: Message| {
+                *function_called.lock().unwrap() = true;
+                Box::pin(async { Effects::new() })
+            },
+        )?;
+
+        let context = Context::new(&state, &address, None);
+        let message = Message::new(to_typed_value("some-type".to_string(), vec![]));
+        let result = futures::executor::block_on(registry.invoke_async(
+            function_type_foo(),
+            context,
+            message,
+        ));
+        assert!(matches!(result, Err(InvocationError::MissingStates(_))));
+        assert!(!*called.lock().unwrap());
+
+        Ok(())
+    }
+
     fn function_type_foo() -> FunctionType {
         FunctionType::new("namespace", "foo")
     }
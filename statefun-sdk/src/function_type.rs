@@ -1,21 +1,34 @@
+use std::borrow::Cow;
 use std::fmt::{Display, Formatter};
 
 /// A reference to a stateful function, consisting of a namespace and a name.
 ///
 /// A function's type is part of a function's [Address](Address) and serves as integral part of an
 /// individual function's identity.
+///
+/// Cloning a `FunctionType` that was created using `from_static()` does not allocate, so
+/// functions on hot paths can create it once and clone it as needed.
 #[derive(PartialEq, Eq, Hash, Clone, Debug)]
 pub struct FunctionType {
-    namespace: String,
-    name: String,
+    namespace: Cow<'static, str>,
+    name: Cow<'static, str>,
 }
 
 impl FunctionType {
     /// Creates a new `FunctionType` from the given namespace and name.
     pub fn new(namespace: &str, name: &str) -> FunctionType {
         FunctionType {
-            namespace: namespace.to_string(),
-            name: name.to_string(),
+            namespace: Cow::Owned(namespace.to_string()),
+            name: Cow::Owned(name.to_string()),
+        }
+    }
+
+    /// Creates a new `FunctionType` from the given static namespace and name, without allocating.
+    /// This can be used to define a `FunctionType` as a constant.
+    pub const fn from_static(namespace: &'static str, name: &'static str) -> FunctionType {
+        FunctionType {
+            namespace: Cow::Borrowed(namespace),
+            name: Cow::Borrowed(name),
         }
     }
 
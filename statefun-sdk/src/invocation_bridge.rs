@@ -1,6 +1,8 @@
 //! A bridge between the Protobuf world and the world of the Rust SDK. For use by `Transports`.
 use std::collections::HashMap;
+use std::time::Instant;
 
+use futures::future::BoxFuture;
 use protobuf::SingularPtrField;
 
 use statefun_proto::request_reply::FromFunction;
@@ -15,126 +17,215 @@ use statefun_proto::request_reply::FromFunction_PersistedValueMutation;
 use statefun_proto::request_reply::FromFunction_PersistedValueMutation_MutationType;
 use statefun_proto::request_reply::FromFunction_PersistedValueSpec;
 use statefun_proto::request_reply::ToFunction;
+use statefun_proto::request_reply::ToFunction_Invocation;
 use statefun_proto::request_reply::ToFunction_PersistedValue;
 use statefun_proto::request_reply::TypedValue;
 
 use crate::function_registry::FunctionRegistry;
+use crate::message_metadata;
 use crate::{
-    Address, Context, DelayedInvocation, EgressIdentifier, Expiration, ExpirationType,
-    InvocationError, Message, StateUpdate, ValueSpecBase,
+    Address, Context, DelayedInvocation, Effects, EgressIdentifier, Expiration, ExpirationType,
+    InvocationError, Message, MissingStates, StateUpdate, ValueSpecBase,
 };
 
 /// An invokable that takes protobuf `ToFunction` as argument and returns a protobuf `FromFunction`.
+/// The optional deadline is made available to the invoked functions via `Context::deadline()`.
 pub trait InvocationBridge {
-    fn invoke_from_proto(&self, to_function: ToFunction) -> Result<FromFunction, InvocationError>;
+    /// Invokes the functions of the batch in order. Asynchronous functions are awaited, see
+    /// `FunctionRegistry::register_async_fn()`.
+    fn invoke_from_proto_async(
+        &self,
+        to_function: ToFunction,
+        deadline: Option<Instant>,
+    ) -> BoxFuture<'_, Result<FromFunction, InvocationError>>;
+
+    /// Blocks on `invoke_from_proto_async()`, for tests and `testing::BatchBuilder`.
+    fn invoke_from_proto(
+        &self,
+        to_function: ToFunction,
+        deadline: Option<Instant>,
+    ) -> Result<FromFunction, InvocationError> {
+        futures::executor::block_on(self.invoke_from_proto_async(to_function, deadline))
+    }
 }
 
 impl InvocationBridge for FunctionRegistry {
-    fn invoke_from_proto(
+    fn invoke_from_proto_async(
         &self,
+        to_function: ToFunction,
+        deadline: Option<Instant>,
+    ) -> BoxFuture<'_, Result<FromFunction, InvocationError>> {
+        Box::pin(async move {
+            let (mut batch, invocations) = Batch::new(to_function, deadline);
+            for (batch_index, invocation) in invocations.into_iter().enumerate() {
+                let (context, message) = batch.prepare(batch_index, invocation)?;
+                let function_type = batch.self_address.function_type.clone();
+                match self.invoke_async(function_type, context, message).await {
+                    Ok(effects) => batch.apply(effects),
+                    Err(InvocationError::MissingStates(missing_states)) => {
+                        self.record_missing_states(&batch.self_address.function_type);
+                        return Ok(incomplete_invocation_context(&missing_states));
+                    }
+                    Err(error) => return Err(error),
+                }
+            }
+            batch.finish()
+        })
+    }
+}
+
+/// The state of a batch of invocations that is processed by the `InvocationBridge`.
+struct Batch {
+    // the target is the same for all invocations of the batch, so we only decode it once
+    self_address: Address,
+    deadline: Option<Instant>,
+    batch_size: usize,
+    persisted_values: HashMap<ValueSpecBase, Vec<u8>>,
+    // we maintain a map of state updates that we update after every invocation. We maintain
+    // this to be able to send back coalesced state updates to the statefun runtime but we
+    // also need to update persisted_values so that subsequent invocations also "see" state
+    // updates
+    coalesced_state_updates: HashMap<String, StateUpdate>,
+    invocation_response: FromFunction_InvocationResponse,
+}
+
+impl Batch {
+    fn new(
         mut to_function: ToFunction,
-    ) -> Result<FromFunction, InvocationError> {
+        deadline: Option<Instant>,
+    ) -> (Batch, Vec<ToFunction_Invocation>) {
         let mut batch_request = to_function.take_invocation();
         log::debug!(
             "FunctionRegistry: processing batch request {:#?}",
             batch_request
         );
 
-        let self_address = batch_request.take_target();
-        let persisted_values = batch_request.take_state();
-        let mut persisted_values = parse_persisted_values(&persisted_values);
-
-        // we maintain a map of state updates that we update after every invocation. We maintain
-        // this to be able to send back coalesced state updates to the statefun runtime but we
-        // also need to update persisted_values so that subsequent invocations also "see" state
-        // updates
-        let mut coalesced_state_updates: HashMap<ValueSpecBase, StateUpdate> = HashMap::new();
-
-        let mut invocation_response = FromFunction_InvocationResponse::new();
-
-        for mut invocation in batch_request.take_invocations().into_iter() {
-            let caller_address = invocation.take_caller();
-            let argument = Message::new(invocation.take_argument());
-            let context = Context::new(&persisted_values, &self_address, &caller_address);
-
-            let effects = match self.invoke(context.self_address().function_type, context, argument)
-            {
-                Ok(effects) => effects,
-                Err(e) => match &e {
-                    InvocationError::MissingStates(state_collection) => {
-                        let mut incomplete_context =
-                            FromFunction_IncompleteInvocationContext::new();
-
-                        for value_spec in state_collection.states.iter() {
-                            let mut expiration_spec = FromFunction_ExpirationSpec::new();
-
-                            match &value_spec.expiration.expiration_type {
-                                Some(expiration_type) => {
-                                    expiration_spec.mode = match expiration_type {
-                                        ExpirationType::AfterInvoke => {
-                                            FromFunction_ExpirationSpec_ExpireMode::AFTER_INVOKE
-                                        }
-                                        ExpirationType::AfterWrite => {
-                                            FromFunction_ExpirationSpec_ExpireMode::AFTER_WRITE
-                                        }
-                                    };
-
-                                    expiration_spec.expire_after_millis =
-                                        value_spec.expiration.time_to_live.as_millis() as i64;
-                                }
-                                None => {
-                                    expiration_spec.mode =
-                                        FromFunction_ExpirationSpec_ExpireMode::NONE;
-                                    expiration_spec.expire_after_millis = 0;
-                                }
-                            }
-
-                            let mut persisted_value_spec = FromFunction_PersistedValueSpec::new();
-                            persisted_value_spec.expiration_spec =
-                                SingularPtrField::some(expiration_spec);
-
-                            persisted_value_spec.state_name = value_spec.name.clone();
-                            persisted_value_spec.type_typename = value_spec.typename.clone();
-
-                            incomplete_context.missing_values.push(persisted_value_spec);
-                        }
-
-                        let mut from_function = FromFunction::new();
-                        from_function.set_incomplete_invocation_context(incomplete_context);
-
-                        return Ok(from_function);
+        let invocations = batch_request.take_invocations().into_vec();
+        let batch = Batch {
+            self_address: Address::from_proto(batch_request.get_target()),
+            deadline,
+            batch_size: invocations.len(),
+            persisted_values: parse_persisted_values(batch_request.get_state()),
+            coalesced_state_updates: HashMap::new(),
+            invocation_response: FromFunction_InvocationResponse::new(),
+        };
+        (batch, invocations)
+    }
+
+    /// Decodes the message of the invocation at the given position of the batch and creates the
+    /// `Context` to invoke the function with.
+    fn prepare(
+        &self,
+        batch_index: usize,
+        mut invocation: ToFunction_Invocation,
+    ) -> Result<(Context<'_>, Message), InvocationError> {
+        // invocations that originate from an ingress don't have a caller
+        let caller_address = if invocation.has_caller() {
+            Some(Address::from_proto(invocation.get_caller()))
+        } else {
+            None
+        };
+        let mut argument = invocation.take_argument();
+        let mut message_metadata = HashMap::new();
+        if argument.get_typename() == message_metadata::MESSAGE_WITH_METADATA_TYPENAME {
+            let (typed_value, metadata) =
+                message_metadata::unwrap(argument.get_value()).map_err(|reason| {
+                    InvocationError::InvalidMessage {
+                        function_type: self.self_address.function_type.clone(),
+                        reason,
                     }
-                    _ => return Err(e),
-                },
-            };
+                })?;
+            argument = typed_value;
+            message_metadata = metadata;
+        }
+        let context = Context::new(&self.persisted_values, &self.self_address, caller_address)
+            .with_deadline(self.deadline)
+            .with_batch_position(batch_index, self.batch_size)
+            .with_message_metadata(message_metadata);
+        Ok((context, Message::new(argument)))
+    }
 
-            serialize_invocation_messages(&mut invocation_response, effects.invocations);
-            serialize_delayed_invocation_messages(
-                &mut invocation_response,
-                effects.delayed_invocations,
-            );
-            serialize_cancelled_delayed_messages(
-                &mut invocation_response,
-                effects.cancelled_delayed_invocations,
-            );
-            serialize_egress_messages(&mut invocation_response, effects.egress_messages);
-            update_state(
-                &mut persisted_values,
-                &mut coalesced_state_updates,
-                effects.state_updates,
+    /// Adds the effects of an invocation to the response.
+    fn apply(&mut self, effects: Effects) {
+        let invocation_response = &mut self.invocation_response;
+        serialize_invocation_messages(invocation_response, effects.invocations);
+        serialize_delayed_invocation_messages(
+            invocation_response,
+            effects.delayed_invocations,
+            effects.cancelled_delayed_invocations,
+        );
+        serialize_egress_messages(invocation_response, effects.egress_messages);
+        update_state(
+            &mut self.persisted_values,
+            &mut self.coalesced_state_updates,
+            effects.state_updates,
+        );
+        for raw_response in effects.raw_responses {
+            merge_raw_response(
+                invocation_response,
+                &mut self.persisted_values,
+                &mut self.coalesced_state_updates,
+                raw_response,
             );
         }
+    }
 
-        let state_values = coalesced_state_updates.drain().map(|(_key, value)| value);
-        serialize_state_updates(&mut invocation_response, state_values)?;
+    fn finish(mut self) -> Result<FromFunction, InvocationError> {
+        let state_values = self
+            .coalesced_state_updates
+            .drain()
+            .map(|(_key, value)| value);
+        serialize_state_updates(&mut self.invocation_response, state_values)?;
 
         let mut from_function = FromFunction::new();
-        from_function.set_invocation_result(invocation_response);
+        from_function.set_invocation_result(self.invocation_response);
 
         Ok(from_function)
     }
 }
 
+/// Creates the response that asks Flink to provide the missing states and to invoke the function
+/// again.
+fn incomplete_invocation_context(missing_states: &MissingStates) -> FromFunction {
+    let mut incomplete_context = FromFunction_IncompleteInvocationContext::new();
+
+    for value_spec in missing_states.states.iter() {
+        let mut expiration_spec = FromFunction_ExpirationSpec::new();
+
+        match &value_spec.expiration.expiration_type {
+            Some(expiration_type) => {
+                expiration_spec.mode = match expiration_type {
+                    ExpirationType::AfterInvoke => {
+                        FromFunction_ExpirationSpec_ExpireMode::AFTER_INVOKE
+                    }
+                    ExpirationType::AfterWrite => {
+                        FromFunction_ExpirationSpec_ExpireMode::AFTER_WRITE
+                    }
+                };
+
+                expiration_spec.expire_after_millis =
+                    value_spec.expiration.time_to_live.as_millis() as i64;
+            }
+            None => {
+                expiration_spec.mode = FromFunction_ExpirationSpec_ExpireMode::NONE;
+                expiration_spec.expire_after_millis = 0;
+            }
+        }
+
+        let mut persisted_value_spec = FromFunction_PersistedValueSpec::new();
+        persisted_value_spec.expiration_spec = SingularPtrField::some(expiration_spec);
+
+        persisted_value_spec.state_name = value_spec.name.clone();
+        persisted_value_spec.type_typename = value_spec.typename.clone();
+
+        incomplete_context.missing_values.push(persisted_value_spec);
+    }
+
+    let mut from_function = FromFunction::new();
+    from_function.set_incomplete_invocation_context(incomplete_context);
+    from_function
+}
+
 fn to_typed_value(typename: String, value: Vec<u8>) -> TypedValue {
     let mut res = TypedValue::new();
     res.set_typename(typename);
@@ -162,9 +253,17 @@ fn parse_persisted_values(
     result
 }
 
+/// Applies the state updates of an invocation to the state that later invocations of the batch
+/// see, and to the coalesced updates that are sent to Flink.
+///
+/// Updates are coalesced by state name and the last update of a state wins, so update→delete
+/// results in a deletion, and delete→update as well as update→delete→update result in the last
+/// value. The specs of the updates of one state can differ, for example a deletion from a raw
+/// function has no typename, and they must not result in separate mutations whose order Flink
+/// would apply arbitrarily.
 fn update_state(
     persisted_state: &mut HashMap<ValueSpecBase, Vec<u8>>,
-    coalesced_state: &mut HashMap<ValueSpecBase, StateUpdate>,
+    coalesced_state: &mut HashMap<String, StateUpdate>,
     state_updates: Vec<StateUpdate>,
 ) {
     for state_update in state_updates {
@@ -186,13 +285,16 @@ fn update_state(
                 //
                 // Instead the state has to be marked as cleared out, but the key is never deleted.
                 // Remember that this key is part of the function's registered state signature.
-                persisted_state.insert(value_spec.clone(), vec![]);
-                coalesced_state.insert(value_spec.clone(), StateUpdate::Delete(value_spec.clone()));
+                persisted_state.insert(persisted_key(&value_spec), vec![]);
+                coalesced_state.insert(
+                    value_spec.name.clone(),
+                    StateUpdate::Delete(value_spec.clone()),
+                );
             }
             StateUpdate::Update(value_spec, state) => {
-                persisted_state.insert(value_spec.clone(), state.clone());
+                persisted_state.insert(persisted_key(&value_spec), state.clone());
                 coalesced_state.insert(
-                    value_spec.clone(),
+                    value_spec.name.clone(),
                     StateUpdate::Update(value_spec.clone(), state.clone()),
                 );
             }
@@ -200,6 +302,55 @@ fn update_state(
     }
 }
 
+/// Returns the key of the state of the given spec in the state that later invocations of the batch
+/// see. Flink doesn't send the expiration of states, so the state it provides is keyed without one,
+/// see `parse_persisted_values()`, and updates have to use the same key to replace it.
+fn persisted_key(value_spec: &ValueSpecBase) -> ValueSpecBase {
+    ValueSpecBase::new(&value_spec.name, &value_spec.typename, Expiration::never())
+}
+
+/// Adds the response of a function that was registered using `FunctionRegistry::register_raw_fn()`
+/// to the batch response. State mutations are treated like the state updates of `Effects`, so
+/// that they are coalesced and visible to later invocations of the batch.
+fn merge_raw_response(
+    invocation_response: &mut FromFunction_InvocationResponse,
+    persisted_state: &mut HashMap<ValueSpecBase, Vec<u8>>,
+    coalesced_state: &mut HashMap<String, StateUpdate>,
+    mut raw_response: FromFunction_InvocationResponse,
+) {
+    invocation_response
+        .outgoing_messages
+        .extend(raw_response.take_outgoing_messages());
+    invocation_response
+        .delayed_invocations
+        .extend(raw_response.take_delayed_invocations());
+    invocation_response
+        .outgoing_egresses
+        .extend(raw_response.take_outgoing_egresses());
+
+    let state_updates = raw_response
+        .take_state_mutations()
+        .into_iter()
+        .map(|mut mutation| {
+            let mut state_value = mutation.take_state_value();
+            let value_spec = ValueSpecBase::new(
+                mutation.get_state_name(),
+                state_value.get_typename(),
+                Expiration::never(),
+            );
+            match mutation.get_mutation_type() {
+                FromFunction_PersistedValueMutation_MutationType::DELETE => {
+                    StateUpdate::Delete(value_spec)
+                }
+                FromFunction_PersistedValueMutation_MutationType::MODIFY => {
+                    StateUpdate::Update(value_spec, state_value.take_value())
+                }
+            }
+        })
+        .collect();
+    update_state(persisted_state, coalesced_state, state_updates);
+}
+
 fn serialize_invocation_messages(
     invocation_response: &mut FromFunction_InvocationResponse,
     invocation_messages: Vec<(Address, String, Vec<u8>)>,
@@ -215,11 +366,18 @@ fn serialize_invocation_messages(
     }
 }
 
+/// Serializes the delayed invocations and the cancellations in the order in which they were added,
+/// so that cancelling a token and then reusing it only cancels the earlier delayed invocation.
 fn serialize_delayed_invocation_messages(
     invocation_response: &mut FromFunction_InvocationResponse,
     delayed_invocations: Vec<DelayedInvocation>,
+    cancelled_delayed_invocations: Vec<(usize, String)>,
 ) {
-    for invocation_message in delayed_invocations {
+    let mut cancellations = cancelled_delayed_invocations.into_iter().peekable();
+    for (position, invocation_message) in delayed_invocations.into_iter().enumerate() {
+        while let Some((_, token)) = cancellations.next_if(|(before, _)| *before <= position) {
+            serialize_cancellation(invocation_response, token);
+        }
         let mut proto_invocation_message = FromFunction_DelayedInvocation::new();
         proto_invocation_message.set_target(invocation_message.address.into_proto());
         proto_invocation_message.set_delay_in_ms(invocation_message.delay.as_millis() as i64);
@@ -230,20 +388,21 @@ fn serialize_delayed_invocation_messages(
             .delayed_invocations
             .push(proto_invocation_message);
     }
+    for (_, token) in cancellations {
+        serialize_cancellation(invocation_response, token);
+    }
 }
 
-fn serialize_cancelled_delayed_messages(
+fn serialize_cancellation(
     invocation_response: &mut FromFunction_InvocationResponse,
-    cancelled_delayed_invocations: Vec<String>,
+    token: String,
 ) {
-    for cancel_invocation_tokens in cancelled_delayed_invocations {
-        let mut proto_invocation_message = FromFunction_DelayedInvocation::new();
-        proto_invocation_message.set_is_cancellation_request(true);
-        proto_invocation_message.set_cancellation_token(cancel_invocation_tokens);
-        invocation_response
-            .delayed_invocations
-            .push(proto_invocation_message);
-    }
+    let mut proto_invocation_message = FromFunction_DelayedInvocation::new();
+    proto_invocation_message.set_is_cancellation_request(true);
+    proto_invocation_message.set_cancellation_token(token);
+    invocation_response
+        .delayed_invocations
+        .push(proto_invocation_message);
 }
 
 fn serialize_egress_messages(
@@ -252,8 +411,8 @@ fn serialize_egress_messages(
 ) {
     for egress_message in egress_messages {
         let mut proto_egress_message = FromFunction_EgressMessage::new();
-        proto_egress_message.set_egress_namespace(egress_message.0.namespace);
-        proto_egress_message.set_egress_type(egress_message.0.name);
+        proto_egress_message.set_egress_namespace(egress_message.0.namespace.into_owned());
+        proto_egress_message.set_egress_type(egress_message.0.name.into_owned());
         let typed_value = to_typed_value(egress_message.1, egress_message.2);
         proto_egress_message.set_argument(typed_value);
         invocation_response
@@ -301,10 +460,12 @@ mod tests {
     use core::time::Duration;
     use protobuf::RepeatedField;
     use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
 
     use statefun_proto::request_reply::FromFunction_DelayedInvocation;
     use statefun_proto::request_reply::FromFunction_EgressMessage;
     use statefun_proto::request_reply::FromFunction_Invocation;
+    use statefun_proto::request_reply::FromFunction_InvocationResponse;
     use statefun_proto::request_reply::FromFunction_PersistedValueMutation;
     use statefun_proto::request_reply::FromFunction_PersistedValueMutation_MutationType;
     use statefun_proto::request_reply::ToFunction;
@@ -312,7 +473,9 @@ mod tests {
     use statefun_proto::request_reply::ToFunction_InvocationBatchRequest;
     use statefun_proto::request_reply::ToFunction_PersistedValue;
 
+    use crate::invocation_bridge;
     use crate::invocation_bridge::InvocationBridge;
+    use crate::testing::{BatchBuilder, BatchResponse};
     use crate::FunctionRegistry;
     use crate::*;
 
@@ -332,41 +495,44 @@ mod tests {
     fn forward_to_function() -> anyhow::Result<()> {
         let mut registry = FunctionRegistry::new();
 
-        registry.register_fn(
-            function_type(),
-            vec![foo_state().into(), bar_state().into()],
-            |context, message: Message| {
-                assert_eq!(context.self_address(), self_address());
-                assert_eq!(context.caller_address(), caller_address());
-                assert_eq!(
-                    context
-                        .get_state::<i32>(foo_state())
-                        .expect("State not here.")
-                        .unwrap(),
-                    42
-                );
-                assert_eq!(
-                    context
-                        .get_state::<i32>(bar_state())
-                        .expect("State not here.")
-                        .unwrap(),
-                    84
-                );
-
-                let string_message = message.get::<String>().unwrap();
-                let mut effects = Effects::new();
-
-                // the test checks against this message to ensure that the function was invoked
-                // and all the asserts above were executed
-                effects.send(self_address(), &string_message).unwrap();
-
-                effects
-            },
-        );
+        registry
+            .register_fn(
+                function_type(),
+                vec![foo_state().into(), bar_state().into()],
+                |context, message: Message| {
+                    assert_eq!(context.self_address(), self_address());
+                    assert_eq!(context.caller_address(), Some(caller_address()));
+                    assert!(!context.is_ingress());
+                    assert_eq!(
+                        context
+                            .get_state::<i32>(foo_state())
+                            .expect("State not here.")
+                            .unwrap(),
+                        42
+                    );
+                    assert_eq!(
+                        context
+                            .get_state::<i32>(bar_state())
+                            .expect("State not here.")
+                            .unwrap(),
+                        84
+                    );
+
+                    let string_message = message.get::<String>().unwrap();
+                    let mut effects = Effects::new();
+
+                    // the test checks against this message to ensure that the function was invoked
+                    // and all the asserts above were executed
+                    effects.send(self_address(), &string_message).unwrap();
+
+                    effects
+                },
+            )
+            .unwrap();
 
         // request
         let to_function = complete_to_function();
-        let mut from_function = registry.invoke_from_proto(to_function)?;
+        let mut from_function = registry.invoke_from_proto(to_function, None)?;
 
         // response
         let mut invocation_response = from_function.take_invocation_result();
@@ -383,22 +549,24 @@ mod tests {
     #[test]
     fn forward_messages_from_function() -> anyhow::Result<()> {
         let mut registry = FunctionRegistry::new();
-        registry.register_fn(
-            function_type(),
-            vec![foo_state().into(), bar_state().into()],
-            |_context, message: Message| {
-                let string_message = message.get::<String>().unwrap();
-                let mut effects = Effects::new();
+        registry
+            .register_fn(
+                function_type(),
+                vec![foo_state().into(), bar_state().into()],
+                |_context, message: Message| {
+                    let string_message = message.get::<String>().unwrap();
+                    let mut effects = Effects::new();
 
-                effects.send(self_address(), &string_message).unwrap();
+                    effects.send(self_address(), &string_message).unwrap();
 
-                effects
-            },
-        );
+                    effects
+                },
+            )
+            .unwrap();
 
         // request
         let to_function = complete_to_function();
-        let mut from_function = registry.invoke_from_proto(to_function)?;
+        let mut from_function = registry.invoke_from_proto(to_function, None)?;
 
         // response
         let mut invocation_response = from_function.take_invocation_result();
@@ -411,28 +579,92 @@ mod tests {
         Ok(())
     }
 
+    // Verifies that functions see the position of their invocation in the batch
+    #[test]
+    fn batch_position() -> anyhow::Result<()> {
+        let seen_positions = Arc::new(Mutex::new(Vec::new()));
+        let function_seen_positions = Arc::clone(&seen_positions);
+        let mut registry = FunctionRegistry::new();
+        registry
+            .register_fn(
+                function_type(),
+                vec![foo_state().into(), bar_state().into()],
+                move |context, _message: Message| {
+                    function_seen_positions
+                        .lock()
+                        .unwrap()
+                        .push((context.batch_index(), context.batch_size()));
+                    Effects::new()
+                },
+            )
+            .unwrap();
+
+        registry.invoke_from_proto(complete_to_function(), None)?;
+
+        assert_eq!(
+            *seen_positions.lock().unwrap(),
+            vec![(0, 3), (1, 3), (2, 3)]
+        );
+
+        Ok(())
+    }
+
+    // Verifies that cleared effects don't show up in the Protobuf FromFunction
+    #[test]
+    fn cleared_effects_from_function() -> anyhow::Result<()> {
+        let mut registry = FunctionRegistry::new();
+        registry
+            .register_fn(
+                function_type(),
+                vec![foo_state().into(), bar_state().into()],
+                |_context, message: Message| {
+                    let string_message = message.get::<String>().unwrap();
+                    let mut effects = Effects::new();
+
+                    effects.send(self_address(), &string_message).unwrap();
+                    effects.update_state(foo_state(), &42).unwrap();
+                    effects.clear();
+
+                    effects
+                },
+            )
+            .unwrap();
+
+        let to_function = complete_to_function();
+        let mut from_function = registry.invoke_from_proto(to_function, None)?;
+
+        assert_eq!(
+            from_function.take_invocation_result(),
+            FromFunction_InvocationResponse::new()
+        );
+
+        Ok(())
+    }
+
     // Verifies that delayed messages are correctly forwarded to the Protobuf FromFunction
     #[test]
     fn forward_delayed_messages_from_function() -> anyhow::Result<()> {
         let mut registry = FunctionRegistry::new();
-        registry.register_fn(function_type(), vec![], |_context, message| {
-            let string_message = message.get::<String>().unwrap();
-            let mut effects = Effects::new();
+        registry
+            .register_fn(function_type(), vec![], |_context, message| {
+                let string_message = message.get::<String>().unwrap();
+                let mut effects = Effects::new();
 
-            effects
-                .send_after(
-                    caller_address(),
-                    Duration::from_secs(5),
-                    "cancel-token".to_string(),
-                    &string_message,
-                )
-                .unwrap();
+                effects
+                    .send_after(
+                        caller_address(),
+                        Duration::from_secs(5),
+                        "cancel-token".to_string(),
+                        &string_message,
+                    )
+                    .unwrap();
 
-            effects
-        });
+                effects
+            })
+            .unwrap();
 
         let to_function = complete_to_function();
-        let mut from_function = registry.invoke_from_proto(to_function)?;
+        let mut from_function = registry.invoke_from_proto(to_function, None)?;
 
         let mut invocation_response = from_function.take_invocation_result();
         let mut delayed = invocation_response.take_delayed_invocations();
@@ -469,21 +701,23 @@ mod tests {
     #[test]
     fn forward_egresses_from_function() -> anyhow::Result<()> {
         let mut registry = FunctionRegistry::new();
-        registry.register_fn(function_type(), vec![], |_context, _message| {
-            let mut effects = Effects::new();
+        registry
+            .register_fn(function_type(), vec![], |_context, _message| {
+                let mut effects = Effects::new();
 
-            effects
-                .egress(
-                    EgressIdentifier::new("namespace", "name"),
-                    &"egress".to_string(),
-                )
-                .unwrap();
+                effects
+                    .egress(
+                        EgressIdentifier::new("namespace", "name"),
+                        &"egress".to_string(),
+                    )
+                    .unwrap();
 
-            effects
-        });
+                effects
+            })
+            .unwrap();
 
         let to_function = complete_to_function();
-        let mut from_function = registry.invoke_from_proto(to_function)?;
+        let mut from_function = registry.invoke_from_proto(to_function, None)?;
 
         let mut invocation_response = from_function.take_invocation_result();
         let mut egresses = invocation_response.take_outgoing_egresses();
@@ -514,22 +748,24 @@ mod tests {
     #[test]
     fn forward_state_mutations_from_function() -> anyhow::Result<()> {
         let mut registry = FunctionRegistry::new();
-        registry.register_fn(
-            function_type(),
-            vec![foo_state().into(), bar_state().into()],
-            |_context, _message: Message| {
-                let mut effects = Effects::new();
+        registry
+            .register_fn(
+                function_type(),
+                vec![foo_state().into(), bar_state().into()],
+                |_context, _message: Message| {
+                    let mut effects = Effects::new();
 
-                effects.update_state(bar_state(), &84).unwrap();
-                effects.delete_state(foo_state());
+                    effects.update_state(bar_state(), &84).unwrap();
+                    effects.delete_state(foo_state());
 
-                effects
-            },
-        );
+                    effects
+                },
+            )
+            .unwrap();
 
         // request
         let to_function = complete_to_function();
-        let mut from_function = registry.invoke_from_proto(to_function)?;
+        let mut from_function = registry.invoke_from_proto(to_function, None)?;
 
         let mut invocation_response = from_function.take_invocation_result();
         let state_mutations = invocation_response.take_state_mutations();
@@ -565,19 +801,21 @@ mod tests {
     #[test]
     fn state_mutations_available_in_subsequent_invocations() -> anyhow::Result<()> {
         let mut registry = FunctionRegistry::new();
-        registry.register_fn(function_type(), vec![], |context, _message| {
-            let state: i32 = context.get_state(bar_state()).unwrap().unwrap();
-            let updated_state = state + 1;
+        registry
+            .register_fn(function_type(), vec![], |context, _message| {
+                let state: i32 = context.get_state(bar_state()).unwrap().unwrap();
+                let updated_state = state + 1;
 
-            let mut effects = Effects::new();
-            effects.update_state(bar_state(), &updated_state).unwrap();
-            effects.delete_state(foo_state());
+                let mut effects = Effects::new();
+                effects.update_state(bar_state(), &updated_state).unwrap();
+                effects.delete_state(foo_state());
 
-            effects
-        });
+                effects
+            })
+            .unwrap();
 
         let to_function = complete_to_function();
-        let mut from_function = registry.invoke_from_proto(to_function)?;
+        let mut from_function = registry.invoke_from_proto(to_function, None)?;
 
         let mut invocation_response = from_function.take_invocation_result();
         let state_mutations = invocation_response.take_state_mutations();
@@ -600,6 +838,631 @@ mod tests {
         Ok(())
     }
 
+    // Verifies that the responses of raw functions end up in the batch response as they are
+    #[test]
+    fn raw_function_response() -> anyhow::Result<()> {
+        let mut registry = FunctionRegistry::new();
+        registry.register_raw_fn(
+            function_type(),
+            vec![foo_state().into(), bar_state().into()],
+            |_context, message: Message| {
+                let mut argument = TypedValue::new();
+                argument.set_typename("com.example/custom".to_string());
+                argument.set_has_value(true);
+                argument.set_value(message.get::<String>().unwrap().into_bytes());
+
+                let mut outgoing = FromFunction_Invocation::new();
+                outgoing.set_target(caller_address().into_proto());
+                outgoing.set_argument(argument);
+
+                let mut response = FromFunction_InvocationResponse::new();
+                response.mut_outgoing_messages().push(outgoing);
+                response
+            },
+        )?;
+
+        let mut from_function = registry.invoke_from_proto(complete_to_function(), None)?;
+
+        let messages = from_function
+            .mut_invocation_result()
+            .take_outgoing_messages();
+        let arguments: Vec<(&str, &[u8])> = messages
+            .iter()
+            .map(|message| {
+                assert_eq!(Address::from_proto(message.get_target()), caller_address());
+                let argument = message.get_argument();
+                (argument.get_typename(), argument.get_value())
+            })
+            .collect();
+        assert_eq!(
+            arguments,
+            vec![
+                ("com.example/custom", MESSAGE1.as_bytes()),
+                ("com.example/custom", MESSAGE2.as_bytes()),
+                ("com.example/custom", MESSAGE3.as_bytes()),
+            ]
+        );
+
+        Ok(())
+    }
+
+    // Verifies that generic messages keep the typename and bytes of the wrapped message
+    #[test]
+    fn generic_message_round_trip() -> anyhow::Result<()> {
+        let mut registry = FunctionRegistry::new();
+        registry
+            .register_fn(function_type(), vec![], |context, message: Message| {
+                let mut effects = Effects::new();
+                if context.is_ingress() {
+                    effects
+                        .send_generic(self_address(), "com.example/Opaque", vec![1, 2, 3])
+                        .unwrap();
+                } else {
+                    let (typename, bytes) = message.get_generic().unwrap();
+                    effects.egress_raw(
+                        EgressIdentifier::new("namespace", "name"),
+                        &typename,
+                        bytes,
+                    );
+                }
+                effects
+            })
+            .unwrap();
+
+        let mut to_function = complete_to_function();
+        let mut ingress_invocation = invocation(caller_address(), MESSAGE1.to_string());
+        ingress_invocation.clear_caller();
+        to_function
+            .mut_invocation()
+            .set_invocations(RepeatedField::from_vec(vec![ingress_invocation]));
+        let mut from_function = registry.invoke_from_proto(to_function, None)?;
+        let mut outgoing = from_function
+            .mut_invocation_result()
+            .take_outgoing_messages();
+
+        let mut to_function = complete_to_function();
+        let mut generic_invocation = invocation(caller_address(), MESSAGE1.to_string());
+        generic_invocation.set_argument(outgoing.remove(0).take_argument());
+        to_function
+            .mut_invocation()
+            .set_invocations(RepeatedField::from_vec(vec![generic_invocation]));
+        let mut from_function = registry.invoke_from_proto(to_function, None)?;
+        let egresses = from_function
+            .mut_invocation_result()
+            .take_outgoing_egresses();
+
+        assert_eq!(egresses.len(), 1);
+        assert_eq!(
+            egresses[0].get_argument().get_typename(),
+            "com.example/Opaque"
+        );
+        assert_eq!(egresses[0].get_argument().get_value(), &[1, 2, 3]);
+
+        Ok(())
+    }
+
+    // Verifies that missing states are requested from Flink with their typename and expiration
+    #[test]
+    fn request_missing_states() -> anyhow::Result<()> {
+        let expiring_state = ValueSpec::<String>::new(
+            "expiring",
+            Expiration::new(ExpirationType::AfterWrite, Duration::from_secs(60)),
+        );
+
+        let mut registry = FunctionRegistry::new();
+        registry
+            .register_fn(
+                function_type(),
+                vec![foo_state().into(), expiring_state.into()],
+                |_context, _message: Message| Effects::new(),
+            )
+            .unwrap();
+
+        // only the state of foo is provided
+        let mut to_function = complete_to_function();
+        let mut states = RepeatedField::new();
+        states.push(state(foo_state().into(), 42));
+        to_function.mut_invocation().set_state(states);
+
+        let from_function = registry.invoke_from_proto(to_function, None)?;
+
+        assert_eq!(
+            BatchResponse::from(from_function).requested_states(),
+            vec![(
+                "expiring".to_string(),
+                String::get_typename().to_string(),
+                Expiration::new(ExpirationType::AfterWrite, Duration::from_secs(60))
+            )]
+        );
+
+        Ok(())
+    }
+
+    // Verifies that missing-state responses are counted per function type
+    #[test]
+    fn count_missing_states() -> anyhow::Result<()> {
+        let mut registry = FunctionRegistry::new();
+        registry
+            .register_fn(
+                function_type(),
+                vec![foo_state().into(), bar_state().into()],
+                |_context, _message: Message| Effects::new(),
+            )
+            .unwrap();
+        assert!(registry.missing_state_counts().is_empty());
+
+        registry.invoke_from_proto(complete_to_function(), None)?;
+        registry.invoke_from_proto(complete_to_function(), None)?;
+
+        let mut to_function = complete_to_function();
+        to_function.mut_invocation().clear_state();
+        registry.invoke_from_proto(to_function.clone(), None)?;
+        registry.invoke_from_proto(to_function, None)?;
+
+        assert_eq!(
+            registry.missing_state_counts().get(&function_type()),
+            Some(&2)
+        );
+
+        Ok(())
+    }
+
+    // Verifies the final state after coalescing the state mutations of all invocations of a batch
+    #[test]
+    fn coalesced_state_after_batch() -> anyhow::Result<()> {
+        let mut registry = FunctionRegistry::new();
+        registry
+            .register_fn(
+                function_type(),
+                vec![foo_state().into(), bar_state().into()],
+                |context, message: Message| {
+                    let mut effects = Effects::new();
+
+                    let foo = context.get_state(foo_state()).unwrap().unwrap();
+                    effects.update_state(foo_state(), &(foo + 1)).unwrap();
+
+                    if message.get::<String>().unwrap() == MESSAGE1 {
+                        effects.update_state(bar_state(), &0).unwrap();
+                    } else {
+                        effects.delete_state(bar_state());
+                    }
+
+                    effects
+                },
+            )
+            .unwrap();
+
+        let to_function = complete_to_function();
+        let from_function = registry.invoke_from_proto(to_function, None)?;
+
+        let state = BatchResponse::from(from_function).coalesced_state();
+        assert_eq!(state.len(), 2);
+        assert_eq!(
+            state.get("foo"),
+            Some(&Some(
+                45.serialize(i32::get_typename().to_string()).unwrap()
+            ))
+        );
+        assert_eq!(state.get("bar"), Some(&None));
+
+        Ok(())
+    }
+
+    // Updates of a state with an expiration must be visible to later invocations of the batch,
+    // although Flink provides the state without its expiration
+    #[test]
+    fn coalesced_state_of_expiring_spec_after_batch() -> anyhow::Result<()> {
+        let seen_count = || {
+            ValueSpec::<i32>::new(
+                "seen_count",
+                Expiration::new(ExpirationType::AfterWrite, Duration::from_secs(60)),
+            )
+        };
+        let mut registry = FunctionRegistry::new();
+        registry.register_fn(
+            function_type(),
+            vec![seen_count().into()],
+            move |context, _message: Message| {
+                let mut effects = Effects::new();
+                let count = context.get_state_or_default(seen_count()).unwrap();
+                effects.update_state(seen_count(), &(count + 1)).unwrap();
+                effects
+            },
+        )?;
+
+        let response = BatchBuilder::new(Address::new(function_type(), "self"))
+            .with_state(seen_count(), &40)
+            .unwrap()
+            .with_message(&MESSAGE1.to_string())
+            .unwrap()
+            .with_message(&MESSAGE2.to_string())
+            .unwrap()
+            .invoke(&registry)?;
+
+        let state = response.coalesced_state();
+        assert_eq!(
+            state.get("seen_count"),
+            Some(&Some(
+                42.serialize(i32::get_typename().to_string()).unwrap()
+            ))
+        );
+
+        Ok(())
+    }
+
+    fn coalesce(state_updates: Vec<StateUpdate>) -> Vec<StateUpdate> {
+        let mut persisted_state = HashMap::new();
+        let mut coalesced_state = HashMap::new();
+        invocation_bridge::update_state(&mut persisted_state, &mut coalesced_state, state_updates);
+        coalesced_state.into_values().collect()
+    }
+
+    fn update(value: i32) -> StateUpdate {
+        StateUpdate::Update(foo_state().into(), value.serialize(String::new()).unwrap())
+    }
+
+    fn delete() -> StateUpdate {
+        StateUpdate::Delete(foo_state().into())
+    }
+
+    #[test]
+    fn coalesce_update_then_delete() {
+        assert_eq!(coalesce(vec![update(1), delete()]), vec![delete()]);
+    }
+
+    #[test]
+    fn coalesce_delete_then_update() {
+        assert_eq!(coalesce(vec![delete(), update(2)]), vec![update(2)]);
+    }
+
+    #[test]
+    fn coalesce_update_delete_update() {
+        assert_eq!(
+            coalesce(vec![update(1), delete(), update(3)]),
+            vec![update(3)]
+        );
+    }
+
+    // Deletions of raw functions have no typename, and specs may differ in their expiration, but
+    // they still refer to the same state
+    #[test]
+    fn coalesce_updates_of_differing_specs() {
+        let untyped_delete =
+            StateUpdate::Delete(ValueSpecBase::new("foo", "", Expiration::never()));
+        assert_eq!(
+            coalesce(vec![update(1), untyped_delete.clone()]),
+            vec![untyped_delete]
+        );
+
+        let expiring_spec = ValueSpec::<i32>::new(
+            "foo",
+            Expiration::new(ExpirationType::AfterWrite, Duration::from_secs(1)),
+        );
+        let expiring_update = StateUpdate::Update(expiring_spec.into(), vec![4]);
+        assert_eq!(
+            coalesce(vec![delete(), expiring_update.clone()]),
+            vec![expiring_update]
+        );
+    }
+
+    // Verifies that a function recognizes the messages that it sent to itself
+    #[test]
+    fn message_from_self() -> anyhow::Result<()> {
+        let mut registry = FunctionRegistry::new();
+        registry.register_fn(function_type(), vec![], |context, message: Message| {
+            let mut effects = Effects::new();
+            if !message.is_from_self(&context) {
+                // broadcast to a group that includes this function
+                for id in &["self", "other"] {
+                    let address = Address::new(function_type(), id);
+                    effects.send(address, &MESSAGE1.to_string()).unwrap();
+                }
+            }
+            effects
+        })?;
+
+        let mut to_function = complete_to_function();
+        let mut invocations = RepeatedField::new();
+        invocations.push(invocation(caller_address(), MESSAGE1.to_string()));
+        invocations.push(invocation(self_address(), MESSAGE1.to_string()));
+        to_function.mut_invocation().set_invocations(invocations);
+
+        let mut from_function = registry.invoke_from_proto(to_function, None)?;
+
+        // only the message from the other function results in a broadcast
+        let messages = from_function
+            .mut_invocation_result()
+            .take_outgoing_messages();
+        assert_eq!(messages.len(), 2);
+
+        Ok(())
+    }
+
+    // Verifies that metadata attached to a message is available to the receiving function
+    #[test]
+    fn message_metadata() -> anyhow::Result<()> {
+        let mut registry = FunctionRegistry::new();
+        registry
+            .register_fn(function_type(), vec![], |context, message: Message| {
+                let mut effects = Effects::new();
+                if context.is_ingress() {
+                    let mut metadata = HashMap::new();
+                    metadata.insert("priority".to_string(), "high".to_string());
+                    effects
+                        .send_with_metadata(
+                            self_address(),
+                            &message.get::<String>().unwrap(),
+                            &metadata,
+                        )
+                        .unwrap();
+                } else {
+                    let egress = format!(
+                        "{} {:?}",
+                        message.get::<String>().unwrap(),
+                        context.message_metadata().get("priority")
+                    );
+                    effects
+                        .egress(EgressIdentifier::new("namespace", "name"), &egress)
+                        .unwrap();
+                }
+                effects
+            })
+            .unwrap();
+
+        let mut to_function = complete_to_function();
+        let mut ingress_invocation = invocation(caller_address(), MESSAGE1.to_string());
+        ingress_invocation.clear_caller();
+        to_function
+            .mut_invocation()
+            .set_invocations(RepeatedField::from_vec(vec![ingress_invocation]));
+        let mut from_function = registry.invoke_from_proto(to_function, None)?;
+        let mut outgoing = from_function
+            .mut_invocation_result()
+            .take_outgoing_messages();
+        assert_eq!(outgoing.len(), 1);
+
+        let mut to_function = complete_to_function();
+        let mut metadata_invocation = invocation(caller_address(), MESSAGE1.to_string());
+        metadata_invocation.set_argument(outgoing.remove(0).take_argument());
+        to_function
+            .mut_invocation()
+            .set_invocations(RepeatedField::from_vec(vec![metadata_invocation]));
+        let mut from_function = registry.invoke_from_proto(to_function, None)?;
+        let mut egresses = from_function
+            .mut_invocation_result()
+            .take_outgoing_egresses();
+
+        assert_egress(
+            egresses.remove(0),
+            "namespace",
+            "name",
+            format!("{} {:?}", MESSAGE1, Some("high")),
+        );
+
+        Ok(())
+    }
+
+    // Verifies that ticks are sent as delayed self-invocations and can be detected as such
+    #[test]
+    fn schedule_tick() -> anyhow::Result<()> {
+        let mut registry = FunctionRegistry::new();
+        registry
+            .register_fn(function_type(), vec![], |context, message: Message| {
+                assert!(!message.is_tick());
+
+                let mut effects = Effects::new();
+                effects
+                    .schedule_tick(&context, Duration::from_secs(10), "tick")
+                    .unwrap();
+                effects
+            })
+            .unwrap();
+
+        let to_function = complete_to_function();
+        let mut from_function = registry.invoke_from_proto(to_function, None)?;
+
+        let mut invocation_response = from_function.take_invocation_result();
+        let mut delayed = invocation_response.take_delayed_invocations();
+        assert_eq!(delayed.len(), 3);
+
+        let tick = delayed.remove(0);
+        assert_eq!(Address::from_proto(tick.get_target()), self_address());
+        assert_eq!(tick.get_delay_in_ms(), 10000);
+        assert_eq!(tick.get_cancellation_token(), "tick");
+        assert!(Message::new(tick.get_argument().clone()).is_tick());
+
+        Ok(())
+    }
+
+    // Verifies that all tracked delayed messages of a batch are cancelled by cancel_all_delayed()
+    #[test]
+    fn cancel_all_tracked_delayed_messages() -> anyhow::Result<()> {
+        let mut registry = FunctionRegistry::new();
+        registry
+            .register_fn(
+                function_type(),
+                vec![DelayedTokens::value_spec().into()],
+                |context, message: Message| {
+                    let string_message = message.get::<String>().unwrap();
+                    let mut effects = Effects::new();
+
+                    effects
+                        .send_after_tracked(
+                            &context,
+                            caller_address(),
+                            Duration::from_secs(5),
+                            string_message.clone(),
+                            &string_message,
+                        )
+                        .unwrap();
+
+                    if string_message == MESSAGE3 {
+                        effects.cancel_all_delayed(&context).unwrap();
+                    }
+
+                    effects
+                },
+            )
+            .unwrap();
+
+        let mut to_function = complete_to_function();
+        let mut delayed_tokens_state = ToFunction_PersistedValue::new();
+        delayed_tokens_state.set_state_name(DelayedTokens::value_spec().spec.name);
+        to_function
+            .mut_invocation()
+            .mut_state()
+            .push(delayed_tokens_state);
+
+        let mut from_function = registry.invoke_from_proto(to_function, None)?;
+
+        let mut invocation_response = from_function.take_invocation_result();
+        let delayed = invocation_response.take_delayed_invocations();
+
+        let cancelled_tokens: Vec<&str> = delayed
+            .iter()
+            .filter(|delayed| delayed.get_is_cancellation_request())
+            .map(|delayed| delayed.get_cancellation_token())
+            .collect();
+        assert_eq!(cancelled_tokens, vec![MESSAGE1, MESSAGE2, MESSAGE3]);
+
+        let state_map = to_state_map(invocation_response.take_state_mutations());
+        assert_state_delete(
+            state_map
+                .get(&DelayedTokens::value_spec().spec.name)
+                .unwrap(),
+            DelayedTokens::value_spec().spec.name.as_str(),
+        );
+
+        Ok(())
+    }
+
+    // Verifies that later invocations can list and cancel tracked delayed messages of earlier ones
+    #[test]
+    fn cancel_scheduled_delayed_message() -> anyhow::Result<()> {
+        let listed_tokens = Arc::new(Mutex::new(Vec::new()));
+        let function_listed_tokens = Arc::clone(&listed_tokens);
+
+        let mut registry = FunctionRegistry::new();
+        registry
+            .register_fn(
+                function_type(),
+                vec![DelayedTokens::value_spec().into()],
+                move |context, message: Message| {
+                    let string_message = message.get::<String>().unwrap();
+                    let mut effects = Effects::new();
+
+                    if string_message == MESSAGE3 {
+                        *function_listed_tokens.lock().unwrap() = context.scheduled_tokens();
+                        effects.cancel_scheduled(&context, MESSAGE1).unwrap();
+                    } else {
+                        effects
+                            .send_after_tracked(
+                                &context,
+                                caller_address(),
+                                Duration::from_secs(5),
+                                string_message.clone(),
+                                &string_message,
+                            )
+                            .unwrap();
+                    }
+
+                    effects
+                },
+            )
+            .unwrap();
+
+        let mut to_function = complete_to_function();
+        let mut delayed_tokens_state = ToFunction_PersistedValue::new();
+        delayed_tokens_state.set_state_name(DelayedTokens::value_spec().spec.name);
+        to_function
+            .mut_invocation()
+            .mut_state()
+            .push(delayed_tokens_state);
+
+        let from_function = registry.invoke_from_proto(to_function, None)?;
+
+        assert_eq!(*listed_tokens.lock().unwrap(), vec![MESSAGE1, MESSAGE2]);
+
+        let cancelled_tokens: Vec<&str> = from_function
+            .get_invocation_result()
+            .get_delayed_invocations()
+            .iter()
+            .filter(|delayed| delayed.get_is_cancellation_request())
+            .map(|delayed| delayed.get_cancellation_token())
+            .collect();
+        assert_eq!(cancelled_tokens, vec![MESSAGE1]);
+
+        let state = BatchResponse::from(from_function).coalesced_state();
+        let tokens = DelayedTokens::deserialize(
+            DelayedTokens::get_typename().to_string(),
+            state[&DelayedTokens::value_spec().spec.name]
+                .as_ref()
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(tokens.tokens(), &[MESSAGE2.to_string()]);
+
+        Ok(())
+    }
+
+    // Verifies that rescheduling a token cancels the previous delayed message before the new one
+    #[test]
+    fn schedule_once() -> anyhow::Result<()> {
+        let mut registry = FunctionRegistry::new();
+        registry.register_fn(
+            function_type(),
+            vec![DelayedTokens::value_spec().into()],
+            |context, message: Message| {
+                let string_message = message.get::<String>().unwrap();
+                let mut effects = Effects::new();
+                effects
+                    .schedule_once(
+                        &context,
+                        context.self_address(),
+                        Duration::from_secs(5),
+                        "flush",
+                        &string_message,
+                    )
+                    .unwrap();
+                effects
+            },
+        )?;
+
+        let mut to_function = complete_to_function();
+        let mut delayed_tokens_state = ToFunction_PersistedValue::new();
+        delayed_tokens_state.set_state_name(DelayedTokens::value_spec().spec.name);
+        to_function
+            .mut_invocation()
+            .mut_state()
+            .push(delayed_tokens_state);
+
+        let from_function = registry.invoke_from_proto(to_function, None)?;
+
+        let delayed_invocations: Vec<(bool, &str)> = from_function
+            .get_invocation_result()
+            .get_delayed_invocations()
+            .iter()
+            .map(|delayed| {
+                (
+                    delayed.get_is_cancellation_request(),
+                    delayed.get_cancellation_token(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            delayed_invocations,
+            vec![
+                (false, "flush"),
+                (true, "flush"),
+                (false, "flush"),
+                (true, "flush"),
+                (false, "flush"),
+            ]
+        );
+
+        Ok(())
+    }
+
     fn assert_invocation(
         invocation: FromFunction_Invocation,
         expected_address: Address,
@@ -1,3 +1,4 @@
-//! A set of traits that allow sending egress messages to systems such as Kafka.
+//! A set of traits that allow sending egress messages to systems such as Kafka and Kinesis.
 
 pub mod kafka;
+pub mod kinesis;
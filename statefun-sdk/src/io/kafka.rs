@@ -6,6 +6,9 @@ use statefun_proto::kafka_egress::KafkaProducerRecord;
 
 use crate::{Effects, EgressIdentifier, Serializable, TypeName};
 
+/// The typename that Flink expects for records that are sent to a Kafka egress.
+pub const RECORD_TYPENAME: &str = "type.googleapis.com/io.statefun.sdk.egress.KafkaProducerRecord";
+
 /// Extension trait for sending egress messages to Kafka using [Effects](crate::Effects).
 pub trait KafkaEgress {
     /// Sends the given message to the Kafka topic `topic` via the egress specified using the
@@ -28,6 +31,32 @@ pub trait KafkaEgress {
         key: &str,
         value: &T,
     ) -> Result<(), String>;
+
+    /// Responds to a request that arrived from an ingress, which has no caller to reply to, by
+    /// sending the response to the Kafka topic `topic` via the egress specified using the
+    /// `EgressIdentifier`.
+    ///
+    /// The correlation key becomes the key of the record, so that the consumer of the topic can
+    /// match the response to its request. Pass a field of the request that identifies it, such as
+    /// a request id, because identical requests can't be told apart by their content.
+    fn kafka_respond<T: Serializable<T> + TypeName>(
+        &mut self,
+        identifier: EgressIdentifier,
+        topic: &str,
+        correlation_key: &str,
+        response: &T,
+    ) -> Result<(), String>;
+
+    /// Sends the given message to each of the Kafka `topics` via the egress specified using the
+    /// `EgressIdentifier`. The message is only serialized once and one record is sent per topic.
+    ///
+    /// Sending to an empty list of topics is a no-op.
+    fn kafka_egress_multi<T: Serializable<T> + TypeName>(
+        &mut self,
+        identifier: EgressIdentifier,
+        topics: &[&str],
+        value: &T,
+    ) -> Result<(), String>;
 }
 
 impl KafkaEgress for Effects {
@@ -52,11 +81,37 @@ impl KafkaEgress for Effects {
         kafka_record.set_key(key.to_owned());
         self.egress(identifier, &kafka_record)
     }
+
+    fn kafka_respond<T: Serializable<T> + TypeName>(
+        &mut self,
+        identifier: EgressIdentifier,
+        topic: &str,
+        correlation_key: &str,
+        response: &T,
+    ) -> Result<(), String> {
+        self.kafka_keyed_egress(identifier, topic, correlation_key, response)
+    }
+
+    fn kafka_egress_multi<T: Serializable<T> + TypeName>(
+        &mut self,
+        identifier: EgressIdentifier,
+        topics: &[&str],
+        value: &T,
+    ) -> Result<(), String> {
+        let serialized = value.serialize(T::get_typename().to_string())?;
+        for topic in topics {
+            let mut kafka_record = KafkaProducerRecord::new();
+            kafka_record.set_topic(topic.to_string());
+            kafka_record.set_value_bytes(serialized.clone());
+            self.egress(identifier.clone(), &kafka_record)?;
+        }
+        Ok(())
+    }
 }
 
 impl TypeName for KafkaProducerRecord {
     fn get_typename() -> &'static str {
-        "type.googleapis.com/io.statefun.sdk.egress.KafkaProducerRecord"
+        RECORD_TYPENAME
     }
 }
 
@@ -76,6 +131,57 @@ impl Serializable<KafkaProducerRecord> for KafkaProducerRecord {
     }
 }
 
+/// The first byte of values in the wire format of the Confluent Schema Registry.
+const CONFLUENT_MAGIC_BYTE: u8 = 0;
+
+/// Wraps a value to be sent to Kafka in the wire format of the Confluent Schema Registry: a zero
+/// magic byte and the 4-byte big-endian id of the schema of the value, followed by the serialized
+/// value. Consumers that use the Schema Registry can then decode records that were produced by
+/// Rust functions, for example:
+///
+/// `effects.kafka_egress(identifier, "greetings", &Confluent::new(schema_id, greeting))`
+///
+/// The typename is the typename of the wrapped value. Registering the schema is up to the user.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Confluent<T> {
+    /// The id of the schema of the value in the Schema Registry.
+    pub schema_id: u32,
+    /// The wrapped value.
+    pub value: T,
+}
+
+impl<T> Confluent<T> {
+    /// Wraps the given value, which is encoded according to the schema with the given id.
+    pub fn new(schema_id: u32, value: T) -> Confluent<T> {
+        Confluent { schema_id, value }
+    }
+}
+
+impl<T: TypeName> TypeName for Confluent<T> {
+    fn get_typename() -> &'static str {
+        T::get_typename()
+    }
+}
+
+impl<T: Serializable<T>> Serializable<Confluent<T>> for Confluent<T> {
+    fn serialize(&self, typename: String) -> Result<Vec<u8>, String> {
+        let mut result = vec![CONFLUENT_MAGIC_BYTE];
+        result.extend_from_slice(&self.schema_id.to_be_bytes());
+        result.extend(self.value.serialize(typename)?);
+        Ok(result)
+    }
+
+    fn deserialize(typename: String, buffer: &[u8]) -> Result<Confluent<T>, String> {
+        match buffer {
+            [CONFLUENT_MAGIC_BYTE, a, b, c, d, value @ ..] => Ok(Confluent {
+                schema_id: u32::from_be_bytes([*a, *b, *c, *d]),
+                value: T::deserialize(typename, value)?,
+            }),
+            _ => Err("Value is not in the Confluent wire format".to_string()),
+        }
+    }
+}
+
 fn egress_record<T: Serializable<T> + TypeName>(
     topic: &str,
     value: &T,
@@ -86,3 +192,128 @@ fn egress_record<T: Serializable<T> + TypeName>(
     result.set_value_bytes(serialized);
     Ok(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use statefun_proto::kafka_egress::KafkaProducerRecord;
+
+    use protobuf::Message as ProtoMessage;
+
+    use crate::io::kafka;
+    use crate::io::kafka::{Confluent, KafkaEgress};
+    use crate::*;
+
+    #[test]
+    fn record_typename() {
+        assert_eq!(
+            kafka::RECORD_TYPENAME,
+            "type.googleapis.com/io.statefun.sdk.egress.KafkaProducerRecord"
+        );
+        assert_eq!(KafkaProducerRecord::get_typename(), kafka::RECORD_TYPENAME);
+        assert_eq!(
+            format!(
+                "type.googleapis.com/{}",
+                KafkaProducerRecord::descriptor_static().full_name()
+            ),
+            kafka::RECORD_TYPENAME
+        );
+    }
+
+    #[test]
+    fn kafka_egress_multi() {
+        let mut effects = Effects::new();
+        effects
+            .kafka_egress_multi(
+                EgressIdentifier::new("namespace", "kafka"),
+                &["topic-a", "topic-b", "topic-c"],
+                &"hello".to_string(),
+            )
+            .unwrap();
+
+        let expected_value = "hello".to_string().serialize("".to_string()).unwrap();
+        let topics: Vec<String> = effects
+            .egress_messages
+            .iter()
+            .map(|(identifier, typename, value)| {
+                assert_eq!(identifier.name, "kafka");
+                assert_eq!(typename, KafkaProducerRecord::get_typename());
+                let record = KafkaProducerRecord::deserialize(typename.clone(), value).unwrap();
+                assert_eq!(record.get_value_bytes(), expected_value.as_slice());
+                record.get_topic().to_string()
+            })
+            .collect();
+        assert_eq!(topics, vec!["topic-a", "topic-b", "topic-c"]);
+    }
+
+    #[test]
+    fn kafka_respond() {
+        let mut effects = Effects::new();
+        for (request_id, response) in [("request-1", "pong"), ("request-2", "pong")] {
+            effects
+                .kafka_respond(
+                    EgressIdentifier::new("namespace", "kafka"),
+                    "responses",
+                    request_id,
+                    &response.to_string(),
+                )
+                .unwrap();
+        }
+
+        let keys: Vec<String> = effects
+            .egress_messages
+            .iter()
+            .map(|(_identifier, typename, value)| {
+                assert_eq!(typename, kafka::RECORD_TYPENAME);
+                let record = KafkaProducerRecord::deserialize(typename.clone(), value).unwrap();
+                assert_eq!(record.get_topic(), "responses");
+                assert_eq!(
+                    String::deserialize(String::new(), record.get_value_bytes()),
+                    Ok("pong".to_string())
+                );
+                record.get_key().to_string()
+            })
+            .collect();
+        assert_eq!(keys, vec!["request-1", "request-2"]);
+    }
+
+    #[test]
+    fn confluent_wire_format() {
+        let mut effects = Effects::new();
+        effects
+            .kafka_egress(
+                EgressIdentifier::new("namespace", "kafka"),
+                "greetings",
+                &Confluent::new(0x0102_0304, "hello".to_string()),
+            )
+            .unwrap();
+
+        let (_identifier, typename, value) = &effects.egress_messages[0];
+        let record = KafkaProducerRecord::deserialize(typename.clone(), value).unwrap();
+        let bytes = record.get_value_bytes();
+        assert_eq!(bytes[..5], [0, 1, 2, 3, 4]);
+        assert_eq!(
+            String::deserialize(String::new(), &bytes[5..]),
+            Ok("hello".to_string())
+        );
+        assert_eq!(
+            Confluent::<String>::deserialize(String::new(), bytes),
+            Ok(Confluent::new(0x0102_0304, "hello".to_string()))
+        );
+
+        assert!(Confluent::<String>::deserialize(String::new(), &[1, 0, 0, 0, 1]).is_err());
+        assert!(Confluent::<String>::deserialize(String::new(), &[0, 0]).is_err());
+    }
+
+    #[test]
+    fn kafka_egress_multi_without_topics() {
+        let mut effects = Effects::new();
+        effects
+            .kafka_egress_multi(
+                EgressIdentifier::new("namespace", "kafka"),
+                &[],
+                &"hello".to_string(),
+            )
+            .unwrap();
+        assert!(effects.egress_messages.is_empty());
+    }
+}
@@ -0,0 +1,157 @@
+//! Provides [KinesisEgress](crate::io::kinesis::KinesisEgress) for sending egress messages to
+//! Kinesis.
+
+use protobuf::Message;
+
+use statefun_proto::kinesis_egress::KinesisEgressRecord;
+
+use crate::{Effects, EgressIdentifier, Serializable, TypeName};
+
+/// The typename that Flink expects for records that are sent to a Kinesis egress.
+pub const RECORD_TYPENAME: &str = "type.googleapis.com/io.statefun.sdk.egress.KinesisEgressRecord";
+
+/// Extension trait for sending egress messages to Kinesis using [Effects](crate::Effects).
+pub trait KinesisEgress {
+    /// Sends the given message to the Kinesis stream `stream` via the egress specified using the
+    /// `EgressIdentifier`. The partition key determines the shard of the record, unless an
+    /// explicit hash key is given, which then overrides the hash of the partition key.
+    fn kinesis_egress<T: Serializable<T> + TypeName>(
+        &mut self,
+        identifier: EgressIdentifier,
+        stream: &str,
+        partition_key: &str,
+        explicit_hash_key: Option<&str>,
+        value: &T,
+    ) -> Result<(), String>;
+}
+
+impl KinesisEgress for Effects {
+    fn kinesis_egress<T: Serializable<T> + TypeName>(
+        &mut self,
+        identifier: EgressIdentifier,
+        stream: &str,
+        partition_key: &str,
+        explicit_hash_key: Option<&str>,
+        value: &T,
+    ) -> Result<(), String> {
+        let kinesis_record = egress_record(stream, partition_key, explicit_hash_key, value)?;
+        self.egress(identifier, &kinesis_record)
+    }
+}
+
+impl TypeName for KinesisEgressRecord {
+    fn get_typename() -> &'static str {
+        RECORD_TYPENAME
+    }
+}
+
+impl Serializable<KinesisEgressRecord> for KinesisEgressRecord {
+    fn serialize(&self, _typename: String) -> Result<Vec<u8>, String> {
+        match self.write_to_bytes() {
+            Ok(result) => Ok(result),
+            Err(result) => Err(result.to_string()),
+        }
+    }
+
+    fn deserialize(_typename: String, buffer: &[u8]) -> Result<KinesisEgressRecord, String> {
+        match KinesisEgressRecord::parse_from_bytes(buffer) {
+            Ok(result) => Ok(result),
+            Err(result) => Err(result.to_string()),
+        }
+    }
+}
+
+fn egress_record<T: Serializable<T> + TypeName>(
+    stream: &str,
+    partition_key: &str,
+    explicit_hash_key: Option<&str>,
+    value: &T,
+) -> Result<KinesisEgressRecord, String> {
+    let mut result = KinesisEgressRecord::new();
+    result.set_stream(stream.to_owned());
+    result.set_partition_key(partition_key.to_owned());
+    if let Some(explicit_hash_key) = explicit_hash_key {
+        result.set_explicit_hash_key(explicit_hash_key.to_owned());
+    }
+    let serialized = value.serialize(T::get_typename().to_string())?;
+    result.set_value_bytes(serialized);
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use statefun_proto::kinesis_egress::KinesisEgressRecord;
+
+    use protobuf::Message as ProtoMessage;
+
+    use crate::io::kinesis;
+    use crate::io::kinesis::KinesisEgress;
+    use crate::*;
+
+    #[test]
+    fn record_typename() {
+        assert_eq!(
+            kinesis::RECORD_TYPENAME,
+            "type.googleapis.com/io.statefun.sdk.egress.KinesisEgressRecord"
+        );
+        assert_eq!(
+            KinesisEgressRecord::get_typename(),
+            kinesis::RECORD_TYPENAME
+        );
+        assert_eq!(
+            format!(
+                "type.googleapis.com/{}",
+                KinesisEgressRecord::descriptor_static().full_name()
+            ),
+            kinesis::RECORD_TYPENAME
+        );
+    }
+
+    #[test]
+    fn kinesis_egress() {
+        let mut effects = Effects::new();
+        let identifier = EgressIdentifier::new("namespace", "kinesis");
+        effects
+            .kinesis_egress(
+                identifier.clone(),
+                "greetings",
+                "user-1",
+                None,
+                &"hello".to_string(),
+            )
+            .unwrap();
+        effects
+            .kinesis_egress(
+                identifier,
+                "greetings",
+                "user-2",
+                Some("42"),
+                &"bye".to_string(),
+            )
+            .unwrap();
+
+        let records: Vec<KinesisEgressRecord> = effects
+            .egress_messages
+            .iter()
+            .map(|(identifier, typename, value)| {
+                assert_eq!(identifier.name, "kinesis");
+                assert_eq!(typename, KinesisEgressRecord::get_typename());
+                KinesisEgressRecord::deserialize(typename.clone(), value).unwrap()
+            })
+            .collect();
+
+        assert_eq!(records[0].get_stream(), "greetings");
+        assert_eq!(records[0].get_partition_key(), "user-1");
+        assert_eq!(records[0].get_explicit_hash_key(), "");
+        assert_eq!(
+            records[0].get_value_bytes(),
+            "hello"
+                .to_string()
+                .serialize(String::new())
+                .unwrap()
+                .as_slice()
+        );
+        assert_eq!(records[1].get_partition_key(), "user-2");
+        assert_eq!(records[1].get_explicit_hash_key(), "42");
+    }
+}
@@ -0,0 +1,137 @@
+//! Wrappers for using JSON-encoded values as messages or state, see `Json` and `StrictJson`.
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{Serializable, TypeName};
+
+/// Wraps a value that is encoded as JSON using `serde`, so that it can be used as a message or
+/// state type without implementing `Serializable` by hand. The typename is the typename of the
+/// wrapped type.
+///
+/// Unknown fields are ignored when deserializing, unless the wrapped type denies them using
+/// `#[serde(deny_unknown_fields)]`. This keeps functions forward compatible: an old function can
+/// still read a state or message that a newer version extended with a field. Use `StrictJson`
+/// to reject unknown fields instead.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Json<T>(pub T);
+
+/// Like `Json`, but deserialization fails if the JSON contains fields that the wrapped type
+/// doesn't know, at any level of nesting.
+///
+/// Unknown fields are detected by serializing the deserialized value again and comparing the
+/// fields, so fields that are skipped when serializing, for example using
+/// `#[serde(skip_serializing_if = "...")]`, are reported as unknown when they are present.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct StrictJson<T>(pub T);
+
+impl<T: TypeName> TypeName for Json<T> {
+    fn get_typename() -> &'static str {
+        T::get_typename()
+    }
+}
+
+impl<T: TypeName> TypeName for StrictJson<T> {
+    fn get_typename() -> &'static str {
+        T::get_typename()
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> Serializable<Json<T>> for Json<T> {
+    fn serialize(&self, _typename: String) -> Result<Vec<u8>, String> {
+        serde_json::to_vec(&self.0).map_err(|error| error.to_string())
+    }
+
+    fn deserialize(_typename: String, buffer: &[u8]) -> Result<Json<T>, String> {
+        serde_json::from_slice(buffer)
+            .map(Json)
+            .map_err(|error| error.to_string())
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> Serializable<StrictJson<T>> for StrictJson<T> {
+    fn serialize(&self, _typename: String) -> Result<Vec<u8>, String> {
+        serde_json::to_vec(&self.0).map_err(|error| error.to_string())
+    }
+
+    fn deserialize(_typename: String, buffer: &[u8]) -> Result<StrictJson<T>, String> {
+        let input: Value = serde_json::from_slice(buffer).map_err(|error| error.to_string())?;
+        let value: T = serde_json::from_value(input.clone()).map_err(|error| error.to_string())?;
+        let known = serde_json::to_value(&value).map_err(|error| error.to_string())?;
+        match unknown_field(&input, &known, "") {
+            Some(path) => Err(format!("Unknown field: {}", path)),
+            None => Ok(StrictJson(value)),
+        }
+    }
+}
+
+/// Returns the path of the first field of `input` that is missing in `known`.
+fn unknown_field(input: &Value, known: &Value, path: &str) -> Option<String> {
+    match (input, known) {
+        (Value::Object(input), Value::Object(known)) => input.iter().find_map(|(key, value)| {
+            let path = format!("{}.{}", path, key);
+            match known.get(key) {
+                Some(known) => unknown_field(value, known, &path),
+                None => Some(path),
+            }
+        }),
+        (Value::Array(input), Value::Array(known)) => input
+            .iter()
+            .zip(known.iter())
+            .enumerate()
+            .find_map(|(index, (value, known))| {
+                unknown_field(value, known, &format!("{}[{}]", path, index))
+            }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::serde::{Deserialize, Serialize};
+
+    use crate::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Profile {
+        name: String,
+        visits: u32,
+    }
+
+    impl TypeName for Profile {
+        fn get_typename() -> &'static str {
+            "com.example/Profile"
+        }
+    }
+
+    const EXTENDED: &[u8] = br#"{"name": "flink", "visits": 3, "email": "flink@example.com"}"#;
+
+    #[test]
+    fn json_ignores_unknown_fields() {
+        let profile = Json::<Profile>::deserialize(String::new(), EXTENDED).unwrap();
+        assert_eq!(
+            profile.0,
+            Profile {
+                name: "flink".to_string(),
+                visits: 3
+            }
+        );
+        assert_eq!(Json::<Profile>::get_typename(), "com.example/Profile");
+    }
+
+    #[test]
+    fn strict_json_rejects_unknown_fields() {
+        let result = StrictJson::<Profile>::deserialize(String::new(), EXTENDED);
+        assert_eq!(result, Err("Unknown field: .email".to_string()));
+
+        let profile = StrictJson(Profile {
+            name: "flink".to_string(),
+            visits: 3,
+        });
+        let serialized = profile.serialize(String::new()).unwrap();
+        assert_eq!(
+            StrictJson::<Profile>::deserialize(String::new(), &serialized),
+            Ok(profile)
+        );
+    }
+}
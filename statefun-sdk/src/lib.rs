@@ -21,43 +21,96 @@
 #![deny(missing_docs)]
 
 pub mod io;
+#[cfg(feature = "msgpack")]
+pub mod serde;
+pub mod serialization;
+pub mod testing;
 pub mod transport;
 
+/// Re-exports for the code that `#[derive(StatefunJson)]` generates. Not part of the public API.
+#[cfg(feature = "json")]
+#[doc(hidden)]
+pub mod __private {
+    pub use serde_json;
+}
+
 pub use crate::transport::hyper::HyperHttpTransport;
+#[cfg(feature = "tonic")]
+pub use crate::transport::tonic::TonicTransport;
 pub use address::Address;
+pub use compressed::{Compressed, Compression};
+#[cfg(feature = "flate2")]
+pub use compressed::{Gzip, GzipCompression};
+#[cfg(feature = "zstd")]
+pub use compressed::{Zstd, ZstdCompression};
 pub use context::Context;
-pub use effects::Effects;
+pub use delayed_invocation::DelayedInvocation;
+pub use delayed_tokens::DelayedTokens;
+pub use effects::{Effects, EffectsCheckpoint};
 pub use egress_identifier::EgressIdentifier;
+pub use error::{InvocationError, RegistrationError};
+pub use error_record::ErrorRecord;
 pub use expiration::{Expiration, ExpirationType};
 pub use function_registry::FunctionRegistry;
 pub use function_type::FunctionType;
-pub use message::Message;
+#[cfg(feature = "json")]
+pub use json::{Json, StrictJson};
+pub use message::{Message, RawMessage};
+pub use middleware::Next;
+pub use non_finite_policy::NonFinitePolicy;
+pub use protobuf_message::{ProtoAny, Protobuf};
+pub use state_backend::StateBackend;
+pub use state_update::StateUpdate;
+#[cfg(feature = "derive")]
+pub use statefun_derive::StatefunJson;
+pub use tick::Tick;
 pub use traits::{Serializable, TypeName};
-pub use value_spec::ValueSpec;
+pub use value_spec::{ValueSpec, ValueSpecBuilder};
+pub use value_spec_base::ValueSpecBase;
+pub use versioned::{SchemaVersion, Versioned};
 
 mod address;
+mod allowed_egresses;
+mod compressed;
 mod context;
 mod delayed_invocation;
+mod delayed_tokens;
 mod effects;
 mod egress_identifier;
 mod error;
+mod error_record;
 mod expiration;
 mod function_registry;
 mod function_type;
 mod invocation_bridge;
+#[cfg(feature = "json")]
+mod json;
 mod macros;
 mod message;
+mod message_metadata;
+mod middleware;
 mod missing_states;
-mod serialization;
+mod module_spec;
+mod non_finite_policy;
+mod protobuf_message;
+mod serialization_errors;
+mod state_backend;
 mod state_update;
+#[cfg(test)]
+mod test_logger;
+mod tick;
 mod traits;
 mod type_name;
 mod value_spec;
 mod value_spec_base;
+mod versioned;
 
-use delayed_invocation::DelayedInvocation;
-use error::InvocationError;
 use missing_states::MissingStates;
-use state_update::StateUpdate;
 use statefun_proto::request_reply::TypedValue;
-use value_spec_base::ValueSpecBase;
+
+/// The version of Apache Flink Stateful Functions that this SDK implements the remote function
+/// protocol of. The SDK is compatible with all StateFun releases of the same major version.
+///
+/// The protocol has no handshake, so Flink can't ask for this. The `HyperHttpTransport` logs it
+/// at startup instead.
+pub const SUPPORTED_STATEFUN_VERSION: &str = "3.2.0";
@@ -12,3 +12,19 @@ macro_rules! specs {
         }
     };
 }
+
+/// This macro registers a function together with its function type and its state specs, keeping
+/// the specs next to the handler that uses them. The specs are given as a list of `ValueSpec<T>`s,
+/// for example:
+///
+/// ```ignore
+/// register!(registry, user_function_type(), [seen_count_spec()], user)?;
+/// ```
+///
+/// In debug builds, a warning is logged whenever a function reads state that it didn't declare.
+#[macro_export]
+macro_rules! register {
+    ( $registry:expr, $function_type:expr, [ $( $spec:expr ),* $(,)? ], $function:expr ) => {
+        $registry.register_fn($function_type, vec![ $( $spec.into() ),* ], $function)
+    };
+}
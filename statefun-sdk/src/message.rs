@@ -1,4 +1,27 @@
-use crate::{Serializable, TypeName, TypedValue};
+use protobuf::well_known_types::Any;
+use protobuf::Message as ProtoMessage;
+
+use crate::versioned::split_version;
+use crate::{Context, SchemaVersion, Serializable, Tick, TypeName, TypedValue};
+
+/// The typename of generic messages, which wrap a message of any type together with its typename
+/// in a `google.protobuf.Any`. See `Message::get_generic()` and `Effects::send_generic()`.
+pub(crate) const GENERIC_TYPENAME: &str = "type.googleapis.com/google.protobuf.Any";
+
+#[cfg(feature = "json")]
+const BUILT_IN_TYPES_NAMESPACE: &str = "io.statefun.types/";
+
+/// The raw parts of a message, as sent by Flink, for bridging to other systems without decoding
+/// the message. See `Message::raw()`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawMessage {
+    /// The typename of the message, for example "io.statefun.types/int".
+    pub typename: String,
+    /// Whether the message has a value. Messages without a value have empty `bytes`.
+    pub has_value: bool,
+    /// The serialized value of the message.
+    pub bytes: Vec<u8>,
+}
 
 /// Contains a message as received by a statefun function
 #[derive(Debug)]
@@ -7,13 +30,96 @@ pub struct Message {
 }
 
 impl Message {
-    /// Check whether the received message is of the specified type.
+    /// Creates a message that contains the given value, serialized under the typename of its
+    /// type, as if it was sent by Flink. This allows calling functions directly in unit tests:
+    ///
+    /// ```
+    /// # use statefun::Message;
+    /// let message = Message::from_value(&"hello".to_string())?;
+    /// assert!(message.is::<String>());
+    /// assert!(!message.is::<i32>());
+    /// assert_eq!(message.get::<String>()?, "hello");
+    /// # Ok::<(), String>(())
+    /// ```
+    pub fn from_value<T: Serializable<T> + TypeName>(value: &T) -> Result<Message, String> {
+        let mut typed_value = TypedValue::new();
+        typed_value.set_typename(T::get_typename().to_string());
+        typed_value.set_has_value(true);
+        typed_value.set_value(value.serialize(T::get_typename().to_string())?);
+        Ok(Message::new(typed_value))
+    }
+
+    /// Check whether the received message is of the specified type, under its typename or one of
+    /// its alternative typenames.
     pub fn is<T: TypeName>(&self) -> bool {
-        self.typed_value.typename.eq(T::get_typename())
+        let typename = self.typed_value.typename.as_str();
+        typename == T::get_typename() || T::get_alternative_typenames().contains(&typename)
+    }
+
+    /// Attempt to deserialize a message that was sent as a [Versioned](crate::Versioned) of any
+    /// schema version of the provided type. Messages of the current schema version are
+    /// deserialized directly, messages of other versions are handed to `migrate` together with
+    /// their schema version, to be converted to the current version.
+    pub fn get_versioned<T, F>(&self, migrate: F) -> Result<T, String>
+    where
+        T: Serializable<T> + TypeName + SchemaVersion,
+        F: Fn(u8, &[u8]) -> Result<T, String>,
+    {
+        let versioned_prefix = format!("{}.v", T::get_typename());
+        if !self.typed_value.typename.starts_with(&versioned_prefix) {
+            return Err(format!(
+                "Incompatible types. Expected: {:?} Payload: {:?}",
+                versioned_prefix, self.typed_value.typename
+            ));
+        }
+
+        let (version, payload) = split_version(&self.typed_value.value)?;
+        if version == T::SCHEMA_VERSION {
+            T::deserialize(T::get_typename().to_string(), payload)
+        } else {
+            migrate(version, payload)
+        }
+    }
+
+    /// Unwraps a generic message that was sent using `Effects::send_generic()` or by another SDK
+    /// as a `google.protobuf.Any`. Returns the typename and the serialized bytes of the wrapped
+    /// message, without interpreting them. This allows routing messages of types that the function
+    /// doesn't know.
+    pub fn get_generic(&self) -> Result<(String, Vec<u8>), String> {
+        if self.typed_value.typename != GENERIC_TYPENAME {
+            return Err(format!(
+                "Incompatible types. Expected: {:?} Payload: {:?}",
+                GENERIC_TYPENAME, self.typed_value.typename
+            ));
+        }
+        match Any::parse_from_bytes(&self.typed_value.value) {
+            Ok(mut any) => Ok((any.take_type_url(), any.take_value())),
+            Err(error) => Err(error.to_string()),
+        }
+    }
+
+    /// Check whether this message was sent by the invoked function to itself, for example because
+    /// it broadcast a message to a group of functions that includes itself. Functions can use this
+    /// to ignore their own messages.
+    ///
+    /// Flink passes the address of the sender along with every message, so this needs no
+    /// cooperation from the sender. Note that a function can't tell whether a message from itself
+    /// was sent using `send()` or `send_after()`.
+    pub fn is_from_self(&self, context: &Context) -> bool {
+        context.caller_address() == Some(context.self_address())
+    }
+
+    /// Check whether the received message is a `Tick` that was scheduled using
+    /// `Effects::schedule_tick()`.
+    pub fn is_tick(&self) -> bool {
+        self.is::<Tick>()
     }
 
     /// Attempt to deserialize the message to the provided type. If the typename of the message
     /// does not match the provided type, or if deserialization fails, it will return an error.
+    ///
+    /// Messages with one of the alternative typenames of the type are accepted as well, see
+    /// `TypeName::get_alternative_typenames()`.
     pub fn get<T: Serializable<T> + TypeName>(&self) -> Result<T, String> {
         if !self.is::<T>() {
             return Err(format!(
@@ -29,6 +135,47 @@ impl Message {
         )
     }
 
+    /// Attempt to deserialize the message payload as untyped JSON. This allows inspecting JSON
+    /// messages without defining a type for them, for example to route on arbitrary fields.
+    ///
+    /// The built-in `io.statefun.types/*` types are Protobuf-encoded and are rejected, as is any
+    /// payload that is not valid JSON.
+    #[cfg(feature = "json")]
+    pub fn get_json_value(&self) -> Result<serde_json::Value, String> {
+        if self
+            .typed_value
+            .typename
+            .starts_with(BUILT_IN_TYPES_NAMESPACE)
+        {
+            return Err(format!(
+                "Built-in type is not JSON-encoded: {:?}",
+                self.typed_value.typename
+            ));
+        }
+
+        match serde_json::from_slice(&self.typed_value.value) {
+            Ok(result) => Ok(result),
+            Err(error) => Err(error.to_string()),
+        }
+    }
+
+    /// Returns a copy of the typename, value flag and serialized bytes of this message, exactly as
+    /// they were received. This doesn't expose the generated Protobuf types, which may change with
+    /// the protocol.
+    pub fn raw(&self) -> RawMessage {
+        RawMessage {
+            typename: self.typed_value.typename.clone(),
+            has_value: self.typed_value.has_value,
+            bytes: self.typed_value.value.clone(),
+        }
+    }
+
+    /// Returns the serialized bytes of this message, exactly as they were received. Use this with
+    /// `get_type()` to pass on messages of unknown type using `Effects::forward_raw()`.
+    pub fn raw_bytes(&self) -> &[u8] {
+        &self.typed_value.value
+    }
+
     /// Get the underyling type name of this message
     pub fn get_type(&self) -> String {
         self.typed_value.typename.to_string()
@@ -39,3 +186,122 @@ impl Message {
         Message { typed_value }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    fn to_typed_value(typename: String, value: Vec<u8>) -> TypedValue {
+        let mut res = TypedValue::new();
+        res.set_typename(typename);
+        res.set_has_value(true);
+        res.set_value(value);
+        res
+    }
+
+    /// A temperature that used to be sent as decimal text and is now sent as a built-in int.
+    #[derive(Debug, PartialEq)]
+    struct Celsius(i32);
+
+    impl TypeName for Celsius {
+        fn get_typename() -> &'static str {
+            "com.example/celsius.v2"
+        }
+
+        fn get_alternative_typenames() -> &'static [&'static str] {
+            &["com.example/celsius"]
+        }
+    }
+
+    impl Serializable<Celsius> for Celsius {
+        fn serialize(&self, _typename: String) -> Result<Vec<u8>, String> {
+            serialization::encode_i32(self.0)
+        }
+
+        fn deserialize(typename: String, buffer: &[u8]) -> Result<Celsius, String> {
+            if typename == "com.example/celsius" {
+                let text = std::str::from_utf8(buffer).map_err(|error| error.to_string())?;
+                return text.parse().map(Celsius).map_err(|error| error.to_string());
+            }
+            serialization::decode_i32(buffer).map(Celsius)
+        }
+    }
+
+    #[test]
+    fn get_with_alternative_typenames() {
+        let current = Message::new(to_typed_value(
+            "com.example/celsius.v2".to_string(),
+            Celsius(21).serialize(String::new()).unwrap(),
+        ));
+        assert!(current.is::<Celsius>());
+        assert_eq!(current.get::<Celsius>(), Ok(Celsius(21)));
+
+        let legacy = Message::new(to_typed_value(
+            "com.example/celsius".to_string(),
+            b"-4".to_vec(),
+        ));
+        assert!(legacy.is::<Celsius>());
+        assert_eq!(legacy.get::<Celsius>(), Ok(Celsius(-4)));
+
+        let unrelated = Message::new(to_typed_value(
+            "com.example/fahrenheit".to_string(),
+            b"70".to_vec(),
+        ));
+        assert!(unrelated.get::<Celsius>().is_err());
+    }
+
+    #[test]
+    fn from_value() {
+        let message = Message::from_value(&Celsius(21)).unwrap();
+        assert_eq!(message.get_type(), "com.example/celsius.v2");
+        assert!(message.raw().has_value);
+        assert_eq!(message.get::<Celsius>(), Ok(Celsius(21)));
+    }
+
+    #[test]
+    fn raw() {
+        let message = Message::new(to_typed_value(
+            "com.example/bytes".to_string(),
+            vec![1, 2, 3],
+        ));
+        assert_eq!(
+            message.raw(),
+            RawMessage {
+                typename: "com.example/bytes".to_string(),
+                has_value: true,
+                bytes: vec![1, 2, 3],
+            }
+        );
+
+        assert_eq!(message.raw_bytes(), [1, 2, 3]);
+
+        let message = Message::new(TypedValue::new());
+        assert!(!message.raw().has_value);
+        assert!(message.raw().bytes.is_empty());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn get_json_value() {
+        let payload = br#"{"user_name": "flink", "login_type": "WEB"}"#.to_vec();
+        let message = Message::new(to_typed_value(
+            "greeter.types/UserLogin".to_string(),
+            payload,
+        ));
+
+        let value = message.get_json_value().unwrap();
+        assert_eq!(value["user_name"], "flink");
+        assert_eq!(value["login_type"], "WEB");
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn get_json_value_of_non_json_message() {
+        let payload = 42.serialize(i32::get_typename().to_string()).unwrap();
+        let message = Message::new(to_typed_value(i32::get_typename().to_string(), payload));
+        assert!(message.get_json_value().is_err());
+
+        let message = Message::new(to_typed_value("some-type".to_string(), vec![0xff]));
+        assert!(message.get_json_value().is_err());
+    }
+}
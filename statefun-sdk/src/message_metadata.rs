@@ -0,0 +1,105 @@
+//! Message metadata is not part of the StateFun protocol, so messages that carry metadata are
+//! wrapped in an envelope with a dedicated typename. The envelope is encoded like this Protobuf
+//! message:
+//!
+//! ```protobuf
+//! message MessageWithMetadata {
+//!     string typename = 1;
+//!     bytes value = 2;
+//!     repeated Entry metadata = 3;
+//!
+//!     message Entry {
+//!         string key = 1;
+//!         string value = 2;
+//!     }
+//! }
+//! ```
+//!
+//! The `InvocationBridge` unwraps envelopes before invoking a function, so functions only see the
+//! wrapped message and can read the metadata using `Context::message_metadata()`. Functions
+//! implemented with other SDKs see the envelope instead.
+use std::collections::HashMap;
+
+use protobuf::{CodedInputStream, CodedOutputStream, ProtobufResult};
+
+use statefun_proto::request_reply::TypedValue;
+
+/// The typename of messages that are wrapped together with metadata.
+pub(crate) const MESSAGE_WITH_METADATA_TYPENAME: &str =
+    "io.statefun.rust.types/message-with-metadata";
+
+/// The metadata key under which `Effects::send_after_valid_until()` stores the end of the
+/// validity of a delayed message, in milliseconds since the Unix epoch.
+pub(crate) const VALID_UNTIL_KEY: &str = "valid-until-millis";
+
+/// Wraps the serialized message with the given typename together with the metadata.
+pub(crate) fn wrap(
+    typename: &str,
+    value: &[u8],
+    metadata: &HashMap<String, String>,
+) -> Result<Vec<u8>, String> {
+    // sort the entries so that equal metadata always results in equal bytes
+    let mut entries: Vec<(&String, &String)> = metadata.iter().collect();
+    entries.sort();
+
+    let encode = || -> ProtobufResult<Vec<u8>> {
+        let mut buffer = Vec::new();
+        {
+            let mut output = CodedOutputStream::vec(&mut buffer);
+            output.write_string(1, typename)?;
+            output.write_bytes(2, value)?;
+            for (key, value) in entries {
+                let mut entry = Vec::new();
+                {
+                    let mut entry_output = CodedOutputStream::vec(&mut entry);
+                    entry_output.write_string(1, key)?;
+                    entry_output.write_string(2, value)?;
+                    entry_output.flush()?;
+                }
+                output.write_bytes(3, &entry)?;
+            }
+            output.flush()?;
+        }
+        Ok(buffer)
+    };
+    encode().map_err(|error| error.to_string())
+}
+
+/// Returns the wrapped message and the metadata of the given envelope.
+pub(crate) fn unwrap(buffer: &[u8]) -> Result<(TypedValue, HashMap<String, String>), String> {
+    let decode = || -> ProtobufResult<(TypedValue, HashMap<String, String>)> {
+        let mut input = CodedInputStream::from_bytes(buffer);
+        let mut typed_value = TypedValue::new();
+        typed_value.set_has_value(true);
+        let mut metadata = HashMap::new();
+        while !input.eof()? {
+            let (field_number, wire_type) = input.read_tag_unpack()?;
+            match field_number {
+                1 => typed_value.set_typename(input.read_string()?),
+                2 => typed_value.set_value(input.read_bytes()?),
+                3 => {
+                    let (key, value) = read_entry(&input.read_bytes()?)?;
+                    metadata.insert(key, value);
+                }
+                _ => input.skip_field(wire_type)?,
+            }
+        }
+        Ok((typed_value, metadata))
+    };
+    decode().map_err(|error| error.to_string())
+}
+
+fn read_entry(buffer: &[u8]) -> ProtobufResult<(String, String)> {
+    let mut input = CodedInputStream::from_bytes(buffer);
+    let mut key = String::new();
+    let mut value = String::new();
+    while !input.eof()? {
+        let (field_number, wire_type) = input.read_tag_unpack()?;
+        match field_number {
+            1 => key = input.read_string()?,
+            2 => value = input.read_string()?,
+            _ => input.skip_field(wire_type)?,
+        }
+    }
+    Ok((key, value))
+}
@@ -0,0 +1,70 @@
+//! Middleware runs around every invocation of the functions in a `FunctionRegistry`, see
+//! `FunctionRegistry::wrap()`.
+use std::sync::Mutex;
+
+use futures::future::{BoxFuture, FutureExt};
+
+use crate::function_registry::InvokableFunction;
+use crate::{Context, Effects, FunctionType, InvocationError, Message};
+
+/// A middleware that was registered using `FunctionRegistry::wrap()`.
+pub(crate) type BoxedMiddleware = Box<
+    dyn for<'a> Fn(FunctionType, Context<'a>, Message, Next<'a>) -> BoxFuture<'a, Effects>
+        + Send
+        + Sync,
+>;
+
+/// The rest of the middleware chain, ending in the invoked function. A middleware awaits `run()`
+/// to continue the invocation, or drops it to short-circuit the invocation.
+pub struct Next<'a> {
+    function_type: &'a FunctionType,
+    middleware: &'a [BoxedMiddleware],
+    function: &'a (dyn InvokableFunction + Send + Sync),
+    error: &'a Mutex<Option<InvocationError>>,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(
+        function_type: &'a FunctionType,
+        middleware: &'a [BoxedMiddleware],
+        function: &'a (dyn InvokableFunction + Send + Sync),
+        error: &'a Mutex<Option<InvocationError>>,
+    ) -> Next<'a> {
+        Next {
+            function_type,
+            middleware,
+            function,
+            error,
+        }
+    }
+
+    /// Invokes the next middleware, or the function itself if this is the last middleware.
+    /// Functions that were registered using `register_async_fn()` are awaited, not blocked on.
+    ///
+    /// If the invocation fails, for example because Flink has to provide missing state first,
+    /// this returns empty `Effects` and the registry reports the failure once the middleware
+    /// returns. The effects that a middleware adds in that case are discarded.
+    pub fn run(self, context: Context<'a>, message: Message) -> BoxFuture<'a, Effects> {
+        // nothing runs before the future is polled, so that the registry can set up the
+        // invocation, see `allowed_egresses::scope()`
+        async move {
+            match self.middleware.split_first() {
+                Some((middleware, rest)) => {
+                    let next = Next {
+                        middleware: rest,
+                        ..self
+                    };
+                    middleware(self.function_type.clone(), context, message, next).await
+                }
+                None => match self.function.invoke_async(context, message).await {
+                    Ok(effects) => effects,
+                    Err(error) => {
+                        self.error.lock().unwrap().replace(error);
+                        Effects::new()
+                    }
+                },
+            }
+        }
+        .boxed()
+    }
+}
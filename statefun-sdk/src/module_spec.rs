@@ -0,0 +1,63 @@
+//! Renders the `module.yaml` that declares the functions and egresses of a `FunctionRegistry` to
+//! Flink, see `FunctionRegistry::export_module_spec()`.
+use std::fmt::Write;
+
+use crate::io::{kafka, kinesis};
+use crate::EgressIdentifier;
+
+/// Returns the kind of the egress that accepts records of the given typename, if we know one.
+pub(crate) fn egress_kind(record_typename: &str) -> Option<&'static str> {
+    match record_typename {
+        kafka::RECORD_TYPENAME => Some("io.statefun.kafka.v1/egress"),
+        kinesis::RECORD_TYPENAME => Some("io.statefun.kinesis.v1/egress"),
+        _ => None,
+    }
+}
+
+/// Renders one http endpoint per function namespace, followed by the egresses. Egresses whose
+/// typename has no known kind are skipped, `FunctionRegistry::register_egress()` rejects them.
+pub(crate) fn render(
+    namespaces: &[String],
+    egresses: &[(EgressIdentifier, String)],
+    endpoint_url: &str,
+) -> String {
+    let mut documents = Vec::new();
+    for namespace in namespaces {
+        let mut document = String::new();
+        writeln!(document, "kind: io.statefun.endpoints.v2/http").unwrap();
+        writeln!(document, "spec:").unwrap();
+        writeln!(document, "  functions: {}/*", namespace).unwrap();
+        writeln!(document, "  urlPathTemplate: {}", endpoint_url).unwrap();
+        documents.push(document);
+    }
+    for (identifier, record_typename) in egresses {
+        let kind = match egress_kind(record_typename) {
+            Some(kind) => kind,
+            None => continue,
+        };
+        let mut document = String::new();
+        writeln!(document, "kind: {}", kind).unwrap();
+        writeln!(document, "spec:").unwrap();
+        writeln!(
+            document,
+            "  id: {}/{}",
+            identifier.namespace, identifier.name
+        )
+        .unwrap();
+        if record_typename == kafka::RECORD_TYPENAME {
+            writeln!(
+                document,
+                "  # replace with the bootstrap servers of the cluster"
+            )
+            .unwrap();
+            writeln!(document, "  address: kafka:9092").unwrap();
+        } else {
+            writeln!(document, "  awsRegion:").unwrap();
+            writeln!(document, "    type: default").unwrap();
+            writeln!(document, "  awsCredentials:").unwrap();
+            writeln!(document, "    type: default").unwrap();
+        }
+        documents.push(document);
+    }
+    documents.join("---\n")
+}
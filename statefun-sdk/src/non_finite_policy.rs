@@ -0,0 +1,32 @@
+/// Specifies how non-finite floating point values (`NaN`, `+Inf`, `-Inf`) are handled when
+/// serializing `f32`/`f64` state.
+///
+/// The protobuf wrappers used for the built-in float types can represent non-finite values, but
+/// many downstream consumers (for example JSON egresses) reject them. Use this together with
+/// `ValueSpec::with_non_finite_policy()` to catch such values before they are persisted.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum NonFinitePolicy {
+    /// Non-finite values are serialized as-is. This is the default.
+    #[default]
+    Allow,
+
+    /// Serialization fails with an error when the value is non-finite.
+    Reject,
+
+    /// Non-finite values are replaced by the given sentinel before serialization.
+    ReplaceWith(f64),
+}
+
+impl NonFinitePolicy {
+    /// Applies this policy to the given value, returning the value that should be serialized.
+    pub(crate) fn apply(&self, value: f64) -> Result<f64, String> {
+        if value.is_finite() {
+            return Ok(value);
+        }
+        match self {
+            NonFinitePolicy::Allow => Ok(value),
+            NonFinitePolicy::Reject => Err(format!("Non-finite float value: {}", value)),
+            NonFinitePolicy::ReplaceWith(sentinel) => Ok(*sentinel),
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use std::ops::{Deref, DerefMut};
+
+use protobuf::well_known_types::Any;
+use protobuf::Message as ProtoMessage;
+
+use crate::message::GENERIC_TYPENAME;
+use crate::type_name::derived_typename;
+use crate::{Serializable, TypeName};
+
+/// Wraps a generated Protobuf message so that it can be used as a message or state type without
+/// implementing `Serializable` and `TypeName` for a newtype by hand.
+///
+/// The typename is derived from the full name of the message, for example
+/// "type.googleapis.com/com.example.UserProfile".
+///
+/// The wrapper dereferences to the message, so generated accessors can be called on it directly,
+/// for example `Protobuf(UserProfile::new()).set_name(...)`.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Protobuf<M>(pub M);
+
+impl<M> Deref for Protobuf<M> {
+    type Target = M;
+
+    fn deref(&self) -> &M {
+        &self.0
+    }
+}
+
+impl<M> DerefMut for Protobuf<M> {
+    fn deref_mut(&mut self) -> &mut M {
+        &mut self.0
+    }
+}
+
+impl<M: protobuf::Message> TypeName for Protobuf<M> {
+    fn get_typename() -> &'static str {
+        derived_typename::<Protobuf<M>, _>(|| {
+            format!("type.googleapis.com/{}", M::descriptor_static().full_name())
+        })
+    }
+}
+
+impl<M: protobuf::Message> Serializable<Protobuf<M>> for Protobuf<M> {
+    fn serialize(&self, _typename: String) -> Result<Vec<u8>, String> {
+        match self.0.write_to_bytes() {
+            Ok(result) => Ok(result),
+            Err(error) => Err(error.to_string()),
+        }
+    }
+
+    fn deserialize(_typename: String, buffer: &[u8]) -> Result<Protobuf<M>, String> {
+        match M::parse_from_bytes(buffer) {
+            Ok(result) => Ok(Protobuf(result)),
+            Err(error) => Err(error.to_string()),
+        }
+    }
+}
+
+/// Wraps a generated Protobuf message that is sent packed into a `google.protobuf.Any`, like
+/// older versions of StateFun and some Java functions do.
+///
+/// The typename is the one of `google.protobuf.Any`, the full name of the wrapped message is the
+/// type URL inside of the `Any`, for example "type.googleapis.com/com.example.UserProfile". This
+/// is the same format as `Effects::send_generic()` and `Message::get_generic()` use. Deserializing
+/// fails if the `Any` contains a message of another type.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct ProtoAny<M>(pub M);
+
+impl<M> Deref for ProtoAny<M> {
+    type Target = M;
+
+    fn deref(&self) -> &M {
+        &self.0
+    }
+}
+
+impl<M> DerefMut for ProtoAny<M> {
+    fn deref_mut(&mut self) -> &mut M {
+        &mut self.0
+    }
+}
+
+impl<M: protobuf::Message> TypeName for ProtoAny<M> {
+    fn get_typename() -> &'static str {
+        GENERIC_TYPENAME
+    }
+}
+
+impl<M: protobuf::Message> Serializable<ProtoAny<M>> for ProtoAny<M> {
+    fn serialize(&self, _typename: String) -> Result<Vec<u8>, String> {
+        let any = match Any::pack(&self.0) {
+            Ok(result) => result,
+            Err(error) => return Err(error.to_string()),
+        };
+        match any.write_to_bytes() {
+            Ok(result) => Ok(result),
+            Err(error) => Err(error.to_string()),
+        }
+    }
+
+    fn deserialize(_typename: String, buffer: &[u8]) -> Result<ProtoAny<M>, String> {
+        let any = match Any::parse_from_bytes(buffer) {
+            Ok(result) => result,
+            Err(error) => return Err(error.to_string()),
+        };
+        match any.unpack::<M>() {
+            Ok(Some(result)) => Ok(ProtoAny(result)),
+            Ok(None) => Err(format!(
+                "Incompatible types. Expected: \"type.googleapis.com/{}\" Payload: {:?}",
+                M::descriptor_static().full_name(),
+                any.get_type_url()
+            )),
+            Err(error) => Err(error.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use protobuf::Message as ProtoMessage;
+    use statefun_proto::request_reply::Address as ProtoAddress;
+    use statefun_proto::request_reply::TypedValue as ProtoTypedValue;
+
+    use crate::*;
+
+    fn profile_spec() -> ValueSpec<Protobuf<ProtoAddress>> {
+        ValueSpec::new("profile", Expiration::never())
+    }
+
+    #[test]
+    fn protobuf_typename() {
+        assert_eq!(
+            Protobuf::<ProtoAddress>::get_typename(),
+            "type.googleapis.com/io.statefun.sdk.reqreply.Address"
+        );
+    }
+
+    fn proto_address() -> ProtoAddress {
+        let mut address = ProtoAddress::new();
+        address.set_namespace("com.example".to_string());
+        address.set_id("flink".to_string());
+        address
+    }
+
+    #[test]
+    fn proto_any_round_trip() {
+        let message = Message::from_value(&ProtoAny(proto_address())).unwrap();
+        assert_eq!(
+            message.get_type(),
+            "type.googleapis.com/google.protobuf.Any"
+        );
+        assert_eq!(
+            message.get::<ProtoAny<ProtoAddress>>(),
+            Ok(ProtoAny(proto_address()))
+        );
+
+        let (type_url, bytes) = message.get_generic().unwrap();
+        assert_eq!(
+            type_url,
+            "type.googleapis.com/io.statefun.sdk.reqreply.Address"
+        );
+        assert_eq!(bytes, proto_address().write_to_bytes().unwrap());
+    }
+
+    #[test]
+    fn proto_any_of_other_type() {
+        let serialized = ProtoAny(ProtoTypedValue::new())
+            .serialize(String::new())
+            .unwrap();
+        assert_eq!(
+            ProtoAny::<ProtoAddress>::deserialize(String::new(), &serialized),
+            Err("Incompatible types. Expected: \"type.googleapis.com/io.statefun.sdk.reqreply.Address\" Payload: \"type.googleapis.com/io.statefun.sdk.reqreply.TypedValue\"".to_string())
+        );
+    }
+
+    #[test]
+    fn mutate_through_wrapper() {
+        let mut address = Protobuf(ProtoAddress::new());
+        address.set_namespace("com.example".to_string());
+        address.set_id("flink".to_string());
+        assert_eq!(address.get_namespace(), "com.example");
+
+        let serialized = address.serialize(String::new()).unwrap();
+        let deserialized =
+            Protobuf::<ProtoAddress>::deserialize(String::new(), &serialized).unwrap();
+        assert_eq!(deserialized.get_id(), "flink");
+        assert_eq!(deserialized, address);
+    }
+
+    #[test]
+    fn protobuf_state_round_trip() {
+        let mut profile = ProtoAddress::new();
+        profile.set_namespace("com.example".to_string());
+        profile.set_field_type("user".to_string());
+        profile.set_id("flink".to_string());
+
+        let mut effects = Effects::new();
+        effects
+            .update_state(profile_spec(), &Protobuf(profile.clone()))
+            .unwrap();
+
+        let mut state = HashMap::new();
+        for state_update in effects.state_updates {
+            if let StateUpdate::Update(value_spec, serialized) = state_update {
+                state.insert(value_spec, serialized);
+            }
+        }
+        let address = Address::new(FunctionType::new("namespace", "foo"), "id");
+        let context = Context::new(&state, &address, None);
+
+        let stored = context.get_state(profile_spec()).unwrap().unwrap();
+        assert_eq!(stored, Protobuf(profile));
+    }
+}
@@ -0,0 +1,104 @@
+//! Wrappers for using values that are encoded with `serde` formats other than JSON as messages or
+//! state, see `MsgPack`. For JSON, see `Json` and `StrictJson`.
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{Serializable, TypeName};
+
+/// Wraps a value that is encoded as MessagePack using `serde`, which is more compact on the wire
+/// than `Json`. The typename is the typename of the wrapped type, so it is configured by
+/// implementing `TypeName` for the wrapped type, and all functions that exchange the value must
+/// agree on it.
+///
+/// Structs are encoded as maps with named fields, so that other SDKs can decode them without
+/// knowing the order of the fields, and fields can be added like with `Json`.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct MsgPack<T>(pub T);
+
+impl<T: TypeName> TypeName for MsgPack<T> {
+    fn get_typename() -> &'static str {
+        T::get_typename()
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> Serializable<MsgPack<T>> for MsgPack<T> {
+    fn serialize(&self, _typename: String) -> Result<Vec<u8>, String> {
+        rmp_serde::to_vec_named(&self.0).map_err(|error| error.to_string())
+    }
+
+    fn deserialize(_typename: String, buffer: &[u8]) -> Result<MsgPack<T>, String> {
+        rmp_serde::from_slice(buffer)
+            .map(MsgPack)
+            .map_err(|error| error.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::serde::{Deserialize, Serialize};
+
+    use crate::serde::MsgPack;
+    use crate::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Profile {
+        name: String,
+        visits: u32,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct ProfileV1 {
+        name: String,
+    }
+
+    impl TypeName for Profile {
+        fn get_typename() -> &'static str {
+            "com.example/Profile"
+        }
+    }
+
+    fn profile() -> Profile {
+        Profile {
+            name: "flink".to_string(),
+            visits: 3,
+        }
+    }
+
+    #[test]
+    fn msgpack_round_trip() {
+        assert_eq!(MsgPack::<Profile>::get_typename(), "com.example/Profile");
+
+        let serialized = MsgPack(profile()).serialize(String::new()).unwrap();
+        assert_eq!(
+            MsgPack::<Profile>::deserialize(String::new(), &serialized),
+            Ok(MsgPack(profile()))
+        );
+
+        // fields are named, so readers ignore fields that they don't know
+        assert_eq!(
+            MsgPack::<ProfileV1>::deserialize(String::new(), &serialized),
+            Ok(MsgPack(ProfileV1 {
+                name: "flink".to_string()
+            }))
+        );
+        assert!(MsgPack::<Profile>::deserialize(String::new(), b"\xc1").is_err());
+    }
+
+    #[test]
+    fn msgpack_message() {
+        let mut effects = Effects::new();
+        effects
+            .send(
+                Address::new(FunctionType::new("com.example", "profiles"), "flink"),
+                &MsgPack(profile()),
+            )
+            .unwrap();
+
+        let (_address, typename, serialized) = &effects.invocations[0];
+        assert_eq!(typename, "com.example/Profile");
+        assert_eq!(
+            MsgPack::<Profile>::deserialize(typename.clone(), serialized),
+            Ok(MsgPack(profile()))
+        );
+    }
+}
@@ -1,103 +1,482 @@
-use crate::Serializable;
-use protobuf::Message;
+//! Encoding of the built-in `io.statefun.types/*` types, which Flink and the other StateFun SDKs
+//! wrap in small Protobuf messages.
+//!
+//! The functions in this module are what the `Serializable` implementations of `bool`, `i32`,
+//! `i64`, `u32`, `u64`, `f32`, `f64`, and `String` use. They are public so that tooling, for
+//! example tools that read state using Flink's State Processor API, can decode and encode these
+//! values in exactly the same way.
+use crate::{NonFinitePolicy, Serializable};
+use protobuf::wire_format::WireType;
+use protobuf::{CodedInputStream, CodedOutputStream, Message, ProtobufError};
 use statefun_proto::types::{
     BooleanWrapper, DoubleWrapper, FloatWrapper, IntWrapper, LongWrapper, StringWrapper,
 };
 
+fn encode<M: Message>(wrapped: M) -> Result<Vec<u8>, String> {
+    match wrapped.write_to_bytes() {
+        Ok(result) => Ok(result),
+        Err(result) => Err(result.to_string()),
+    }
+}
+
+fn decode<M: Message>(buffer: &[u8]) -> Result<M, String> {
+    match M::parse_from_bytes(buffer) {
+        Ok(result) => Ok(result),
+        Err(result) => Err(result.to_string()),
+    }
+}
+
+/// Encodes a `bool` as an `io.statefun.types/bool`.
+pub fn encode_bool(value: bool) -> Result<Vec<u8>, String> {
+    let mut wrapped = BooleanWrapper::new();
+    wrapped.set_value(value);
+    encode(wrapped)
+}
+
+/// Decodes an `io.statefun.types/bool`.
+pub fn decode_bool(buffer: &[u8]) -> Result<bool, String> {
+    decode::<BooleanWrapper>(buffer).map(|wrapped| wrapped.get_value())
+}
+
+/// Encodes an `i32` as an `io.statefun.types/int`.
+pub fn encode_i32(value: i32) -> Result<Vec<u8>, String> {
+    let mut wrapped = IntWrapper::new();
+    wrapped.set_value(value);
+    encode(wrapped)
+}
+
+/// Decodes an `io.statefun.types/int`.
+pub fn decode_i32(buffer: &[u8]) -> Result<i32, String> {
+    decode::<IntWrapper>(buffer).map(|wrapped| wrapped.get_value())
+}
+
+/// Encodes an `i64` as an `io.statefun.types/long`.
+pub fn encode_i64(value: i64) -> Result<Vec<u8>, String> {
+    let mut wrapped = LongWrapper::new();
+    wrapped.set_value(value);
+    encode(wrapped)
+}
+
+/// Decodes an `io.statefun.types/long`.
+pub fn decode_i64(buffer: &[u8]) -> Result<i64, String> {
+    decode::<LongWrapper>(buffer).map(|wrapped| wrapped.get_value())
+}
+
+/// Encodes an `f32` as an `io.statefun.types/float`.
+pub fn encode_f32(value: f32) -> Result<Vec<u8>, String> {
+    let mut wrapped = FloatWrapper::new();
+    wrapped.set_value(value);
+    encode(wrapped)
+}
+
+/// Decodes an `io.statefun.types/float`.
+pub fn decode_f32(buffer: &[u8]) -> Result<f32, String> {
+    decode::<FloatWrapper>(buffer).map(|wrapped| wrapped.get_value())
+}
+
+/// Encodes an `f64` as an `io.statefun.types/double`.
+pub fn encode_f64(value: f64) -> Result<Vec<u8>, String> {
+    let mut wrapped = DoubleWrapper::new();
+    wrapped.set_value(value);
+    encode(wrapped)
+}
+
+/// Decodes an `io.statefun.types/double`.
+pub fn decode_f64(buffer: &[u8]) -> Result<f64, String> {
+    decode::<DoubleWrapper>(buffer).map(|wrapped| wrapped.get_value())
+}
+
+/// Encodes a string as an `io.statefun.types/string`.
+pub fn encode_string(value: &str) -> Result<Vec<u8>, String> {
+    // this writes the same bytes as a `StringWrapper`, without copying the string into one first
+    if value.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut buffer = vec![0; protobuf::rt::string_size(1, value) as usize];
+    let result = {
+        let mut output = CodedOutputStream::bytes(&mut buffer);
+        output.write_string(1, value).and_then(|()| output.flush())
+    };
+    match result {
+        Ok(()) => Ok(buffer),
+        Err(error) => Err(error.to_string()),
+    }
+}
+
+/// Decodes an `io.statefun.types/string`.
+pub fn decode_string(buffer: &[u8]) -> Result<String, String> {
+    decode::<StringWrapper>(buffer).map(|mut wrapped| wrapped.take_value())
+}
+
 impl Serializable<bool> for bool {
     fn serialize(&self, _typename: String) -> Result<Vec<u8>, String> {
-        let mut wrapped = BooleanWrapper::new();
-        wrapped.set_value(*self);
-        match wrapped.write_to_bytes() {
-            Ok(result) => Ok(result),
-            Err(result) => Err(result.to_string()),
-        }
+        encode_bool(*self)
     }
 
     fn deserialize(_typename: String, buffer: &[u8]) -> Result<bool, String> {
-        match BooleanWrapper::parse_from_bytes(buffer) {
-            Ok(result) => Ok(result.get_value()),
-            Err(result) => Err(result.to_string()),
-        }
+        decode_bool(buffer)
     }
 }
 
 impl Serializable<i32> for i32 {
     fn serialize(&self, _typename: String) -> Result<Vec<u8>, String> {
-        let mut wrapped = IntWrapper::new();
-        wrapped.set_value(*self);
-        let res = wrapped.write_to_bytes().unwrap();
-        Ok(res)
+        encode_i32(*self)
     }
 
     fn deserialize(_typename: String, buffer: &[u8]) -> Result<i32, String> {
-        match IntWrapper::parse_from_bytes(buffer) {
-            Ok(result) => Ok(result.get_value()),
-            Err(result) => Err(result.to_string()),
-        }
+        decode_i32(buffer)
     }
 }
 
 impl Serializable<i64> for i64 {
     fn serialize(&self, _typename: String) -> Result<Vec<u8>, String> {
-        let mut wrapped = LongWrapper::new();
-        wrapped.set_value(*self);
-        let res = wrapped.write_to_bytes().unwrap();
-        Ok(res)
+        encode_i64(*self)
     }
 
     fn deserialize(_typename: String, buffer: &[u8]) -> Result<i64, String> {
-        match LongWrapper::parse_from_bytes(buffer) {
-            Ok(result) => Ok(result.get_value()),
-            Err(result) => Err(result.to_string()),
-        }
+        decode_i64(buffer)
+    }
+}
+
+/// Unsigned integers share the `io.statefun.types/int` encoding of `i32`, reinterpreting the bits.
+/// Values above `i32::MAX` wrap around, so functions of other SDKs read them as negative numbers.
+impl Serializable<u32> for u32 {
+    fn serialize(&self, _typename: String) -> Result<Vec<u8>, String> {
+        encode_i32(*self as i32)
+    }
+
+    fn deserialize(_typename: String, buffer: &[u8]) -> Result<u32, String> {
+        decode_i32(buffer).map(|value| value as u32)
+    }
+}
+
+/// Unsigned integers share the `io.statefun.types/long` encoding of `i64`, reinterpreting the
+/// bits. Values above `i64::MAX` wrap around, so functions of other SDKs read them as negative
+/// numbers.
+impl Serializable<u64> for u64 {
+    fn serialize(&self, _typename: String) -> Result<Vec<u8>, String> {
+        encode_i64(*self as i64)
+    }
+
+    fn deserialize(_typename: String, buffer: &[u8]) -> Result<u64, String> {
+        decode_i64(buffer).map(|value| value as u64)
     }
 }
 
 impl Serializable<f32> for f32 {
     fn serialize(&self, _typename: String) -> Result<Vec<u8>, String> {
-        let mut wrapped = FloatWrapper::new();
-        wrapped.set_value(*self);
-        let res = wrapped.write_to_bytes().unwrap();
-        Ok(res)
+        encode_f32(*self)
     }
 
     fn deserialize(_typename: String, buffer: &[u8]) -> Result<f32, String> {
-        match FloatWrapper::parse_from_bytes(buffer) {
-            Ok(result) => Ok(result.get_value()),
-            Err(result) => Err(result.to_string()),
-        }
+        decode_f32(buffer)
+    }
+
+    fn serialize_with_policy(
+        &self,
+        _typename: String,
+        policy: NonFinitePolicy,
+    ) -> Result<Vec<u8>, String> {
+        encode_f32(policy.apply(f64::from(*self))? as f32)
     }
 }
 
 impl Serializable<f64> for f64 {
     fn serialize(&self, _typename: String) -> Result<Vec<u8>, String> {
-        let mut wrapped = DoubleWrapper::new();
-        wrapped.set_value(*self);
-        let res = wrapped.write_to_bytes().unwrap();
-        Ok(res)
+        encode_f64(*self)
     }
 
     fn deserialize(_typename: String, buffer: &[u8]) -> Result<f64, String> {
-        match DoubleWrapper::parse_from_bytes(buffer) {
-            Ok(result) => Ok(result.get_value()),
-            Err(result) => Err(result.to_string()),
-        }
+        decode_f64(buffer)
+    }
+
+    fn serialize_with_policy(
+        &self,
+        _typename: String,
+        policy: NonFinitePolicy,
+    ) -> Result<Vec<u8>, String> {
+        encode_f64(policy.apply(*self)?)
     }
 }
 
 impl Serializable<String> for String {
     fn serialize(&self, _typename: String) -> Result<Vec<u8>, String> {
-        let mut wrapped = StringWrapper::new();
-        wrapped.set_value(self.clone());
-        let res = wrapped.write_to_bytes().unwrap();
-        Ok(res)
+        encode_string(self)
     }
 
     fn deserialize(_typename: String, buffer: &[u8]) -> Result<String, String> {
-        match StringWrapper::parse_from_bytes(buffer) {
-            Ok(result) => Ok(result.get_value().to_string()),
-            Err(result) => Err(result.to_string()),
+        decode_string(buffer)
+    }
+}
+
+/// Raw bytes of the `io.statefun.types/binary` type are passed through as they are, without a
+/// wrapper message.
+impl Serializable<Vec<u8>> for Vec<u8> {
+    fn serialize(&self, _typename: String) -> Result<Vec<u8>, String> {
+        Ok(self.clone())
+    }
+
+    fn deserialize(_typename: String, buffer: &[u8]) -> Result<Vec<u8>, String> {
+        Ok(buffer.to_vec())
+    }
+}
+
+/// The Protobuf field that holds the encoding of a present value in the encoding of `Option<T>`.
+const OPTIONAL_VALUE_FIELD: u32 = 1;
+
+/// `None` is serialized as an empty value, while `Some` is serialized as a Protobuf message whose
+/// field 1 holds the encoding of the wrapped type. Other SDKs can decode this using
+///
+/// ```protobuf
+/// message Optional {
+///     optional bytes value = 1;
+/// }
+/// ```
+///
+/// where an empty value or an unset `value` field means null/absent. The field is needed because
+/// the default values of many types, for example `0`, `false` or an empty `String`, serialize to
+/// an empty buffer themselves.
+impl<T: Serializable<T>> Serializable<Option<T>> for Option<T> {
+    fn serialize(&self, typename: String) -> Result<Vec<u8>, String> {
+        let value = match self {
+            Some(value) => value.serialize(typename)?,
+            None => return Ok(Vec::new()),
+        };
+        let mut buffer = vec![0; protobuf::rt::bytes_size(OPTIONAL_VALUE_FIELD, &value) as usize];
+        let result = {
+            let mut output = CodedOutputStream::bytes(&mut buffer);
+            output
+                .write_bytes(OPTIONAL_VALUE_FIELD, &value)
+                .and_then(|()| output.flush())
+        };
+        match result {
+            Ok(()) => Ok(buffer),
+            Err(error) => Err(error.to_string()),
         }
     }
+
+    fn deserialize(typename: String, buffer: &[u8]) -> Result<Option<T>, String> {
+        let invalid = |error: ProtobufError| {
+            format!("invalid optional value of type {}: {}", typename, error)
+        };
+        let mut input = CodedInputStream::from_bytes(buffer);
+        let mut value = None;
+        while !input.eof().map_err(invalid)? {
+            let (field_number, wire_type) = input.read_tag_unpack().map_err(invalid)?;
+            if field_number == OPTIONAL_VALUE_FIELD
+                && wire_type == WireType::WireTypeLengthDelimited
+            {
+                value = Some(input.read_bytes().map_err(invalid)?);
+            } else {
+                input.skip_field(wire_type).map_err(invalid)?;
+            }
+        }
+        match value {
+            Some(value) => T::deserialize(typename, &value).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    fn nan_state() -> ValueSpec<f64> {
+        ValueSpec::<f64>::new("nan", Expiration::never())
+    }
+
+    #[test]
+    fn serialize_nan_with_allow_policy() {
+        let serialized = f64::NAN
+            .serialize_with_policy(f64::get_typename().to_string(), NonFinitePolicy::Allow)
+            .unwrap();
+        let deserialized = f64::deserialize(f64::get_typename().to_string(), &serialized).unwrap();
+        assert!(deserialized.is_nan());
+    }
+
+    #[test]
+    fn serialize_nan_with_reject_policy() {
+        let result = f64::NAN
+            .serialize_with_policy(f64::get_typename().to_string(), NonFinitePolicy::Reject);
+        assert!(result.is_err());
+
+        let result = f32::INFINITY
+            .serialize_with_policy(f32::get_typename().to_string(), NonFinitePolicy::Reject);
+        assert!(result.is_err());
+
+        let result =
+            1.5f64.serialize_with_policy(f64::get_typename().to_string(), NonFinitePolicy::Reject);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn serialize_nan_with_replace_policy() {
+        let serialized = f32::NAN
+            .serialize_with_policy(
+                f32::get_typename().to_string(),
+                NonFinitePolicy::ReplaceWith(-1.0),
+            )
+            .unwrap();
+        let deserialized = f32::deserialize(f32::get_typename().to_string(), &serialized).unwrap();
+        assert_eq!(deserialized, -1.0);
+    }
+
+    #[test]
+    fn update_state_applies_policy_of_value_spec() {
+        let mut effects = Effects::new();
+
+        let result = effects.update_state(
+            nan_state().with_non_finite_policy(NonFinitePolicy::Reject),
+            &f64::NAN,
+        );
+        assert!(result.is_err());
+        assert!(effects.state_updates.is_empty());
+
+        effects.update_state(nan_state(), &f64::NAN).unwrap();
+        assert_eq!(effects.state_updates.len(), 1);
+    }
+
+    #[test]
+    fn option_round_trip() {
+        let typename = Option::<i32>::get_typename().to_string();
+        assert_eq!(typename, "io.statefun.types/int?");
+
+        let serialized = None::<i32>.serialize(typename.clone()).unwrap();
+        assert!(serialized.is_empty());
+        assert_eq!(
+            Option::<i32>::deserialize(typename.clone(), &serialized).unwrap(),
+            None
+        );
+
+        let serialized = Some(5).serialize(typename.clone()).unwrap();
+        // field 1 of length 5, holding the `IntWrapper` of 5
+        assert_eq!(serialized, vec![0x0a, 5, 0x0d, 5, 0, 0, 0]);
+        assert_eq!(
+            Option::<i32>::deserialize(typename.clone(), &serialized).unwrap(),
+            Some(5)
+        );
+
+        // a message without field 1 is absent as well
+        assert_eq!(
+            Option::<i32>::deserialize(typename.clone(), &[0x10, 1]).unwrap(),
+            None
+        );
+        assert!(Option::<i32>::deserialize(typename, &[0x0a, 5, 0x0d]).is_err());
+    }
+
+    // The defaults of the wrapped types serialize to an empty buffer, just like `None`
+    #[test]
+    fn option_round_trip_of_defaults() {
+        fn round_trip<T: Serializable<T> + TypeName + 'static>(value: Option<T>) -> Option<T> {
+            let typename = Option::<T>::get_typename().to_string();
+            let serialized = value.serialize(typename.clone()).unwrap();
+            Option::<T>::deserialize(typename, &serialized).unwrap()
+        }
+
+        assert_eq!(round_trip(Some(0)), Some(0));
+        assert_eq!(round_trip(Some(false)), Some(false));
+        assert_eq!(round_trip(Some(String::new())), Some(String::new()));
+        assert_eq!(round_trip(Some(Vec::<u8>::new())), Some(vec![]));
+        assert_eq!(round_trip(None::<String>), None);
+    }
+
+    #[test]
+    fn wrapper_round_trips() {
+        use crate::serialization::*;
+
+        assert!(decode_bool(&encode_bool(true).unwrap()).unwrap());
+        assert_eq!(decode_i32(&encode_i32(-42).unwrap()), Ok(-42));
+        assert_eq!(decode_i64(&encode_i64(i64::MAX).unwrap()), Ok(i64::MAX));
+        assert_eq!(decode_f32(&encode_f32(1.5).unwrap()), Ok(1.5));
+        assert_eq!(decode_f64(&encode_f64(-2.25).unwrap()), Ok(-2.25));
+        assert_eq!(
+            decode_string(&encode_string("flink").unwrap()),
+            Ok("flink".to_string())
+        );
+    }
+
+    #[test]
+    fn unsigned_round_trips() {
+        let serialized = u32::MAX.serialize(u32::get_typename().to_string()).unwrap();
+        assert_eq!(
+            u32::deserialize(u32::get_typename().to_string(), &serialized),
+            Ok(u32::MAX)
+        );
+        // other SDKs see the wrapped around value
+        assert_eq!(
+            i32::deserialize(i32::get_typename().to_string(), &serialized),
+            Ok(-1)
+        );
+
+        let serialized = u64::MAX.serialize(u64::get_typename().to_string()).unwrap();
+        assert_eq!(
+            u64::deserialize(u64::get_typename().to_string(), &serialized),
+            Ok(u64::MAX)
+        );
+        assert_eq!(
+            i64::deserialize(i64::get_typename().to_string(), &serialized),
+            Ok(-1)
+        );
+    }
+
+    #[test]
+    fn binary_round_trips() {
+        for bytes in [vec![0x00, 0xff, 0x0a, 0x80, 0x7f], vec![]] {
+            let serialized = bytes
+                .serialize(Vec::<u8>::get_typename().to_string())
+                .unwrap();
+            assert_eq!(serialized, bytes);
+            assert_eq!(
+                Vec::<u8>::deserialize(Vec::<u8>::get_typename().to_string(), &serialized),
+                Ok(bytes)
+            );
+        }
+    }
+
+    #[test]
+    fn wrappers_match_serializable() {
+        use crate::serialization::*;
+
+        assert_eq!(
+            encode_i64(42).unwrap(),
+            42i64.serialize(i64::get_typename().to_string()).unwrap()
+        );
+        assert_eq!(
+            encode_string("flink").unwrap(),
+            "flink"
+                .to_string()
+                .serialize(String::get_typename().to_string())
+                .unwrap()
+        );
+        assert!(decode_i32(&[0xff]).is_err());
+    }
+
+    #[test]
+    fn encoding_matches_protobuf() {
+        use crate::serialization::*;
+        use protobuf::Message;
+        use statefun_proto::types::{IntWrapper, StringWrapper};
+
+        let long = "x".repeat(1024);
+        let mut wrapped = StringWrapper::new();
+        wrapped.set_value(long.clone());
+        assert_eq!(
+            encode_string(&long).unwrap(),
+            wrapped.write_to_bytes().unwrap()
+        );
+
+        let mut wrapped = StringWrapper::new();
+        wrapped.set_value("flink".to_string());
+        assert_eq!(
+            encode_string("flink").unwrap(),
+            wrapped.write_to_bytes().unwrap()
+        );
+        assert!(encode_string("").unwrap().is_empty());
+
+        let mut wrapped = IntWrapper::new();
+        wrapped.set_value(-1);
+        assert_eq!(encode_i32(-1).unwrap(), wrapped.write_to_bytes().unwrap());
+        assert!(encode_i32(0).unwrap().is_empty());
+    }
 }
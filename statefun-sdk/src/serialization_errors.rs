@@ -0,0 +1,96 @@
+//! Reports failed serializations of `Effects` to the hook of the transport, see
+//! `HyperHttpTransport::on_serialization_error()`.
+//!
+//! Like the allowed egresses, the hook is published in a thread-local for the duration of an
+//! invocation, because `Effects` don't know which transport serves them.
+use std::cell::RefCell;
+use std::future::Future;
+use std::sync::Arc;
+
+use futures::future::{self, BoxFuture};
+
+/// A hook that is called with the typename and the error of every failed serialization.
+pub(crate) type SerializationErrorHook = Arc<dyn Fn(&str, &str) + Send + Sync>;
+
+thread_local! {
+    static HOOK: RefCell<Option<SerializationErrorHook>> = const { RefCell::new(None) };
+}
+
+/// Publishes the hook of the transport until it is dropped, and then restores the previously
+/// published hook, so that a nested scope doesn't clobber the outer one.
+pub(crate) struct SerializationErrorHookGuard {
+    previous: Option<SerializationErrorHook>,
+}
+
+impl SerializationErrorHookGuard {
+    pub(crate) fn set(hook: Option<SerializationErrorHook>) -> SerializationErrorHookGuard {
+        let previous = HOOK.with(|current| current.replace(hook));
+        SerializationErrorHookGuard { previous }
+    }
+}
+
+impl Drop for SerializationErrorHookGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        HOOK.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+/// Calls the hook of the transport, if there is one, with the given typename and error.
+pub(crate) fn report(typename: &str, error: &str) {
+    // clone the hook so that it may itself use Effects without a nested borrow
+    let hook = HOOK.with(|current| current.borrow().clone());
+    if let Some(hook) = hook {
+        hook(typename, error);
+    }
+}
+
+/// Publishes the hook whenever the given future is polled. An asynchronous function can resume on
+/// another thread after an `.await`, so setting the thread-local once is not enough.
+pub(crate) fn scope<'a, T: 'a>(
+    hook: Option<SerializationErrorHook>,
+    mut future: BoxFuture<'a, T>,
+) -> impl Future<Output = T> + 'a {
+    future::poll_fn(move |cx| {
+        let _guard = SerializationErrorHookGuard::set(hook.clone());
+        future.as_mut().poll(cx)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crate::serialization_errors::{
+        report, SerializationErrorHook, SerializationErrorHookGuard,
+    };
+
+    fn recording_hook(
+        reports: &Arc<Mutex<Vec<String>>>,
+        name: &'static str,
+    ) -> SerializationErrorHook {
+        let reports = Arc::clone(reports);
+        Arc::new(move |typename: &str, _error: &str| {
+            reports
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", name, typename))
+        })
+    }
+
+    #[test]
+    fn nested_guard_restores_outer_hook() {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+
+        let outer = SerializationErrorHookGuard::set(Some(recording_hook(&reports, "outer")));
+        {
+            let _inner = SerializationErrorHookGuard::set(Some(recording_hook(&reports, "inner")));
+            report("a", "error");
+        }
+        report("b", "error");
+        drop(outer);
+        report("c", "error");
+
+        assert_eq!(*reports.lock().unwrap(), vec!["inner a", "outer b"]);
+    }
+}
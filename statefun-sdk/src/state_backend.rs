@@ -0,0 +1,106 @@
+//! Support for keeping some of the state of a function outside of Flink, see `StateBackend`.
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+
+use futures::future::{self, BoxFuture};
+
+use crate::Address;
+
+/// A store for state values that are kept outside of Flink, for example in Redis.
+///
+/// States are assigned to a backend using `FunctionRegistry::back_state_with()`. The function
+/// then reads and writes them through `Context::get_state()` and `Effects::update_state()` like
+/// any other state: before the function is invoked, all of its backed states are read
+/// concurrently using `get()`, and once the function returns, the state updates and deletions of
+/// the invocation are applied using `set()` and `delete()` instead of being sent to Flink. All
+/// other states stay in Flink.
+///
+/// The methods return futures that are awaited on the runtime that serves the request, like the
+/// functions registered with `FunctionRegistry::register_async_fn()`. A backend should therefore
+/// use asynchronous IO instead of blocking the thread.
+///
+/// Note that Flink doesn't know about these states, so they are not part of its checkpoints and
+/// the backend is responsible for durability and consistency.
+pub trait StateBackend: Send + Sync {
+    /// Returns the serialized value of the state with the given name of the function at the given
+    /// address, or `None` if there is no value.
+    fn get<'a>(
+        &'a self,
+        address: &'a Address,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Vec<u8>>, String>>;
+
+    /// Stores the serialized value of the state with the given name of the function at the given
+    /// address.
+    fn set<'a>(
+        &'a self,
+        address: &'a Address,
+        name: &'a str,
+        value: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), String>>;
+
+    /// Deletes the state with the given name of the function at the given address.
+    fn delete<'a>(
+        &'a self,
+        address: &'a Address,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<(), String>>;
+}
+
+/// The states of a function that are kept in a `StateBackend`, by name.
+#[derive(Default)]
+pub(crate) struct BackedStates {
+    pub(crate) backends: Vec<(String, Arc<dyn StateBackend>)>,
+}
+
+impl BackedStates {
+    /// Returns the backend of the state with the given name, if it is kept outside of Flink.
+    pub(crate) fn backend(&self, name: &str) -> Option<&dyn StateBackend> {
+        self.backends
+            .iter()
+            .find(|(backed_name, _backend)| backed_name == name)
+            .map(|(_name, backend)| backend.as_ref())
+    }
+
+    /// Reads the values of all states concurrently, so that the function can access them without
+    /// waiting for the backend.
+    pub(crate) async fn read(&self, address: &Address) -> BackedValues {
+        let reads = self
+            .backends
+            .iter()
+            .map(|(name, backend)| backend.get(address, name));
+        let values = future::join_all(reads).await;
+        BackedValues {
+            values: self
+                .backends
+                .iter()
+                .map(|(name, _backend)| name.clone())
+                .zip(values)
+                .collect(),
+        }
+    }
+}
+
+/// The values of the states of a function that are kept in a `StateBackend`, as they were read
+/// before the function was invoked. A failed read is kept, so that only reading that state fails.
+#[derive(Debug, Default)]
+pub(crate) struct BackedValues {
+    values: HashMap<String, Result<Option<Vec<u8>>, String>>,
+}
+
+impl BackedValues {
+    /// Returns the serialized value of the state with the given name, or `None` if the state is
+    /// not kept in a `StateBackend`.
+    pub(crate) fn get(&self, name: &str) -> Option<&Result<Option<Vec<u8>>, String>> {
+        self.values.get(name)
+    }
+}
+
+impl Debug for BackedStates {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.backends.iter().map(|(name, _backend)| name))
+            .finish()
+    }
+}
@@ -1,7 +1,11 @@
 use crate::ValueSpecBase;
 
-#[derive(Debug)]
-pub(crate) enum StateUpdate {
+/// A change of the state of a stateful function, as added by `Effects::update_state()` and
+/// `Effects::delete_state()`. See `Effects::state_updates()`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum StateUpdate {
+    /// The state of the spec is set to the serialized value.
     Update(ValueSpecBase, Vec<u8>),
+    /// The state of the spec is deleted.
     Delete(ValueSpecBase),
 }
@@ -0,0 +1,48 @@
+//! A logger that captures log messages, so that tests can assert on them.
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::sync::Mutex;
+
+static LOGGER: CapturingLogger = CapturingLogger {
+    messages: Mutex::new(Vec::new()),
+};
+
+struct CapturingLogger {
+    messages: Mutex<Vec<(Level, String)>>,
+}
+
+impl Log for CapturingLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        self.messages
+            .lock()
+            .unwrap()
+            .push((record.level(), record.args().to_string()));
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the capturing logger, if it is not installed yet. Messages below `Info` are not
+/// captured to keep the verbose request/response logging out.
+pub(crate) fn init() {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(LevelFilter::Info);
+    }
+}
+
+/// Returns all messages that were captured at the given level so far. Note that tests run
+/// concurrently, so this may contain messages from other tests.
+pub(crate) fn captured(level: Level) -> Vec<String> {
+    LOGGER
+        .messages
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(message_level, _)| *message_level == level)
+        .map(|(_, message)| message.clone())
+        .collect()
+}
@@ -0,0 +1,427 @@
+//! Helpers for unit testing stateful functions without a transport. `ContextBuilder` is for
+//! calling a function directly, `BatchBuilder` runs a batch of invocations through a
+//! `FunctionRegistry` the way a transport would.
+//!
+//! ```
+//! use statefun::testing::ContextBuilder;
+//! use statefun::{Address, Context, Effects, Expiration, FunctionType, Message, ValueSpec};
+//!
+//! fn count_spec() -> ValueSpec<i32> {
+//!     ValueSpec::new("count", Expiration::never())
+//! }
+//!
+//! fn counter(context: Context, _message: Message) -> Effects {
+//!     let count = context.get_state_or_default(count_spec()).unwrap() + 1;
+//!     let mut effects = Effects::new();
+//!     effects.update_state(count_spec(), &count).unwrap();
+//!     effects
+//! }
+//!
+//! let self_address = Address::new(FunctionType::new("example", "counter"), "flink");
+//! let builder = ContextBuilder::new(self_address).with_state(count_spec(), &41)?;
+//!
+//! let effects = counter(builder.build(), Message::from_value(&"hello".to_string())?);
+//! assert_eq!(effects.pending_state_updates(), 1);
+//! # Ok::<(), String>(())
+//! ```
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use protobuf::RepeatedField;
+
+use statefun_proto::request_reply::FromFunction;
+use statefun_proto::request_reply::FromFunction_ExpirationSpec_ExpireMode;
+use statefun_proto::request_reply::FromFunction_PersistedValueMutation_MutationType;
+use statefun_proto::request_reply::ToFunction;
+use statefun_proto::request_reply::ToFunction_Invocation;
+use statefun_proto::request_reply::ToFunction_InvocationBatchRequest;
+use statefun_proto::request_reply::ToFunction_PersistedValue;
+use statefun_proto::request_reply::TypedValue;
+
+use crate::invocation_bridge::InvocationBridge;
+use crate::{
+    Address, Context, Expiration, ExpirationType, FunctionRegistry, InvocationError, Serializable,
+    TypeName, ValueSpec, ValueSpecBase,
+};
+
+/// Builds a `Context` for calling a function directly in a unit test. The builder owns the state
+/// and addresses that the `Context` refers to, so it has to outlive the `Context`.
+#[derive(Debug)]
+pub struct ContextBuilder {
+    self_address: Address,
+    caller_address: Option<Address>,
+    state: HashMap<ValueSpecBase, Vec<u8>>,
+}
+
+impl ContextBuilder {
+    /// Creates a builder for a `Context` of an invocation of the function at the given address,
+    /// without a caller and without state.
+    pub fn new(self_address: Address) -> ContextBuilder {
+        ContextBuilder {
+            self_address,
+            caller_address: None,
+            state: HashMap::new(),
+        }
+    }
+
+    /// Sets the address of the function that sent the message. Without a caller, the message
+    /// originates from an ingress.
+    pub fn with_caller(mut self, caller_address: Address) -> ContextBuilder {
+        self.caller_address = Some(caller_address);
+        self
+    }
+
+    /// Adds the given value of the state of the given spec, as if a previous invocation had
+    /// written it. This fails if the value cannot be serialized.
+    pub fn with_state<T: Serializable<T>>(
+        mut self,
+        value_spec: ValueSpec<T>,
+        value: &T,
+    ) -> Result<ContextBuilder, String> {
+        let serialized = value.serialize(value_spec.spec.typename.clone())?;
+        // Flink doesn't send the expiration of states, so neither do we
+        let key = ValueSpecBase::new(
+            &value_spec.spec.name,
+            &value_spec.spec.typename,
+            Expiration::never(),
+        );
+        self.state.insert(key, serialized);
+        Ok(self)
+    }
+
+    /// Returns a `Context` with the configured addresses and state.
+    pub fn build(&self) -> Context<'_> {
+        Context::new(&self.state, &self.self_address, self.caller_address.clone())
+    }
+}
+
+/// Builds a batch of invocations of one function, as Flink sends it to a transport, and runs it
+/// through a `FunctionRegistry`. Unlike calling a function directly, this coalesces the state
+/// updates of the batch and asks for missing states like a real deployment.
+///
+/// ```
+/// use statefun::testing::BatchBuilder;
+/// use statefun::{
+///     Address, Effects, Expiration, FunctionRegistry, FunctionType, Serializable, ValueSpec,
+/// };
+///
+/// let count_spec = || ValueSpec::<i32>::new("count", Expiration::never());
+/// let function_type = FunctionType::new("example", "counter");
+///
+/// let mut registry = FunctionRegistry::new();
+/// let specs = vec![count_spec().into()];
+/// registry.register_fn(function_type.clone(), specs, move |context, _message| {
+///     let count = context.get_state_or_default(count_spec()).unwrap() + 1;
+///     let mut effects = Effects::new();
+///     effects.update_state(count_spec(), &count).unwrap();
+///     effects
+/// })?;
+///
+/// let response = BatchBuilder::new(Address::new(function_type, "flink"))
+///     .with_state(count_spec(), &40)?
+///     .with_message(&"hello".to_string())?
+///     .with_message(&"world".to_string())?
+///     .invoke(&registry)?;
+///
+/// let count = response.coalesced_state()["count"].clone().unwrap();
+/// assert_eq!(i32::deserialize(String::new(), &count)?, 42);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct BatchBuilder {
+    self_address: Address,
+    state: Vec<ToFunction_PersistedValue>,
+    invocations: Vec<ToFunction_Invocation>,
+}
+
+impl BatchBuilder {
+    /// Creates a builder for an empty batch of invocations of the function at the given address.
+    pub fn new(self_address: Address) -> BatchBuilder {
+        BatchBuilder {
+            self_address,
+            state: Vec::new(),
+            invocations: Vec::new(),
+        }
+    }
+
+    /// Adds the given value of the state of the given spec, as if a previous batch had written
+    /// it. States that are not added are missing from the batch. This fails if the value cannot be
+    /// serialized.
+    pub fn with_state<T: Serializable<T>>(
+        mut self,
+        value_spec: ValueSpec<T>,
+        value: &T,
+    ) -> Result<BatchBuilder, String> {
+        let serialized = value.serialize(value_spec.spec.typename.clone())?;
+        let mut state = ToFunction_PersistedValue::new();
+        state.set_state_name(value_spec.spec.name.clone());
+        state.set_state_value(typed_value(value_spec.spec.typename, serialized));
+        self.state.push(state);
+        Ok(self)
+    }
+
+    /// Adds an invocation with the given message from an ingress.
+    pub fn with_message<T: Serializable<T> + TypeName>(
+        self,
+        message: &T,
+    ) -> Result<BatchBuilder, String> {
+        self.push_invocation(None, message)
+    }
+
+    /// Adds an invocation with the given message from the function at the given address.
+    pub fn with_message_from<T: Serializable<T> + TypeName>(
+        self,
+        caller_address: Address,
+        message: &T,
+    ) -> Result<BatchBuilder, String> {
+        self.push_invocation(Some(caller_address), message)
+    }
+
+    fn push_invocation<T: Serializable<T> + TypeName>(
+        mut self,
+        caller_address: Option<Address>,
+        message: &T,
+    ) -> Result<BatchBuilder, String> {
+        let typename = T::get_typename().to_string();
+        let serialized = message.serialize(typename.clone())?;
+        let mut invocation = ToFunction_Invocation::new();
+        if let Some(caller_address) = caller_address {
+            invocation.set_caller(caller_address.into_proto());
+        }
+        invocation.set_argument(typed_value(typename, serialized));
+        self.invocations.push(invocation);
+        Ok(self)
+    }
+
+    /// Returns the batch as the Protobuf `ToFunction` that a transport receives from Flink.
+    pub fn to_proto(&self) -> ToFunction {
+        let mut batch = ToFunction_InvocationBatchRequest::new();
+        batch.set_target(self.self_address.clone().into_proto());
+        batch.set_state(RepeatedField::from_vec(self.state.clone()));
+        batch.set_invocations(RepeatedField::from_vec(self.invocations.clone()));
+
+        let mut to_function = ToFunction::new();
+        to_function.set_invocation(batch);
+        to_function
+    }
+
+    /// Invokes the function of the batch in the given registry and returns the response that a
+    /// transport would send back to Flink. Asynchronous functions are run to completion on the
+    /// current thread.
+    pub fn invoke(&self, registry: &FunctionRegistry) -> Result<BatchResponse, InvocationError> {
+        let from_function = registry.invoke_from_proto(self.to_proto(), None)?;
+        Ok(BatchResponse::from(from_function))
+    }
+}
+
+fn typed_value(typename: String, value: Vec<u8>) -> TypedValue {
+    let mut typed_value = TypedValue::new();
+    typed_value.set_typename(typename);
+    typed_value.set_has_value(true);
+    typed_value.set_value(value);
+    typed_value
+}
+
+/// The response to a batch of invocations, see `BatchBuilder::invoke()`.
+#[derive(Debug, Clone)]
+pub struct BatchResponse {
+    from_function: FromFunction,
+}
+
+impl BatchResponse {
+    /// Returns the final value of each state that was mutated by the batch, as the runtime will
+    /// see it after applying the coalesced state mutations. Deleted states are mapped to `None`.
+    pub fn coalesced_state(&self) -> HashMap<String, Option<Vec<u8>>> {
+        self.from_function
+            .get_invocation_result()
+            .get_state_mutations()
+            .iter()
+            .map(|mutation| {
+                let value = match mutation.get_mutation_type() {
+                    FromFunction_PersistedValueMutation_MutationType::DELETE => None,
+                    FromFunction_PersistedValueMutation_MutationType::MODIFY => {
+                        Some(mutation.get_state_value().get_value().to_vec())
+                    }
+                };
+                (mutation.get_state_name().to_string(), value)
+            })
+            .collect()
+    }
+
+    /// Returns the states that the response asks Flink to allocate, as
+    /// `(name, typename, expiration)`. This is empty unless the function declared states that
+    /// were missing from the batch, in which case Flink retries the batch with the states.
+    pub fn requested_states(&self) -> Vec<(String, String, Expiration)> {
+        self.from_function
+            .get_incomplete_invocation_context()
+            .get_missing_values()
+            .iter()
+            .map(|value_spec| {
+                let expiration_spec = value_spec.get_expiration_spec();
+                let time_to_live =
+                    Duration::from_millis(expiration_spec.get_expire_after_millis() as u64);
+                let expiration = match expiration_spec.get_mode() {
+                    FromFunction_ExpirationSpec_ExpireMode::NONE => Expiration::never(),
+                    FromFunction_ExpirationSpec_ExpireMode::AFTER_WRITE => {
+                        Expiration::new(ExpirationType::AfterWrite, time_to_live)
+                    }
+                    FromFunction_ExpirationSpec_ExpireMode::AFTER_INVOKE => {
+                        Expiration::new(ExpirationType::AfterInvoke, time_to_live)
+                    }
+                };
+                (
+                    value_spec.get_state_name().to_string(),
+                    value_spec.get_type_typename().to_string(),
+                    expiration,
+                )
+            })
+            .collect()
+    }
+
+    /// Returns the Protobuf `FromFunction` that a transport sends back to Flink.
+    pub fn into_proto(self) -> FromFunction {
+        self.from_function
+    }
+}
+
+impl From<FromFunction> for BatchResponse {
+    fn from(from_function: FromFunction) -> BatchResponse {
+        BatchResponse { from_function }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::testing::{BatchBuilder, ContextBuilder};
+    use crate::*;
+
+    fn address(id: &str) -> Address {
+        Address::new(FunctionType::new("namespace", "foo"), id)
+    }
+
+    #[test]
+    fn build() {
+        let expiring = || {
+            ValueSpec::<i32>::new(
+                "count",
+                Expiration::new(ExpirationType::AfterWrite, Duration::from_secs(60)),
+            )
+        };
+        let builder = ContextBuilder::new(address("self"))
+            .with_caller(address("caller"))
+            .with_state(expiring(), &42)
+            .unwrap()
+            .with_state(
+                ValueSpec::new("name", Expiration::never()),
+                &"flink".to_string(),
+            )
+            .unwrap();
+
+        let context = builder.build();
+        assert_eq!(context.self_address(), address("self"));
+        assert_eq!(context.caller_address(), Some(address("caller")));
+        assert_eq!(context.get_state(expiring()), Some(Ok(42)));
+        assert_eq!(
+            context.get_state(ValueSpec::<String>::new("name", Expiration::never())),
+            Some(Ok("flink".to_string()))
+        );
+        assert_eq!(
+            context.get_state(ValueSpec::<i32>::new("missing", Expiration::never())),
+            None
+        );
+
+        let builder = ContextBuilder::new(address("self"));
+        assert!(builder.build().is_ingress());
+    }
+
+    #[test]
+    fn coalesced_state() -> anyhow::Result<()> {
+        let count = || ValueSpec::<i32>::new("count", Expiration::never());
+        let flag = || ValueSpec::<bool>::new("flag", Expiration::never());
+
+        let mut registry = FunctionRegistry::new();
+        registry.register_fn(
+            address("self").function_type,
+            vec![count().into(), flag().into()],
+            move |context, message: Message| {
+                let mut effects = Effects::new();
+                let value = context.get_state_or_default(count()).unwrap() + 1;
+                effects.update_state(count(), &value).unwrap();
+                if message.get::<String>().unwrap() == "delete" {
+                    effects.delete_state(flag());
+                } else {
+                    effects.update_state(flag(), &true).unwrap();
+                }
+                effects
+            },
+        )?;
+
+        let response = BatchBuilder::new(address("self"))
+            .with_state(count(), &1)
+            .unwrap()
+            .with_state(flag(), &false)
+            .unwrap()
+            .with_message(&"update".to_string())
+            .unwrap()
+            .with_message_from(address("caller"), &"delete".to_string())
+            .unwrap()
+            .invoke(&registry)?;
+
+        let state = response.coalesced_state();
+        assert_eq!(state.len(), 2);
+        assert_eq!(state["count"], Some(3.serialize(String::new()).unwrap()));
+        assert_eq!(state["flag"], None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn requested_states() -> anyhow::Result<()> {
+        let count = || ValueSpec::<i32>::new("count", Expiration::never());
+        let session = || {
+            ValueSpec::<String>::new(
+                "session",
+                Expiration::new(ExpirationType::AfterInvoke, Duration::from_secs(30)),
+            )
+        };
+
+        let mut registry = FunctionRegistry::new();
+        registry.register_fn(
+            address("self").function_type,
+            vec![count().into(), session().into()],
+            |_context, _message: Message| Effects::new(),
+        )?;
+
+        let batch = BatchBuilder::new(address("self"))
+            .with_message(&"hello".to_string())
+            .unwrap();
+
+        let response = batch
+            .clone()
+            .with_state(count(), &1)
+            .unwrap()
+            .invoke(&registry)?;
+        assert_eq!(
+            response.requested_states(),
+            vec![(
+                "session".to_string(),
+                String::get_typename().to_string(),
+                Expiration::new(ExpirationType::AfterInvoke, Duration::from_secs(30))
+            )]
+        );
+        assert!(response.coalesced_state().is_empty());
+
+        let response = batch
+            .with_state(count(), &1)
+            .unwrap()
+            .with_state(session(), &"flink".to_string())
+            .unwrap()
+            .invoke(&registry)?;
+        assert!(response.requested_states().is_empty());
+
+        Ok(())
+    }
+}
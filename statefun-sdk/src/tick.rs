@@ -0,0 +1,24 @@
+use crate::{Serializable, TypeName};
+
+/// The payload of the delayed self-invocations that are scheduled by `Effects::schedule_tick()`.
+///
+/// A tick carries no data, use `Message::is_tick()` to check whether an invocation was caused by
+/// a tick.
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct Tick;
+
+impl TypeName for Tick {
+    fn get_typename() -> &'static str {
+        "io.statefun.rust.types/tick"
+    }
+}
+
+impl Serializable<Tick> for Tick {
+    fn serialize(&self, _typename: String) -> Result<Vec<u8>, String> {
+        Ok(Vec::new())
+    }
+
+    fn deserialize(_typename: String, _buffer: &[u8]) -> Result<Tick, String> {
+        Ok(Tick)
+    }
+}
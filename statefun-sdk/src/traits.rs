@@ -1,9 +1,19 @@
+use crate::NonFinitePolicy;
+
 /// Each message type must implement this trait, which returns the fully qualified type name of
 /// this type. For example, for native integers the SDK provides an implementation of this trait
 /// which returns "io.statefun.types/bool".
 pub trait TypeName {
     /// Returns the fully qualified name of this type, for example "com.my.company/user-type"
     fn get_typename() -> &'static str;
+
+    /// Returns other typenames that messages of this type may arrive with, for example the
+    /// typename of a previous encoding while senders are being migrated to a new one.
+    /// `Message::get()` accepts these as well and passes the actual typename to
+    /// `Serializable::deserialize()`, which has to decode each of the encodings.
+    fn get_alternative_typenames() -> &'static [&'static str] {
+        &[]
+    }
 }
 
 /// Each message type must implement this trait, which allows the client code to define how the
@@ -17,4 +27,15 @@ pub trait Serializable<T> {
 
     /// Implements deserialization
     fn deserialize(typename: String, buffer: &[u8]) -> Result<T, String>;
+
+    /// Implements serialization of state values under the `NonFinitePolicy` of their `ValueSpec`.
+    /// Only the built-in floating point types apply the policy, all other types simply forward
+    /// to `serialize()`.
+    fn serialize_with_policy(
+        &self,
+        typename: String,
+        _policy: NonFinitePolicy,
+    ) -> Result<Vec<u8>, String> {
+        self.serialize(typename)
+    }
 }
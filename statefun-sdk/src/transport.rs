@@ -3,6 +3,10 @@
 use crate::function_registry::FunctionRegistry;
 
 pub mod hyper;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "tonic")]
+pub mod tonic;
 
 /// Serves up stateful functions in a [FunctionRegistry](crate::FunctionRegistry) to make them
 /// invokable in a Statefun deployment.
@@ -1,81 +1,550 @@
 //! `Transport` that uses [Hyper](http://docs.rs/hyper) to serve stateful functions.
+use std::any::Any;
 use std::convert::Infallible;
+use std::future::Future;
 use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
+use std::panic::AssertUnwindSafe;
+#[cfg(feature = "tls")]
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 use bytes::buf::BufExt;
-use hyper::service::{make_service_fn, service_fn};
-use hyper::{http, Body, Request, Response, Server};
+use bytes::{Buf, Bytes, BytesMut};
+use futures::FutureExt;
+use hyper::body::{self, HttpBody};
+use hyper::server::conn::AddrIncoming;
+use hyper::service::{make_service_fn, service_fn, Service};
+use hyper::{header, http, Body, Method, Request, Response, Server, StatusCode};
+use protobuf::error::WireError;
 use protobuf::{Message, ProtobufError};
 use thiserror::Error;
-use tokio::runtime;
+use tokio::runtime::{self, Runtime};
+use tokio::sync::oneshot;
+#[cfg(feature = "tls")]
+use tokio_rustls::rustls::ServerConfig;
 
-use statefun_proto::request_reply::ToFunction;
+use statefun_proto::request_reply::{FromFunction, ToFunction};
 
 use crate::function_registry::FunctionRegistry;
 use crate::invocation_bridge::InvocationBridge;
-use crate::transport::hyper::HyperTransportError::TokioInitializationFailure;
+use crate::serialization_errors::{self, SerializationErrorHook};
+use crate::transport::hyper::HyperTransportError::{
+    Draining, FunctionPanic, MalformedRequest, TokioInitializationFailure,
+    UnsupportedProtocolVersion,
+};
+#[cfg(feature = "tls")]
+use crate::transport::tls;
 use crate::transport::Transport;
-use crate::InvocationError;
+use crate::SUPPORTED_STATEFUN_VERSION;
+use crate::{ExpirationType, InvocationError};
 
 /// A [Transport](crate::transport::Transport) that serves stateful functions on a http endpoint at
 /// the given `bind_address`.
 pub struct HyperHttpTransport {
     bind_address: SocketAddr,
+    invocation_timeout: Option<Duration>,
+    protocol_version: Option<String>,
+    framed_streaming: bool,
+    log_topology: bool,
+    worker_threads: Option<usize>,
+    serialization_error_hook: Option<SerializationErrorHook>,
+    probe_paths: Arc<ProbePaths>,
+    draining: Arc<AtomicBool>,
+    shutdown_callbacks: Vec<Box<dyn FnOnce() + Send>>,
+    #[cfg(feature = "tls")]
+    tls_config: Option<Arc<ServerConfig>>,
 }
 
 impl HyperHttpTransport {
     /// Creates a new `HyperHttpTransport` that can serve stateful functions at the given
     /// `bind_address`.
     pub fn new(bind_address: SocketAddr) -> HyperHttpTransport {
-        HyperHttpTransport { bind_address }
+        HyperHttpTransport {
+            bind_address,
+            invocation_timeout: None,
+            protocol_version: None,
+            framed_streaming: false,
+            log_topology: false,
+            worker_threads: None,
+            serialization_error_hook: None,
+            probe_paths: Arc::new(ProbePaths {
+                health: "/healthz".to_string(),
+                readiness: "/ready".to_string(),
+            }),
+            draining: Arc::new(AtomicBool::new(false)),
+            shutdown_callbacks: Vec::new(),
+            #[cfg(feature = "tls")]
+            tls_config: None,
+        }
     }
-}
 
-impl Transport for HyperHttpTransport {
-    type Error = HyperTransportError;
+    /// Creates a new `HyperHttpTransport` that serves stateful functions over HTTPS at the given
+    /// `bind_address`, so that no sidecar is needed to terminate TLS in front of the functions.
+    /// The certificate chain and the PKCS#8 or RSA private key are read from the given PEM files.
+    ///
+    /// The files are loaded right away, so a missing or invalid certificate or key fails here
+    /// with an `HyperTransportError::InvalidTlsConfiguration`, and not once Flink connects.
+    #[cfg(feature = "tls")]
+    pub fn with_tls<C: AsRef<Path>, K: AsRef<Path>>(
+        bind_address: SocketAddr,
+        cert_path: C,
+        key_path: K,
+    ) -> Result<HyperHttpTransport, HyperTransportError> {
+        let tls_config = tls::load_config(cert_path.as_ref(), key_path.as_ref())?;
+        let mut transport = HyperHttpTransport::new(bind_address);
+        transport.tls_config = Some(Arc::new(tls_config));
+        Ok(transport)
+    }
+
+    /// Sets the time budget of a request. Functions can retrieve the resulting deadline using
+    /// `Context::deadline()` to abort work that won't finish in time.
+    ///
+    /// Flink does not send its timeout with the request, so this should be set to (at most) the
+    /// timeout that is configured for the functions in the remote module.
+    pub fn with_invocation_timeout(mut self, timeout: Duration) -> HyperHttpTransport {
+        self.invocation_timeout = Some(timeout);
+        self
+    }
+
+    /// Pins the StateFun version that the deployment runs, for example `"3.2"`. The transport
+    /// refuses to start if that version doesn't share the major version of
+    /// `SUPPORTED_STATEFUN_VERSION`, instead of failing on the first request that uses an
+    /// incompatible protocol.
+    pub fn with_protocol_version(mut self, version: &str) -> HyperHttpTransport {
+        self.protocol_version = Some(version.to_string());
+        self
+    }
+
+    /// Enables the experimental framed streaming mode, in which a single request carries any
+    /// number of `ToFunction` messages, see `FRAMED_CONTENT_TYPE`. This saves a request per batch
+    /// for clients that support it.
+    ///
+    /// Flink itself always sends one `ToFunction` per request, so this is only useful for custom
+    /// clients, for example a proxy that runs next to the functions.
+    pub fn with_framed_streaming(mut self) -> HyperHttpTransport {
+        self.framed_streaming = true;
+        self
+    }
+
+    /// Logs the served functions at startup, together with the name, typename, and expiration of
+    /// the states that they declare. This helps to check what a deployment actually serves.
+    pub fn with_topology_logging(mut self) -> HyperHttpTransport {
+        self.log_topology = true;
+        self
+    }
+
+    /// Sets the number of worker threads of the tokio runtime that serves requests. By default,
+    /// tokio starts one worker thread per CPU core, which might be too many when running in a
+    /// container with a CPU limit.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `threads` is zero.
+    pub fn with_worker_threads(mut self, threads: usize) -> HyperHttpTransport {
+        assert!(threads > 0, "The number of worker threads must be positive");
+        self.worker_threads = Some(threads);
+        self
+    }
+
+    /// Sets the path of the health endpoint, `/healthz` by default. A `GET` request to this path
+    /// is answered with `200 OK` and a small JSON body, without invoking any function, for
+    /// example for a Kubernetes liveness probe.
+    pub fn with_health_path(mut self, path: &str) -> HyperHttpTransport {
+        Arc::make_mut(&mut self.probe_paths).health = path.to_string();
+        self
+    }
+
+    /// Sets the path of the readiness endpoint, `/ready` by default. A `GET` request to this path
+    /// is answered like one to the health endpoint, except that it is answered with
+    /// `503 Service Unavailable` while the server is draining, see `ShutdownTrigger::drain()`.
+    pub fn with_readiness_path(mut self, path: &str) -> HyperHttpTransport {
+        Arc::make_mut(&mut self.probe_paths).readiness = path.to_string();
+        self
+    }
+
+    /// Registers a hook that is called with the typename and the error whenever a function fails
+    /// to serialize a message or state using the methods of `Effects`, such as `send()`,
+    /// `egress()` or `update_state()`. The function still gets the error, this allows alerting
+    /// on serialization failures in one place, no matter how the function handles them.
+    ///
+    /// The hook is called on the thread that invokes the function, while the function runs.
+    pub fn on_serialization_error<F: Fn(&str, &str) + Send + Sync + 'static>(
+        mut self,
+        hook: F,
+    ) -> HyperHttpTransport {
+        self.serialization_error_hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Registers a callback that is invoked after the server has shut down and all in-flight
+    /// requests were answered. This can be used to flush and close external resources, such as
+    /// clients that buffer egress messages. Callbacks are invoked in the order of registration.
+    pub fn on_shutdown<F: FnOnce() + Send + 'static>(mut self, callback: F) -> HyperHttpTransport {
+        self.shutdown_callbacks.push(Box::new(callback));
+        self
+    }
+
+    /// Serves the stateful functions in the given `FunctionRegistry` on a separate thread.
+    ///
+    /// Returns a handle of the serving thread and a `ShutdownTrigger` that drains the server or
+    /// initiates a graceful shutdown. The thread finishes once the shutdown is complete and all
+    /// shutdown callbacks were invoked.
+    pub fn spawn(
+        self,
+        function_registry: FunctionRegistry,
+    ) -> (JoinHandle<Result<(), HyperTransportError>>, ShutdownTrigger) {
+        let (sender, receiver) = oneshot::channel();
+        let draining = Arc::clone(&self.draining);
+        let handle = thread::spawn(move || {
+            self.serve(function_registry, async move {
+                // a dropped trigger also shuts down the server, it could never be triggered
+                // otherwise
+                let _ = receiver.await;
+            })
+        });
+        (handle, ShutdownTrigger { sender, draining })
+    }
+
+    /// Serves the stateful functions in the given `FunctionRegistry` in the background, so that
+    /// they can be served alongside other services in the same process.
+    ///
+    /// Unlike `spawn()`, this returns once the server listens, or fails if it can't be started,
+    /// for example because the bind address is already in use. The server runs on its own
+    /// thread and tokio runtime until `ServerHandle::shutdown()` is called or the handle is
+    /// dropped.
+    pub fn run_background(
+        self,
+        function_registry: FunctionRegistry,
+    ) -> Result<ServerHandle, HyperTransportError> {
+        let (sender, receiver) = oneshot::channel();
+        let draining = Arc::clone(&self.draining);
+        let (local_address, thread) = self.start(function_registry, async move {
+            // a dropped handle also shuts down the server, it could never be shut down otherwise
+            let _ = receiver.await;
+        })?;
+        Ok(ServerHandle {
+            local_address,
+            trigger: ShutdownTrigger { sender, draining },
+            thread,
+        })
+    }
+
+    fn serve<S: Future<Output = ()> + Send + 'static>(
+        self,
+        function_registry: FunctionRegistry,
+        shutdown: S,
+    ) -> Result<(), HyperTransportError> {
+        let (_local_address, thread) = self.start(function_registry, shutdown)?;
+        join(thread);
+        Ok(())
+    }
+
+    /// Binds the server and serves requests on a new thread until `shutdown` completes. The
+    /// thread invokes the shutdown callbacks before it finishes.
+    fn start<S: Future<Output = ()> + Send + 'static>(
+        self,
+        function_registry: FunctionRegistry,
+        shutdown: S,
+    ) -> Result<(SocketAddr, JoinHandle<()>), HyperTransportError> {
+        if let Some(protocol_version) = &self.protocol_version {
+            if major_version(protocol_version) != major_version(SUPPORTED_STATEFUN_VERSION) {
+                return Err(UnsupportedProtocolVersion(protocol_version.clone()));
+            }
+        }
 
-    fn run(self, function_registry: FunctionRegistry) -> Result<(), Self::Error> {
         log::info!(
             "Hyper transport will start listening on {}",
             self.bind_address
         );
+        log::info!(
+            "Serving the remote function protocol of StateFun {}{}",
+            SUPPORTED_STATEFUN_VERSION,
+            match &self.protocol_version {
+                Some(protocol_version) => format!(" (pinned to {})", protocol_version),
+                None => String::new(),
+            }
+        );
+
+        if self.log_topology {
+            log_topology(&function_registry, self.bind_address);
+        }
 
-        let runtime = runtime::Builder::new()
-            .threaded_scheduler()
-            .enable_all()
-            .build();
-        let mut runtime = match runtime {
+        let mut runtime_builder = runtime::Builder::new();
+        runtime_builder.threaded_scheduler().enable_all();
+        if let Some(worker_threads) = self.worker_threads {
+            runtime_builder.core_threads(worker_threads);
+        }
+        let runtime = match runtime_builder.build() {
             Ok(rt) => rt,
             Err(error) => return Err(TokioInitializationFailure(error)),
         };
 
-        let function_registry = Arc::new(Mutex::new(function_registry));
+        let state = ServerState {
+            function_registry: Arc::new(function_registry),
+            invocation_timeout: self.invocation_timeout,
+            framed_streaming: self.framed_streaming,
+            serialization_error_hook: self.serialization_error_hook.clone(),
+            probe_paths: Arc::clone(&self.probe_paths),
+            draining: Arc::clone(&self.draining),
+        };
+        let bind_address = self.bind_address;
+        let incoming = runtime.enter(|| AddrIncoming::bind(&bind_address))?;
+        let local_address = incoming.local_addr();
+        let shutdown_callbacks = self.shutdown_callbacks;
 
-        runtime.block_on(async {
-            let make_svc = make_service_fn(|_conn| {
-                let function_registry = Arc::clone(&function_registry);
-                async move {
-                    Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
-                        let function_registry = Arc::clone(&function_registry);
-                        async move { handle_request(function_registry, req).await }
-                    }))
-                }
-            });
-            let server = Server::bind(&self.bind_address).serve(make_svc);
-            let graceful = server.with_graceful_shutdown(shutdown_signal());
+        #[cfg(feature = "tls")]
+        {
+            if let Some(tls_config) = self.tls_config {
+                let make_svc = make_service_fn(move |_conn| {
+                    let service = state.service();
+                    async move { Ok::<_, Infallible>(service) }
+                });
+                let connections = tls::connections(incoming, tls_config);
+                let server = Server::builder(hyper::server::accept::from_stream(connections))
+                    .serve(make_svc)
+                    .with_graceful_shutdown(shutdown);
+                let thread = spawn_server(runtime, server, shutdown_callbacks);
+                return Ok((local_address, thread));
+            }
+        }
+
+        let make_svc = make_service_fn(move |_conn| {
+            let service = state.service();
+            async move { Ok::<_, Infallible>(service) }
+        });
+        let server = Server::builder(incoming)
+            .serve(make_svc)
+            .with_graceful_shutdown(shutdown);
+        let thread = spawn_server(runtime, server, shutdown_callbacks);
+        Ok((local_address, thread))
+    }
+}
+
+/// What the requests of a server share.
+#[derive(Clone)]
+struct ServerState {
+    function_registry: Arc<FunctionRegistry>,
+    invocation_timeout: Option<Duration>,
+    framed_streaming: bool,
+    serialization_error_hook: Option<SerializationErrorHook>,
+    probe_paths: Arc<ProbePaths>,
+    draining: Arc<AtomicBool>,
+}
 
-            if let Err(e) = graceful.await {
+impl ServerState {
+    /// Returns the service that answers the requests of a connection.
+    fn service(
+        &self,
+    ) -> impl Service<
+        Request<Body>,
+        Response = Response<Body>,
+        Error = Infallible,
+        Future = impl Future<Output = Result<Response<Body>, Infallible>> + Send,
+    > + Send {
+        let state = self.clone();
+        service_fn(move |req: Request<Body>| {
+            let state = state.clone();
+            async move {
+                serve_request(
+                    state.function_registry,
+                    state.invocation_timeout,
+                    state.framed_streaming,
+                    state.serialization_error_hook,
+                    &state.probe_paths,
+                    &state.draining,
+                    req,
+                )
+                .await
+            }
+        })
+    }
+}
+
+/// Runs the given server on a new thread until it shuts down, and then invokes the shutdown
+/// callbacks.
+fn spawn_server<F: Future<Output = Result<(), hyper::Error>> + Send + 'static>(
+    mut runtime: Runtime,
+    server: F,
+    shutdown_callbacks: Vec<Box<dyn FnOnce() + Send>>,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        runtime.block_on(async {
+            if let Err(e) = server.await {
                 eprintln!("server error: {}", e);
             }
         });
 
-        Ok(())
+        for callback in shutdown_callbacks {
+            callback();
+        }
+    })
+}
+
+/// Waits for the serving thread to finish, passing on a panic of the thread.
+fn join(thread: JoinHandle<()>) {
+    if let Err(panic) = thread.join() {
+        std::panic::resume_unwind(panic);
+    }
+}
+
+impl Transport for HyperHttpTransport {
+    type Error = HyperTransportError;
+
+    fn run(self, function_registry: FunctionRegistry) -> Result<(), Self::Error> {
+        self.serve(function_registry, shutdown_signal())
+    }
+}
+
+/// Initiates a graceful shutdown of a `HyperHttpTransport` that was started using
+/// `HyperHttpTransport::spawn()`. Dropping the trigger also initiates the shutdown.
+#[derive(Debug)]
+pub struct ShutdownTrigger {
+    sender: oneshot::Sender<()>,
+    draining: Arc<AtomicBool>,
+}
+
+impl ShutdownTrigger {
+    /// Puts the server into drain mode, for example during a deploy: new requests are answered
+    /// with a `503 Service Unavailable`, so that Flink retries them, possibly on another instance,
+    /// while requests that are in flight are still answered. Call `shutdown()` once the in-flight
+    /// requests are done.
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    /// Initiates a graceful shutdown of the server. Requests that are in flight are still
+    /// answered.
+    pub fn shutdown(self) {
+        // the server might already have stopped because of an error
+        let _ = self.sender.send(());
+    }
+}
+
+/// A handle of a `HyperHttpTransport` that was started using
+/// `HyperHttpTransport::run_background()`. Dropping the handle initiates a graceful shutdown,
+/// without waiting for it to complete.
+#[derive(Debug)]
+pub struct ServerHandle {
+    local_address: SocketAddr,
+    trigger: ShutdownTrigger,
+    thread: JoinHandle<()>,
+}
+
+impl ServerHandle {
+    /// Returns the address that the server listens on. This is useful when binding to port `0`,
+    /// which lets the operating system pick a free port.
+    pub fn local_address(&self) -> SocketAddr {
+        self.local_address
+    }
+
+    /// Puts the server into drain mode, see `ShutdownTrigger::drain()`.
+    pub fn drain(&self) {
+        self.trigger.drain();
+    }
+
+    /// Gracefully shuts down the server and waits until the requests that are in flight are
+    /// answered and all shutdown callbacks were invoked.
+    pub fn shutdown(self) {
+        self.trigger.shutdown();
+        join(self.thread);
+    }
+}
+
+async fn serve_request(
+    function_registry: Arc<FunctionRegistry>,
+    invocation_timeout: Option<Duration>,
+    framed_streaming: bool,
+    serialization_error_hook: Option<SerializationErrorHook>,
+    probe_paths: &ProbePaths,
+    draining: &AtomicBool,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    if let Some(response) = probe_response(probe_paths, draining, &req) {
+        Ok(response)
+    } else if draining.load(Ordering::SeqCst) {
+        log::debug!("Rejected request because the server is draining");
+        Ok(error_response(&Draining))
+    } else if framed_streaming && is_framed(&req) {
+        handle_framed_request(
+            function_registry,
+            invocation_timeout,
+            serialization_error_hook,
+            req,
+        )
+        .await
+    } else {
+        handle_request(
+            function_registry,
+            invocation_timeout,
+            serialization_error_hook,
+            req,
+        )
+        .await
     }
 }
 
+/// The paths of the health and readiness endpoints, see `HyperHttpTransport::with_health_path()`.
+#[derive(Debug, Clone)]
+struct ProbePaths {
+    health: String,
+    readiness: String,
+}
+
+/// Answers requests to the health and readiness endpoints, which don't invoke any function.
+fn probe_response(
+    probe_paths: &ProbePaths,
+    draining: &AtomicBool,
+    req: &Request<Body>,
+) -> Option<Response<Body>> {
+    if req.method() != Method::GET {
+        return None;
+    }
+    let path = req.uri().path();
+    let (status, body) = if path == probe_paths.health {
+        (StatusCode::OK, r#"{"status":"ok"}"#)
+    } else if path == probe_paths.readiness && draining.load(Ordering::SeqCst) {
+        (StatusCode::SERVICE_UNAVAILABLE, r#"{"status":"draining"}"#)
+    } else if path == probe_paths.readiness {
+        (StatusCode::OK, r#"{"status":"ok"}"#)
+    } else {
+        return None;
+    };
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("application/json"),
+    );
+    Some(response)
+}
+
 async fn handle_request(
-    function_registry: Arc<Mutex<FunctionRegistry>>,
+    function_registry: Arc<FunctionRegistry>,
+    invocation_timeout: Option<Duration>,
+    serialization_error_hook: Option<SerializationErrorHook>,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    // the time budget starts when we receive the request
+    let deadline = invocation_timeout.map(|timeout| Instant::now() + timeout);
+    match process_request(function_registry, deadline, serialization_error_hook, req).await {
+        Ok(response) => Ok(response),
+        Err(error) => {
+            match error.class() {
+                ErrorClass::Validation => log::warn!("Rejected request: {}", error),
+                ErrorClass::Infrastructure => log::error!("Could not process request: {}", error),
+            }
+            Ok(error_response(&error))
+        }
+    }
+}
+
+async fn process_request(
+    function_registry: Arc<FunctionRegistry>,
+    deadline: Option<Instant>,
+    serialization_error_hook: Option<SerializationErrorHook>,
     req: Request<Body>,
 ) -> Result<Response<Body>, HyperTransportError> {
     let (_parts, body) = req.into_parts();
@@ -83,11 +552,15 @@ async fn handle_request(
 
     let full_body = hyper::body::to_bytes(body).await?;
     let mut reader = full_body.reader();
-    let to_function: ToFunction = ToFunction::parse_from_reader(&mut reader)?;
-    let from_function = {
-        let function_registry = function_registry.lock().unwrap();
-        function_registry.invoke_from_proto(to_function)?
-    };
+    let to_function: ToFunction =
+        ToFunction::parse_from_reader(&mut reader).map_err(MalformedRequest)?;
+    let from_function = invoke(
+        &function_registry,
+        to_function,
+        deadline,
+        serialization_error_hook,
+    )
+    .await?;
 
     log::debug!("Response: {:#?}", from_function);
 
@@ -102,14 +575,162 @@ async fn handle_request(
     Ok(response)
 }
 
+pub(crate) async fn invoke(
+    function_registry: &FunctionRegistry,
+    to_function: ToFunction,
+    deadline: Option<Instant>,
+    serialization_error_hook: Option<SerializationErrorHook>,
+) -> Result<FromFunction, HyperTransportError> {
+    let invocation = function_registry.invoke_from_proto_async(to_function, deadline);
+    // we catch panics of user functions so that we can respond with a proper error instead of
+    // tearing down the connection
+    let result = AssertUnwindSafe(serialization_errors::scope(
+        serialization_error_hook,
+        invocation,
+    ))
+    .catch_unwind()
+    .await;
+    match result {
+        Ok(from_function) => Ok(from_function?),
+        Err(panic) => Err(FunctionPanic(panic_message(panic))),
+    }
+}
+
+/// The content type of requests and responses in the framed streaming mode, see
+/// `HyperHttpTransport::with_framed_streaming()`.
+///
+/// The body of such a request is a sequence of frames, each consisting of the length of a
+/// serialized `ToFunction` as a 4 byte big-endian integer, followed by the `ToFunction` itself.
+/// The response body contains one such frame with a `FromFunction` for every request frame, in
+/// the same order. Responses are written as soon as a frame was processed, so a client can keep
+/// the request body open and stream further frames.
+///
+/// Errors can't be reported with a status code once the response has started, instead the
+/// response body is aborted.
+pub const FRAMED_CONTENT_TYPE: &str = "application/vnd.statefun.framed";
+
+fn is_framed(req: &Request<Body>) -> bool {
+    matches!(
+        req.headers().get(header::CONTENT_TYPE),
+        Some(content_type) if content_type == FRAMED_CONTENT_TYPE
+    )
+}
+
+async fn handle_framed_request(
+    function_registry: Arc<FunctionRegistry>,
+    invocation_timeout: Option<Duration>,
+    serialization_error_hook: Option<SerializationErrorHook>,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let (mut sender, response_body) = Body::channel();
+    tokio::spawn(async move {
+        let result = process_frames(
+            function_registry,
+            invocation_timeout,
+            serialization_error_hook,
+            req.into_body(),
+            &mut sender,
+        )
+        .await;
+        if let Err(error) = result {
+            log::error!("Could not process framed request: {}", error);
+            sender.abort();
+        }
+    });
+
+    let response = Response::builder()
+        .header(header::CONTENT_TYPE, FRAMED_CONTENT_TYPE)
+        .body(response_body);
+    match response {
+        Ok(response) => Ok(response),
+        Err(error) => Ok(error_response(&error.into())),
+    }
+}
+
+async fn process_frames(
+    function_registry: Arc<FunctionRegistry>,
+    invocation_timeout: Option<Duration>,
+    serialization_error_hook: Option<SerializationErrorHook>,
+    mut body: Body,
+    sender: &mut body::Sender,
+) -> Result<(), HyperTransportError> {
+    let mut buffer = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        buffer.extend_from_slice(&chunk?);
+        while let Some(frame) = next_frame(&mut buffer) {
+            // every frame gets the full time budget, like a separate request would
+            let deadline = invocation_timeout.map(|timeout| Instant::now() + timeout);
+            let to_function = ToFunction::parse_from_bytes(&frame).map_err(MalformedRequest)?;
+            let from_function = invoke(
+                &function_registry,
+                to_function,
+                deadline,
+                serialization_error_hook.clone(),
+            )
+            .await?;
+            sender
+                .send_data(encode_frame(&from_function.write_to_bytes()?))
+                .await?;
+        }
+    }
+    if !buffer.is_empty() {
+        return Err(MalformedRequest(ProtobufError::WireError(
+            WireError::UnexpectedEof,
+        )));
+    }
+    Ok(())
+}
+
+/// Removes the next complete frame from the buffer and returns its payload, if there is one.
+fn next_frame(buffer: &mut BytesMut) -> Option<Bytes> {
+    if buffer.len() < 4 {
+        return None;
+    }
+    let length = u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as usize;
+    if buffer.len() < 4 + length {
+        return None;
+    }
+    buffer.advance(4);
+    Some(buffer.split_to(length).freeze())
+}
+
+fn encode_frame(payload: &[u8]) -> Bytes {
+    let mut frame = BytesMut::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame.freeze()
+}
+
+fn error_response(error: &HyperTransportError) -> Response<Body> {
+    let mut response = Response::new(Body::from(error.to_string()));
+    *response.status_mut() = error.status_code();
+    response
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
 /// The error type for the `HyperHttpTransport` `Transport`.
 ///
 /// Errors can originate from many different source because a `Transport` is the entry point that
 /// pulls everything together. This mostly wraps error types of other crates/modules that we use.
+/// Use `class()` to tell errors that are caused by the request apart from errors of the
+/// infrastructure.
 #[derive(Error, Debug)]
 #[non_exhaustive]
 pub enum HyperTransportError {
-    /// Something went wrong with Protobuf parsing, writing, packing, or unpacking.
+    /// The request body is not a valid `ToFunction` message.
+    #[error("malformed request: {0}")]
+    MalformedRequest(#[source] ProtobufError),
+
+    /// Something went wrong with Protobuf writing, packing, or unpacking.
     #[error(transparent)]
     ProtobufError(#[from] ProtobufError),
 
@@ -128,6 +749,136 @@ pub enum HyperTransportError {
     /// Something went wrong with Tokio.
     #[error("Tokio runtime could not be initialized")]
     TokioInitializationFailure(#[source] std::io::Error),
+
+    /// A user function panicked while it was invoked.
+    #[error("function panicked: {0}")]
+    FunctionPanic(String),
+
+    /// The StateFun version that was pinned using `with_protocol_version()` is not supported.
+    #[error(
+        "StateFun version {0} is not supported, this SDK supports StateFun {}",
+        SUPPORTED_STATEFUN_VERSION
+    )]
+    UnsupportedProtocolVersion(String),
+
+    /// The server is in drain mode and doesn't accept new requests, see `ShutdownTrigger::drain()`.
+    #[error("the server is draining and does not accept new requests")]
+    Draining,
+
+    /// The certificate or private key that was passed to `HyperHttpTransport::with_tls()` could
+    /// not be loaded.
+    #[cfg(feature = "tls")]
+    #[error("invalid TLS configuration in {}: {reason}", .path.display())]
+    InvalidTlsConfiguration {
+        /// The file that could not be loaded.
+        path: PathBuf,
+        /// Why the file could not be loaded.
+        reason: String,
+    },
+}
+
+/// Whether a `HyperTransportError` was caused by the request or by the infrastructure, see
+/// `HyperTransportError::class()`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ErrorClass {
+    /// The request can never succeed, for example because it is malformed, targets an unknown
+    /// function, or carries a message that the function rejects. Retrying doesn't help.
+    Validation,
+    /// Something went wrong while serving the request, for example a function panicked or the
+    /// response could not be encoded. These are logged as errors.
+    Infrastructure,
+}
+
+impl HyperTransportError {
+    /// Returns whether this error was caused by the request or by the infrastructure.
+    ///
+    /// Request bodies that end prematurely, for example because a proxy cut them off, count as
+    /// infrastructure errors, because the same request succeeds when it is sent again.
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            HyperTransportError::MalformedRequest(error) if !is_truncation(error) => {
+                ErrorClass::Validation
+            }
+            HyperTransportError::InvocationError(InvocationError::FunctionNotFound(_))
+            | HyperTransportError::InvocationError(InvocationError::InvalidMessage { .. })
+            | HyperTransportError::InvocationError(InvocationError::DisallowedTypename {
+                ..
+            }) => ErrorClass::Validation,
+            _ => ErrorClass::Infrastructure,
+        }
+    }
+
+    /// Returns the HTTP status code that is sent to Flink when a request fails with this error.
+    ///
+    /// Validation errors are answered with a 4xx status, while infrastructure errors result in a
+    /// 5xx, see `class()`. Truncated request bodies, and requests that arrive while the server is
+    /// draining, are answered with a 503 so that Flink retries them promptly.
+    /// Missing state is not an error on the wire, it is answered with a regular response that
+    /// asks Flink to provide the state.
+    pub fn status_code(&self) -> StatusCode {
+        match (self.class(), self) {
+            (
+                ErrorClass::Validation,
+                HyperTransportError::InvocationError(InvocationError::FunctionNotFound(_)),
+            ) => StatusCode::NOT_FOUND,
+            (ErrorClass::Validation, _) => StatusCode::BAD_REQUEST,
+            (ErrorClass::Infrastructure, HyperTransportError::MalformedRequest(_))
+            | (ErrorClass::Infrastructure, HyperTransportError::Draining) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            (ErrorClass::Infrastructure, _) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Returns `true` if the error was caused by input that ended prematurely, rather than by input
+/// that is malformed.
+fn is_truncation(error: &ProtobufError) -> bool {
+    match error {
+        ProtobufError::IoError(error) => error.kind() == std::io::ErrorKind::UnexpectedEof,
+        ProtobufError::WireError(WireError::UnexpectedEof)
+        | ProtobufError::WireError(WireError::TruncatedMessage) => true,
+        _ => false,
+    }
+}
+
+fn log_topology(function_registry: &FunctionRegistry, bind_address: SocketAddr) {
+    let function_types = function_registry.function_types();
+    log::info!(
+        "Serving {} function(s) on {}",
+        function_types.len(),
+        bind_address
+    );
+    for function_type in function_types {
+        log::info!(
+            "  {}/{}",
+            function_type.get_namespace(),
+            function_type.get_name()
+        );
+        let value_specs = function_registry.value_specs(&function_type).unwrap_or(&[]);
+        for value_spec in value_specs {
+            let expiration = value_spec.expiration();
+            let expiration = match &expiration.expiration_type {
+                Some(ExpirationType::AfterWrite) => {
+                    format!("expires {:?} after write", expiration.time_to_live)
+                }
+                Some(ExpirationType::AfterInvoke) => {
+                    format!("expires {:?} after invoke", expiration.time_to_live)
+                }
+                None => "never expires".to_string(),
+            };
+            log::info!(
+                "    state {:?}: {}, {}",
+                value_spec.name(),
+                value_spec.typename(),
+                expiration
+            );
+        }
+    }
+}
+
+fn major_version(version: &str) -> &str {
+    version.split('.').next().unwrap_or(version)
 }
 
 async fn shutdown_signal() {
@@ -135,3 +886,736 @@ async fn shutdown_signal() {
         .await
         .expect("failed to install CTRL+C signal handler");
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use futures::future::join_all;
+    use hyper::{header, Body, Request, StatusCode};
+    use protobuf::Message as ProtoMessage;
+    use protobuf::ProtobufError;
+    use tokio::sync::{oneshot, Barrier};
+
+    use statefun_proto::request_reply::ToFunction;
+    use statefun_proto::request_reply::ToFunction_Invocation;
+    use statefun_proto::request_reply::ToFunction_InvocationBatchRequest;
+
+    use bytes::BytesMut;
+    use statefun_proto::request_reply::FromFunction;
+
+    use crate::serialization_errors::SerializationErrorHook;
+    use crate::transport::hyper::{
+        encode_frame, handle_framed_request, handle_request, is_framed, next_frame, probe_response,
+        serve_request, ErrorClass, HyperTransportError, ProbePaths, ShutdownTrigger,
+        FRAMED_CONTENT_TYPE,
+    };
+    use crate::*;
+
+    fn function_type() -> FunctionType {
+        FunctionType::new("namespace", "foo")
+    }
+
+    fn to_function_request(function_type: FunctionType) -> Request<Body> {
+        let mut invocation_batch = ToFunction_InvocationBatchRequest::new();
+        invocation_batch.set_target(Address::new(function_type, "self").into_proto());
+        invocation_batch
+            .mut_invocations()
+            .push(ToFunction_Invocation::new());
+
+        let mut to_function = ToFunction::new();
+        to_function.set_invocation(invocation_batch);
+
+        Request::new(Body::from(to_function.write_to_bytes().unwrap()))
+    }
+
+    fn registry() -> Arc<FunctionRegistry> {
+        let mut registry = FunctionRegistry::new();
+        registry
+            .register_fn(function_type(), vec![], |_context, message: Message| {
+                message.get::<String>().unwrap();
+                Effects::new()
+            })
+            .unwrap();
+        Arc::new(registry)
+    }
+
+    #[test]
+    fn shutdown_callbacks_run_after_server_stops() {
+        let (stopped_sender, stopped_receiver) = mpsc::channel();
+        let (callback_sender, callback_receiver) = mpsc::channel();
+        let first_callback_sender = callback_sender.clone();
+
+        let transport = HyperHttpTransport::new(([127, 0, 0, 1], 0).into())
+            .on_shutdown(move || first_callback_sender.send("first").unwrap())
+            .on_shutdown(move || callback_sender.send("second").unwrap());
+
+        // the server stops immediately because the shutdown future is already complete
+        transport
+            .serve(FunctionRegistry::new(), async move {
+                stopped_sender.send(()).unwrap();
+            })
+            .unwrap();
+
+        stopped_receiver.try_recv().unwrap();
+        let callbacks: Vec<&str> = callback_receiver.try_iter().collect();
+        assert_eq!(callbacks, vec!["first", "second"]);
+    }
+
+    #[test]
+    fn reports_protocol_version() {
+        crate::test_logger::init();
+
+        HyperHttpTransport::new(([127, 0, 0, 1], 0).into())
+            .with_protocol_version("3.1")
+            .serve(FunctionRegistry::new(), async {})
+            .unwrap();
+
+        let expected = format!(
+            "Serving the remote function protocol of StateFun {} (pinned to 3.1)",
+            SUPPORTED_STATEFUN_VERSION
+        );
+        assert!(crate::test_logger::captured(log::Level::Info).contains(&expected));
+    }
+
+    #[test]
+    fn run_background() {
+        let (callback_sender, callback_receiver) = mpsc::channel();
+        let handle = HyperHttpTransport::new(([127, 0, 0, 1], 0).into())
+            .on_shutdown(move || callback_sender.send(()).unwrap())
+            .run_background(FunctionRegistry::new())
+            .unwrap();
+        assert_ne!(handle.local_address().port(), 0);
+
+        let mut stream = TcpStream::connect(handle.local_address()).unwrap();
+        stream
+            .write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+
+        assert!(callback_receiver.try_recv().is_err());
+        handle.shutdown();
+        callback_receiver.try_recv().unwrap();
+    }
+
+    #[test]
+    fn run_background_fails_if_address_is_in_use() {
+        let handle = HyperHttpTransport::new(([127, 0, 0, 1], 0).into())
+            .run_background(FunctionRegistry::new())
+            .unwrap();
+
+        let result =
+            HyperHttpTransport::new(handle.local_address()).run_background(FunctionRegistry::new());
+        assert!(matches!(result, Err(HyperTransportError::HyperError(_))));
+        handle.shutdown();
+    }
+
+    #[test]
+    fn serves_with_single_worker_thread() {
+        let mut registry = FunctionRegistry::new();
+        registry
+            .register_fn(function_type(), vec![], |_context, _message: Message| {
+                Effects::new()
+            })
+            .unwrap();
+
+        let (stopped_sender, stopped_receiver) = mpsc::channel();
+        HyperHttpTransport::new(([127, 0, 0, 1], 0).into())
+            .with_worker_threads(1)
+            .serve(registry, async move {
+                stopped_sender.send(()).unwrap();
+            })
+            .unwrap();
+        stopped_receiver.try_recv().unwrap();
+    }
+
+    // With a single worker thread, the first invocation can only complete once the second one was
+    // handled, so this fails if middleware blocks the worker on the asynchronous function
+    #[test]
+    fn middleware_awaits_async_function_with_single_worker_thread() {
+        let barrier = Arc::new(Barrier::new(2));
+        let mut registry = FunctionRegistry::new();
+        registry
+            .register_async_fn(
+                function_type(),
+                vec![],
+                move |_context, _message: Message| {
+                    let barrier = Arc::clone(&barrier);
+                    Box::pin(async move {
+                        barrier.wait().await;
+                        Effects::new()
+                    })
+                },
+            )
+            .unwrap();
+        registry.wrap(|_function_type, context, message, next| next.run(context, message));
+
+        let handle = HyperHttpTransport::new(([127, 0, 0, 1], 0).into())
+            .with_worker_threads(1)
+            .run_background(registry)
+            .unwrap();
+        let address = handle.local_address();
+        let body = testing::BatchBuilder::new(Address::new(function_type(), "self"))
+            .with_message(&"hello".to_string())
+            .unwrap()
+            .to_proto()
+            .write_to_bytes()
+            .unwrap();
+
+        let requests: Vec<_> = (0..2)
+            .map(|_| {
+                let body = body.clone();
+                std::thread::spawn(move || {
+                    let mut stream = TcpStream::connect(address).unwrap();
+                    stream
+                        .set_read_timeout(Some(Duration::from_secs(10)))
+                        .unwrap();
+                    write!(
+                        stream,
+                        "POST /statefun HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\
+                         Connection: close\r\n\r\n",
+                        body.len()
+                    )
+                    .unwrap();
+                    stream.write_all(&body).unwrap();
+                    let mut response = Vec::new();
+                    stream
+                        .read_to_end(&mut response)
+                        .expect("the invocations were not handled concurrently");
+                    response
+                })
+            })
+            .collect();
+        for request in requests {
+            let response = request.join().unwrap();
+            assert!(
+                response.starts_with(b"HTTP/1.1 200 OK"),
+                "{}",
+                String::from_utf8_lossy(&response)
+            );
+        }
+
+        handle.shutdown();
+    }
+
+    #[test]
+    #[should_panic(expected = "The number of worker threads must be positive")]
+    fn zero_worker_threads() {
+        HyperHttpTransport::new(([127, 0, 0, 1], 0).into()).with_worker_threads(0);
+    }
+
+    #[test]
+    fn logs_topology() {
+        crate::test_logger::init();
+
+        let mut registry = FunctionRegistry::new();
+        registry
+            .register_fn(
+                FunctionType::new("com.example.topology", "counter"),
+                vec![
+                    ValueSpec::<i32>::new("count", Expiration::never()).into(),
+                    ValueSpec::<String>::new(
+                        "name",
+                        Expiration::new(ExpirationType::AfterWrite, Duration::from_secs(60)),
+                    )
+                    .into(),
+                ],
+                |_context, _message: Message| Effects::new(),
+            )
+            .unwrap();
+
+        HyperHttpTransport::new(([127, 0, 0, 1], 0).into())
+            .with_topology_logging()
+            .serve(registry, async {})
+            .unwrap();
+
+        let captured = crate::test_logger::captured(log::Level::Info);
+        for expected in &[
+            "  com.example.topology/counter",
+            "    state \"count\": io.statefun.types/int, never expires",
+            "    state \"name\": io.statefun.types/string, expires 60s after write",
+        ] {
+            assert!(captured.iter().any(|line| line == expected), "{}", expected);
+        }
+    }
+
+    #[test]
+    fn reject_unsupported_protocol_version() {
+        let result = HyperHttpTransport::new(([127, 0, 0, 1], 0).into())
+            .with_protocol_version("2.2.0")
+            .serve(FunctionRegistry::new(), async {});
+        assert!(matches!(
+            result,
+            Err(HyperTransportError::UnsupportedProtocolVersion(version)) if version == "2.2.0"
+        ));
+    }
+
+    #[tokio::test]
+    async fn malformed_request_is_bad_request() {
+        // wire type 7 does not exist, a truncated body would be retryable instead
+        let request = Request::new(Body::from(vec![0x0f, 0x01, 0x02]));
+        let response = handle_request(registry(), None, None, request)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn truncated_request_is_retryable() {
+        let request = to_function_request(function_type());
+        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+        let truncated = Request::new(Body::from(body.slice(0..body.len() / 2)));
+        let response = handle_request(registry(), None, None, truncated)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn unknown_function_is_not_found() {
+        let request = to_function_request(FunctionType::new("namespace", "unknown"));
+        let response = handle_request(registry(), None, None, request)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn function_panic_is_internal_server_error() {
+        let request = to_function_request(function_type());
+        let response = handle_request(registry(), None, None, request)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn protocol_serialization_error_is_internal_server_error() {
+        let error = HyperTransportError::InvocationError(
+            InvocationError::ProtocolSerializationError(ProtobufError::MessageNotInitialized {
+                message: "ToFunction",
+            }),
+        );
+        assert_eq!(error.class(), ErrorClass::Infrastructure);
+        assert_eq!(error.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let error = HyperTransportError::ProtobufError(ProtobufError::MessageNotInitialized {
+            message: "FromFunction",
+        });
+        assert_eq!(error.class(), ErrorClass::Infrastructure);
+        assert_eq!(error.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn validation_errors_are_client_errors() {
+        let error = HyperTransportError::InvocationError(InvocationError::InvalidMessage {
+            function_type: function_type(),
+            reason: "not a String".to_string(),
+        });
+        assert_eq!(error.class(), ErrorClass::Validation);
+        assert!(error.status_code().is_client_error());
+
+        let error = HyperTransportError::InvocationError(InvocationError::FunctionNotFound(
+            function_type(),
+        ));
+        assert_eq!(error.class(), ErrorClass::Validation);
+        assert_eq!(error.status_code(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn invalid_message_is_bad_request() {
+        let mut registry = FunctionRegistry::new();
+        registry
+            .register_checked_fn(function_type(), vec![], |_context, _message: String| {
+                Effects::new()
+            })
+            .unwrap();
+
+        // the request carries an empty message, which is not a String
+        let request = to_function_request(function_type());
+        let response = handle_request(Arc::new(registry), None, None, request)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn missing_state_is_ok() {
+        let mut registry = FunctionRegistry::new();
+        registry
+            .register_fn(
+                function_type(),
+                vec![ValueSpec::<i32>::new("foo", Expiration::never()).into()],
+                |_context, _message: Message| Effects::new(),
+            )
+            .unwrap();
+
+        let request = to_function_request(function_type());
+        let response = handle_request(Arc::new(registry), None, None, request)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn framed_requests() {
+        let mut registry = FunctionRegistry::new();
+        registry
+            .register_fn(function_type(), vec![], |context, _message: Message| {
+                let mut effects = Effects::new();
+                effects
+                    .send(context.self_address(), &context.self_address().id)
+                    .unwrap();
+                effects
+            })
+            .unwrap();
+
+        let mut request_body = Vec::new();
+        for id in &["first", "second"] {
+            let mut invocation_batch = ToFunction_InvocationBatchRequest::new();
+            invocation_batch.set_target(Address::new(function_type(), id).into_proto());
+            invocation_batch
+                .mut_invocations()
+                .push(ToFunction_Invocation::new());
+            let mut to_function = ToFunction::new();
+            to_function.set_invocation(invocation_batch);
+            request_body.extend_from_slice(&encode_frame(&to_function.write_to_bytes().unwrap()));
+        }
+        let request = Request::builder()
+            .header("content-type", FRAMED_CONTENT_TYPE)
+            .body(Body::from(request_body))
+            .unwrap();
+        assert!(is_framed(&request));
+
+        let response = handle_framed_request(Arc::new(registry), None, None, request)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response_body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let mut buffer = BytesMut::from(&response_body[..]);
+        let mut ids = Vec::new();
+        while let Some(frame) = next_frame(&mut buffer) {
+            let from_function = FromFunction::parse_from_bytes(&frame).unwrap();
+            let outgoing = &from_function
+                .get_invocation_result()
+                .get_outgoing_messages()[0];
+            let message = Message::new(outgoing.get_argument().clone());
+            ids.push(message.get::<String>().unwrap());
+        }
+        assert!(buffer.is_empty());
+        assert_eq!(ids, vec!["first", "second"]);
+    }
+
+    /// A message that can never be serialized.
+    struct Unserializable;
+
+    impl TypeName for Unserializable {
+        fn get_typename() -> &'static str {
+            "com.example/unserializable"
+        }
+    }
+
+    impl Serializable<Unserializable> for Unserializable {
+        fn serialize(&self, _typename: String) -> Result<Vec<u8>, String> {
+            Err("not today".to_string())
+        }
+
+        fn deserialize(_typename: String, _buffer: &[u8]) -> Result<Unserializable, String> {
+            Ok(Unserializable)
+        }
+    }
+
+    #[tokio::test]
+    async fn serialization_errors_are_reported() {
+        let mut registry = FunctionRegistry::new();
+        registry
+            .register_fn(function_type(), vec![], |_context, _message: Message| {
+                let mut effects = Effects::new();
+                let result = effects.send(
+                    Address::new(FunctionType::new("namespace", "bar"), "id"),
+                    &Unserializable,
+                );
+                assert_eq!(result, Err("not today".to_string()));
+                effects
+            })
+            .unwrap();
+
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let hook_reported = Arc::clone(&reported);
+        let hook: SerializationErrorHook = Arc::new(move |typename: &str, error: &str| {
+            hook_reported
+                .lock()
+                .unwrap()
+                .push((typename.to_string(), error.to_string()));
+        });
+
+        let request = to_function_request(function_type());
+        let response = handle_request(Arc::new(registry), None, Some(hook), request)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            *reported.lock().unwrap(),
+            vec![(
+                "com.example/unserializable".to_string(),
+                "not today".to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_function_is_internal_server_error() {
+        let mut registry = FunctionRegistry::new();
+        registry
+            .register_fallible_fn(function_type(), vec![], |_context, message: Message| {
+                message.get::<String>()?;
+                Ok(Effects::new())
+            })
+            .unwrap();
+
+        let request = to_function_request(function_type());
+        let response = handle_request(Arc::new(registry), None, None, request)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.starts_with("function FunctionType namespace/foo failed: Incompatible types."));
+    }
+
+    #[tokio::test]
+    async fn async_function() {
+        let mut registry = FunctionRegistry::new();
+        registry
+            .register_async_fn(function_type(), vec![], |_context, _message: Message| {
+                Box::pin(async move {
+                    let (sender, receiver) = oneshot::channel();
+                    tokio::spawn(async move { sender.send("enriched".to_string()) });
+                    let enriched = receiver.await.unwrap();
+                    let mut effects = Effects::new();
+                    effects
+                        .egress(EgressIdentifier::new("namespace", "egress"), &enriched)
+                        .unwrap();
+                    effects
+                })
+            })
+            .unwrap();
+
+        let request = to_function_request(function_type());
+        let response = handle_request(Arc::new(registry), None, None, request)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let from_function = FromFunction::parse_from_bytes(&body).unwrap();
+        assert_eq!(
+            from_function
+                .get_invocation_result()
+                .get_outgoing_egresses()
+                .len(),
+            1
+        );
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn concurrent_requests() {
+        const REQUESTS: usize = 16;
+
+        // every invocation waits until all of them have started, so this only completes if the
+        // requests are handled concurrently
+        let barrier = Arc::new(Barrier::new(REQUESTS));
+        let mut registry = FunctionRegistry::new();
+        registry
+            .register_async_fn(
+                function_type(),
+                vec![],
+                move |_context, _message: Message| {
+                    let barrier = Arc::clone(&barrier);
+                    Box::pin(async move {
+                        barrier.wait().await;
+                        Effects::new()
+                    })
+                },
+            )
+            .unwrap();
+        let registry = Arc::new(registry);
+
+        let requests = (0..REQUESTS).map(|_| {
+            let registry = Arc::clone(&registry);
+            tokio::spawn(async move {
+                let request = to_function_request(function_type());
+                handle_request(registry, None, None, request).await.unwrap()
+            })
+        });
+        let responses = tokio::time::timeout(Duration::from_secs(10), join_all(requests))
+            .await
+            .expect("requests were not handled concurrently");
+        for response in responses {
+            assert_eq!(response.unwrap().status(), StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn deadline_is_available_to_function() {
+        let mut registry = FunctionRegistry::new();
+        registry
+            .register_fn(function_type(), vec![], |context, _message: Message| {
+                let deadline = context.deadline().expect("No deadline.");
+                assert!(deadline > Instant::now());
+                assert!(deadline <= Instant::now() + Duration::from_secs(60));
+                Effects::new()
+            })
+            .unwrap();
+
+        let request = to_function_request(function_type());
+        let response = handle_request(
+            Arc::new(registry),
+            Some(Duration::from_secs(60)),
+            None,
+            request,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    fn probe_paths() -> ProbePaths {
+        ProbePaths {
+            health: "/healthz".to_string(),
+            readiness: "/ready".to_string(),
+        }
+    }
+
+    fn probe_request(path: &str) -> Request<Body> {
+        Request::get(path).body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn probes() {
+        let draining = AtomicBool::new(false);
+        for path in &["/healthz", "/ready"] {
+            let request = probe_request(path);
+            let response = serve_request(
+                registry(),
+                None,
+                false,
+                None,
+                &probe_paths(),
+                &draining,
+                request,
+            )
+            .await
+            .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert_eq!(&body[..], br#"{"status":"ok"}"#);
+        }
+
+        // requests to other paths are invocations
+        let request = probe_request("/other");
+        let response = serve_request(
+            registry(),
+            None,
+            false,
+            None,
+            &probe_paths(),
+            &draining,
+            request,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/octet-stream"
+        );
+        let request = Request::post("/healthz").body(Body::empty()).unwrap();
+        assert!(probe_response(&probe_paths(), &draining, &request).is_none());
+
+        let custom = ProbePaths {
+            health: "/live".to_string(),
+            readiness: "/ready".to_string(),
+        };
+        assert!(probe_response(&custom, &draining, &probe_request("/live")).is_some());
+        assert!(probe_response(&custom, &draining, &probe_request("/healthz")).is_none());
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn draining_rejects_new_requests() {
+        let (started_sender, started_receiver) = mpsc::channel();
+        let (release_sender, release_receiver) = mpsc::channel::<()>();
+        let started_sender = Mutex::new(started_sender);
+        let release_receiver = Mutex::new(release_receiver);
+
+        let mut registry = FunctionRegistry::new();
+        registry
+            .register_fn(
+                function_type(),
+                vec![],
+                move |_context, _message: Message| {
+                    started_sender.lock().unwrap().send(()).unwrap();
+                    release_receiver.lock().unwrap().recv().unwrap();
+                    Effects::new()
+                },
+            )
+            .unwrap();
+        let registry = Arc::new(registry);
+
+        let (sender, _receiver) = oneshot::channel();
+        let trigger = ShutdownTrigger {
+            sender,
+            draining: Arc::new(AtomicBool::new(false)),
+        };
+
+        let draining = Arc::clone(&trigger.draining);
+        let in_flight_registry = Arc::clone(&registry);
+        let in_flight = tokio::spawn(async move {
+            let request = to_function_request(function_type());
+            serve_request(
+                in_flight_registry,
+                None,
+                false,
+                None,
+                &probe_paths(),
+                &draining,
+                request,
+            )
+            .await
+        });
+        started_receiver.recv().unwrap();
+
+        trigger.drain();
+        let request = to_function_request(function_type());
+        let response = serve_request(
+            registry,
+            None,
+            false,
+            None,
+            &probe_paths(),
+            &trigger.draining,
+            request,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let response = probe_response(&probe_paths(), &trigger.draining, &probe_request("/ready"));
+        assert_eq!(response.unwrap().status(), StatusCode::SERVICE_UNAVAILABLE);
+        let response = probe_response(
+            &probe_paths(),
+            &trigger.draining,
+            &probe_request("/healthz"),
+        );
+        assert_eq!(response.unwrap().status(), StatusCode::OK);
+
+        release_sender.send(()).unwrap();
+        let response = in_flight.await.unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
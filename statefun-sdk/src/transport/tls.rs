@@ -0,0 +1,228 @@
+//! Serving of HTTPS for the [HyperHttpTransport](crate::transport::hyper::HyperHttpTransport).
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future;
+use futures::stream::{self, Stream, StreamExt};
+use hyper::server::accept::Accept;
+use hyper::server::conn::{AddrIncoming, AddrStream};
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{NoClientAuth, PrivateKey, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+use crate::transport::hyper::HyperTransportError;
+
+/// How long a client gets to complete the TLS handshake before the connection is dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many TLS handshakes may be in flight at the same time, so that slow clients don't hold up
+/// the accepting of new connections.
+const MAX_CONCURRENT_HANDSHAKES: usize = 64;
+
+/// Loads the certificate chain and private key from the given PEM files into a `ServerConfig`
+/// that speaks HTTP/2 and HTTP/1.1.
+pub(crate) fn load_config(
+    cert_path: &Path,
+    key_path: &Path,
+) -> Result<ServerConfig, HyperTransportError> {
+    let certs =
+        pemfile::certs(&mut open(cert_path)?).map_err(|()| invalid(cert_path, "not a PEM file"))?;
+    if certs.is_empty() {
+        return Err(invalid(cert_path, "no certificate found"));
+    }
+    let key = load_private_key(key_path)?;
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(certs, key)
+        .map_err(|error| invalid(key_path, &error.to_string()))?;
+    config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+    Ok(config)
+}
+
+/// Loads the first PKCS#8 private key from the given PEM file, falling back to the first RSA
+/// private key.
+fn load_private_key(key_path: &Path) -> Result<PrivateKey, HyperTransportError> {
+    let pkcs8_keys = pemfile::pkcs8_private_keys(&mut open(key_path)?)
+        .map_err(|()| invalid(key_path, "not a PEM file"))?;
+    if let Some(key) = pkcs8_keys.into_iter().next() {
+        return Ok(key);
+    }
+    let rsa_keys = pemfile::rsa_private_keys(&mut open(key_path)?)
+        .map_err(|()| invalid(key_path, "not a PEM file"))?;
+    rsa_keys
+        .into_iter()
+        .next()
+        .ok_or_else(|| invalid(key_path, "no PKCS#8 or RSA private key found"))
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, HyperTransportError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|error| invalid(path, &error.to_string()))
+}
+
+fn invalid(path: &Path, reason: &str) -> HyperTransportError {
+    HyperTransportError::InvalidTlsConfiguration {
+        path: path.to_path_buf(),
+        reason: reason.to_string(),
+    }
+}
+
+/// Performs the TLS handshake on the connections that are accepted by `incoming`. Connections
+/// whose handshake fails or times out are logged and dropped, they don't stop the server.
+pub(crate) fn connections(
+    mut incoming: AddrIncoming,
+    config: Arc<ServerConfig>,
+) -> impl Stream<Item = Result<TlsStream<AddrStream>, io::Error>> {
+    let acceptor = TlsAcceptor::from(config);
+    stream::poll_fn(move |cx| Pin::new(&mut incoming).poll_accept(cx))
+        .filter_map(|connection| {
+            future::ready(match connection {
+                Ok(connection) => Some(connection),
+                Err(error) => {
+                    log::warn!("Could not accept connection: {}", error);
+                    None
+                }
+            })
+        })
+        .map(move |connection| {
+            let acceptor = acceptor.clone();
+            async move {
+                let remote_address = connection.remote_addr();
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(connection)).await {
+                    Ok(Ok(stream)) => Some(Ok(stream)),
+                    Ok(Err(error)) => {
+                        log::warn!("TLS handshake with {} failed: {}", remote_address, error);
+                        None
+                    }
+                    Err(_) => {
+                        log::warn!("TLS handshake with {} timed out", remote_address);
+                        None
+                    }
+                }
+            }
+        })
+        .buffer_unordered(MAX_CONCURRENT_HANDSHAKES)
+        .filter_map(future::ready)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::{BufReader, ErrorKind, Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    use tokio_rustls::rustls::{ClientConfig, ClientSession, StreamOwned};
+    use tokio_rustls::webpki::DNSNameRef;
+
+    use crate::transport::hyper::HyperTransportError;
+    use crate::*;
+
+    fn testdata(file: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("src/transport/testdata")
+            .join(file)
+    }
+
+    fn https_get(address: SocketAddr, path: &str) -> String {
+        let mut config = ClientConfig::new();
+        let mut cert = BufReader::new(File::open(testdata("localhost.crt")).unwrap());
+        config.root_store.add_pem_file(&mut cert).unwrap();
+        let session = ClientSession::new(
+            &Arc::new(config),
+            DNSNameRef::try_from_ascii_str("localhost").unwrap(),
+        );
+        let mut stream = StreamOwned::new(session, TcpStream::connect(address).unwrap());
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            path
+        )
+        .unwrap();
+        // rustls reports the close_notify of the server as an error, the response is complete
+        let mut response = Vec::new();
+        match stream.read_to_end(&mut response) {
+            Err(error) if error.kind() == ErrorKind::ConnectionAborted => {}
+            result => {
+                result.unwrap();
+            }
+        }
+        String::from_utf8(response).unwrap()
+    }
+
+    #[test]
+    fn serves_https() {
+        let handle = HyperHttpTransport::with_tls(
+            ([127, 0, 0, 1], 0).into(),
+            testdata("localhost.crt"),
+            testdata("localhost.key"),
+        )
+        .unwrap()
+        .run_background(FunctionRegistry::new())
+        .unwrap();
+
+        let response = https_get(handle.local_address(), "/healthz");
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        handle.shutdown();
+    }
+
+    #[test]
+    fn failed_handshake_does_not_stop_the_server() {
+        let handle = HyperHttpTransport::with_tls(
+            ([127, 0, 0, 1], 0).into(),
+            testdata("localhost.crt"),
+            testdata("localhost.key"),
+        )
+        .unwrap()
+        .run_background(FunctionRegistry::new())
+        .unwrap();
+
+        let mut plain = TcpStream::connect(handle.local_address()).unwrap();
+        plain
+            .write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = Vec::new();
+        let _ = plain.read_to_end(&mut response);
+        assert!(!response.starts_with(b"HTTP/1.1 200 OK"));
+
+        let response = https_get(handle.local_address(), "/healthz");
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        handle.shutdown();
+    }
+
+    #[test]
+    fn missing_certificate() {
+        let result = HyperHttpTransport::with_tls(
+            ([127, 0, 0, 1], 0).into(),
+            testdata("missing.crt"),
+            testdata("localhost.key"),
+        );
+        assert!(matches!(
+            result,
+            Err(HyperTransportError::InvalidTlsConfiguration { path, .. })
+                if path == testdata("missing.crt")
+        ));
+    }
+
+    #[test]
+    fn key_file_without_key() {
+        let result = HyperHttpTransport::with_tls(
+            ([127, 0, 0, 1], 0).into(),
+            testdata("localhost.crt"),
+            testdata("localhost.crt"),
+        );
+        assert!(matches!(
+            result,
+            Err(HyperTransportError::InvalidTlsConfiguration { path, .. })
+                if path == testdata("localhost.crt")
+        ));
+    }
+}
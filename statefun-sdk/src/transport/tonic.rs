@@ -0,0 +1,399 @@
+//! `Transport` that uses [Tonic](http://docs.rs/tonic) to serve stateful functions as a gRPC
+//! service.
+//!
+//! The service is named `io.statefun.sdk.reqreply.RequestReply` and has a single unary method
+//! `Invoke`, which takes the `ToFunction` that StateFun sends to remote functions and returns the
+//! `FromFunction`. StateFun itself speaks plain HTTP, so this is meant for deployments where a
+//! service mesh in front of the functions prefers gRPC, for example for tracing or load
+//! balancing.
+use std::convert::Infallible;
+use std::future::Future;
+use std::io;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use bytes::{Buf, BufMut};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use hyper::service::Service;
+use hyper::{http, Body};
+use thiserror::Error;
+use tokio::runtime;
+use tonic::body::BoxBody;
+use tonic::codec::{Codec, DecodeBuf, Decoder, EncodeBuf, Encoder};
+use tonic::server::{Grpc, UnaryService};
+use tonic::transport::{NamedService, Server};
+use tonic::Status;
+
+use statefun_proto::request_reply::{FromFunction, ToFunction};
+
+use crate::function_registry::FunctionRegistry;
+use crate::transport::hyper::{invoke, ErrorClass, HyperTransportError};
+use crate::transport::Transport;
+use crate::InvocationError;
+
+/// The full name of the gRPC service that `TonicTransport` serves.
+pub const SERVICE_NAME: &str = "io.statefun.sdk.reqreply.RequestReply";
+
+/// The path of the `Invoke` method of the gRPC service.
+pub const INVOKE_PATH: &str = "/io.statefun.sdk.reqreply.RequestReply/Invoke";
+
+/// A [Transport](crate::transport::Transport) that serves stateful functions as a gRPC service on
+/// the given `bind_address`, see the [module documentation](crate::transport::tonic).
+///
+/// Invocations go through the same `FunctionRegistry` code path as with the
+/// [HyperHttpTransport](crate::transport::hyper::HyperHttpTransport), so functions behave the
+/// same on both transports.
+pub struct TonicTransport {
+    bind_address: SocketAddr,
+    invocation_timeout: Option<Duration>,
+}
+
+impl TonicTransport {
+    /// Creates a new `TonicTransport` that can serve stateful functions at the given
+    /// `bind_address`.
+    pub fn new(bind_address: SocketAddr) -> TonicTransport {
+        TonicTransport {
+            bind_address,
+            invocation_timeout: None,
+        }
+    }
+
+    /// Sets the time budget of an invocation batch, see
+    /// `HyperHttpTransport::with_invocation_timeout()`.
+    pub fn with_invocation_timeout(mut self, timeout: Duration) -> TonicTransport {
+        self.invocation_timeout = Some(timeout);
+        self
+    }
+
+    /// Binds the server and serves requests on a new thread until `shutdown` completes.
+    fn start<S: Future<Output = ()> + Send + 'static>(
+        self,
+        function_registry: FunctionRegistry,
+        shutdown: S,
+    ) -> Result<(SocketAddr, JoinHandle<Result<(), TonicTransportError>>), TonicTransportError>
+    {
+        log::info!(
+            "Tonic transport will start listening on {}",
+            self.bind_address
+        );
+
+        let mut runtime = runtime::Builder::new()
+            .threaded_scheduler()
+            .enable_all()
+            .build()
+            .map_err(TonicTransportError::TokioInitializationFailure)?;
+        let listener = runtime
+            .enter(|| {
+                std::net::TcpListener::bind(self.bind_address)
+                    .and_then(tokio::net::TcpListener::from_std)
+            })
+            .map_err(TonicTransportError::BindFailure)?;
+        let local_address = listener
+            .local_addr()
+            .map_err(TonicTransportError::BindFailure)?;
+
+        let service = RequestReplyService {
+            function_registry: Arc::new(function_registry),
+            invocation_timeout: self.invocation_timeout,
+        };
+        let thread = thread::spawn(move || {
+            let mut listener = listener;
+            runtime.block_on(
+                Server::builder()
+                    .add_service(service)
+                    .serve_with_incoming_shutdown(listener.incoming(), shutdown),
+            )?;
+            Ok(())
+        });
+        Ok((local_address, thread))
+    }
+}
+
+impl Transport for TonicTransport {
+    type Error = TonicTransportError;
+
+    fn run(self, function_registry: FunctionRegistry) -> Result<(), Self::Error> {
+        let (_local_address, thread) = self.start(function_registry, async {
+            tokio::signal::ctrl_c()
+                .await
+                .expect("failed to install CTRL+C signal handler");
+        })?;
+        match thread.join() {
+            Ok(result) => result,
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    }
+}
+
+/// The gRPC service that answers `Invoke` requests.
+#[derive(Clone)]
+struct RequestReplyService {
+    function_registry: Arc<FunctionRegistry>,
+    invocation_timeout: Option<Duration>,
+}
+
+impl NamedService for RequestReplyService {
+    const NAME: &'static str = SERVICE_NAME;
+}
+
+impl Service<http::Request<Body>> for RequestReplyService {
+    type Response = http::Response<BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<Body>) -> Self::Future {
+        let service = self.clone();
+        async move {
+            if req.uri().path() != INVOKE_PATH {
+                return Ok(unimplemented_response());
+            }
+            let mut grpc = Grpc::new(ProtobufCodec::<FromFunction, ToFunction>::default());
+            Ok(grpc.unary(service, req).await)
+        }
+        .boxed()
+    }
+}
+
+impl UnaryService<ToFunction> for RequestReplyService {
+    type Response = FromFunction;
+    type Future = BoxFuture<'static, Result<tonic::Response<FromFunction>, Status>>;
+
+    fn call(&mut self, request: tonic::Request<ToFunction>) -> Self::Future {
+        // the time budget starts when we receive the request
+        let deadline = self
+            .invocation_timeout
+            .map(|timeout| Instant::now() + timeout);
+        let function_registry = Arc::clone(&self.function_registry);
+        async move {
+            let to_function = request.into_inner();
+            match invoke(&function_registry, to_function, deadline, None).await {
+                Ok(from_function) => Ok(tonic::Response::new(from_function)),
+                Err(error) => {
+                    match error.class() {
+                        ErrorClass::Validation => log::warn!("Rejected request: {}", error),
+                        ErrorClass::Infrastructure => {
+                            log::error!("Could not process request: {}", error)
+                        }
+                    }
+                    Err(status(&error))
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+/// Returns the gRPC status that a failed invocation is answered with.
+fn status(error: &HyperTransportError) -> Status {
+    match (error.class(), error) {
+        (_, HyperTransportError::InvocationError(InvocationError::FunctionNotFound(_))) => {
+            Status::not_found(error.to_string())
+        }
+        (ErrorClass::Validation, _) => Status::invalid_argument(error.to_string()),
+        (ErrorClass::Infrastructure, _) => Status::internal(error.to_string()),
+    }
+}
+
+fn unimplemented_response() -> http::Response<BoxBody> {
+    http::Response::builder()
+        .status(http::StatusCode::OK)
+        .header("grpc-status", "12")
+        .header("content-type", "application/grpc")
+        .body(BoxBody::empty())
+        .expect("static response is valid")
+}
+
+/// A tonic `Codec` for the messages that `statefun-proto` generates using `rust-protobuf`.
+#[derive(Debug)]
+struct ProtobufCodec<E, D> {
+    _messages: PhantomData<fn() -> (E, D)>,
+}
+
+impl<E, D> Default for ProtobufCodec<E, D> {
+    fn default() -> Self {
+        ProtobufCodec {
+            _messages: PhantomData,
+        }
+    }
+}
+
+impl<E, D> Codec for ProtobufCodec<E, D>
+where
+    E: protobuf::Message + Sync,
+    D: protobuf::Message,
+{
+    type Encode = E;
+    type Decode = D;
+    type Encoder = ProtobufCodec<E, D>;
+    type Decoder = ProtobufCodec<E, D>;
+
+    fn encoder(&mut self) -> Self::Encoder {
+        ProtobufCodec::default()
+    }
+
+    fn decoder(&mut self) -> Self::Decoder {
+        ProtobufCodec::default()
+    }
+}
+
+impl<E: protobuf::Message, D> Encoder for ProtobufCodec<E, D> {
+    type Item = E;
+    type Error = Status;
+
+    fn encode(&mut self, item: E, dst: &mut EncodeBuf<'_>) -> Result<(), Status> {
+        let bytes = item
+            .write_to_bytes()
+            .map_err(|error| Status::internal(error.to_string()))?;
+        dst.put_slice(&bytes);
+        Ok(())
+    }
+}
+
+impl<E, D: protobuf::Message> Decoder for ProtobufCodec<E, D> {
+    type Item = D;
+    type Error = Status;
+
+    fn decode(&mut self, src: &mut DecodeBuf<'_>) -> Result<Option<D>, Status> {
+        let bytes = src.to_bytes();
+        let message = D::parse_from_bytes(&bytes)
+            .map_err(|error| Status::invalid_argument(error.to_string()))?;
+        Ok(Some(message))
+    }
+}
+
+/// Errors that can occur when serving stateful functions using a `TonicTransport`.
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum TonicTransportError {
+    /// The tokio runtime could not be created.
+    #[error("failed to initialize tokio runtime")]
+    TokioInitializationFailure(#[source] io::Error),
+
+    /// The server could not listen on the bind address, for example because it is already in
+    /// use.
+    #[error("failed to bind the server")]
+    BindFailure(#[source] io::Error),
+
+    /// The gRPC server failed.
+    #[error(transparent)]
+    TransportError(#[from] tonic::transport::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use hyper::http::uri::PathAndQuery;
+    use tokio::runtime::Runtime;
+    use tokio::sync::oneshot;
+    use tonic::client::Grpc;
+    use tonic::transport::Endpoint;
+    use tonic::{Code, Status};
+
+    use statefun_proto::request_reply::{FromFunction, ToFunction};
+
+    use crate::testing::{BatchBuilder, BatchResponse};
+    use crate::transport::tonic::{ProtobufCodec, INVOKE_PATH};
+    use crate::*;
+
+    fn address(id: &str) -> Address {
+        Address::new(FunctionType::new("namespace", "foo"), id)
+    }
+
+    /// Calls `Invoke` on a runtime of its own, which closes the connection when it is dropped.
+    /// Otherwise the graceful shutdown of the server waits for it.
+    fn call_invoke(address: SocketAddr, to_function: ToFunction) -> Result<FromFunction, Code> {
+        let mut runtime = Runtime::new().unwrap();
+        runtime
+            .block_on(call_invoke_async(address, to_function))
+            .map_err(|status| status.code())
+    }
+
+    async fn call_invoke_async(
+        address: SocketAddr,
+        to_function: ToFunction,
+    ) -> Result<FromFunction, Status> {
+        let channel = Endpoint::from_shared(format!("http://{}", address))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut client = Grpc::new(channel);
+        client.ready().await.unwrap();
+        let response = client
+            .unary(
+                tonic::Request::new(to_function),
+                PathAndQuery::from_static(INVOKE_PATH),
+                ProtobufCodec::<ToFunction, FromFunction>::default(),
+            )
+            .await?;
+        Ok(response.into_inner())
+    }
+
+    #[test]
+    fn invoke() -> anyhow::Result<()> {
+        let count = || ValueSpec::<i32>::new("count", Expiration::never());
+        let mut registry = FunctionRegistry::new();
+        registry.register_fn(
+            address("self").function_type,
+            vec![count().into()],
+            move |context, _message: Message| {
+                let mut effects = Effects::new();
+                let value = context.get_state_or_default(count()).unwrap() + 1;
+                effects.update_state(count(), &value).unwrap();
+                effects
+            },
+        )?;
+
+        let (sender, receiver) = oneshot::channel::<()>();
+        let (local_address, thread) =
+            TonicTransport::new(([127, 0, 0, 1], 0).into()).start(registry, async move {
+                let _ = receiver.await;
+            })?;
+
+        let to_function = BatchBuilder::new(address("self"))
+            .with_state(count(), &1)
+            .unwrap()
+            .with_message(&"hello".to_string())
+            .unwrap()
+            .to_proto();
+        let from_function = call_invoke(local_address, to_function).unwrap();
+        let state = BatchResponse::from(from_function).coalesced_state();
+        assert_eq!(state["count"], Some(2.serialize(String::new()).unwrap()));
+
+        sender.send(()).unwrap();
+        thread.join().unwrap()?;
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_function() -> anyhow::Result<()> {
+        let (sender, receiver) = oneshot::channel::<()>();
+        let (local_address, thread) = TonicTransport::new(([127, 0, 0, 1], 0).into()).start(
+            FunctionRegistry::new(),
+            async move {
+                let _ = receiver.await;
+            },
+        )?;
+
+        let to_function = BatchBuilder::new(address("self"))
+            .with_message(&"hello".to_string())
+            .unwrap()
+            .to_proto();
+        let code = call_invoke(local_address, to_function).unwrap_err();
+        assert_eq!(code, Code::NotFound);
+
+        sender.send(()).unwrap();
+        thread.join().unwrap()?;
+        Ok(())
+    }
+}
@@ -1,4 +1,8 @@
 use crate::TypeName;
+use std::any::TypeId;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::Mutex;
 
 impl TypeName for bool {
     ///
@@ -21,6 +25,20 @@ impl TypeName for i64 {
     }
 }
 
+/// Shares the typename of `i32`, see the `Serializable` implementation of `u32`.
+impl TypeName for u32 {
+    fn get_typename() -> &'static str {
+        "io.statefun.types/int"
+    }
+}
+
+/// Shares the typename of `i64`, see the `Serializable` implementation of `u64`.
+impl TypeName for u64 {
+    fn get_typename() -> &'static str {
+        "io.statefun.types/long"
+    }
+}
+
 impl TypeName for f32 {
     ///
     fn get_typename() -> &'static str {
@@ -41,3 +59,54 @@ impl TypeName for String {
         "io.statefun.types/string"
     }
 }
+
+impl TypeName for Vec<u8> {
+    fn get_typename() -> &'static str {
+        "io.statefun.types/binary"
+    }
+}
+
+/// Optional values use the typename of the wrapped type with a `?` suffix, for example
+/// "io.statefun.types/int?".
+impl<T: TypeName + 'static> TypeName for Option<T> {
+    fn get_typename() -> &'static str {
+        derived_typename::<Option<T>, _>(|| format!("{}?", T::get_typename()))
+    }
+}
+
+/// Returns the typename of the type `D`, which `derive` computes from another typename at runtime.
+/// `derive` only runs the first time a thread asks for the typename of `D`, later calls are a
+/// lookup in a thread-local map, so that sending or storing such a type doesn't take a lock.
+pub(crate) fn derived_typename<D: 'static, F: FnOnce() -> String>(derive: F) -> &'static str {
+    thread_local! {
+        static DERIVED_TYPENAMES: RefCell<HashMap<TypeId, &'static str>> =
+            RefCell::new(HashMap::new());
+    }
+
+    let type_id = TypeId::of::<D>();
+    if let Some(typename) =
+        DERIVED_TYPENAMES.with(|derived| derived.borrow().get(&type_id).copied())
+    {
+        return typename;
+    }
+    // deriving may ask for the typename of another derived type, so we don't hold the borrow
+    let typename = intern_typename(derive());
+    DERIVED_TYPENAMES.with(|derived| derived.borrow_mut().insert(type_id, typename));
+    typename
+}
+
+/// Returns a `'static` version of a typename that is derived from another typename at runtime.
+/// Each distinct typename is only allocated once and kept around for the lifetime of the program.
+fn intern_typename(typename: String) -> &'static str {
+    static TYPENAMES: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+
+    let mut typenames = TYPENAMES.lock().unwrap();
+    match typenames.iter().find(|interned| **interned == typename) {
+        Some(interned) => interned,
+        None => {
+            let interned: &'static str = Box::leak(typename.into_boxed_str());
+            typenames.push(interned);
+            interned
+        }
+    }
+}
@@ -1,5 +1,6 @@
-use crate::{Expiration, Serializable, TypeName, ValueSpecBase};
+use crate::{Expiration, ExpirationType, NonFinitePolicy, Serializable, TypeName, ValueSpecBase};
 use std::marker::PhantomData;
+use std::time::Duration;
 
 /// Defines the state of the function. Client code can use this type in the call to
 /// `Context::get_state()` as a type-safe method of looking up existing state.
@@ -9,6 +10,8 @@ use std::marker::PhantomData;
 // #[derive(Debug, Hash, Eq, PartialEq, Clone)]
 pub struct ValueSpec<T> {
     pub(crate) spec: ValueSpecBase,
+    pub(crate) non_finite_policy: NonFinitePolicy,
+    pub(crate) default: Option<Vec<u8>>,
     phantom: PhantomData<T>,
 }
 
@@ -17,9 +20,78 @@ impl<T: Serializable<T> + TypeName> ValueSpec<T> {
     pub fn new(name: &'static str, expiration: Expiration) -> ValueSpec<T> {
         ValueSpec {
             spec: ValueSpecBase::new(name, T::get_typename(), expiration),
+            non_finite_policy: NonFinitePolicy::default(),
+            default: None,
             phantom: PhantomData,
         }
     }
+
+    /// Returns a `ValueSpecBuilder` for a state with the given name, which never expires unless
+    /// configured otherwise:
+    ///
+    /// ```
+    /// # use statefun::ValueSpec;
+    /// # use std::time::Duration;
+    /// let seen_count = ValueSpec::<i32>::builder("seen_count")
+    ///     .expire_after_write(Duration::from_secs(5))
+    ///     .build();
+    /// ```
+    pub fn builder(name: &'static str) -> ValueSpecBuilder<T> {
+        ValueSpecBuilder {
+            name,
+            expiration: Expiration::never(),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Sets the `NonFinitePolicy` that is applied when updating this state. This only has an
+    /// effect for `f32` and `f64` state, other types ignore the policy.
+    pub fn with_non_finite_policy(mut self, policy: NonFinitePolicy) -> ValueSpec<T> {
+        self.non_finite_policy = policy;
+        self
+    }
+
+    /// Sets the value that `Context::get_state()` returns when Flink has allocated the state but
+    /// it was never written. This fails if the default value cannot be serialized.
+    pub fn with_default(mut self, default: &T) -> Result<ValueSpec<T>, String> {
+        self.default = Some(default.serialize(T::get_typename().to_string())?);
+        Ok(self)
+    }
+}
+
+/// Builds a `ValueSpec`, see `ValueSpec::builder()`. The expiration methods replace each other,
+/// the last one that is called wins.
+#[derive(Debug)]
+pub struct ValueSpecBuilder<T> {
+    name: &'static str,
+    expiration: Expiration,
+    phantom: PhantomData<T>,
+}
+
+impl<T: Serializable<T> + TypeName> ValueSpecBuilder<T> {
+    /// Expires the state the given time after its initial creation or last write.
+    pub fn expire_after_write(mut self, time_to_live: Duration) -> ValueSpecBuilder<T> {
+        self.expiration = Expiration::new(ExpirationType::AfterWrite, time_to_live);
+        self
+    }
+
+    /// Expires the state the given time after its last read or write.
+    pub fn expire_after_invoke(mut self, time_to_live: Duration) -> ValueSpecBuilder<T> {
+        self.expiration = Expiration::new(ExpirationType::AfterInvoke, time_to_live);
+        self
+    }
+
+    /// Never expires the state, which is the default.
+    pub fn never_expire(mut self) -> ValueSpecBuilder<T> {
+        self.expiration = Expiration::never();
+        self
+    }
+
+    /// Returns the `ValueSpec`, which is the same as one created using `ValueSpec::new()` with the
+    /// configured expiration.
+    pub fn build(self) -> ValueSpec<T> {
+        ValueSpec::new(self.name, self.expiration)
+    }
 }
 
 ///
@@ -29,3 +101,46 @@ impl<T> From<ValueSpec<T>> for ValueSpecBase {
         val.spec
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{Expiration, ExpirationType, ValueSpec, ValueSpecBase};
+
+    fn base(value_spec: ValueSpec<i32>) -> ValueSpecBase {
+        value_spec.into()
+    }
+
+    #[test]
+    fn builder() {
+        let ttl = Duration::from_secs(5);
+        assert_eq!(
+            base(ValueSpec::builder("count").build()),
+            base(ValueSpec::new("count", Expiration::never()))
+        );
+        assert_eq!(
+            base(ValueSpec::builder("count").expire_after_write(ttl).build()),
+            base(ValueSpec::new(
+                "count",
+                Expiration::new(ExpirationType::AfterWrite, ttl)
+            ))
+        );
+        assert_eq!(
+            base(ValueSpec::builder("count").expire_after_invoke(ttl).build()),
+            base(ValueSpec::new(
+                "count",
+                Expiration::new(ExpirationType::AfterInvoke, ttl)
+            ))
+        );
+        assert_eq!(
+            base(
+                ValueSpec::builder("count")
+                    .expire_after_write(ttl)
+                    .never_expire()
+                    .build()
+            ),
+            base(ValueSpec::new("count", Expiration::never()))
+        );
+    }
+}
@@ -1,6 +1,10 @@
+use std::borrow::Borrow;
+use std::hash::{Hash, Hasher};
+
 use crate::Expiration;
 
-/// Used internally by the crate
+/// The untyped part of a `ValueSpec`: the name, typename, and expiration of a state. Functions
+/// are registered with these, and can retrieve them using `Context::declared_specs()`.
 #[derive(Debug, Hash, Eq, PartialEq, Clone)]
 pub struct ValueSpecBase {
     pub(crate) name: String,           // state name
@@ -17,4 +21,90 @@ impl ValueSpecBase {
             expiration,
         }
     }
+
+    /// Returns the name of the state.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the typename of the values of the state.
+    pub fn typename(&self) -> &str {
+        &self.typename
+    }
+
+    /// Returns the `Expiration` of the state.
+    pub fn expiration(&self) -> &Expiration {
+        &self.expiration
+    }
+}
+
+/// The parts of a `ValueSpecBase` that identify a state. Maps that are keyed by `ValueSpecBase` can
+/// be queried with a `(name, typename, expiration)` tuple of borrowed parts through this trait,
+/// which avoids allocating a `ValueSpecBase` for every lookup.
+pub(crate) trait StateKey {
+    /// Returns the name, typename, and expiration of the state.
+    fn parts(&self) -> (&str, &str, &Expiration);
+}
+
+impl StateKey for ValueSpecBase {
+    fn parts(&self) -> (&str, &str, &Expiration) {
+        (&self.name, &self.typename, &self.expiration)
+    }
+}
+
+impl StateKey for (&str, &str, &Expiration) {
+    fn parts(&self) -> (&str, &str, &Expiration) {
+        (self.0, self.1, self.2)
+    }
+}
+
+impl<'a> Borrow<dyn StateKey + 'a> for ValueSpecBase {
+    fn borrow(&self) -> &(dyn StateKey + 'a) {
+        self
+    }
+}
+
+/// Hashes like the derived `Hash` of `ValueSpecBase`, which `Borrow` requires.
+impl Hash for dyn StateKey + '_ {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let (name, typename, expiration) = self.parts();
+        name.hash(state);
+        typename.hash(state);
+        expiration.hash(state);
+    }
+}
+
+impl PartialEq for dyn StateKey + '_ {
+    fn eq(&self, other: &Self) -> bool {
+        self.parts() == other.parts()
+    }
+}
+
+impl Eq for dyn StateKey + '_ {}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use crate::value_spec_base::StateKey;
+    use crate::{Expiration, ExpirationType, ValueSpecBase};
+
+    #[test]
+    fn lookup_by_borrowed_parts() {
+        let expiration = Expiration::new(ExpirationType::AfterWrite, Duration::from_secs(5));
+        let mut state = HashMap::new();
+        state.insert(
+            ValueSpecBase::new("seen", "io.statefun.types/int", expiration.clone()),
+            1,
+        );
+        state.insert(ValueSpecBase::new("seen", "", Expiration::never()), 2);
+
+        let key = ("seen", "io.statefun.types/int", &expiration);
+        assert_eq!(state.get(&key as &dyn StateKey), Some(&1));
+        let key = ("seen", "", &Expiration::never());
+        assert_eq!(state.get(&key as &dyn StateKey), Some(&2));
+        let key = ("seen", "io.statefun.types/int", &Expiration::never());
+        assert_eq!(state.get(&key as &dyn StateKey), None);
+    }
 }
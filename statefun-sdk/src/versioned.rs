@@ -0,0 +1,176 @@
+use crate::type_name::derived_typename;
+use crate::{Serializable, TypeName};
+
+/// Declares the schema version of a message type, for use with [Versioned](Versioned).
+pub trait SchemaVersion {
+    /// The current schema version of this type.
+    const SCHEMA_VERSION: u8;
+}
+
+/// Wraps a message type to serialize it together with its schema version. This allows old and
+/// new versions of a message type to coexist while a deployment is upgraded.
+///
+/// The serialized bytes are prefixed with the schema version and the typename is the typename of
+/// the wrapped type with a version suffix, for example "com.example/User.v2". Use
+/// `Message::get_versioned()` to read messages of any version of the type.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Versioned<T>(pub T);
+
+impl<T: TypeName + SchemaVersion + 'static> TypeName for Versioned<T> {
+    fn get_typename() -> &'static str {
+        derived_typename::<Versioned<T>, _>(|| {
+            format!("{}.v{}", T::get_typename(), T::SCHEMA_VERSION)
+        })
+    }
+}
+
+impl<T: Serializable<T> + TypeName + SchemaVersion> Serializable<Versioned<T>> for Versioned<T> {
+    fn serialize(&self, _typename: String) -> Result<Vec<u8>, String> {
+        let mut result = vec![T::SCHEMA_VERSION];
+        result.extend(self.0.serialize(T::get_typename().to_string())?);
+        Ok(result)
+    }
+
+    fn deserialize(_typename: String, buffer: &[u8]) -> Result<Versioned<T>, String> {
+        let (version, payload) = split_version(buffer)?;
+        if version != T::SCHEMA_VERSION {
+            return Err(format!(
+                "Incompatible schema versions. Expected: {} Payload: {}",
+                T::SCHEMA_VERSION,
+                version
+            ));
+        }
+        T::deserialize(T::get_typename().to_string(), payload).map(Versioned)
+    }
+}
+
+/// Splits the serialized bytes of a [Versioned](Versioned) into the schema version and the
+/// serialized wrapped value.
+pub(crate) fn split_version(buffer: &[u8]) -> Result<(u8, &[u8]), String> {
+    match buffer.split_first() {
+        Some((version, payload)) => Ok((*version, payload)),
+        None => Err("Missing schema version".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use statefun_proto::request_reply::TypedValue;
+
+    #[derive(Debug, PartialEq)]
+    struct UserV1 {
+        name: String,
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct UserV2 {
+        first_name: String,
+        last_name: String,
+    }
+
+    impl TypeName for UserV1 {
+        fn get_typename() -> &'static str {
+            "com.example/User"
+        }
+    }
+
+    impl SchemaVersion for UserV1 {
+        const SCHEMA_VERSION: u8 = 1;
+    }
+
+    impl Serializable<UserV1> for UserV1 {
+        fn serialize(&self, typename: String) -> Result<Vec<u8>, String> {
+            self.name.serialize(typename)
+        }
+
+        fn deserialize(typename: String, buffer: &[u8]) -> Result<UserV1, String> {
+            String::deserialize(typename, buffer).map(|name| UserV1 { name })
+        }
+    }
+
+    impl TypeName for UserV2 {
+        fn get_typename() -> &'static str {
+            "com.example/User"
+        }
+    }
+
+    impl SchemaVersion for UserV2 {
+        const SCHEMA_VERSION: u8 = 2;
+    }
+
+    impl Serializable<UserV2> for UserV2 {
+        fn serialize(&self, typename: String) -> Result<Vec<u8>, String> {
+            format!("{}|{}", self.first_name, self.last_name).serialize(typename)
+        }
+
+        fn deserialize(typename: String, buffer: &[u8]) -> Result<UserV2, String> {
+            let name = String::deserialize(typename, buffer)?;
+            let mut parts = name.splitn(2, '|');
+            Ok(UserV2 {
+                first_name: parts.next().unwrap_or_default().to_string(),
+                last_name: parts.next().unwrap_or_default().to_string(),
+            })
+        }
+    }
+
+    fn to_message<T: Serializable<T> + TypeName>(value: &T) -> Message {
+        let mut typed_value = TypedValue::new();
+        typed_value.set_typename(T::get_typename().to_string());
+        typed_value.set_has_value(true);
+        typed_value.set_value(value.serialize(T::get_typename().to_string()).unwrap());
+        Message::new(typed_value)
+    }
+
+    fn migrate(version: u8, payload: &[u8]) -> Result<UserV2, String> {
+        match version {
+            1 => {
+                let user = UserV1::deserialize(UserV1::get_typename().to_string(), payload)?;
+                Ok(UserV2 {
+                    first_name: user.name,
+                    last_name: "".to_string(),
+                })
+            }
+            _ => Err(format!("Unknown version {}", version)),
+        }
+    }
+
+    #[test]
+    fn versioned_typename() {
+        assert_eq!(Versioned::<UserV1>::get_typename(), "com.example/User.v1");
+        assert_eq!(Versioned::<UserV2>::get_typename(), "com.example/User.v2");
+    }
+
+    #[test]
+    fn decode_old_version_through_migration() {
+        let message = to_message(&Versioned(UserV1 {
+            name: "Flink".to_string(),
+        }));
+
+        assert!(message.get::<Versioned<UserV2>>().is_err());
+        assert_eq!(
+            message.get_versioned::<UserV2, _>(migrate).unwrap(),
+            UserV2 {
+                first_name: "Flink".to_string(),
+                last_name: "".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn decode_current_version() {
+        let user = UserV2 {
+            first_name: "Apache".to_string(),
+            last_name: "Flink".to_string(),
+        };
+        let message = to_message(&Versioned(user));
+
+        assert_eq!(
+            message.get_versioned::<UserV2, _>(migrate).unwrap(),
+            UserV2 {
+                first_name: "Apache".to_string(),
+                last_name: "Flink".to_string(),
+            }
+        );
+    }
+}
@@ -0,0 +1,102 @@
+use std::net::{SocketAddr, TcpListener};
+use std::time::Duration;
+
+use hyper::{Body, Client, Method, Request, StatusCode};
+use protobuf::Message as ProtoMessage;
+
+use statefun::transport::hyper::HyperHttpTransport;
+use statefun::{
+    Address, Effects, EgressIdentifier, FunctionRegistry, FunctionType, Message, Serializable,
+    TypeName,
+};
+use statefun_proto::request_reply::{
+    FromFunction, ToFunction, ToFunction_Invocation, ToFunction_InvocationBatchRequest, TypedValue,
+};
+
+const GREETER: FunctionType = FunctionType::from_static("example", "greeter");
+
+fn free_address() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap()
+}
+
+fn to_function(name: &str) -> ToFunction {
+    let mut argument = TypedValue::new();
+    argument.set_typename(String::get_typename().to_string());
+    argument.set_has_value(true);
+    argument.set_value(
+        name.to_string()
+            .serialize(String::get_typename().to_string())
+            .unwrap(),
+    );
+
+    let mut invocation = ToFunction_Invocation::new();
+    invocation.set_argument(argument);
+
+    let mut batch = ToFunction_InvocationBatchRequest::new();
+    batch.set_target(Address::new(GREETER, "id").into_proto());
+    batch.mut_invocations().push(invocation);
+
+    let mut to_function = ToFunction::new();
+    to_function.set_invocation(batch);
+    to_function
+}
+
+async fn invoke(address: SocketAddr, to_function: ToFunction) -> FromFunction {
+    let client = Client::new();
+    // the server is started on another thread, so we retry until it accepts connections
+    for _ in 0..50 {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(format!("http://{}/", address))
+            .body(Body::from(to_function.write_to_bytes().unwrap()))
+            .unwrap();
+        match client.request(request).await {
+            Ok(response) => {
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                return FromFunction::parse_from_bytes(&body).unwrap();
+            }
+            Err(_) => tokio::time::delay_for(Duration::from_millis(100)).await,
+        }
+    }
+    panic!("Server at {} did not accept connections.", address);
+}
+
+#[tokio::test]
+async fn spawn_invoke_and_shutdown() {
+    let mut registry = FunctionRegistry::new();
+    registry
+        .register_fn(GREETER, vec![], |_context, message: Message| {
+            let greeting = format!("Hello {}", message.get::<String>().unwrap());
+            let mut effects = Effects::new();
+            effects
+                .egress(
+                    EgressIdentifier::from_static("example", "greets"),
+                    &greeting,
+                )
+                .unwrap();
+            effects
+        })
+        .unwrap();
+
+    let address = free_address();
+    let (handle, shutdown_trigger) = HyperHttpTransport::new(address).spawn(registry);
+
+    let mut from_function = invoke(address, to_function("Flink")).await;
+    let egresses = from_function
+        .mut_invocation_result()
+        .take_outgoing_egresses();
+    assert_eq!(egresses.len(), 1);
+    assert_eq!(
+        String::deserialize(
+            String::get_typename().to_string(),
+            egresses[0].get_argument().get_value()
+        )
+        .unwrap(),
+        "Hello Flink"
+    );
+
+    shutdown_trigger.shutdown();
+    handle.join().unwrap().unwrap();
+}